@@ -18,11 +18,19 @@ fn main() -> SynthResult<()> {
     let mut midi = module::MidiModuleBase::open(MIDI_PATH)?;
     midi.set_track(1)?;
     let midi_ptr = Rc::new(midi);
-    let midi_note = Rc::new(module::MidiNoteOutput::new(midi_ptr));
 
-    let mut oscillator = module::Oscillator::new();
-    oscillator.set_exponential_freq_input(Some(midi_note));
-    synth.get_output_module_mut().set_audio_input(Some(Rc::new(oscillator)));
+    let mut patch = module::Patch::new();
+    let midi_note = patch.add_node("midi", module::MidiNoteOutput::new(midi_ptr))?;
+    let oscillator = patch.add_node("oscillator", module::Oscillator::new())?;
+    let envelope = patch.add_node("envelope", module::Envelope::new())?;
+    let vca = patch.add_node("vca", module::Vca::with_mode(module::VcaMode::Exponential))?;
+    patch.connect(midi_note, "pitch", oscillator, "exponential freq")?;
+    patch.connect(midi_note, "gate", envelope, "trigger")?;
+    patch.connect(midi_note, "velocity", envelope, "velocity")?;
+    patch.connect(oscillator, "out", vca, "signal")?;
+    patch.connect(envelope, "out", vca, "control")?;
+    patch.set_output(vca, "out")?;
+    synth.set_patch(patch);
 
     synth.play()?;
 
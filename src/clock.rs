@@ -81,6 +81,12 @@ impl SampleRange {
         self.n_samples
     }
 
+    /// Gets part of the range that starts `offset` samples in
+    pub fn get_sub_range(&self, offset: usize, n_samples: usize) -> SampleRange {
+        let initial_value = (self.initial_value + offset) % self.sample_rate;
+        SampleRange::new(self.sample_rate, initial_value, n_samples)
+    }
+
     pub fn contains_sample(&self, sample_number: usize) -> bool {
         if sample_number > self.sample_rate {
             return false;
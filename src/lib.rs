@@ -10,10 +10,12 @@ pub mod prelude;
 
 mod midi;
 pub mod note;
+pub mod tuning;
 mod clock;
 pub mod module;
 mod output;
 pub mod synth;
+pub mod patch_file;
 
 pub use crate::synth::Synth;
 pub use error::{SynthError, SynthResult};
@@ -1,4 +1,6 @@
 mod sample_buffer;
+mod detector;
+mod limiter;
 mod compressor;
 mod attenuverter;
 mod noise;
@@ -8,22 +10,54 @@ mod mixer;
 mod envelope;
 mod midi;
 mod output;
+mod bitcrusher;
+mod gate;
+mod vca;
+mod multi_segment_envelope;
+mod envelope_follower;
+mod slew;
+mod math;
+mod quantizer;
+mod port;
+mod render_cache;
+mod patch;
+mod parameter;
+mod smoothed;
 //mod voice;
 
+pub use detector::{LevelDetector, DetectionMode};
+pub use limiter::Limiter;
 pub use compressor::Compressor;
 pub use attenuverter::Attenuverter;
 pub use noise::NoiseGenerator;
-pub use oscillator::Oscillator;
-pub use sequencer::Sequencer;
-pub use mixer::Mixer;
-pub use envelope::Envelope;
+pub use oscillator::{Oscillator, Waveform};
+pub use sequencer::{Sequencer, SequencerDirection, SequencerStepKind, StepInfo, SongEntry};
+pub use mixer::{Mixer, MixerInput};
+pub use envelope::{Envelope, EnvelopeCurve, RetriggerMode};
 pub use midi::MidiModuleBase;
-pub use midi::midi_note::MidiNoteOutput;
+pub use midi::midi_note::{MidiNoteOutput, MidiNoteSignal};
 pub use output::Output;
+pub use bitcrusher::Bitcrusher;
+pub use gate::{Gate, GateMode};
+pub use vca::{Vca, VcaMode};
+pub use multi_segment_envelope::{MultiSegmentEnvelope, MultiSegmentMode, Breakpoint};
+pub use envelope_follower::EnvelopeFollower;
+pub use slew::{Slew, SlewShape};
+pub use math::{Combiner, CombineMode, Utility, UtilityMode, Crossfade};
+pub use quantizer::{Quantizer, QuantizerSignal};
+pub use port::OutputPort;
+pub use render_cache::RenderCache;
+pub use patch::{Patch, NodeId, Connection, ConnectionKind};
+pub use parameter::{Parameters, ParameterInfo, ParameterScale};
+pub use smoothed::{SmoothedValue, DEFAULT_SMOOTHING_TIME};
 
+use std::rc::Rc;
 use std::time::Instant;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use crate::clock::SampleRange;
+use crate::{SynthError, SynthResult};
+use crate::patch_file::ModuleSettings;
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub enum EdgeDetection {
@@ -36,9 +70,19 @@ pub enum EdgeDetection {
 pub enum CompressionMode {
     None,
     Compress,
-    Limit
+    Limit,
+    /// Uses a `Limiter`. Only modules that own a `Limiter` can do this since it needs to keep state
+    LookaheadLimit
 }
 
+/// Names of the compression modes in patch files
+const COMPRESSION_MODE_NAMES: [(&str, CompressionMode); 4] = [
+    ("None", CompressionMode::None),
+    ("Compress", CompressionMode::Compress),
+    ("Limit", CompressionMode::Limit),
+    ("LookaheadLimit", CompressionMode::LookaheadLimit)
+];
+
 pub fn compress_audio(data: &mut [f32], compression_mode: CompressionMode) {
     match compression_mode {
         CompressionMode::None => (),
@@ -64,7 +108,9 @@ pub fn compress_audio(data: &mut [f32], compression_mode: CompressionMode) {
                 *datum /= reduction_factor;
             }
         }
-        CompressionMode::Limit => {
+        CompressionMode::Limit | CompressionMode::LookaheadLimit => {
+            // There's no state here to look ahead with so a lookahead limit falls back to clipping.
+            // Modules that want the real thing should run their audio through a `Limiter`
             for datum in data.iter_mut() {
                 if *datum > 1.0 {
                     *datum = 1.0;
@@ -77,11 +123,21 @@ pub fn compress_audio(data: &mut [f32], compression_mode: CompressionMode) {
     }
 }
 
+/// Block IDs handed out so far. Starts at 1 so caches can't mistake a new block for the first one
+static NEXT_BLOCK_ID: AtomicUsize = AtomicUsize::new(1);
+
+fn next_block_id() -> usize {
+    NEXT_BLOCK_ID.fetch_add(1, Ordering::Relaxed)
+}
+
 pub struct OutputInfo {
     pub sample_rate: usize,
     pub channel_count: u16,
     pub current_sample_range: SampleRange,
-    pub timestamp: Instant
+    pub timestamp: Instant,
+    /// Identifies the block being rendered. Every `OutputInfo` gets a different one so modules
+    /// can tell when they're being asked for a block they've already rendered
+    pub block_id: usize
 }
 
 impl OutputInfo {
@@ -89,14 +145,22 @@ impl OutputInfo {
         sample_rate: usize, channel_count: u16,
         current_sample_range: SampleRange, timestamp: Instant
     ) -> Self {
-        OutputInfo { sample_rate, channel_count, current_sample_range, timestamp }
+        let block_id = next_block_id();
+        OutputInfo { sample_rate, channel_count, current_sample_range, timestamp, block_id }
+    }
+
+    /// Makes the info for rendering part of this block on its own. It gets a block ID of its own
+    pub fn get_sub_block(&self, offset: usize, n_samples: usize) -> Self {
+        let current_sample_range = self.current_sample_range.get_sub_range(offset, n_samples);
+        OutputInfo::new(self.sample_rate, self.channel_count, current_sample_range, self.timestamp)
     }
 
     #[cfg(test)]
     pub fn new_basic(sample_rate: usize, current_sample_range: SampleRange) -> Self {
         let channel_count = 1;
         let timestamp = Instant::now();
-        OutputInfo { sample_rate, channel_count, current_sample_range, timestamp }
+        let block_id = next_block_id();
+        OutputInfo { sample_rate, channel_count, current_sample_range, timestamp, block_id }
     }
 }
 
@@ -104,4 +168,55 @@ impl OutputInfo {
 pub trait SynthModule {
     /// Fills a provided buffer with the signal output
     fn fill_output_buffer(&self, buffer: &mut [f32], output_info: &OutputInfo);
+}
+
+/// Trait for modules that put out more than one signal. Each signal comes out of a named port.
+/// Use an `OutputPort` to connect one of them to another module's input
+pub trait MultiOutputModule {
+    /// Gets the names of every output port. A port's index is its position in this list
+    fn get_output_names(&self) -> Vec<String>;
+
+    /// Fills a provided buffer with the signal from one of the output ports
+    fn fill_port_buffer(&self, port: usize, buffer: &mut [f32], output_info: &OutputInfo);
+
+    /// Gets the index of the output port with the given name
+    fn get_output_index(&self, name: &str) -> Option<usize> {
+        self.get_output_names().iter().position(|output_name| output_name == name)
+    }
+}
+
+/// Trait for modules that can be put in a `Patch`. Lets the patch find a module's inputs by name
+/// and connect other modules to them without knowing what kind of module it is
+pub trait PatchModule: SynthModule + Parameters {
+    /// Gets the names of every input. An input's index is its position in this list
+    fn get_input_names(&self) -> Vec<String>;
+
+    /// Connects a module to one of the inputs, or disconnects the input if given `None`
+    fn set_input(&mut self, input: usize, module: Option<Rc<dyn SynthModule>>) -> SynthResult<()>;
+
+    /// Gets the index of the input with the given name
+    fn get_input_index(&self, name: &str) -> Option<usize> {
+        self.get_input_names().iter().position(|input_name| input_name == name)
+    }
+
+    /// Gets the module's output ports if it has more than one output
+    fn as_multi_output(&self) -> Option<&dyn MultiOutputModule> {
+        None
+    }
+
+    /// Gets the module's type and settings to write in a patch file. Modules that can't be saved give `None`
+    fn save_settings(&self) -> Option<ModuleSettings> {
+        None
+    }
+}
+
+/// Makes the error for a `PatchModule` being asked to set an input it doesn't have
+fn no_such_input(input: usize) -> SynthResult<()> {
+    let msg = format!("Module has no input {}", input);
+    Err(SynthError::new(&msg))
+}
+
+/// Turns a list of names into the owned strings that `PatchModule::get_input_names` gives
+fn input_names(names: &[&str]) -> Vec<String> {
+    names.iter().map(|name| name.to_string()).collect()
 }
\ No newline at end of file
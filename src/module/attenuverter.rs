@@ -1,22 +1,41 @@
 use std::rc::Rc;
 
-use super::{SynthModule, OutputInfo};
+use super::{SynthModule, PatchModule, OutputInfo, SmoothedValue, no_such_input, input_names};
+use crate::SynthResult;
+use super::parameter::{Parameters, ParameterInfo, ParameterScale, clamp_parameter, no_such_parameter};
+use crate::patch_file::{Table, ModuleSettings};
+use super::render_cache::BlockCache;
 
 #[derive(Clone)]
 pub struct Attenuverter {
     signal_in: Option<Rc<dyn SynthModule>>,
     control_in: Option<Rc<dyn SynthModule>>,
-    gain: f32,
+    gain: SmoothedValue,
     control_gain: f32,
+    block_cache: BlockCache
 }
 
 impl Attenuverter {
     pub fn new() -> Self {
         let signal_in = None;
         let control_in = None;
-        let gain = 0_f32;
+        let gain = SmoothedValue::new(0.0);
         let control_gain = 1_f32;
-        Self { signal_in, control_in, gain, control_gain }
+        let block_cache = BlockCache::new();
+        Self { signal_in, control_in, gain, control_gain, block_cache }
+    }
+
+    /// Makes an attenuverter from its settings in a patch file
+    pub fn load_settings(settings: &Table) -> SynthResult<Self> {
+        settings.check_keys(&["gain", "control_gain"])?;
+        let mut attenuverter = Self::new();
+        if let Some(gain) = settings.get_float("gain")? {
+            attenuverter.set_gain(gain);
+        }
+        if let Some(control_gain) = settings.get_float("control_gain")? {
+            attenuverter.set_control_gain(control_gain);
+        }
+        Ok(attenuverter)
     }
 
     pub fn set_signal_in(&mut self, signal_in: Option<Rc<dyn SynthModule>>) {
@@ -28,42 +47,104 @@ impl Attenuverter {
     }
 
     pub fn set_gain(&mut self, gain: f32) {
-        self.gain = gain;
+        self.gain.set(gain);
+    }
+
+    pub fn get_gain(&self) -> f32 {
+        self.gain.get()
+    }
+
+    /// Sets how long in milliseconds a gain change takes to ramp in
+    pub fn set_smoothing_time(&mut self, smoothing_time: f32) {
+        self.gain.set_ramp_time(smoothing_time);
+    }
+
+    pub fn get_smoothing_time(&self) -> f32 {
+        self.gain.get_ramp_time()
     }
 
     pub fn set_control_gain(&mut self, control_gain: f32) {
         self.control_gain = control_gain;
     }
 
-    pub fn copy_state_from(&mut self, other: &Self) {
-        // Note: Does not update connections
-        self.gain = other.gain;
-        self.control_gain = other.control_gain;
+    pub fn get_control_gain(&self) -> f32 {
+        self.control_gain
     }
 }
 
 impl SynthModule for Attenuverter {
     fn fill_output_buffer(&self, buffer: &mut [f32], output_info: &OutputInfo) {
-        let buffer_len = buffer.len();
+        self.block_cache.fill(buffer, output_info, |buffer| {
+            let buffer_len = buffer.len();
+
+            // Get raw, unattenuated signal
+            let mut raw_signal = vec![0.0; buffer_len];
+            if let Some(signal_in) = &self.signal_in {
+                signal_in.fill_output_buffer(&mut raw_signal, output_info);   
+            }
+
+            // Get control signal
+            let mut control = vec![0.0; buffer_len];
+            if let Some(control_in) = &self.control_in {
+                control_in.fill_output_buffer(&mut control, output_info);   
+            }
+
+            for i in 0..buffer_len {
+                let control_datum = control[i];
+                let amplitude_factor = 1_f32.min(control_datum + self.gain.next(output_info.sample_rate)); // control + gain or 1.0 if > 1
+                let attenuverted_datum = raw_signal[i] * amplitude_factor;
+                buffer[i] = attenuverted_datum;
+            }
+        });
+    }
+}
+
+impl PatchModule for Attenuverter {
+    fn get_input_names(&self) -> Vec<String> {
+        input_names(&["signal", "control"])
+    }
 
-        // Get raw, unattenuated signal
-        let mut raw_signal = vec![0.0; buffer_len];
-        if let Some(signal_in) = &self.signal_in {
-            signal_in.fill_output_buffer(&mut raw_signal, output_info);   
+    fn set_input(&mut self, input: usize, module: Option<Rc<dyn SynthModule>>) -> SynthResult<()> {
+        match input {
+            0 => self.set_signal_in(module),
+            1 => self.set_control_in(module),
+            _ => return no_such_input(input)
         }
+        Ok(())
+    }
+
+    fn save_settings(&self) -> Option<ModuleSettings> {
+        let mut settings = Table::new();
+        settings.set("gain", self.gain.get());
+        settings.set("control_gain", self.control_gain);
+        Some(ModuleSettings::new("Attenuverter", settings))
+    }
+}
 
-        // Get control signal
-        let mut control = vec![0.0; buffer_len];
-        if let Some(control_in) = &self.control_in {
-            control_in.fill_output_buffer(&mut control, output_info);   
+impl Parameters for Attenuverter {
+    fn get_parameter_info(&self) -> Vec<ParameterInfo> {
+        vec![
+            ParameterInfo::new("gain", "Gain", "", -1.0, 1.0, 0.0, ParameterScale::Linear),
+            ParameterInfo::new("control_gain", "Control Gain", "", -1.0, 1.0, 1.0, ParameterScale::Linear)
+        ]
+    }
+
+    fn get_parameter(&self, id: &str) -> Option<f32> {
+        match id {
+            "gain" => Some(self.gain.get()),
+            "control_gain" => Some(self.control_gain),
+            _ => None
         }
+    }
 
-        for i in 0..buffer_len {
-            let control_datum = control[i];
-            let amplitude_factor = 1_f32.min(control_datum + self.gain); // control + gain or 1.0 if > 1
-            let attenuverted_datum = raw_signal[i] * amplitude_factor;
-            buffer[i] = attenuverted_datum;
+    fn set_parameter(&mut self, id: &str, value: f32) -> SynthResult<()> {
+        let value = clamp_parameter(self, id, value)?;
+        match id {
+            "gain" => self.set_gain(value),
+            "control_gain" => self.set_control_gain(value),
+            _ => return no_such_parameter(id)
         }
+        Ok(())
     }
 }
 
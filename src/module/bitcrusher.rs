@@ -0,0 +1,447 @@
+extern crate rand;
+
+use std::rc::Rc;
+use std::cell::{Cell, RefCell};
+
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+
+use super::{SynthModule, PatchModule, OutputInfo, SmoothedValue, no_such_input, input_names};
+use crate::SynthResult;
+use super::parameter::{Parameters, ParameterInfo, ParameterScale, clamp_parameter, no_such_parameter};
+use super::render_cache::BlockCache;
+
+const MIN_BIT_DEPTH: f32 = 1.0;
+// An f32 only has 24 bits of precision so more than that changes nothing
+const MAX_BIT_DEPTH: f32 = 24.0;
+
+/// Lo-fi effect that reduces the bit depth and the sample rate of a signal.
+/// The bit depth may be fractional. The sample rate reduction is a zero-order hold
+/// running at an arbitrary rate which can optionally be jittered.
+pub struct Bitcrusher {
+    signal_in: Option<Rc<dyn SynthModule>>,
+
+    /// Bits of resolution the signal is quantized to. Fractional values are allowed
+    bit_depth: f32,
+    bit_depth_in: Option<Rc<dyn SynthModule>>,
+    bit_depth_control_gain: f32, // Bits added per unit of control signal
+
+    /// Rate in Hz at which the input is sampled and held
+    hold_rate: f32,
+    hold_rate_in: Option<Rc<dyn SynthModule>>,
+    hold_rate_control_gain: f32, // Hz added per unit of control signal
+    /// Amount the hold period is randomly varied by. 0.0 is no jitter, 1.0 is maximum jitter
+    jitter: f32,
+
+    /// Balance between the dry and crushed signal. 0.0 is fully dry, 1.0 is fully crushed
+    mix: SmoothedValue,
+    mix_in: Option<Rc<dyn SynthModule>>,
+
+    held_value: Cell<f32>,
+    hold_phase: Cell<f32>,
+    hold_threshold: Cell<f32>,
+    rng: RefCell<StdRng>,
+    block_cache: BlockCache
+}
+
+impl Bitcrusher {
+    pub fn new() -> Self {
+        let signal_in = None;
+
+        let bit_depth = 8.0;
+        let bit_depth_in = None;
+        let bit_depth_control_gain = 1.0;
+
+        let hold_rate = 8_000.0;
+        let hold_rate_in = None;
+        let hold_rate_control_gain = 1_000.0;
+        let jitter = 0.0;
+
+        let mix = SmoothedValue::new(1.0);
+        let mix_in = None;
+
+        let held_value = Cell::new(0.0);
+        // Start at the threshold so the very first sample is always latched
+        let hold_phase = Cell::new(1.0);
+        let hold_threshold = Cell::new(1.0);
+        let rng = RefCell::new(StdRng::from_entropy());
+        let block_cache = BlockCache::new();
+
+        Self {
+            signal_in,
+            bit_depth, bit_depth_in, bit_depth_control_gain,
+            hold_rate, hold_rate_in, hold_rate_control_gain, jitter,
+            mix, mix_in,
+            held_value, hold_phase, hold_threshold, rng, block_cache
+        }
+    }
+
+    pub fn set_signal_in(&mut self, signal_in: Option<Rc<dyn SynthModule>>) {
+        self.signal_in = signal_in;
+    }
+
+    pub fn set_bit_depth(&mut self, bit_depth: f32) {
+        self.bit_depth = bit_depth;
+    }
+
+    pub fn get_bit_depth(&self) -> f32 {
+        self.bit_depth
+    }
+
+    pub fn set_bit_depth_in(&mut self, bit_depth_in: Option<Rc<dyn SynthModule>>) {
+        self.bit_depth_in = bit_depth_in;
+    }
+
+    pub fn set_bit_depth_control_gain(&mut self, bit_depth_control_gain: f32) {
+        self.bit_depth_control_gain = bit_depth_control_gain;
+    }
+
+    pub fn get_bit_depth_control_gain(&self) -> f32 {
+        self.bit_depth_control_gain
+    }
+
+    pub fn set_hold_rate(&mut self, hold_rate: f32) {
+        self.hold_rate = hold_rate;
+    }
+
+    pub fn get_hold_rate(&self) -> f32 {
+        self.hold_rate
+    }
+
+    pub fn set_hold_rate_in(&mut self, hold_rate_in: Option<Rc<dyn SynthModule>>) {
+        self.hold_rate_in = hold_rate_in;
+    }
+
+    pub fn set_hold_rate_control_gain(&mut self, hold_rate_control_gain: f32) {
+        self.hold_rate_control_gain = hold_rate_control_gain;
+    }
+
+    pub fn get_hold_rate_control_gain(&self) -> f32 {
+        self.hold_rate_control_gain
+    }
+
+    pub fn set_jitter(&mut self, jitter: f32) {
+        self.jitter = jitter.clamp(0.0, 1.0);
+    }
+
+    pub fn get_jitter(&self) -> f32 {
+        self.jitter
+    }
+
+    /// Seeds the random numbers used for jitter so that the output can be reproduced
+    pub fn set_seed(&mut self, seed: u64) {
+        *self.rng.get_mut() = StdRng::seed_from_u64(seed);
+    }
+
+    pub fn set_mix(&mut self, mix: f32) {
+        self.mix.set(mix);
+    }
+
+    pub fn get_mix(&self) -> f32 {
+        self.mix.get()
+    }
+
+    /// Sets how long in milliseconds a mix change takes to ramp in
+    pub fn set_smoothing_time(&mut self, smoothing_time: f32) {
+        self.mix.set_ramp_time(smoothing_time);
+    }
+
+    pub fn get_smoothing_time(&self) -> f32 {
+        self.mix.get_ramp_time()
+    }
+
+    pub fn set_mix_in(&mut self, mix_in: Option<Rc<dyn SynthModule>>) {
+        self.mix_in = mix_in;
+    }
+
+    /// Quantizes a sample in the range [-1.0, 1.0] to the given number of bits.
+    /// N bits gives 2^N levels spread evenly from -1.0 to 1.0, so 1 bit is just -1.0 and 1.0
+    fn quantize(sample: f32, bit_depth: f32) -> f32 {
+        let bit_depth = bit_depth.clamp(MIN_BIT_DEPTH, MAX_BIT_DEPTH);
+        // Using powf means fractional bit depths smoothly change the step size
+        // rather than jumping between whole bits
+        let levels = 2_f32.powf(bit_depth);
+        let step = 2.0 / (levels - 1.0);
+        // With a fractional bit depth the steps don't land on 1.0, so the top one is cut short
+        let quantized = ((sample.clamp(-1.0, 1.0) + 1.0) / step).round() * step - 1.0;
+        quantized.min(1.0)
+    }
+
+    /// Advances the sample and hold by one sample and returns the value currently held
+    fn hold(&self, sample: f32, hold_rate: f32, sample_rate: usize) -> f32 {
+        let sample_rate = sample_rate as f32;
+        if hold_rate >= sample_rate {
+            // Holding at or above the sample rate means every sample is latched
+            self.held_value.set(sample);
+            return sample;
+        }
+
+        let mut phase = self.hold_phase.get();
+        if phase >= self.hold_threshold.get() {
+            self.held_value.set(sample);
+            phase -= self.hold_threshold.get();
+
+            // Pick how long the next hold lasts. With no jitter it's always a full period
+            let threshold = if self.jitter > 0.0 {
+                let offset = self.rng.borrow_mut().gen::<f32>() * 2.0 - 1.0;
+                1.0 + offset * self.jitter * 0.5
+            }
+            else {
+                1.0
+            };
+            self.hold_threshold.set(threshold);
+        }
+        self.hold_phase.set(phase + hold_rate.max(0.0) / sample_rate);
+        self.held_value.get()
+    }
+}
+
+impl Default for Bitcrusher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SynthModule for Bitcrusher {
+    fn fill_output_buffer(&self, buffer: &mut [f32], output_info: &OutputInfo) {
+        self.block_cache.fill(buffer, output_info, |buffer| {
+            let buffer_len = buffer.len();
+
+            // Get the uncrushed signal
+            let mut signal = vec![0.0; buffer_len];
+            if let Some(signal_in) = &self.signal_in {
+                signal_in.fill_output_buffer(&mut signal, output_info);
+            }
+            else {
+                buffer.fill(0.0);
+                return;
+            }
+
+            // Get modulation signals
+            let mut bit_depth_control = vec![0.0; buffer_len];
+            if let Some(bit_depth_in) = &self.bit_depth_in {
+                bit_depth_in.fill_output_buffer(&mut bit_depth_control, output_info);
+            }
+
+            let mut hold_rate_control = vec![0.0; buffer_len];
+            if let Some(hold_rate_in) = &self.hold_rate_in {
+                hold_rate_in.fill_output_buffer(&mut hold_rate_control, output_info);
+            }
+
+            let mut mix_control = vec![0.0; buffer_len];
+            if let Some(mix_in) = &self.mix_in {
+                mix_in.fill_output_buffer(&mut mix_control, output_info);
+            }
+
+            for i in 0..buffer_len {
+                let dry = signal[i];
+
+                let hold_rate = self.hold_rate + hold_rate_control[i] * self.hold_rate_control_gain;
+                let held = self.hold(dry, hold_rate, output_info.sample_rate);
+
+                let bit_depth = self.bit_depth + bit_depth_control[i] * self.bit_depth_control_gain;
+                let wet = Self::quantize(held, bit_depth);
+
+                let mix = (self.mix.next(output_info.sample_rate) + mix_control[i]).clamp(0.0, 1.0);
+                buffer[i] = dry * (1.0 - mix) + wet * mix;
+            }
+        });
+    }
+}
+
+impl PatchModule for Bitcrusher {
+    fn get_input_names(&self) -> Vec<String> {
+        input_names(&["signal", "bit depth", "hold rate", "mix"])
+    }
+
+    fn set_input(&mut self, input: usize, module: Option<Rc<dyn SynthModule>>) -> SynthResult<()> {
+        match input {
+            0 => self.set_signal_in(module),
+            1 => self.set_bit_depth_in(module),
+            2 => self.set_hold_rate_in(module),
+            3 => self.set_mix_in(module),
+            _ => return no_such_input(input)
+        }
+        Ok(())
+    }
+}
+
+impl Parameters for Bitcrusher {
+    fn get_parameter_info(&self) -> Vec<ParameterInfo> {
+        vec![
+            ParameterInfo::new("bit_depth", "Bit Depth", "bits", MIN_BIT_DEPTH, MAX_BIT_DEPTH, 8.0, ParameterScale::Linear),
+            ParameterInfo::new(
+                "bit_depth_control_gain", "Bit Depth Control Gain", "bits", -24.0, 24.0, 1.0, ParameterScale::Linear
+            ),
+            ParameterInfo::new("hold_rate", "Hold Rate", "Hz", 1.0, 48_000.0, 8_000.0, ParameterScale::Logarithmic),
+            ParameterInfo::new(
+                "hold_rate_control_gain", "Hold Rate Control Gain", "Hz", -20_000.0, 20_000.0, 1_000.0, ParameterScale::Linear
+            ),
+            ParameterInfo::new("jitter", "Jitter", "", 0.0, 1.0, 0.0, ParameterScale::Linear),
+            ParameterInfo::new("mix", "Mix", "", 0.0, 1.0, 1.0, ParameterScale::Linear)
+        ]
+    }
+
+    fn get_parameter(&self, id: &str) -> Option<f32> {
+        match id {
+            "bit_depth" => Some(self.bit_depth),
+            "bit_depth_control_gain" => Some(self.bit_depth_control_gain),
+            "hold_rate" => Some(self.hold_rate),
+            "hold_rate_control_gain" => Some(self.hold_rate_control_gain),
+            "jitter" => Some(self.jitter),
+            "mix" => Some(self.mix.get()),
+            _ => None
+        }
+    }
+
+    fn set_parameter(&mut self, id: &str, value: f32) -> SynthResult<()> {
+        let value = clamp_parameter(self, id, value)?;
+        match id {
+            "bit_depth" => self.set_bit_depth(value),
+            "bit_depth_control_gain" => self.set_bit_depth_control_gain(value),
+            "hold_rate" => self.set_hold_rate(value),
+            "hold_rate_control_gain" => self.set_hold_rate_control_gain(value),
+            "jitter" => self.set_jitter(value),
+            "mix" => self.set_mix(value),
+            _ => return no_such_parameter(id)
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::*;
+    use crate::module::sample_buffer::SampleBuffer;
+    use crate::clock;
+
+    const SAMPLE_RATE: usize = 8;
+    const RAMP: [f32; SAMPLE_RATE] = [0.0, 0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7];
+
+    fn get_bitcrusher_output(bitcrusher: &Bitcrusher) -> Vec<f32> {
+        let mut clock = clock::SampleClock::new(SAMPLE_RATE);
+        let clock_values = clock.get_range(SAMPLE_RATE);
+        let output_info = OutputInfo::new_basic(SAMPLE_RATE, clock_values);
+
+        let mut output_buffer = vec![0_f32; SAMPLE_RATE];
+        bitcrusher.fill_output_buffer(&mut output_buffer, &output_info);
+        output_buffer
+    }
+
+    fn assert_output_eq(got: &[f32], expected: &[f32]) {
+        for (got_datum, expected_datum) in got.iter().zip(expected.iter()) {
+            assert!(
+                float_eq(*got_datum, *expected_datum, 0.0001),
+                "Bitcrusher output does not match expected:\n\tGot: {:?}\n\tExpected: {:?}", got, expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_bit_reduction() {
+        const SIGNAL: [f32; SAMPLE_RATE] = [-1.0, -0.7, -0.6, -0.2, 0.2, 0.6, 0.7, 1.0];
+        const EXPECTED_DATA: [f32; SAMPLE_RATE] = [-1.0, -1.0, -1.0/3.0, -1.0/3.0, 1.0/3.0, 1.0/3.0, 1.0, 1.0];
+        let mut bitcrusher = Bitcrusher::new();
+        bitcrusher.set_signal_in(Some(Rc::new(SampleBuffer::new(SIGNAL.to_vec()))));
+        bitcrusher.set_hold_rate(SAMPLE_RATE as f32);
+        bitcrusher.set_bit_depth(2.0);
+
+        let output = get_bitcrusher_output(&bitcrusher);
+        assert_output_eq(&output, &EXPECTED_DATA);
+    }
+
+    #[test]
+    fn test_one_bit() {
+        const SIGNAL: [f32; SAMPLE_RATE] = [-0.9, -0.5, -0.1, 0.0, 0.1, 0.5, 0.9, -2.0];
+        const EXPECTED_DATA: [f32; SAMPLE_RATE] = [-1.0, -1.0, -1.0, 1.0, 1.0, 1.0, 1.0, -1.0];
+        let mut bitcrusher = Bitcrusher::new();
+        bitcrusher.set_signal_in(Some(Rc::new(SampleBuffer::new(SIGNAL.to_vec()))));
+        bitcrusher.set_hold_rate(SAMPLE_RATE as f32);
+        bitcrusher.set_bit_depth(1.0);
+
+        let output = get_bitcrusher_output(&bitcrusher);
+        assert_output_eq(&output, &EXPECTED_DATA);
+    }
+
+    #[test]
+    fn test_fractional_bits() {
+        // 1.5 bits has levels at -1.0, about 0.094 and a shortened top step up to 1.0
+        const SIGNAL: [f32; SAMPLE_RATE] = [-1.0, 1.0, -0.9, 0.9, 0.0, 0.2, -2.0, 2.0];
+        const EXPECTED_DATA: [f32; SAMPLE_RATE] = [-1.0, 1.0, -1.0, 1.0, 0.09384, 0.09384, -1.0, 1.0];
+        let mut bitcrusher = Bitcrusher::new();
+        bitcrusher.set_signal_in(Some(Rc::new(SampleBuffer::new(SIGNAL.to_vec()))));
+        bitcrusher.set_hold_rate(SAMPLE_RATE as f32);
+        bitcrusher.set_bit_depth(1.5);
+
+        let output = get_bitcrusher_output(&bitcrusher);
+        assert!(output.iter().all(|datum| datum.abs() <= 1.0), "Output went past full scale: {:?}", output);
+        assert_output_eq(&output, &EXPECTED_DATA);
+    }
+
+    #[test]
+    fn test_sample_rate_reduction() {
+        const EXPECTED_DATA: [f32; SAMPLE_RATE] = [0.0, 0.0, 0.0, 0.0, 0.4, 0.4, 0.4, 0.4];
+        let mut bitcrusher = Bitcrusher::new();
+        bitcrusher.set_signal_in(Some(Rc::new(SampleBuffer::new(RAMP.to_vec()))));
+        bitcrusher.set_hold_rate(2.0);
+        bitcrusher.set_bit_depth(MAX_BIT_DEPTH);
+
+        let output = get_bitcrusher_output(&bitcrusher);
+        assert_output_eq(&output, &EXPECTED_DATA);
+    }
+
+    #[test]
+    fn test_dry_mix() {
+        let mut bitcrusher = Bitcrusher::new();
+        bitcrusher.set_signal_in(Some(Rc::new(SampleBuffer::new(RAMP.to_vec()))));
+        bitcrusher.set_hold_rate(1.0);
+        bitcrusher.set_bit_depth(1.0);
+        bitcrusher.set_mix(0.0);
+
+        let output = get_bitcrusher_output(&bitcrusher);
+        assert_output_eq(&output, &RAMP);
+    }
+
+    /// Gets the lengths of each run of held samples, leaving out the last run which may be cut short
+    fn get_jittered_holds(seed: u64) -> Vec<usize> {
+        const BLOCK_SIZE: usize = 128;
+        let ramp: Vec<f32> = (0..BLOCK_SIZE).map(|i| i as f32 / BLOCK_SIZE as f32).collect();
+        let mut bitcrusher = Bitcrusher::new();
+        bitcrusher.set_signal_in(Some(Rc::new(SampleBuffer::new(ramp))));
+        bitcrusher.set_hold_rate(2.0);
+        bitcrusher.set_bit_depth(MAX_BIT_DEPTH);
+        bitcrusher.set_jitter(1.0);
+        bitcrusher.set_seed(seed);
+
+        let mut clock = clock::SampleClock::new(SAMPLE_RATE);
+        let clock_values = clock.get_range(BLOCK_SIZE);
+        let output_info = OutputInfo::new_basic(SAMPLE_RATE, clock_values);
+        let mut output = vec![0_f32; BLOCK_SIZE];
+        bitcrusher.fill_output_buffer(&mut output, &output_info);
+
+        let mut holds = vec![1];
+        for i in 1..BLOCK_SIZE {
+            if output[i] == output[i - 1] {
+                *holds.last_mut().unwrap() += 1;
+            }
+            else {
+                holds.push(1);
+            }
+        }
+        holds.pop();
+        holds
+    }
+
+    #[test]
+    fn test_jitter() {
+        // Without jitter a 2Hz hold at 8Hz lasts 4 samples. Full jitter varies the period by up to half
+        let holds = get_jittered_holds(7);
+        assert!(holds.len() > 10);
+        assert!(holds.iter().all(|hold| (1..=7).contains(hold)), "Holds out of range: {:?}", holds);
+        assert!(holds.iter().any(|hold| *hold != 4), "Jitter did not change any holds: {:?}", holds);
+
+        assert_eq!(holds, get_jittered_holds(7), "Bitcrushers with the same seed held differently");
+        assert_ne!(holds, get_jittered_holds(8), "Bitcrushers with different seeds held the same");
+    }
+}
@@ -1,67 +1,419 @@
 use std::rc::Rc;
 use std::cell::Cell;
 
-use super::{SynthModule, OutputInfo};
-
-const MICROSECONDS_PER_SECOND: f32 = 1_000_000.0;
+use crate::prelude::*;
+use crate::SynthResult;
+use crate::patch_file::{Table, ModuleSettings};
+use super::{SynthModule, PatchModule, OutputInfo, SmoothedValue, no_such_input, input_names};
+use super::detector::{LevelDetector, DetectionMode, DETECTION_MODE_NAMES};
+use super::parameter::{Parameters, ParameterInfo, ParameterScale, clamp_parameter, no_such_parameter, choice_index, choice_value};
+use super::render_cache::BlockCache;
 
+/// A feed-forward compressor. The level of the input (or the sidechain, if one is connected) is
+/// detected and any part of it above the threshold is reduced by the ratio.
 pub struct Compressor {
     signal_in: Option<Rc<dyn SynthModule>>,
-    slew_time: f32, // microseconds
-    compression_factor: Cell<f32>,
-    over_compression: f32, // A boost to the initial compression factor
+    /// If connected, this signal is used to detect the level instead of `signal_in`
+    sidechain_in: Option<Rc<dyn SynthModule>>,
+
+    // Levels here should be in decibels
+    threshold: SmoothedValue,
+    ratio: SmoothedValue,
+    knee_width: SmoothedValue,
+    makeup_gain: SmoothedValue,
+
+    detector: LevelDetector,
+
+    /// Gain reduction in decibels applied to the most recent sample
+    gain_reduction: Cell<f32>,
+    block_cache: BlockCache
 }
 
 impl Compressor {
     pub fn new() -> Self {
         let signal_in = None;
-        let slew_time = MICROSECONDS_PER_SECOND;
-        let compression_factor = Cell::new(1.0);
-        let over_compression = 0.1;
-        Compressor { signal_in, slew_time, compression_factor, over_compression }
+        let sidechain_in = None;
+
+        let threshold = SmoothedValue::new(-12.0);
+        let ratio = SmoothedValue::new(4.0);
+        let knee_width = SmoothedValue::new(0.0);
+        let makeup_gain = SmoothedValue::new(0.0);
+
+        let attack_time = 10.0;
+        let release_time = 100.0;
+        let detector = LevelDetector::new(DetectionMode::Peak, attack_time, release_time);
+
+        let gain_reduction = Cell::new(0.0);
+
+        let block_cache = BlockCache::new();
+        Self { signal_in, sidechain_in, threshold, ratio, knee_width, makeup_gain, detector, gain_reduction, block_cache }
+    }
+
+    /// Makes a compressor from its settings in a patch file
+    pub fn load_settings(settings: &Table) -> SynthResult<Self> {
+        settings.check_keys(&[
+            "threshold", "ratio", "knee_width", "makeup_gain", "attack_time", "release_time", "detection_mode"
+        ])?;
+        let mut compressor = Self::new();
+        if let Some(threshold) = settings.get_float("threshold")? {
+            compressor.set_threshold(threshold);
+        }
+        if let Some(ratio) = settings.get_float("ratio")? {
+            compressor.set_ratio(ratio);
+        }
+        if let Some(knee_width) = settings.get_float("knee_width")? {
+            compressor.set_knee_width(knee_width);
+        }
+        if let Some(makeup_gain) = settings.get_float("makeup_gain")? {
+            compressor.set_makeup_gain(makeup_gain);
+        }
+        if let Some(attack_time) = settings.get_float("attack_time")? {
+            compressor.set_attack_time(attack_time);
+        }
+        if let Some(release_time) = settings.get_float("release_time")? {
+            compressor.set_release_time(release_time);
+        }
+        if let Some(detection_mode) = settings.get_choice("detection_mode", &DETECTION_MODE_NAMES)? {
+            compressor.set_detection_mode(detection_mode);
+        }
+        Ok(compressor)
     }
 
     pub fn set_signal_in(&mut self, input: Option<Rc<dyn SynthModule>>) {
         self.signal_in = input;
     }
 
-    pub fn set_slew_time(&mut self, slew_time: f32) {
-        self.slew_time = slew_time;
+    pub fn set_sidechain_in(&mut self, sidechain_in: Option<Rc<dyn SynthModule>>) {
+        self.sidechain_in = sidechain_in;
     }
 
-    pub fn set_over_compression(&mut self, over_compression: f32) {
-        self.over_compression = over_compression;
+    pub fn set_threshold(&mut self, threshold: f32) {
+        self.threshold.set(threshold);
     }
-}
 
-impl SynthModule for Compressor {
-    fn fill_output_buffer(&self, buffer: &mut [f32], output_info: &OutputInfo) {
-        let buffer_len = buffer.len();
+    pub fn get_threshold(&self) -> f32 {
+        self.threshold.get()
+    }
+
+    pub fn set_ratio(&mut self, ratio: f32) {
+        // A ratio below 1.0 would be expansion
+        self.ratio.set(ratio.max(1.0));
+    }
+
+    pub fn get_ratio(&self) -> f32 {
+        self.ratio.get()
+    }
+
+    pub fn set_knee_width(&mut self, knee_width: f32) {
+        self.knee_width.set(knee_width.max(0.0));
+    }
+
+    pub fn get_knee_width(&self) -> f32 {
+        self.knee_width.get()
+    }
 
-        // Get signal from input
-        let mut signal = vec![0.0; buffer_len];
-        if let Some(signal_in) = &self.signal_in {
-            signal_in.fill_output_buffer(&mut signal, output_info)
+    pub fn set_makeup_gain(&mut self, makeup_gain: f32) {
+        self.makeup_gain.set(makeup_gain);
+    }
+
+    pub fn get_makeup_gain(&self) -> f32 {
+        self.makeup_gain.get()
+    }
+
+    /// Sets how long in milliseconds threshold, ratio, knee width and makeup gain changes take to ramp in
+    pub fn set_smoothing_time(&mut self, smoothing_time: f32) {
+        self.threshold.set_ramp_time(smoothing_time);
+        self.ratio.set_ramp_time(smoothing_time);
+        self.knee_width.set_ramp_time(smoothing_time);
+        self.makeup_gain.set_ramp_time(smoothing_time);
+    }
+
+    pub fn get_smoothing_time(&self) -> f32 {
+        self.threshold.get_ramp_time()
+    }
+
+    pub fn set_attack_time(&mut self, attack_time: f32) {
+        self.detector.set_attack_time(attack_time);
+    }
+
+    pub fn get_attack_time(&self) -> f32 {
+        self.detector.get_attack_time()
+    }
+
+    pub fn set_release_time(&mut self, release_time: f32) {
+        self.detector.set_release_time(release_time);
+    }
+
+    pub fn get_release_time(&self) -> f32 {
+        self.detector.get_release_time()
+    }
+
+    pub fn set_detection_mode(&mut self, detection_mode: DetectionMode) {
+        self.detector.set_mode(detection_mode);
+    }
+
+    pub fn get_detection_mode(&self) -> DetectionMode {
+        self.detector.get_mode()
+    }
+
+    /// Gets the gain reduction in decibels that was applied to the most recently output sample.
+    /// This does not include makeup gain.
+    pub fn get_gain_reduction(&self) -> f32 {
+        self.gain_reduction.get()
+    }
+
+    /// Calculates the gain reduction in decibels for a detected level in decibels
+    fn compute_gain_reduction(level: f32, threshold: f32, ratio: f32, knee_width: f32) -> f32 {
+        let overshoot = level - threshold;
+        let slope = 1.0 / ratio - 1.0;
+        let half_knee = knee_width / 2.0;
+
+        if overshoot <= -half_knee {
+            // Below the knee, nothing happens
+            0.0
+        }
+        else if overshoot < half_knee {
+            // Inside the knee the ratio eases in quadratically
+            let knee_position = overshoot + half_knee;
+            -slope * knee_position * knee_position / (2.0 * knee_width)
         }
         else {
-            buffer.fill(0.0);
-            return;
+            -slope * overshoot
         }
+    }
+}
+
+impl SynthModule for Compressor {
+    fn fill_output_buffer(&self, buffer: &mut [f32], output_info: &OutputInfo) {
+        self.block_cache.fill(buffer, output_info, |buffer| {
+            let buffer_len = buffer.len();
 
-        let mut compression_factor = self.compression_factor.get();
-        for i in 0..buffer_len {
-            if signal[i] < 1.0 {
-                // Set a new compression factor if we need to
-                let new_compression_factor = signal[i] + self.over_compression;
-                compression_factor = compression_factor.max(new_compression_factor);
+            // Get signal from input
+            let mut signal = vec![0.0; buffer_len];
+            if let Some(signal_in) = &self.signal_in {
+                signal_in.fill_output_buffer(&mut signal, output_info)
             }
             else {
-                let microseconds_per_sample = MICROSECONDS_PER_SECOND / output_info.sample_rate as f32;
-                let compression_decrease = microseconds_per_sample / self.slew_time;
-                compression_factor -= compression_decrease;
+                buffer.fill(0.0);
+                return;
             }
-            buffer[i] = signal[i] / compression_factor
+
+            // Detect the level from the sidechain if there is one, otherwise from the signal itself
+            let mut levels = vec![0.0; buffer_len];
+            if let Some(sidechain_in) = &self.sidechain_in {
+                let mut sidechain = vec![0.0; buffer_len];
+                sidechain_in.fill_output_buffer(&mut sidechain, output_info);
+                self.detector.detect(&sidechain, &mut levels, output_info.sample_rate);
+            }
+            else {
+                self.detector.detect(&signal, &mut levels, output_info.sample_rate);
+            }
+
+            let sample_rate = output_info.sample_rate;
+            for i in 0..buffer_len {
+                let threshold = self.threshold.next(sample_rate);
+                let ratio = self.ratio.next(sample_rate);
+                let knee_width = self.knee_width.next(sample_rate);
+                let gain_reduction = Self::compute_gain_reduction(amplitude_to_db(levels[i]), threshold, ratio, knee_width);
+                let makeup_gain = self.makeup_gain.next(sample_rate);
+                buffer[i] = signal[i] * db_to_amplitude(makeup_gain - gain_reduction);
+                self.gain_reduction.set(gain_reduction);
+            }
+        });
+    }
+}
+
+impl PatchModule for Compressor {
+    fn get_input_names(&self) -> Vec<String> {
+        input_names(&["signal", "sidechain"])
+    }
+
+    fn set_input(&mut self, input: usize, module: Option<Rc<dyn SynthModule>>) -> SynthResult<()> {
+        match input {
+            0 => self.set_signal_in(module),
+            1 => self.set_sidechain_in(module),
+            _ => return no_such_input(input)
         }
-        self.compression_factor.set(compression_factor);
+        Ok(())
     }
-}
\ No newline at end of file
+
+    fn save_settings(&self) -> Option<ModuleSettings> {
+        let mut settings = Table::new();
+        settings.set("threshold", self.threshold.get());
+        settings.set("ratio", self.ratio.get());
+        settings.set("knee_width", self.knee_width.get());
+        settings.set("makeup_gain", self.makeup_gain.get());
+        settings.set("attack_time", self.get_attack_time());
+        settings.set("release_time", self.get_release_time());
+        settings.set_choice("detection_mode", self.get_detection_mode(), &DETECTION_MODE_NAMES);
+        Some(ModuleSettings::new("Compressor", settings))
+    }
+}
+
+impl Parameters for Compressor {
+    fn get_parameter_info(&self) -> Vec<ParameterInfo> {
+        vec![
+            ParameterInfo::new("threshold", "Threshold", "dB", -60.0, 0.0, -12.0, ParameterScale::Linear),
+            ParameterInfo::new("ratio", "Ratio", "", 1.0, 20.0, 4.0, ParameterScale::Logarithmic),
+            ParameterInfo::new("knee_width", "Knee Width", "dB", 0.0, 24.0, 0.0, ParameterScale::Linear),
+            ParameterInfo::new("makeup_gain", "Makeup Gain", "dB", -24.0, 24.0, 0.0, ParameterScale::Linear),
+            ParameterInfo::new("attack_time", "Attack Time", "ms", 0.0, 1_000.0, 10.0, ParameterScale::Logarithmic),
+            ParameterInfo::new("release_time", "Release Time", "ms", 0.0, 5_000.0, 100.0, ParameterScale::Logarithmic),
+            ParameterInfo::choice("detection_mode", "Detection Mode", &DETECTION_MODE_NAMES, DetectionMode::Peak)
+        ]
+    }
+
+    fn get_parameter(&self, id: &str) -> Option<f32> {
+        match id {
+            "threshold" => Some(self.threshold.get()),
+            "ratio" => Some(self.ratio.get()),
+            "knee_width" => Some(self.knee_width.get()),
+            "makeup_gain" => Some(self.makeup_gain.get()),
+            "attack_time" => Some(self.get_attack_time()),
+            "release_time" => Some(self.get_release_time()),
+            "detection_mode" => Some(choice_index(&DETECTION_MODE_NAMES, self.get_detection_mode())),
+            _ => None
+        }
+    }
+
+    fn set_parameter(&mut self, id: &str, value: f32) -> SynthResult<()> {
+        let value = clamp_parameter(self, id, value)?;
+        match id {
+            "threshold" => self.set_threshold(value),
+            "ratio" => self.set_ratio(value),
+            "knee_width" => self.set_knee_width(value),
+            "makeup_gain" => self.set_makeup_gain(value),
+            "attack_time" => self.set_attack_time(value),
+            "release_time" => self.set_release_time(value),
+            "detection_mode" => self.set_detection_mode(choice_value(&DETECTION_MODE_NAMES, value)),
+            _ => return no_such_parameter(id)
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module::sample_buffer::SampleBuffer;
+    use crate::clock;
+
+    const SAMPLE_RATE: usize = 10;
+
+    fn get_constant_signal(amplitude: f32) -> SampleBuffer {
+        let samples = vec![amplitude; SAMPLE_RATE];
+        SampleBuffer::new(samples)
+    }
+
+    fn get_compressor_output(compressor: &Compressor) -> Vec<f32> {
+        let mut clock = clock::SampleClock::new(SAMPLE_RATE);
+        let clock_values = clock.get_range(SAMPLE_RATE);
+        let output_info = OutputInfo::new_basic(SAMPLE_RATE, clock_values);
+
+        let mut output_buffer = vec![0_f32; SAMPLE_RATE];
+        compressor.fill_output_buffer(&mut output_buffer, &output_info);
+        output_buffer
+    }
+
+    fn create_instant_compressor() -> Compressor {
+        let mut compressor = Compressor::new();
+        compressor.set_attack_time(0.0);
+        compressor.set_release_time(0.0);
+        compressor.set_threshold(-12.0);
+        compressor.set_ratio(4.0);
+        compressor
+    }
+
+    #[test]
+    fn test_below_threshold() {
+        let mut compressor = create_instant_compressor();
+        compressor.set_signal_in(Some(Rc::new(get_constant_signal(0.1))));
+
+        let output = get_compressor_output(&compressor);
+        for datum in output {
+            assert!(float_eq(datum, 0.1, 0.0001), "Expected signal below threshold to be untouched. Got {}", datum);
+        }
+        assert!(float_eq(compressor.get_gain_reduction(), 0.0, 0.0001));
+    }
+
+    #[test]
+    fn test_above_threshold() {
+        // 0dB in, 12dB over the threshold at 4:1 should come out 3dB over the threshold
+        let expected = db_to_amplitude(-9.0);
+        let mut compressor = create_instant_compressor();
+        compressor.set_signal_in(Some(Rc::new(get_constant_signal(1.0))));
+
+        let output = get_compressor_output(&compressor);
+        for datum in output {
+            assert!(float_eq(datum, expected, 0.0001), "Expected {}. Got {}", expected, datum);
+        }
+        assert!(float_eq(compressor.get_gain_reduction(), 9.0, 0.0001));
+    }
+
+    #[test]
+    fn test_soft_knee() {
+        // Right at the threshold a 12dB knee applies a quarter of the full slope over half the knee
+        let mut compressor = create_instant_compressor();
+        compressor.set_knee_width(12.0);
+        compressor.set_signal_in(Some(Rc::new(get_constant_signal(db_to_amplitude(-12.0)))));
+
+        get_compressor_output(&compressor);
+        let expected_reduction = 0.75 * 6.0 * 6.0 / 24.0;
+        let gain_reduction = compressor.get_gain_reduction();
+        assert!(float_eq(gain_reduction, expected_reduction, 0.0001), "Expected {}. Got {}", expected_reduction, gain_reduction);
+    }
+
+    #[test]
+    fn test_makeup_gain() {
+        let expected = db_to_amplitude(-3.0);
+        let mut compressor = create_instant_compressor();
+        compressor.set_makeup_gain(6.0);
+        compressor.set_signal_in(Some(Rc::new(get_constant_signal(1.0))));
+
+        let output = get_compressor_output(&compressor);
+        for datum in output {
+            assert!(float_eq(datum, expected, 0.0001), "Expected {}. Got {}", expected, datum);
+        }
+    }
+
+    #[test]
+    fn test_sidechain() {
+        // A quiet signal gets ducked by a loud sidechain
+        let expected = 0.1 * db_to_amplitude(-9.0);
+        let mut compressor = create_instant_compressor();
+        compressor.set_signal_in(Some(Rc::new(get_constant_signal(0.1))));
+        compressor.set_sidechain_in(Some(Rc::new(get_constant_signal(1.0))));
+
+        let output = get_compressor_output(&compressor);
+        for datum in output {
+            assert!(float_eq(datum, expected, 0.0001), "Expected {}. Got {}", expected, datum);
+        }
+    }
+
+    #[test]
+    fn test_threshold_smoothing() {
+        const SMOOTHING_SAMPLE_RATE: usize = 1000;
+        let get_output = |compressor: &Compressor| {
+            let mut clock = clock::SampleClock::new(SMOOTHING_SAMPLE_RATE);
+            let output_info = OutputInfo::new_basic(SMOOTHING_SAMPLE_RATE, clock.get_range(SAMPLE_RATE * 2));
+            let mut output_buffer = vec![0_f32; SAMPLE_RATE * 2];
+            compressor.fill_output_buffer(&mut output_buffer, &output_info);
+            output_buffer
+        };
+
+        // 0dB in, 12dB over the threshold at 4:1 should come out at -9dB
+        let mut compressor = create_instant_compressor();
+        compressor.set_signal_in(Some(Rc::new(SampleBuffer::new(vec![1.0; SAMPLE_RATE * 2]))));
+        let output = get_output(&compressor);
+        assert!(float_eq(amplitude_to_db(output[SAMPLE_RATE * 2 - 1]), -9.0, 0.01));
+
+        // Changing the threshold mid playback ramps over 10ms, which is 10 samples here
+        compressor.set_threshold(-24.0);
+        let output = get_output(&compressor);
+        for (i, got) in output.iter().enumerate() {
+            let threshold = -12.0 - 1.2 * (i + 1).min(10) as f32;
+            let expected = 0.75 * threshold;
+            assert!(float_eq(amplitude_to_db(*got), expected, 0.01), "Expected {}dB at sample {}. Got {:?}", expected, i, output);
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use std::cell::Cell;
+
+const MILLISECONDS_PER_SECOND: f32 = 1000.0;
+
+/// Gets the one-pole smoothing coefficient for a time constant in milliseconds.
+/// A time of 0 gives a coefficient of 0 which means the smoothed value jumps immediately.
+pub(super) fn smoothing_coefficient(time: f32, sample_rate: usize) -> f32 {
+    let time_in_samples = time * sample_rate as f32 / MILLISECONDS_PER_SECOND;
+    if time_in_samples <= 0.0 {
+        return 0.0;
+    }
+    (-1.0 / time_in_samples).exp()
+}
+
+/// How a `LevelDetector` measures the level of a signal
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum DetectionMode {
+    /// Follows the absolute value of the signal
+    Peak,
+    /// Follows the root mean square of the signal
+    Rms
+}
+
+/// Names of the detection modes in patch files
+pub(super) const DETECTION_MODE_NAMES: [(&str, DetectionMode); 2] = [
+    ("Peak", DetectionMode::Peak),
+    ("Rms", DetectionMode::Rms)
+];
+
+/// Follows the level of a signal with separate attack and release ballistics.
+/// Used by the dynamics modules to decide how loud their input is.
+#[derive(Debug, Clone)]
+pub struct LevelDetector {
+    mode: DetectionMode,
+    // Times here should be in milliseconds
+    attack_time: f32,
+    release_time: f32,
+
+    // For RMS detection this is the smoothed power, not the level
+    state: Cell<f32>
+}
+
+impl LevelDetector {
+    pub fn new(mode: DetectionMode, attack_time: f32, release_time: f32) -> Self {
+        let state = Cell::new(0.0);
+        Self { mode, attack_time, release_time, state }
+    }
+
+    pub fn set_mode(&mut self, mode: DetectionMode) {
+        self.mode = mode;
+    }
+
+    pub fn get_mode(&self) -> DetectionMode {
+        self.mode
+    }
+
+    pub fn set_attack_time(&mut self, attack_time: f32) {
+        self.attack_time = attack_time;
+    }
+
+    pub fn get_attack_time(&self) -> f32 {
+        self.attack_time
+    }
+
+    pub fn set_release_time(&mut self, release_time: f32) {
+        self.release_time = release_time;
+    }
+
+    pub fn get_release_time(&self) -> f32 {
+        self.release_time
+    }
+
+    /// Gets the most recently detected level
+    pub fn get_level(&self) -> f32 {
+        match self.mode {
+            DetectionMode::Peak => self.state.get(),
+            DetectionMode::Rms  => self.state.get().sqrt()
+        }
+    }
+
+    /// Forgets the level of everything that has been detected so far
+    pub fn reset(&self) {
+        self.state.set(0.0);
+    }
+
+    /// Detects the level of each sample in `signal` and writes it to `levels`
+    pub fn detect(&self, signal: &[f32], levels: &mut [f32], sample_rate: usize) {
+        debug_assert!(signal.len() == levels.len());
+        let attack_coefficient = smoothing_coefficient(self.attack_time, sample_rate);
+        let release_coefficient = smoothing_coefficient(self.release_time, sample_rate);
+
+        let mut state = self.state.get();
+        for (sample, level) in signal.iter().zip(levels.iter_mut()) {
+            let input = match self.mode {
+                DetectionMode::Peak => sample.abs(),
+                DetectionMode::Rms  => sample * sample
+            };
+            let coefficient = if input > state { attack_coefficient } else { release_coefficient };
+            state = coefficient * state + (1.0 - coefficient) * input;
+
+            *level = match self.mode {
+                DetectionMode::Peak => state,
+                DetectionMode::Rms  => state.sqrt()
+            };
+        }
+        self.state.set(state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::*;
+
+    #[test]
+    fn test_instant_peak() {
+        const SIGNAL: [f32; 4] = [0.5, -1.0, 0.25, 0.0];
+        let detector = LevelDetector::new(DetectionMode::Peak, 0.0, 0.0);
+        let mut levels = [0.0; 4];
+        detector.detect(&SIGNAL, &mut levels, 4);
+        assert_eq!(levels, [0.5, 1.0, 0.25, 0.0]);
+    }
+
+    #[test]
+    fn test_rms_of_square() {
+        // A full scale square wave has an RMS of 1.0
+        let signal: Vec<f32> = (0..1000).map(|i| if i % 2 == 0 { 1.0 } else { -1.0 }).collect();
+        let detector = LevelDetector::new(DetectionMode::Rms, 1.0, 1.0);
+        let mut levels = vec![0.0; signal.len()];
+        detector.detect(&signal, &mut levels, 1000);
+        assert!(float_eq(detector.get_level(), 1.0, 0.001), "Expected RMS of 1.0. Got {}", detector.get_level());
+    }
+}
@@ -1,51 +1,251 @@
 use std::rc::Rc;
 use std::cell::Cell;
 
-use super::{SynthModule, OutputInfo};
+use super::{SynthModule, PatchModule, OutputInfo, SmoothedValue, no_such_input, input_names};
+use crate::SynthResult;
+use crate::patch_file::{Table, Value, ModuleSettings};
+use super::parameter::{Parameters, ParameterInfo, ParameterScale, clamp_parameter, no_such_parameter, choice_index, choice_value, toggle_value};
+use super::render_cache::BlockCache;
 
-#[derive(Debug, Clone, Copy, Hash)]
-enum Adsr {
+const MILLISECONDS_PER_SECOND: f32 = 1000.0;
+
+/// Curvature used by `EnvelopeCurve::Exponential`. `EnvelopeCurve::Logarithmic` uses the negative of this
+const DEFAULT_CURVATURE: f32 = 4.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Stage {
+    Delay,
     Attack,
+    Hold,
     Decay,
     Sustain,
     Release,
     Done
 }
 
+/// The shape of an envelope segment as it moves from one level to the next
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EnvelopeCurve {
+    /// Moves at a constant rate
+    Linear,
+    /// Moves quickly at first and eases into its target, like an analog envelope
+    Exponential,
+    /// Starts slowly and speeds up as it approaches its target
+    Logarithmic,
+    /// Any curvature. 0.0 is linear, positive values behave like `Exponential`
+    /// and negative values behave like `Logarithmic`. Larger magnitudes bend more
+    Custom(f32)
+}
+
+impl EnvelopeCurve {
+    /// Gets the named curve with this curvature if there is one, otherwise a custom curve
+    fn from_curvature(curvature: f32) -> Self {
+        match CURVE_NAMES.iter().find(|(_, curve)| curve.get_curvature() == curvature) {
+            Some((_, curve)) => *curve,
+            None => EnvelopeCurve::Custom(curvature)
+        }
+    }
+
+    fn get_curvature(&self) -> f32 {
+        match self {
+            EnvelopeCurve::Linear => 0.0,
+            EnvelopeCurve::Exponential => DEFAULT_CURVATURE,
+            EnvelopeCurve::Logarithmic => -DEFAULT_CURVATURE,
+            EnvelopeCurve::Custom(curvature) => *curvature
+        }
+    }
+
+    /// Maps how far through a segment we are (0.0 to 1.0) to how far we've moved towards its target (0.0 to 1.0)
+    pub fn apply(&self, progress: f32) -> f32 {
+        let curvature = self.get_curvature();
+        if curvature.abs() < 0.0001 {
+            return progress;
+        }
+        (1.0 - (-curvature * progress).exp()) / (1.0 - (-curvature).exp())
+    }
+}
+
+/// What an `Envelope` does when it is triggered while it is already running
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RetriggerMode {
+    /// Drop to zero and start again from the beginning
+    Restart,
+    /// Start again from the beginning, but attack from whatever level the envelope is at now
+    FromCurrentLevel,
+    /// Ignore triggers while the envelope is held. If it's releasing, head back to the sustain
+    /// level without attacking again
+    Legato
+}
+
+/// Names of the curves in patch files. Custom curves are written as their curvature
+const CURVE_NAMES: [(&str, EnvelopeCurve); 3] = [
+    ("Linear", EnvelopeCurve::Linear),
+    ("Exponential", EnvelopeCurve::Exponential),
+    ("Logarithmic", EnvelopeCurve::Logarithmic)
+];
+
+const RETRIGGER_MODE_NAMES: [(&str, RetriggerMode); 3] = [
+    ("Restart", RetriggerMode::Restart),
+    ("FromCurrentLevel", RetriggerMode::FromCurrentLevel),
+    ("Legato", RetriggerMode::Legato)
+];
+
+fn save_curve(settings: &mut Table, key: &str, curve: EnvelopeCurve) {
+    match curve {
+        EnvelopeCurve::Custom(curvature) => settings.set(key, curvature),
+        curve => settings.set_choice(key, curve, &CURVE_NAMES)
+    }
+}
+
+fn load_curve(settings: &Table, key: &str) -> SynthResult<Option<EnvelopeCurve>> {
+    match settings.get(key) {
+        Some(Value::String(_)) => settings.get_choice(key, &CURVE_NAMES),
+        _ => Ok(settings.get_float(key)?.map(EnvelopeCurve::Custom))
+    }
+}
+
+/// A DAHDSR envelope. The delay and hold stages are skipped when their times are zero, which
+/// makes this a regular ADSR by default.
 #[derive(Clone)]
 pub struct Envelope {
-    // Times here should be in milliseconds
+    // Times here should be in milliseconds. Attack and release times are how long it takes
+    // to move across the full range so moving from part way through is quicker.
+    delay_time: f32,
     attack_time: f32,
+    hold_time: f32,
     decay_time: f32,
-    sustain_level: f32,
+    sustain_level: SmoothedValue,
     release_time: f32,
 
-    stage: Cell<Adsr>,
+    attack_curve: EnvelopeCurve,
+    decay_curve: EnvelopeCurve,
+    release_curve: EnvelopeCurve,
+
+    retrigger_mode: RetriggerMode,
+    /// If true, the attack and decay stages cycle for as long as the envelope is held
+    looping: bool,
+
+    // Velocity sensitivity amounts between 0.0 and 1.0. At 1.0 the peak level follows the velocity
+    // exactly and a full velocity note has no attack
+    velocity_to_level: f32,
+    velocity_to_attack: f32,
+    velocity_in: Option<Rc<dyn SynthModule>>,
+    // Scaling taken from the velocity of the most recent trigger
+    velocity_level: Cell<f32>,
+    velocity_attack_scale: Cell<f32>,
+
+    stage: Cell<Stage>,
     previous_value: Cell<f32>,
+    stage_start_value: Cell<f32>,
+    stage_samples_elapsed: Cell<usize>,
 
     trigger: Option<Rc<dyn SynthModule>>,
     trigger_tolerance: f32, // Minimum value at which envelope is triggered
-    triggered: Cell<bool>
+    triggered: Cell<bool>,
+    block_cache: BlockCache
 }
 
 impl Envelope {
     pub fn new() -> Self {
+        let delay_time = 0.0;
         let attack_time = 0.0;
+        let hold_time = 0.0;
         let decay_time = 0.0;
-        let sustain_level = 1.0;
+        let sustain_level = SmoothedValue::new(1.0);
         let release_time = 0.0;
 
-        let stage = Cell::new(Adsr::Done);
+        let attack_curve = EnvelopeCurve::Linear;
+        let decay_curve = EnvelopeCurve::Linear;
+        let release_curve = EnvelopeCurve::Linear;
+
+        let retrigger_mode = RetriggerMode::FromCurrentLevel;
+        let looping = false;
+
+        let velocity_to_level = 0.0;
+        let velocity_to_attack = 0.0;
+        let velocity_in = None;
+        let velocity_level = Cell::new(1.0);
+        let velocity_attack_scale = Cell::new(1.0);
+
+        let stage = Cell::new(Stage::Done);
         let previous_value = Cell::new(0.0);
+        let stage_start_value = Cell::new(0.0);
+        let stage_samples_elapsed = Cell::new(0);
 
         let trigger = None;
         let trigger_tolerance = 0.5;
         let triggered = Cell::new(false);
 
+        let block_cache = BlockCache::new();
+
         Self { 
-            attack_time, decay_time, sustain_level, release_time,
-            stage, previous_value, trigger, trigger_tolerance, triggered
+            delay_time, attack_time, hold_time, decay_time, sustain_level, release_time,
+            attack_curve, decay_curve, release_curve, retrigger_mode, looping,
+            velocity_to_level, velocity_to_attack, velocity_in, velocity_level, velocity_attack_scale,
+            stage, previous_value, stage_start_value, stage_samples_elapsed,
+            trigger, trigger_tolerance, triggered, block_cache
+        }
+    }
+
+    /// Makes an envelope from its settings in a patch file
+    pub fn load_settings(settings: &Table) -> SynthResult<Self> {
+        settings.check_keys(&[
+            "delay_time", "attack_time", "hold_time", "decay_time", "sustain_level", "release_time",
+            "attack_curve", "decay_curve", "release_curve", "retrigger_mode", "looping",
+            "velocity_to_level", "velocity_to_attack", "trigger_tolerance"
+        ])?;
+        let mut envelope = Self::new();
+        if let Some(delay_time) = settings.get_float("delay_time")? {
+            envelope.set_delay_time(delay_time);
+        }
+        if let Some(attack_time) = settings.get_float("attack_time")? {
+            envelope.set_attack_time(attack_time);
+        }
+        if let Some(hold_time) = settings.get_float("hold_time")? {
+            envelope.set_hold_time(hold_time);
+        }
+        if let Some(decay_time) = settings.get_float("decay_time")? {
+            envelope.set_decay_time(decay_time);
+        }
+        if let Some(sustain_level) = settings.get_float("sustain_level")? {
+            envelope.set_sustain_level(sustain_level);
+        }
+        if let Some(release_time) = settings.get_float("release_time")? {
+            envelope.set_release_time(release_time);
+        }
+        if let Some(attack_curve) = load_curve(settings, "attack_curve")? {
+            envelope.set_attack_curve(attack_curve);
+        }
+        if let Some(decay_curve) = load_curve(settings, "decay_curve")? {
+            envelope.set_decay_curve(decay_curve);
         }
+        if let Some(release_curve) = load_curve(settings, "release_curve")? {
+            envelope.set_release_curve(release_curve);
+        }
+        if let Some(retrigger_mode) = settings.get_choice("retrigger_mode", &RETRIGGER_MODE_NAMES)? {
+            envelope.set_retrigger_mode(retrigger_mode);
+        }
+        if let Some(looping) = settings.get_bool("looping")? {
+            envelope.set_looping(looping);
+        }
+        if let Some(velocity_to_level) = settings.get_float("velocity_to_level")? {
+            envelope.set_velocity_to_level(velocity_to_level);
+        }
+        if let Some(velocity_to_attack) = settings.get_float("velocity_to_attack")? {
+            envelope.set_velocity_to_attack(velocity_to_attack);
+        }
+        if let Some(trigger_tolerance) = settings.get_float("trigger_tolerance")? {
+            envelope.set_trigger_tolerance(trigger_tolerance);
+        }
+        Ok(envelope)
+    }
+
+    pub fn set_delay_time(&mut self, delay_time: f32) {
+        self.delay_time = delay_time;
+    }
+
+    pub fn get_delay_time(&self) -> f32 {
+        self.delay_time
     }
 
     pub fn set_attack_time(&mut self, attack_time: f32) {
@@ -55,6 +255,14 @@ impl Envelope {
     pub fn get_attack_time(&self) -> f32 {
         self.attack_time
     }
+
+    pub fn set_hold_time(&mut self, hold_time: f32) {
+        self.hold_time = hold_time;
+    }
+
+    pub fn get_hold_time(&self) -> f32 {
+        self.hold_time
+    }
     
     pub fn set_decay_time(&mut self, decay_time: f32) {
         self.decay_time = decay_time;
@@ -65,11 +273,20 @@ impl Envelope {
     }
 
     pub fn set_sustain_level(&mut self, sustain_level: f32) {
-        self.sustain_level = sustain_level;
+        self.sustain_level.set(sustain_level);
     }
 
     pub fn get_sustain_level(&self) -> f32 {
-        self.sustain_level
+        self.sustain_level.get()
+    }
+
+    /// Sets how long in milliseconds a sustain level change takes to ramp in
+    pub fn set_smoothing_time(&mut self, smoothing_time: f32) {
+        self.sustain_level.set_ramp_time(smoothing_time);
+    }
+
+    pub fn get_smoothing_time(&self) -> f32 {
+        self.sustain_level.get_ramp_time()
     }
 
     pub fn set_release_time(&mut self, release_time: f32) {
@@ -80,6 +297,68 @@ impl Envelope {
         self.release_time
     }
 
+    pub fn set_attack_curve(&mut self, attack_curve: EnvelopeCurve) {
+        self.attack_curve = attack_curve;
+    }
+
+    pub fn get_attack_curve(&self) -> EnvelopeCurve {
+        self.attack_curve
+    }
+
+    pub fn set_decay_curve(&mut self, decay_curve: EnvelopeCurve) {
+        self.decay_curve = decay_curve;
+    }
+
+    pub fn get_decay_curve(&self) -> EnvelopeCurve {
+        self.decay_curve
+    }
+
+    pub fn set_release_curve(&mut self, release_curve: EnvelopeCurve) {
+        self.release_curve = release_curve;
+    }
+
+    pub fn get_release_curve(&self) -> EnvelopeCurve {
+        self.release_curve
+    }
+
+    pub fn set_retrigger_mode(&mut self, retrigger_mode: RetriggerMode) {
+        self.retrigger_mode = retrigger_mode;
+    }
+
+    pub fn get_retrigger_mode(&self) -> RetriggerMode {
+        self.retrigger_mode
+    }
+
+    pub fn set_looping(&mut self, looping: bool) {
+        self.looping = looping;
+    }
+
+    pub fn is_looping(&self) -> bool {
+        self.looping
+    }
+
+    pub fn set_velocity_to_level(&mut self, velocity_to_level: f32) {
+        self.velocity_to_level = velocity_to_level.clamp(0.0, 1.0);
+    }
+
+    pub fn get_velocity_to_level(&self) -> f32 {
+        self.velocity_to_level
+    }
+
+    pub fn set_velocity_to_attack(&mut self, velocity_to_attack: f32) {
+        self.velocity_to_attack = velocity_to_attack.clamp(0.0, 1.0);
+    }
+
+    pub fn get_velocity_to_attack(&self) -> f32 {
+        self.velocity_to_attack
+    }
+
+    /// Sets where the velocity comes from, e.g. a `MidiNoteOutput` putting out `MidiNoteSignal::Velocity`.
+    /// Velocity is read when the envelope is triggered. Without an input velocity is always 1.0
+    pub fn set_velocity_in(&mut self, velocity_in: Option<Rc<dyn SynthModule>>) {
+        self.velocity_in = velocity_in;
+    }
+
     pub fn set_trigger(&mut self, trigger: Option<Rc<dyn SynthModule>>) {
         self.trigger = trigger;
     }
@@ -93,115 +372,292 @@ impl Envelope {
     }
 
     pub fn trigger(&self) {
-        self.stage.set(Adsr::Attack);
+        self.trigger_with_velocity(1.0);
+    }
+
+    /// Triggers the envelope for a note with a velocity between 0.0 and 1.0
+    pub fn trigger_with_velocity(&self, velocity: f32) {
+        match self.retrigger_mode {
+            RetriggerMode::Restart => self.previous_value.set(0.0),
+            RetriggerMode::FromCurrentLevel => (),
+            RetriggerMode::Legato => {
+                if self.triggered.get() {
+                    return;
+                }
+                if self.stage.get() != Stage::Done {
+                    // Still sounding. Pick up where we left off without attacking again
+                    self.triggered.set(true);
+                    self.enter_stage(Stage::Decay);
+                    return;
+                }
+            }
+        }
+
+        // Rescale where we are so the output doesn't jump when the new note has a different peak
+        let velocity = velocity.clamp(0.0, 1.0);
+        let old_level = self.velocity_level.get();
+        let new_level = 1.0 - self.velocity_to_level * (1.0 - velocity);
+        if new_level > 0.0 {
+            self.previous_value.set((self.previous_value.get() * old_level / new_level).min(1.0));
+        }
+        self.velocity_level.set(new_level);
+        self.velocity_attack_scale.set(1.0 - self.velocity_to_attack * velocity);
+
         self.triggered.set(true);
+        self.enter_stage(Stage::Delay);
     }
 
     pub fn release(&self) {
-        self.stage.set(Adsr::Release);
         self.triggered.set(false);
+        self.enter_stage(Stage::Release);
     }
 
-    pub fn copy_state_from(&mut self, other: &Self) {
-        // Note: Does not update trigger connection
-        self.attack_time = other.attack_time;
-        self.decay_time = other.decay_time;
-        self.sustain_level = other.sustain_level;
-        self.release_time = other.release_time;
-        self.trigger_tolerance = other.trigger_tolerance;
+    /// Moves to a new stage starting from the current value. Stages that would take no time are skipped.
+    fn enter_stage(&self, stage: Stage) {
+        let mut stage = stage;
+        loop {
+            stage = match stage {
+                Stage::Delay if self.delay_time <= 0.0 => Stage::Attack,
+                Stage::Hold if self.hold_time <= 0.0 => Stage::Decay,
+                Stage::Decay if self.decay_time <= 0.0 || self.sustain_level.get_current() >= 1.0 => {
+                    // There is no decay stage
+                    self.previous_value.set(self.sustain_level.get_current());
+                    Stage::Sustain
+                },
+                // Looping goes back to attack rather than sustaining. Attack always takes at least a sample
+                // so this can't spin forever
+                Stage::Sustain if self.looping && self.triggered.get() => Stage::Attack,
+                _ => break
+            };
+        }
+        self.stage.set(stage);
+        self.stage_start_value.set(self.previous_value.get());
+        self.stage_samples_elapsed.set(0);
     }
 
-    fn get_attack(&self, sample_rate: usize) -> f32 {
-        let time_in_milliseconds = 1000.0 / sample_rate as f32;
-        let increase_factor = time_in_milliseconds / self.attack_time;
-        let envelope_value = self.previous_value.get() + increase_factor;
+    fn milliseconds_to_samples(time: f32, sample_rate: usize) -> f32 {
+        time * sample_rate as f32 / MILLISECONDS_PER_SECOND
+    }
+
+    /// Advances a stage that only waits and returns true if it is done
+    fn advance_wait(&self, time: f32, sample_rate: usize) -> bool {
+        let elapsed = self.stage_samples_elapsed.get() + 1;
+        self.stage_samples_elapsed.set(elapsed);
+        elapsed as f32 >= Self::milliseconds_to_samples(time, sample_rate)
+    }
+
+    /// Advances a stage that moves towards `target` over `time` milliseconds along `curve`.
+    /// Enters `next_stage` once the target is reached.
+    fn advance_segment(
+        &self, time: f32, target: f32, curve: EnvelopeCurve, next_stage: Stage, sample_rate: usize
+    ) -> f32 {
+        let elapsed = self.stage_samples_elapsed.get() + 1;
+        self.stage_samples_elapsed.set(elapsed);
+
+        let duration = Self::milliseconds_to_samples(time, sample_rate);
+        let progress = if duration > 0.0 { (elapsed as f32 / duration).min(1.0) } else { 1.0 };
+        let start = self.stage_start_value.get();
+        let envelope_value = start + (target - start) * curve.apply(progress);
         debug_assert!(envelope_value.is_finite());
-        if envelope_value >= 1.0 {
-            if self.decay_time > 0.0 && self.sustain_level != 1.0 {
-                // There is a decay stage
-                self.stage.set(Adsr::Decay);
-                self.previous_value.set(1.0);
-                return 1.0;
-            }
-            else {
-                // There is no decay stage
-                self.stage.set(Adsr::Sustain);
-                self.previous_value.set(self.sustain_level);
-                return 1.0
-            }
-            
+
+        if progress >= 1.0 {
+            self.previous_value.set(target);
+            self.enter_stage(next_stage);
+            return target;
         }
         self.previous_value.set(envelope_value);
         envelope_value
     }
 
+    fn get_attack(&self, sample_rate: usize) -> f32 {
+        let distance = (1.0 - self.stage_start_value.get()).max(0.0);
+        let attack_time = self.attack_time * self.velocity_attack_scale.get();
+        self.advance_segment(attack_time * distance, 1.0, self.attack_curve, Stage::Hold, sample_rate)
+    }
+
     fn get_decay(&self, sample_rate: usize) -> f32 {
-        let time_in_milliseconds = 1000.0 / sample_rate as f32;
-        let decrease_factor = time_in_milliseconds * (1.0 - self.sustain_level) / self.decay_time;
-        let envelope_value = self.previous_value.get() - decrease_factor;
-        debug_assert!(envelope_value.is_finite());
-        if envelope_value <= self.sustain_level {
-            self.stage.set(Adsr::Sustain);
-            self.previous_value.set(self.sustain_level);
-            return self.sustain_level;
-        }
-        self.previous_value.set(envelope_value);
-        envelope_value
+        let sustain_level = self.sustain_level.get_current();
+        let distance = (self.stage_start_value.get() - sustain_level).abs() / (1.0 - sustain_level);
+        self.advance_segment(
+            self.decay_time * distance, sustain_level, self.decay_curve, Stage::Sustain, sample_rate
+        )
     }
 
     fn get_release(&self, sample_rate: usize) -> f32 {
-        let time_in_milliseconds = 1000.0 / sample_rate as f32;
-        let decrease_factor = time_in_milliseconds / self.release_time;
-        let envelope_value = self.previous_value.get() - decrease_factor;
-        debug_assert!(envelope_value.is_finite());
-        if envelope_value <= 0.0 {
-            self.stage.set(Adsr::Done);
-            self.previous_value.set(0.0);
-            return 0.0;
-        }
-        self.previous_value.set(envelope_value);
-        envelope_value
+        let distance = self.stage_start_value.get().max(0.0);
+        self.advance_segment(self.release_time * distance, 0.0, self.release_curve, Stage::Done, sample_rate)
     }
 
     pub fn get(&self, sample_rate: usize) -> f32 {
+        // The stages read the sustain level as of this sample
+        self.sustain_level.next(sample_rate);
+        self.get_unscaled(sample_rate) * self.velocity_level.get()
+    }
+
+    /// Gets the next value before it's scaled by velocity
+    fn get_unscaled(&self, sample_rate: usize) -> f32 {
         match self.stage.get() {
-            Adsr::Attack  => self.get_attack(sample_rate),
-            Adsr::Decay   => self.get_decay(sample_rate),
-            Adsr::Sustain => self.sustain_level,
-            Adsr::Release => self.get_release(sample_rate),
-            Adsr::Done    => 0.0
+            Stage::Delay => {
+                if self.advance_wait(self.delay_time, sample_rate) {
+                    self.enter_stage(Stage::Attack);
+                }
+                self.previous_value.get()
+            },
+            Stage::Attack  => self.get_attack(sample_rate),
+            Stage::Hold => {
+                if self.advance_wait(self.hold_time, sample_rate) {
+                    self.enter_stage(Stage::Decay);
+                }
+                1.0
+            },
+            Stage::Decay   => self.get_decay(sample_rate),
+            Stage::Sustain => {
+                if self.looping && self.triggered.get() {
+                    self.enter_stage(Stage::Attack);
+                }
+                self.sustain_level.get_current()
+            },
+            Stage::Release => self.get_release(sample_rate),
+            Stage::Done    => 0.0
         }
     }
 }
 
 impl SynthModule for Envelope {
     fn fill_output_buffer(&self, data: &mut [f32], output_info: &OutputInfo) {
-        let data_size = data.len();
-        let mut trigger_data = Vec::with_capacity(data_size);
-        trigger_data.resize(data_size, 0.0);
+        self.block_cache.fill(data, output_info, |data| {
+            let data_size = data.len();
+            let mut trigger_data = Vec::with_capacity(data_size);
+            trigger_data.resize(data_size, 0.0);
 
-        if let Some(trigger) = &self.trigger {
-            trigger.fill_output_buffer(&mut trigger_data, output_info);
-        }
-        else {
-            data.fill(0.0);
-            return;
-        }
+            if let Some(trigger) = &self.trigger {
+                trigger.fill_output_buffer(&mut trigger_data, output_info);
+            }
+            else {
+                data.fill(0.0);
+                return;
+            }
 
-        for (i, datum) in data.iter_mut().enumerate() {
-            let triggered = trigger_data[i] > self.trigger_tolerance;
-            if triggered != self.triggered.get() {
-                // Triggered state has changed. We should either start attack or release
-                if triggered {
-                    self.trigger();
-                }
-                else {
-                    self.release();
+            let mut velocity_data = vec![1.0; data_size];
+            if let Some(velocity_in) = &self.velocity_in {
+                velocity_in.fill_output_buffer(&mut velocity_data, output_info);
+            }
+
+            for (i, datum) in data.iter_mut().enumerate() {
+                let triggered = trigger_data[i] > self.trigger_tolerance;
+                if triggered != self.triggered.get() {
+                    // Triggered state has changed. We should either start attack or release
+                    if triggered {
+                        self.trigger_with_velocity(velocity_data[i]);
+                    }
+                    else {
+                        self.release();
+                    }
                 }
+                *datum = self.get(output_info.sample_rate);
             }
-            *datum = self.get(output_info.sample_rate);
+        });
+    }
+}
+
+impl PatchModule for Envelope {
+    fn get_input_names(&self) -> Vec<String> {
+        input_names(&["trigger", "velocity"])
+    }
+
+    fn set_input(&mut self, input: usize, module: Option<Rc<dyn SynthModule>>) -> SynthResult<()> {
+        match input {
+            0 => self.set_trigger(module),
+            1 => self.set_velocity_in(module),
+            _ => return no_such_input(input)
+        }
+        Ok(())
+    }
+
+    fn save_settings(&self) -> Option<ModuleSettings> {
+        let mut settings = Table::new();
+        settings.set("delay_time", self.delay_time);
+        settings.set("attack_time", self.attack_time);
+        settings.set("hold_time", self.hold_time);
+        settings.set("decay_time", self.decay_time);
+        settings.set("sustain_level", self.sustain_level.get());
+        settings.set("release_time", self.release_time);
+        save_curve(&mut settings, "attack_curve", self.attack_curve);
+        save_curve(&mut settings, "decay_curve", self.decay_curve);
+        save_curve(&mut settings, "release_curve", self.release_curve);
+        settings.set_choice("retrigger_mode", self.retrigger_mode, &RETRIGGER_MODE_NAMES);
+        settings.set("looping", self.looping);
+        settings.set("velocity_to_level", self.velocity_to_level);
+        settings.set("velocity_to_attack", self.velocity_to_attack);
+        settings.set("trigger_tolerance", self.trigger_tolerance);
+        Some(ModuleSettings::new("Envelope", settings))
+    }
+}
+
+/// Curves are parameters through their curvature. See `EnvelopeCurve::Custom`
+impl Parameters for Envelope {
+    fn get_parameter_info(&self) -> Vec<ParameterInfo> {
+        vec![
+            ParameterInfo::new("delay_time", "Delay Time", "ms", 0.0, 10_000.0, 0.0, ParameterScale::Logarithmic),
+            ParameterInfo::new("attack_time", "Attack Time", "ms", 0.0, 10_000.0, 0.0, ParameterScale::Logarithmic),
+            ParameterInfo::new("hold_time", "Hold Time", "ms", 0.0, 10_000.0, 0.0, ParameterScale::Logarithmic),
+            ParameterInfo::new("decay_time", "Decay Time", "ms", 0.0, 10_000.0, 0.0, ParameterScale::Logarithmic),
+            ParameterInfo::new("sustain_level", "Sustain Level", "", 0.0, 1.0, 1.0, ParameterScale::Linear),
+            ParameterInfo::new("release_time", "Release Time", "ms", 0.0, 10_000.0, 0.0, ParameterScale::Logarithmic),
+            ParameterInfo::new("attack_curve", "Attack Curve", "", -10.0, 10.0, 0.0, ParameterScale::Linear),
+            ParameterInfo::new("decay_curve", "Decay Curve", "", -10.0, 10.0, 0.0, ParameterScale::Linear),
+            ParameterInfo::new("release_curve", "Release Curve", "", -10.0, 10.0, 0.0, ParameterScale::Linear),
+            ParameterInfo::choice(
+                "retrigger_mode", "Retrigger Mode", &RETRIGGER_MODE_NAMES, RetriggerMode::FromCurrentLevel
+            ),
+            ParameterInfo::toggle("looping", "Looping", false),
+            ParameterInfo::new("velocity_to_level", "Velocity to Level", "", 0.0, 1.0, 0.0, ParameterScale::Linear),
+            ParameterInfo::new("velocity_to_attack", "Velocity to Attack", "", 0.0, 1.0, 0.0, ParameterScale::Linear),
+            ParameterInfo::new("trigger_tolerance", "Trigger Tolerance", "", 0.0, 1.0, 0.5, ParameterScale::Linear)
+        ]
+    }
+
+    fn get_parameter(&self, id: &str) -> Option<f32> {
+        match id {
+            "delay_time" => Some(self.delay_time),
+            "attack_time" => Some(self.attack_time),
+            "hold_time" => Some(self.hold_time),
+            "decay_time" => Some(self.decay_time),
+            "sustain_level" => Some(self.sustain_level.get()),
+            "release_time" => Some(self.release_time),
+            "attack_curve" => Some(self.attack_curve.get_curvature()),
+            "decay_curve" => Some(self.decay_curve.get_curvature()),
+            "release_curve" => Some(self.release_curve.get_curvature()),
+            "retrigger_mode" => Some(choice_index(&RETRIGGER_MODE_NAMES, self.retrigger_mode)),
+            "looping" => Some(toggle_value(self.looping)),
+            "velocity_to_level" => Some(self.velocity_to_level),
+            "velocity_to_attack" => Some(self.velocity_to_attack),
+            "trigger_tolerance" => Some(self.trigger_tolerance),
+            _ => None
         }
     }
+
+    fn set_parameter(&mut self, id: &str, value: f32) -> SynthResult<()> {
+        let value = clamp_parameter(self, id, value)?;
+        match id {
+            "delay_time" => self.set_delay_time(value),
+            "attack_time" => self.set_attack_time(value),
+            "hold_time" => self.set_hold_time(value),
+            "decay_time" => self.set_decay_time(value),
+            "sustain_level" => self.set_sustain_level(value),
+            "release_time" => self.set_release_time(value),
+            "attack_curve" => self.set_attack_curve(EnvelopeCurve::from_curvature(value)),
+            "decay_curve" => self.set_decay_curve(EnvelopeCurve::from_curvature(value)),
+            "release_curve" => self.set_release_curve(EnvelopeCurve::from_curvature(value)),
+            "retrigger_mode" => self.set_retrigger_mode(choice_value(&RETRIGGER_MODE_NAMES, value)),
+            "looping" => self.set_looping(value > 0.0),
+            "velocity_to_level" => self.set_velocity_to_level(value),
+            "velocity_to_attack" => self.set_velocity_to_attack(value),
+            "trigger_tolerance" => self.set_trigger_tolerance(value),
+            _ => return no_such_parameter(id)
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -209,6 +665,7 @@ mod tests {
     use super::*;
     use crate::prelude::*;
     use crate::clock;
+    use crate::module::sample_buffer::SampleBuffer;
 
     struct ConstantTrigger;
     impl SynthModule for ConstantTrigger {
@@ -293,4 +750,166 @@ mod tests {
             );
         }
     }
-}
\ No newline at end of file
+
+    fn get_envelope_output(envelope: &mut Envelope, sample_rate: usize, trigger_data: &[f32]) -> Vec<f32> {
+        let output_info = create_output_info(sample_rate, trigger_data.len());
+        envelope.set_trigger(Some(Rc::new(SampleBuffer::new(trigger_data.to_vec()))));
+
+        let mut data = vec![0.0; trigger_data.len()];
+        envelope.fill_output_buffer(&mut data, &output_info);
+        data
+    }
+
+    fn assert_envelope_eq(got: &[f32], expected: &[f32]) {
+        for (got_datum, expected_datum) in got.iter().zip(expected.iter()) {
+            assert!(
+                float_eq(*got_datum, *expected_datum, 0.0001),
+                "Envelope output does not match expected:\n\tGot: {:?}\n\tExpected: {:?}", got, expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_curves() {
+        for curve in [EnvelopeCurve::Exponential, EnvelopeCurve::Logarithmic, EnvelopeCurve::Custom(2.0)].iter() {
+            assert!(float_eq(curve.apply(0.0), 0.0, 0.0001), "Curve {:?} doesn't start at 0", curve);
+            assert!(float_eq(curve.apply(1.0), 1.0, 0.0001), "Curve {:?} doesn't end at 1", curve);
+        }
+        assert!(EnvelopeCurve::Exponential.apply(0.5) > 0.5, "Exponential curve should move quickly at first");
+        assert!(EnvelopeCurve::Logarithmic.apply(0.5) < 0.5, "Logarithmic curve should move slowly at first");
+
+        const SAMPLE_RATE: usize = 4_usize;
+        let mut envelope = Envelope::new();
+        envelope.set_attack_time(1000.0);
+        envelope.set_attack_curve(EnvelopeCurve::Exponential);
+        let data = get_envelope_output(&mut envelope, SAMPLE_RATE, &[1.0; 4]);
+        assert!(data[0] > 0.25 && data[1] > 0.5 && data[2] > 0.75, "Exponential attack isn't ahead of linear: {:?}", data);
+        assert!(float_eq(data[3], 1.0, 0.0001));
+    }
+
+    #[test]
+    fn test_delay_and_hold() {
+        const SAMPLE_RATE: usize = 4_usize;
+        const EXPECTED_DATA: [f32; 10] = [0.0, 0.0, 0.5, 1.0, 1.0, 1.0, 0.875, 0.75, 0.625, 0.5];
+
+        let mut envelope = Envelope::new();
+        envelope.set_delay_time(500.0);
+        envelope.set_attack_time(500.0);
+        envelope.set_hold_time(500.0);
+        envelope.set_decay_time(1000.0);
+        envelope.set_sustain_level(0.5);
+
+        let data = get_envelope_output(&mut envelope, SAMPLE_RATE, &[1.0; 10]);
+        assert_envelope_eq(&data, &EXPECTED_DATA);
+    }
+
+    #[test]
+    fn test_retrigger_modes() {
+        const SAMPLE_RATE: usize = 4_usize;
+        // Held long enough to reach full, let go for one sample, then held again
+        const TRIGGER_DATA: [f32; 8] = [1.0, 1.0, 1.0, 1.0, 0.0, 1.0, 1.0, 1.0];
+
+        let create_envelope = |retrigger_mode: RetriggerMode| {
+            let mut envelope = Envelope::new();
+            envelope.set_attack_time(1000.0);
+            envelope.set_decay_time(1000.0);
+            envelope.set_sustain_level(0.5);
+            envelope.set_release_time(1000.0);
+            envelope.set_retrigger_mode(retrigger_mode);
+            envelope
+        };
+
+        let mut envelope = create_envelope(RetriggerMode::Restart);
+        let data = get_envelope_output(&mut envelope, SAMPLE_RATE, &TRIGGER_DATA);
+        assert_envelope_eq(&data, &[0.25, 0.5, 0.75, 1.0, 0.75, 0.25, 0.5, 0.75]);
+
+        let mut envelope = create_envelope(RetriggerMode::FromCurrentLevel);
+        let data = get_envelope_output(&mut envelope, SAMPLE_RATE, &TRIGGER_DATA);
+        assert_envelope_eq(&data, &[0.25, 0.5, 0.75, 1.0, 0.75, 1.0, 0.875, 0.75]);
+
+        // Heads back down to sustain without attacking again
+        let mut envelope = create_envelope(RetriggerMode::Legato);
+        let data = get_envelope_output(&mut envelope, SAMPLE_RATE, &TRIGGER_DATA);
+        assert_envelope_eq(&data, &[0.25, 0.5, 0.75, 1.0, 0.75, 0.625, 0.5, 0.5]);
+    }
+
+    #[test]
+    fn test_looping() {
+        const SAMPLE_RATE: usize = 4_usize;
+        const EXPECTED_DATA: [f32; 10] = [0.5, 1.0, 0.75, 0.5, 1.0, 0.75, 0.5, 1.0, 0.75, 0.5];
+
+        let mut envelope = Envelope::new();
+        envelope.set_attack_time(500.0);
+        envelope.set_decay_time(500.0);
+        envelope.set_sustain_level(0.5);
+        envelope.set_looping(true);
+
+        let data = get_envelope_output(&mut envelope, SAMPLE_RATE, &[1.0; 10]);
+        assert_envelope_eq(&data, &EXPECTED_DATA);
+    }
+
+    #[test]
+    fn test_velocity_sensitivity() {
+        const SAMPLE_RATE: usize = 4_usize;
+        const TRIGGER_DATA: [f32; 8] = [1.0, 1.0, 1.0, 1.0, 0.0, 1.0, 1.0, 1.0];
+        const VELOCITY_DATA: [f32; 8] = [0.5, 0.5, 0.5, 0.5, 1.0, 1.0, 1.0, 1.0];
+        // The first note peaks at half level with three quarters of the attack. The second peaks
+        // at full level with half the attack
+        const EXPECTED_DATA: [f32; 8] = [0.5 / 3.0, 1.0 / 3.0, 0.5, 0.5, 0.0, 0.5, 1.0, 1.0];
+
+        let mut envelope = Envelope::new();
+        envelope.set_attack_time(1000.0);
+        envelope.set_velocity_to_level(1.0);
+        envelope.set_velocity_to_attack(0.5);
+        envelope.set_velocity_in(Some(Rc::new(SampleBuffer::new(VELOCITY_DATA.to_vec()))));
+
+        let data = get_envelope_output(&mut envelope, SAMPLE_RATE, &TRIGGER_DATA);
+        assert_envelope_eq(&data, &EXPECTED_DATA);
+    }
+
+    #[test]
+    fn test_sustain_level_smoothing() {
+        const SAMPLE_RATE: usize = 1000_usize;
+        const BLOCK_SIZE: usize = 20_usize;
+
+        let mut envelope = Envelope::new();
+        envelope.set_sustain_level(0.5);
+        envelope.set_trigger(Some(Rc::new(ConstantTrigger)));
+        let mut data = vec![0_f32; BLOCK_SIZE];
+        envelope.fill_output_buffer(&mut data, &create_output_info(SAMPLE_RATE, BLOCK_SIZE));
+        assert_eq!(data[BLOCK_SIZE - 1], 0.5);
+
+        // Changing the sustain level while sustaining ramps over 10ms, which is 10 samples here
+        envelope.set_sustain_level(1.0);
+        envelope.fill_output_buffer(&mut data, &create_output_info(SAMPLE_RATE, BLOCK_SIZE));
+        for (i, datum) in data[..10].iter().enumerate() {
+            assert!(float_eq(*datum, 0.5 + 0.05 * (i + 1) as f32, 0.0001), "Sustain level didn't ramp: {:?}", data);
+        }
+        assert!(data[10..].iter().all(|datum| *datum == 1.0), "Sustain level didn't settle: {:?}", data);
+    }
+
+    #[test]
+    fn test_shared_envelope() {
+        const SAMPLE_RATE: usize = 4_usize;
+        const EXPECTED_DATA: [f32; 8] = [0.125, 0.25, 0.375, 0.5, 0.625, 0.75, 0.875, 1.0];
+
+        // Two modules reading the same envelope each block shouldn't make it run twice as fast
+        let mut envelope = Envelope::new();
+        envelope.set_attack_time(2000.0);
+        envelope.set_trigger(Some(Rc::new(ConstantTrigger)));
+        let envelope = Rc::new(envelope);
+
+        let mut clock = clock::SampleClock::new(SAMPLE_RATE);
+        let mut data = Vec::new();
+        for _ in 0..2 {
+            let output_info = OutputInfo::new_basic(SAMPLE_RATE, clock.get_range(SAMPLE_RATE));
+            let mut first_read = vec![0_f32; SAMPLE_RATE];
+            envelope.fill_output_buffer(&mut first_read, &output_info);
+            let mut second_read = vec![0_f32; SAMPLE_RATE];
+            envelope.fill_output_buffer(&mut second_read, &output_info);
+            assert_eq!(first_read, second_read, "Reading the same block twice gave different output");
+            data.extend(first_read);
+        }
+        assert_envelope_eq(&data, &EXPECTED_DATA);
+    }
+}
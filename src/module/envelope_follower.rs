@@ -0,0 +1,233 @@
+use std::rc::Rc;
+
+use super::{SynthModule, PatchModule, OutputInfo, SmoothedValue, no_such_input, input_names};
+use crate::SynthResult;
+use super::detector::{LevelDetector, DetectionMode, DETECTION_MODE_NAMES};
+use super::parameter::{Parameters, ParameterInfo, ParameterScale, clamp_parameter, no_such_parameter, choice_index, choice_value};
+use super::render_cache::BlockCache;
+
+/// Turns the level of an audio signal into a control signal. The detected level is scaled by
+/// the output gain and then has the offset added, so it can be pointed at any other input.
+pub struct EnvelopeFollower {
+    signal_in: Option<Rc<dyn SynthModule>>,
+    detector: LevelDetector,
+    output_gain: SmoothedValue,
+    output_offset: SmoothedValue,
+    block_cache: BlockCache
+}
+
+impl EnvelopeFollower {
+    pub fn new() -> Self {
+        let signal_in = None;
+        let detector = LevelDetector::new(DetectionMode::Peak, 10.0, 100.0);
+        let output_gain = SmoothedValue::new(1.0);
+        let output_offset = SmoothedValue::new(0.0);
+        let block_cache = BlockCache::new();
+        Self { signal_in, detector, output_gain, output_offset, block_cache }
+    }
+
+    pub fn set_signal_in(&mut self, signal_in: Option<Rc<dyn SynthModule>>) {
+        self.signal_in = signal_in;
+    }
+
+    pub fn set_detection_mode(&mut self, mode: DetectionMode) {
+        self.detector.set_mode(mode);
+    }
+
+    pub fn get_detection_mode(&self) -> DetectionMode {
+        self.detector.get_mode()
+    }
+
+    pub fn set_attack_time(&mut self, attack_time: f32) {
+        self.detector.set_attack_time(attack_time.max(0.0));
+    }
+
+    pub fn get_attack_time(&self) -> f32 {
+        self.detector.get_attack_time()
+    }
+
+    pub fn set_release_time(&mut self, release_time: f32) {
+        self.detector.set_release_time(release_time.max(0.0));
+    }
+
+    pub fn get_release_time(&self) -> f32 {
+        self.detector.get_release_time()
+    }
+
+    pub fn set_output_gain(&mut self, output_gain: f32) {
+        self.output_gain.set(output_gain);
+    }
+
+    pub fn get_output_gain(&self) -> f32 {
+        self.output_gain.get()
+    }
+
+    pub fn set_output_offset(&mut self, output_offset: f32) {
+        self.output_offset.set(output_offset);
+    }
+
+    pub fn get_output_offset(&self) -> f32 {
+        self.output_offset.get()
+    }
+
+    /// Sets how long in milliseconds output gain and offset changes take to ramp in
+    pub fn set_smoothing_time(&mut self, smoothing_time: f32) {
+        self.output_gain.set_ramp_time(smoothing_time);
+        self.output_offset.set_ramp_time(smoothing_time);
+    }
+
+    pub fn get_smoothing_time(&self) -> f32 {
+        self.output_gain.get_ramp_time()
+    }
+}
+
+impl Default for EnvelopeFollower {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SynthModule for EnvelopeFollower {
+    fn fill_output_buffer(&self, buffer: &mut [f32], output_info: &OutputInfo) {
+        self.block_cache.fill(buffer, output_info, |buffer| {
+            // Get the signal to follow
+            let mut signal = vec![0.0; buffer.len()];
+            if let Some(signal_in) = &self.signal_in {
+                signal_in.fill_output_buffer(&mut signal, output_info);
+            }
+            else {
+                for datum in buffer.iter_mut() {
+                    *datum = self.output_offset.next(output_info.sample_rate);
+                }
+                return;
+            }
+
+            self.detector.detect(&signal, buffer, output_info.sample_rate);
+            for datum in buffer.iter_mut() {
+                let output_gain = self.output_gain.next(output_info.sample_rate);
+                *datum = *datum * output_gain + self.output_offset.next(output_info.sample_rate);
+            }
+        });
+    }
+}
+
+impl PatchModule for EnvelopeFollower {
+    fn get_input_names(&self) -> Vec<String> {
+        input_names(&["signal"])
+    }
+
+    fn set_input(&mut self, input: usize, module: Option<Rc<dyn SynthModule>>) -> SynthResult<()> {
+        match input {
+            0 => self.set_signal_in(module),
+            _ => return no_such_input(input)
+        }
+        Ok(())
+    }
+}
+
+impl Parameters for EnvelopeFollower {
+    fn get_parameter_info(&self) -> Vec<ParameterInfo> {
+        vec![
+            ParameterInfo::choice("detection_mode", "Detection Mode", &DETECTION_MODE_NAMES, DetectionMode::Peak),
+            ParameterInfo::new("attack_time", "Attack Time", "ms", 0.0, 1_000.0, 10.0, ParameterScale::Logarithmic),
+            ParameterInfo::new("release_time", "Release Time", "ms", 0.0, 5_000.0, 100.0, ParameterScale::Logarithmic),
+            ParameterInfo::new("output_gain", "Output Gain", "", -10.0, 10.0, 1.0, ParameterScale::Linear),
+            ParameterInfo::new("output_offset", "Output Offset", "", -1.0, 1.0, 0.0, ParameterScale::Linear)
+        ]
+    }
+
+    fn get_parameter(&self, id: &str) -> Option<f32> {
+        match id {
+            "detection_mode" => Some(choice_index(&DETECTION_MODE_NAMES, self.get_detection_mode())),
+            "attack_time" => Some(self.get_attack_time()),
+            "release_time" => Some(self.get_release_time()),
+            "output_gain" => Some(self.output_gain.get()),
+            "output_offset" => Some(self.output_offset.get()),
+            _ => None
+        }
+    }
+
+    fn set_parameter(&mut self, id: &str, value: f32) -> SynthResult<()> {
+        let value = clamp_parameter(self, id, value)?;
+        match id {
+            "detection_mode" => self.set_detection_mode(choice_value(&DETECTION_MODE_NAMES, value)),
+            "attack_time" => self.set_attack_time(value),
+            "release_time" => self.set_release_time(value),
+            "output_gain" => self.set_output_gain(value),
+            "output_offset" => self.set_output_offset(value),
+            _ => return no_such_parameter(id)
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::*;
+    use crate::module::sample_buffer::SampleBuffer;
+    use crate::clock;
+
+    const SAMPLE_RATE: usize = 8;
+
+    fn get_follower_output(follower: &EnvelopeFollower) -> Vec<f32> {
+        let mut clock = clock::SampleClock::new(SAMPLE_RATE);
+        let clock_values = clock.get_range(SAMPLE_RATE);
+        let output_info = OutputInfo::new_basic(SAMPLE_RATE, clock_values);
+
+        let mut output_buffer = vec![0_f32; SAMPLE_RATE];
+        follower.fill_output_buffer(&mut output_buffer, &output_info);
+        output_buffer
+    }
+
+    fn assert_output_eq(got: &[f32], expected: &[f32]) {
+        for (got_datum, expected_datum) in got.iter().zip(expected.iter()) {
+            assert!(
+                float_eq(*got_datum, *expected_datum, 0.0001),
+                "Envelope follower output does not match expected:\n\tGot: {:?}\n\tExpected: {:?}", got, expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_instant_peak() {
+        let signal = vec![0.5, -1.0, 0.25, 0.0, -0.5, 0.5, 0.0, 1.0];
+        let mut follower = EnvelopeFollower::new();
+        follower.set_attack_time(0.0);
+        follower.set_release_time(0.0);
+        follower.set_signal_in(Some(Rc::new(SampleBuffer::new(signal))));
+
+        let output = get_follower_output(&follower);
+        assert_output_eq(&output, &[0.5, 1.0, 0.25, 0.0, 0.5, 0.5, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn test_gain_and_offset() {
+        let signal = vec![1.0, -1.0, 0.5, -0.5, 0.0, 0.0, 0.25, -0.25];
+        let mut follower = EnvelopeFollower::new();
+        follower.set_attack_time(0.0);
+        follower.set_release_time(0.0);
+        follower.set_output_gain(-2.0);
+        follower.set_output_offset(1.0);
+        follower.set_signal_in(Some(Rc::new(SampleBuffer::new(signal))));
+
+        let output = get_follower_output(&follower);
+        assert_output_eq(&output, &[-1.0, -1.0, 0.0, 0.0, 1.0, 1.0, 0.5, 0.5]);
+    }
+
+    #[test]
+    fn test_release() {
+        // The level should fall away slowly once the signal stops
+        let signal = vec![1.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0];
+        let mut follower = EnvelopeFollower::new();
+        follower.set_attack_time(0.0);
+        follower.set_release_time(500.0);
+        follower.set_signal_in(Some(Rc::new(SampleBuffer::new(signal))));
+
+        let output = get_follower_output(&follower);
+        assert_eq!(output[1], 1.0);
+        for i in 2..SAMPLE_RATE {
+            assert!(output[i] < output[i - 1] && output[i] > 0.0, "Level did not release smoothly: {:?}", output);
+        }
+    }
+}
@@ -0,0 +1,450 @@
+use std::rc::Rc;
+use std::cell::Cell;
+
+use crate::prelude::*;
+use crate::SynthResult;
+use super::{SynthModule, PatchModule, OutputInfo, SmoothedValue, no_such_input, input_names};
+use super::detector::{LevelDetector, DetectionMode, smoothing_coefficient};
+use super::parameter::{Parameters, ParameterInfo, ParameterScale, clamp_parameter, no_such_parameter, choice_index, choice_value};
+use super::render_cache::BlockCache;
+
+const MILLISECONDS_PER_SECOND: f32 = 1000.0;
+
+/// Release time of the level detector in milliseconds. Keeps the gate from chattering
+/// as an audio signal crosses zero
+const DETECTOR_RELEASE_TIME: f32 = 10.0;
+
+/// How a `Gate` treats signals below its threshold
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum GateMode {
+    /// Signals below the threshold are attenuated by the full range
+    Gate,
+    /// Signals below the threshold are attenuated more the further below the threshold they are.
+    /// The attenuation is limited by the range
+    Expander
+}
+
+const MODE_NAMES: [(&str, GateMode); 2] = [
+    ("Gate", GateMode::Gate),
+    ("Expander", GateMode::Expander)
+];
+
+/// A noise gate / downward expander. The level is detected from the key input if one is
+/// connected, otherwise from the signal itself.
+pub struct Gate {
+    signal_in: Option<Rc<dyn SynthModule>>,
+    key_in: Option<Rc<dyn SynthModule>>,
+
+    mode: GateMode,
+    // Levels here should be in decibels
+    threshold: SmoothedValue,
+    hysteresis: SmoothedValue, // The gate closes this far below the threshold
+    range: SmoothedValue, // Gain applied when the gate is fully closed
+    ratio: SmoothedValue, // Only used as an expander
+
+    // Times here should be in milliseconds
+    attack_time: f32,
+    hold_time: f32,
+    release_time: f32,
+
+    detector: LevelDetector,
+    open: Cell<bool>,
+    hold_samples_remaining: Cell<usize>,
+    gain: Cell<f32>,
+    block_cache: BlockCache
+}
+
+impl Gate {
+    pub fn new() -> Self {
+        let signal_in = None;
+        let key_in = None;
+
+        let mode = GateMode::Gate;
+        let threshold = SmoothedValue::new(-40.0);
+        let hysteresis = SmoothedValue::new(6.0);
+        let range = SmoothedValue::new(-80.0);
+        let ratio = SmoothedValue::new(4.0);
+
+        let attack_time = 1.0;
+        let hold_time = 50.0;
+        let release_time = 100.0;
+
+        let detector = LevelDetector::new(DetectionMode::Peak, 0.0, DETECTOR_RELEASE_TIME);
+        let open = Cell::new(false);
+        let hold_samples_remaining = Cell::new(0);
+        let gain = Cell::new(db_to_amplitude(range.get()));
+        let block_cache = BlockCache::new();
+
+        Self {
+            signal_in, key_in,
+            mode, threshold, hysteresis, range, ratio,
+            attack_time, hold_time, release_time,
+            detector, open, hold_samples_remaining, gain, block_cache
+        }
+    }
+
+    pub fn set_signal_in(&mut self, signal_in: Option<Rc<dyn SynthModule>>) {
+        self.signal_in = signal_in;
+    }
+
+    pub fn set_key_in(&mut self, key_in: Option<Rc<dyn SynthModule>>) {
+        self.key_in = key_in;
+    }
+
+    pub fn set_mode(&mut self, mode: GateMode) {
+        self.mode = mode;
+    }
+
+    pub fn get_mode(&self) -> GateMode {
+        self.mode
+    }
+
+    pub fn set_threshold(&mut self, threshold: f32) {
+        self.threshold.set(threshold);
+    }
+
+    pub fn get_threshold(&self) -> f32 {
+        self.threshold.get()
+    }
+
+    pub fn set_hysteresis(&mut self, hysteresis: f32) {
+        self.hysteresis.set(hysteresis.max(0.0));
+    }
+
+    pub fn get_hysteresis(&self) -> f32 {
+        self.hysteresis.get()
+    }
+
+    pub fn set_range(&mut self, range: f32) {
+        self.range.set(range.min(0.0));
+    }
+
+    pub fn get_range(&self) -> f32 {
+        self.range.get()
+    }
+
+    pub fn set_ratio(&mut self, ratio: f32) {
+        self.ratio.set(ratio.max(1.0));
+    }
+
+    pub fn get_ratio(&self) -> f32 {
+        self.ratio.get()
+    }
+
+    /// Sets how long in milliseconds threshold, hysteresis, range and ratio changes take to ramp in
+    pub fn set_smoothing_time(&mut self, smoothing_time: f32) {
+        self.threshold.set_ramp_time(smoothing_time);
+        self.hysteresis.set_ramp_time(smoothing_time);
+        self.range.set_ramp_time(smoothing_time);
+        self.ratio.set_ramp_time(smoothing_time);
+    }
+
+    pub fn get_smoothing_time(&self) -> f32 {
+        self.threshold.get_ramp_time()
+    }
+
+    pub fn set_attack_time(&mut self, attack_time: f32) {
+        self.attack_time = attack_time;
+    }
+
+    pub fn get_attack_time(&self) -> f32 {
+        self.attack_time
+    }
+
+    pub fn set_hold_time(&mut self, hold_time: f32) {
+        self.hold_time = hold_time;
+    }
+
+    pub fn get_hold_time(&self) -> f32 {
+        self.hold_time
+    }
+
+    pub fn set_release_time(&mut self, release_time: f32) {
+        self.release_time = release_time;
+    }
+
+    pub fn get_release_time(&self) -> f32 {
+        self.release_time
+    }
+
+    /// Whether the gate was open at the most recently output sample
+    pub fn is_open(&self) -> bool {
+        self.open.get()
+    }
+
+    /// Updates the open/closed state for a detected level in decibels
+    fn update_open(&self, level: f32, threshold: f32, hysteresis: f32, hold_samples: usize) {
+        if level >= threshold {
+            self.open.set(true);
+            self.hold_samples_remaining.set(hold_samples);
+        }
+        else if level < threshold - hysteresis {
+            let hold_samples_remaining = self.hold_samples_remaining.get();
+            if hold_samples_remaining > 0 {
+                self.hold_samples_remaining.set(hold_samples_remaining - 1);
+            }
+            else {
+                self.open.set(false);
+            }
+        }
+        // Within the hysteresis band nothing changes
+    }
+
+    /// Gets the gain in decibels the gate is heading towards for a detected level in decibels
+    fn get_target_gain(&self, level: f32, threshold: f32, range: f32, ratio: f32) -> f32 {
+        if self.open.get() {
+            return 0.0;
+        }
+        match self.mode {
+            GateMode::Gate => range,
+            GateMode::Expander => {
+                let undershoot = (threshold - level).max(0.0);
+                (-undershoot * (ratio - 1.0)).max(range)
+            }
+        }
+    }
+}
+
+impl Default for Gate {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SynthModule for Gate {
+    fn fill_output_buffer(&self, buffer: &mut [f32], output_info: &OutputInfo) {
+        self.block_cache.fill(buffer, output_info, |buffer| {
+            let buffer_len = buffer.len();
+            let sample_rate = output_info.sample_rate;
+
+            // Get signal from input
+            let mut signal = vec![0.0; buffer_len];
+            if let Some(signal_in) = &self.signal_in {
+                signal_in.fill_output_buffer(&mut signal, output_info);
+            }
+            else {
+                buffer.fill(0.0);
+                return;
+            }
+
+            // Detect the level from the key if there is one, otherwise from the signal itself
+            let mut levels = vec![0.0; buffer_len];
+            if let Some(key_in) = &self.key_in {
+                let mut key = vec![0.0; buffer_len];
+                key_in.fill_output_buffer(&mut key, output_info);
+                self.detector.detect(&key, &mut levels, sample_rate);
+            }
+            else {
+                self.detector.detect(&signal, &mut levels, sample_rate);
+            }
+
+            let hold_samples = (self.hold_time * sample_rate as f32 / MILLISECONDS_PER_SECOND) as usize;
+            let attack_coefficient = smoothing_coefficient(self.attack_time, sample_rate);
+            let release_coefficient = smoothing_coefficient(self.release_time, sample_rate);
+
+            let mut gain = self.gain.get();
+            for i in 0..buffer_len {
+                let level = amplitude_to_db(levels[i]);
+                let threshold = self.threshold.next(sample_rate);
+                let hysteresis = self.hysteresis.next(sample_rate);
+                let range = self.range.next(sample_rate);
+                let ratio = self.ratio.next(sample_rate);
+                self.update_open(level, threshold, hysteresis, hold_samples);
+
+                let target_gain = db_to_amplitude(self.get_target_gain(level, threshold, range, ratio));
+                let coefficient = if target_gain > gain { attack_coefficient } else { release_coefficient };
+                gain = coefficient * gain + (1.0 - coefficient) * target_gain;
+
+                buffer[i] = signal[i] * gain;
+            }
+            self.gain.set(gain);
+        });
+    }
+}
+
+impl PatchModule for Gate {
+    fn get_input_names(&self) -> Vec<String> {
+        input_names(&["signal", "key"])
+    }
+
+    fn set_input(&mut self, input: usize, module: Option<Rc<dyn SynthModule>>) -> SynthResult<()> {
+        match input {
+            0 => self.set_signal_in(module),
+            1 => self.set_key_in(module),
+            _ => return no_such_input(input)
+        }
+        Ok(())
+    }
+}
+
+impl Parameters for Gate {
+    fn get_parameter_info(&self) -> Vec<ParameterInfo> {
+        vec![
+            ParameterInfo::choice("mode", "Mode", &MODE_NAMES, GateMode::Gate),
+            ParameterInfo::new("threshold", "Threshold", "dB", -96.0, 0.0, -40.0, ParameterScale::Linear),
+            ParameterInfo::new("hysteresis", "Hysteresis", "dB", 0.0, 24.0, 6.0, ParameterScale::Linear),
+            ParameterInfo::new("range", "Range", "dB", -96.0, 0.0, -80.0, ParameterScale::Linear),
+            ParameterInfo::new("ratio", "Ratio", "", 1.0, 20.0, 4.0, ParameterScale::Logarithmic),
+            ParameterInfo::new("attack_time", "Attack Time", "ms", 0.0, 1_000.0, 1.0, ParameterScale::Logarithmic),
+            ParameterInfo::new("hold_time", "Hold Time", "ms", 0.0, 2_000.0, 50.0, ParameterScale::Logarithmic),
+            ParameterInfo::new("release_time", "Release Time", "ms", 0.0, 5_000.0, 100.0, ParameterScale::Logarithmic)
+        ]
+    }
+
+    fn get_parameter(&self, id: &str) -> Option<f32> {
+        match id {
+            "mode" => Some(choice_index(&MODE_NAMES, self.mode)),
+            "threshold" => Some(self.threshold.get()),
+            "hysteresis" => Some(self.hysteresis.get()),
+            "range" => Some(self.range.get()),
+            "ratio" => Some(self.ratio.get()),
+            "attack_time" => Some(self.attack_time),
+            "hold_time" => Some(self.hold_time),
+            "release_time" => Some(self.release_time),
+            _ => None
+        }
+    }
+
+    fn set_parameter(&mut self, id: &str, value: f32) -> SynthResult<()> {
+        let value = clamp_parameter(self, id, value)?;
+        match id {
+            "mode" => self.set_mode(choice_value(&MODE_NAMES, value)),
+            "threshold" => self.set_threshold(value),
+            "hysteresis" => self.set_hysteresis(value),
+            "range" => self.set_range(value),
+            "ratio" => self.set_ratio(value),
+            "attack_time" => self.set_attack_time(value),
+            "hold_time" => self.set_hold_time(value),
+            "release_time" => self.set_release_time(value),
+            _ => return no_such_parameter(id)
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module::sample_buffer::SampleBuffer;
+    use crate::clock;
+
+    const SAMPLE_RATE: usize = 10;
+
+    fn get_gate_output(gate: &Gate) -> Vec<f32> {
+        let mut clock = clock::SampleClock::new(SAMPLE_RATE);
+        let clock_values = clock.get_range(SAMPLE_RATE);
+        let output_info = OutputInfo::new_basic(SAMPLE_RATE, clock_values);
+
+        let mut output_buffer = vec![0_f32; SAMPLE_RATE];
+        gate.fill_output_buffer(&mut output_buffer, &output_info);
+        output_buffer
+    }
+
+    fn create_instant_gate() -> Gate {
+        let mut gate = Gate::new();
+        gate.set_attack_time(0.0);
+        gate.set_hold_time(0.0);
+        gate.set_release_time(0.0);
+        gate
+    }
+
+    fn assert_output_eq(got: &[f32], expected: &[f32]) {
+        for (got_datum, expected_datum) in got.iter().zip(expected.iter()) {
+            assert!(
+                float_eq(*got_datum, *expected_datum, 0.001),
+                "Gate output does not match expected:\n\tGot: {:?}\n\tExpected: {:?}", got, expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_key_input() {
+        const EXPECTED_DATA: [f32; SAMPLE_RATE] = [0.5, 0.5, 0.5, 0.5, 0.5, 0.0, 0.0, 0.0, 0.0, 0.0];
+        let mut gate = create_instant_gate();
+        gate.set_signal_in(Some(Rc::new(SampleBuffer::new(vec![0.5; SAMPLE_RATE]))));
+        let key = vec![1.0, 1.0, 1.0, 1.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0];
+        gate.set_key_in(Some(Rc::new(SampleBuffer::new(key))));
+
+        let output = get_gate_output(&gate);
+        assert_output_eq(&output, &EXPECTED_DATA);
+    }
+
+    #[test]
+    fn test_hold() {
+        const EXPECTED_DATA: [f32; SAMPLE_RATE] = [0.5, 0.5, 0.5, 0.5, 0.5, 0.5, 0.5, 0.5, 0.0, 0.0];
+        let mut gate = create_instant_gate();
+        gate.set_hold_time(300.0);
+        gate.set_signal_in(Some(Rc::new(SampleBuffer::new(vec![0.5; SAMPLE_RATE]))));
+        let key = vec![1.0, 1.0, 1.0, 1.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0];
+        gate.set_key_in(Some(Rc::new(SampleBuffer::new(key))));
+
+        let output = get_gate_output(&gate);
+        assert_output_eq(&output, &EXPECTED_DATA);
+    }
+
+    #[test]
+    fn test_hysteresis() {
+        // The signal dips below the threshold but not far enough to close the gate
+        let signal = vec![
+            1.0, 1.0, db_to_amplitude(-43.0), db_to_amplitude(-43.0), db_to_amplitude(-50.0),
+            db_to_amplitude(-50.0), db_to_amplitude(-43.0), 1.0, 1.0, 1.0
+        ];
+        let mut gate = create_instant_gate();
+        gate.set_threshold(-40.0);
+        gate.set_hysteresis(6.0);
+        gate.set_signal_in(Some(Rc::new(SampleBuffer::new(signal.clone()))));
+
+        let output = get_gate_output(&gate);
+        let closed_gain = db_to_amplitude(gate.get_range());
+        let mut expected = signal.clone();
+        expected[4] *= closed_gain;
+        expected[5] *= closed_gain;
+        expected[6] *= closed_gain;
+        assert_output_eq(&output, &expected);
+    }
+
+    #[test]
+    fn test_expander() {
+        // 20dB under the threshold at 2:1 should be turned down another 20dB
+        let signal = vec![db_to_amplitude(-60.0); SAMPLE_RATE];
+        let expected = [db_to_amplitude(-80.0); SAMPLE_RATE];
+        let mut gate = create_instant_gate();
+        gate.set_mode(GateMode::Expander);
+        gate.set_threshold(-40.0);
+        gate.set_ratio(2.0);
+        gate.set_range(-100.0);
+        gate.set_signal_in(Some(Rc::new(SampleBuffer::new(signal))));
+
+        let output = get_gate_output(&gate);
+        for (got, expected) in output.iter().zip(expected.iter()) {
+            assert!(float_eq(amplitude_to_db(*got), amplitude_to_db(*expected), 0.01), "Expected {}. Got {}", expected, got);
+        }
+    }
+
+    #[test]
+    fn test_range_smoothing() {
+        const SMOOTHING_SAMPLE_RATE: usize = 1000;
+        let get_output = |gate: &Gate| {
+            let mut clock = clock::SampleClock::new(SMOOTHING_SAMPLE_RATE);
+            let output_info = OutputInfo::new_basic(SMOOTHING_SAMPLE_RATE, clock.get_range(SAMPLE_RATE * 2));
+            let mut output_buffer = vec![0_f32; SAMPLE_RATE * 2];
+            gate.fill_output_buffer(&mut output_buffer, &output_info);
+            output_buffer
+        };
+
+        // A closed gate turns the signal down by the range
+        let mut gate = create_instant_gate();
+        gate.set_range(-20.0);
+        gate.set_signal_in(Some(Rc::new(SampleBuffer::new(vec![db_to_amplitude(-60.0); SAMPLE_RATE * 2]))));
+        let output = get_output(&gate);
+        assert!(float_eq(amplitude_to_db(output[SAMPLE_RATE * 2 - 1]), -80.0, 0.01));
+
+        // Changing the range mid playback ramps over 10ms, which is 10 samples here
+        gate.set_range(-40.0);
+        let output = get_output(&gate);
+        for (i, got) in output.iter().enumerate() {
+            let expected = -80.0 - 2.0 * (i + 1).min(10) as f32;
+            assert!(float_eq(amplitude_to_db(*got), expected, 0.01), "Expected {}dB at sample {}. Got {:?}", expected, i, output);
+        }
+    }
+}
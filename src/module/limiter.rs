@@ -0,0 +1,372 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+
+use crate::prelude::*;
+use crate::SynthResult;
+use crate::patch_file::Table;
+use super::SmoothedValue;
+use super::detector::smoothing_coefficient;
+use super::parameter::{Parameters, ParameterInfo, ParameterScale, clamp_parameter, no_such_parameter};
+
+const MILLISECONDS_PER_SECOND: f32 = 1000.0;
+
+/// Positions between two samples that are checked when estimating the true peak
+const TRUE_PEAK_POSITIONS: [f32; 3] = [0.25, 0.5, 0.75];
+
+/// Running state of a `Limiter`. This is rebuilt whenever the sample rate or lookahead changes
+struct LimiterState {
+    sample_rate: usize,
+    lookahead_samples: usize,
+
+    /// The last four input samples, oldest first. Used to estimate inter-sample peaks
+    history: [f32; 4],
+    /// Audio waiting to be output once the gain has caught up with it
+    delay_line: VecDeque<f32>,
+
+    /// Index of the next gain to go into `min_window`
+    gain_index: usize,
+    /// Monotonic queue of (index, gain) used to find the minimum required gain over the lookahead window
+    min_window: VecDeque<(usize, f32)>,
+    /// Gain after release smoothing
+    release_gain: f32,
+    /// Last `lookahead_samples` release smoothed gains and their sum, used to ramp the gain down
+    ramp_window: VecDeque<f32>,
+    ramp_sum: f32,
+    samples_since_resum: usize
+}
+
+impl LimiterState {
+    fn new(sample_rate: usize, lookahead_samples: usize) -> Self {
+        let history = [0.0; 4];
+        let delay_line = VecDeque::from(vec![0.0; lookahead_samples + 1]);
+
+        let gain_index = 0;
+        let min_window = VecDeque::new();
+        let release_gain = 1.0;
+        let ramp_length = lookahead_samples.max(1);
+        let ramp_window = VecDeque::from(vec![1.0; ramp_length]);
+        let ramp_sum = ramp_length as f32;
+        let samples_since_resum = 0;
+
+        Self {
+            sample_rate, lookahead_samples,
+            history, delay_line,
+            gain_index, min_window, release_gain, ramp_window, ramp_sum, samples_since_resum
+        }
+    }
+}
+
+/// Put in front of the ids of a limiter's parameters when they're listed by the module that owns it
+pub(super) const LIMITER_PARAMETER_PREFIX: &str = "limiter_";
+
+/// A lookahead brickwall limiter. The signal is delayed by the lookahead time so the gain can be
+/// ramped down before a peak arrives, which keeps the output under the ceiling without clipping.
+/// Peaks between samples are estimated so the limiter also catches most true peaks.
+/// All state carries over between calls so the output does not depend on how the audio is split
+/// into buffers.
+pub struct Limiter {
+    // Ceiling is in decibels, times are in milliseconds
+    ceiling: SmoothedValue,
+    lookahead_time: f32,
+    release_time: f32,
+
+    state: RefCell<Option<LimiterState>>
+}
+
+impl Limiter {
+    pub fn new() -> Self {
+        let ceiling = SmoothedValue::new(-0.3);
+        let lookahead_time = 5.0;
+        let release_time = 50.0;
+        let state = RefCell::new(None);
+        Self { ceiling, lookahead_time, release_time, state }
+    }
+
+    pub fn set_ceiling(&mut self, ceiling: f32) {
+        self.ceiling.set(ceiling);
+    }
+
+    pub fn get_ceiling(&self) -> f32 {
+        self.ceiling.get()
+    }
+
+    /// Sets how long in milliseconds a ceiling change takes to ramp in
+    pub fn set_smoothing_time(&mut self, smoothing_time: f32) {
+        self.ceiling.set_ramp_time(smoothing_time);
+    }
+
+    pub fn get_smoothing_time(&self) -> f32 {
+        self.ceiling.get_ramp_time()
+    }
+
+    pub fn set_lookahead_time(&mut self, lookahead_time: f32) {
+        self.lookahead_time = lookahead_time.max(0.0);
+    }
+
+    pub fn get_lookahead_time(&self) -> f32 {
+        self.lookahead_time
+    }
+
+    pub fn set_release_time(&mut self, release_time: f32) {
+        self.release_time = release_time.max(0.0);
+    }
+
+    pub fn get_release_time(&self) -> f32 {
+        self.release_time
+    }
+
+    /// Gets the limiter's settings to write in a patch file
+    pub fn save_settings(&self) -> Table {
+        let mut settings = Table::new();
+        settings.set("ceiling", self.ceiling.get());
+        settings.set("lookahead_time", self.lookahead_time);
+        settings.set("release_time", self.release_time);
+        settings
+    }
+
+    /// Changes the limiter to match its settings in a patch file
+    pub fn apply_settings(&mut self, settings: &Table) -> SynthResult<()> {
+        settings.check_keys(&["ceiling", "lookahead_time", "release_time"])?;
+        if let Some(ceiling) = settings.get_float("ceiling")? {
+            self.set_ceiling(ceiling);
+        }
+        if let Some(lookahead_time) = settings.get_float("lookahead_time")? {
+            self.set_lookahead_time(lookahead_time);
+        }
+        if let Some(release_time) = settings.get_float("release_time")? {
+            self.set_release_time(release_time);
+        }
+        Ok(())
+    }
+
+    /// Describes the limiter's parameters the way the module that owns it lists them
+    pub(super) fn get_owner_parameter_info(&self) -> Vec<ParameterInfo> {
+        let mut info = self.get_parameter_info();
+        for parameter in info.iter_mut() {
+            parameter.id = format!("{}{}", LIMITER_PARAMETER_PREFIX, parameter.id);
+            parameter.name = format!("Limiter {}", parameter.name);
+        }
+        info
+    }
+
+    /// Gets the number of samples the limiter delays audio by at a given sample rate
+    pub fn get_latency(&self, sample_rate: usize) -> usize {
+        self.get_lookahead_samples(sample_rate) + 1
+    }
+
+    /// Clears any audio and gain reduction the limiter is holding on to
+    pub fn reset(&self) {
+        *self.state.borrow_mut() = None;
+    }
+
+    fn get_lookahead_samples(&self, sample_rate: usize) -> usize {
+        (self.lookahead_time * sample_rate as f32 / MILLISECONDS_PER_SECOND).round() as usize
+    }
+
+    /// Estimates the largest absolute value of the signal between the middle two of four samples
+    /// using Catmull-Rom interpolation
+    fn estimate_true_peak(history: &[f32; 4]) -> f32 {
+        let [p0, p1, p2, p3] = *history;
+        let mut peak = p1.abs().max(p2.abs());
+        for t in TRUE_PEAK_POSITIONS.iter() {
+            let t2 = t * t;
+            let t3 = t2 * t;
+            let value = 0.5 * (
+                2.0 * p1 +
+                (p2 - p0) * t +
+                (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3) * t2 +
+                (3.0 * p1 - p0 - 3.0 * p2 + p3) * t3
+            );
+            peak = peak.max(value.abs());
+        }
+        peak
+    }
+
+    /// Limits the audio in `data` in place
+    pub fn process(&self, data: &mut [f32], sample_rate: usize) {
+        let lookahead_samples = self.get_lookahead_samples(sample_rate);
+        let mut state_option = self.state.borrow_mut();
+        let needs_new_state = match state_option.as_ref() {
+            Some(state) => state.sample_rate != sample_rate || state.lookahead_samples != lookahead_samples,
+            None => true
+        };
+        if needs_new_state {
+            *state_option = Some(LimiterState::new(sample_rate, lookahead_samples));
+        }
+        let state = state_option.as_mut().unwrap();
+
+        let release_coefficient = smoothing_coefficient(self.release_time, sample_rate);
+        // The minimum gain is held one sample past the lookahead so the samples on either side of an
+        // estimated inter-sample peak are both turned down
+        let hold_length = lookahead_samples + 2;
+
+        for datum in data.iter_mut() {
+            // Estimate the peak around the previous sample now that we know what comes after it
+            state.history.rotate_left(1);
+            state.history[3] = *datum;
+            let peak = Self::estimate_true_peak(&state.history);
+            let ceiling = db_to_amplitude(self.ceiling.next(sample_rate));
+            let required_gain = if peak > ceiling { ceiling / peak } else { 1.0 };
+
+            // Find the smallest gain required by anything in the lookahead window
+            while let Some(&(_, gain)) = state.min_window.back() {
+                if gain < required_gain {
+                    break;
+                }
+                state.min_window.pop_back();
+            }
+            state.min_window.push_back((state.gain_index, required_gain));
+            while let Some(&(index, _)) = state.min_window.front() {
+                if index + hold_length > state.gain_index {
+                    break;
+                }
+                state.min_window.pop_front();
+            }
+            state.gain_index += 1;
+            let held_gain = state.min_window.front().unwrap().1;
+
+            // Drop to the held gain immediately. It will be ramped by the averaging below.
+            // Recover from it slowly.
+            if held_gain < state.release_gain {
+                state.release_gain = held_gain;
+            }
+            else {
+                state.release_gain = release_coefficient * state.release_gain + (1.0 - release_coefficient) * held_gain;
+            }
+
+            // Average the gain over the lookahead window so it ramps down to meet peaks
+            state.ramp_sum += state.release_gain - state.ramp_window.pop_front().unwrap();
+            state.ramp_window.push_back(state.release_gain);
+            state.samples_since_resum += 1;
+            if state.samples_since_resum == state.ramp_window.len() {
+                // Resum every so often so error doesn't pile up in the running sum
+                state.ramp_sum = state.ramp_window.iter().sum();
+                state.samples_since_resum = 0;
+            }
+            let gain = (state.ramp_sum / state.ramp_window.len() as f32).min(1.0);
+
+            state.delay_line.push_back(*datum);
+            let delayed = state.delay_line.pop_front().unwrap();
+            *datum = delayed * gain;
+        }
+    }
+}
+
+impl Default for Limiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Parameters for Limiter {
+    fn get_parameter_info(&self) -> Vec<ParameterInfo> {
+        vec![
+            ParameterInfo::new("ceiling", "Ceiling", "dB", -24.0, 0.0, -0.3, ParameterScale::Linear),
+            ParameterInfo::new("lookahead_time", "Lookahead Time", "ms", 0.0, 20.0, 5.0, ParameterScale::Linear),
+            ParameterInfo::new("release_time", "Release Time", "ms", 0.0, 1_000.0, 50.0, ParameterScale::Logarithmic)
+        ]
+    }
+
+    fn get_parameter(&self, id: &str) -> Option<f32> {
+        match id {
+            "ceiling" => Some(self.ceiling.get()),
+            "lookahead_time" => Some(self.lookahead_time),
+            "release_time" => Some(self.release_time),
+            _ => None
+        }
+    }
+
+    fn set_parameter(&mut self, id: &str, value: f32) -> SynthResult<()> {
+        let value = clamp_parameter(self, id, value)?;
+        match id {
+            "ceiling" => self.set_ceiling(value),
+            "lookahead_time" => self.set_lookahead_time(value),
+            "release_time" => self.set_release_time(value),
+            _ => return no_such_parameter(id)
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: usize = 1000;
+    // Float error in the running gain average can leave samples a hair over the ceiling
+    const CEILING_TOLERANCE: f32 = 0.00001;
+
+    fn get_loud_signal() -> Vec<f32> {
+        // A sine that jumps from quiet to much louder than full scale partway through
+        (0..SAMPLE_RATE).map(|i| {
+            let amplitude = if i < SAMPLE_RATE / 3 { 0.25 } else { 4.0 };
+            amplitude * (i as f32 * 0.37).sin()
+        })
+        .collect()
+    }
+
+    #[test]
+    fn test_stays_under_ceiling() {
+        let limiter = Limiter::new();
+        let ceiling = db_to_amplitude(limiter.get_ceiling());
+        let mut data = get_loud_signal();
+        limiter.process(&mut data, SAMPLE_RATE);
+        for datum in data.iter() {
+            assert!(datum.abs() <= ceiling + CEILING_TOLERANCE, "Sample {} went over the ceiling {}", datum, ceiling);
+        }
+
+        // The loud part should be turned down to the ceiling, not further
+        let loudest = data.iter().fold(0_f32, |loudest, datum| loudest.max(datum.abs()));
+        assert!(loudest > ceiling * 0.9, "Limiter turned the signal down too far. Loudest sample was {}", loudest);
+    }
+
+    #[test]
+    fn test_quiet_signal_is_only_delayed() {
+        let limiter = Limiter::new();
+        let latency = limiter.get_latency(SAMPLE_RATE);
+        let signal: Vec<f32> = (0..100).map(|i| 0.5 * (i as f32 * 0.1).sin()).collect();
+        let mut data = signal.clone();
+        limiter.process(&mut data, SAMPLE_RATE);
+        for i in latency..data.len() {
+            assert!(float_eq(data[i], signal[i - latency], 0.000001), "Quiet signal was changed by the limiter");
+        }
+    }
+
+    #[test]
+    fn test_buffer_size_independent() {
+        let whole_limiter = Limiter::new();
+        let mut whole = get_loud_signal();
+        whole_limiter.process(&mut whole, SAMPLE_RATE);
+
+        for chunk_size in [1, 7, 64, 333] {
+            let chunked_limiter = Limiter::new();
+            let mut chunked = get_loud_signal();
+            for chunk in chunked.chunks_mut(chunk_size) {
+                chunked_limiter.process(chunk, SAMPLE_RATE);
+            }
+            assert_eq!(whole, chunked, "Limiter output changed with a buffer size of {}", chunk_size);
+        }
+    }
+
+    #[test]
+    fn test_ceiling_smoothing() {
+        const BLOCK_SIZE: usize = 20;
+        // Without lookahead or release the gain follows the ceiling exactly
+        let mut limiter = Limiter::new();
+        limiter.set_lookahead_time(0.0);
+        limiter.set_release_time(0.0);
+        limiter.set_ceiling(-6.0);
+        let mut data = vec![1.0; BLOCK_SIZE];
+        limiter.process(&mut data, SAMPLE_RATE);
+        assert!(float_eq(data[BLOCK_SIZE - 1], db_to_amplitude(-6.0), 0.0001));
+
+        // Lowering the ceiling mid playback ramps over 10ms, which is 10 samples here
+        limiter.set_ceiling(-12.0);
+        let mut data = vec![1.0; BLOCK_SIZE];
+        limiter.process(&mut data, SAMPLE_RATE);
+        for (i, datum) in data.iter().enumerate() {
+            let expected = db_to_amplitude(-6.0 - 0.6 * (i + 1).min(10) as f32);
+            assert!(float_eq(*datum, expected, 0.0001), "Expected {} at sample {}. Got {:?}", expected, i, data);
+        }
+    }
+}
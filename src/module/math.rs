@@ -0,0 +1,536 @@
+use std::rc::Rc;
+
+use super::{SynthModule, PatchModule, OutputInfo, SmoothedValue, no_such_input, input_names};
+use crate::{SynthError, SynthResult};
+use super::parameter::{Parameters, ParameterInfo, ParameterScale, clamp_parameter, no_such_parameter, choice_index, choice_value};
+use super::render_cache::BlockCache;
+
+/// How a `Combiner` merges its inputs
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum CombineMode {
+    /// Adds every input together
+    Sum,
+    /// Subtracts every other input from the first
+    Difference,
+    /// Multiplies every input together
+    Product,
+    /// Takes the smallest input at each sample
+    Min,
+    /// Takes the largest input at each sample
+    Max
+}
+
+const COMBINE_MODE_NAMES: [(&str, CombineMode); 5] = [
+    ("Sum", CombineMode::Sum),
+    ("Difference", CombineMode::Difference),
+    ("Product", CombineMode::Product),
+    ("Min", CombineMode::Min),
+    ("Max", CombineMode::Max)
+];
+
+/// Combines any number of signals into one. Unconnected inputs are ignored.
+pub struct Combiner {
+    inputs: Vec<Option<Rc<dyn SynthModule>>>,
+    mode: CombineMode,
+    block_cache: BlockCache
+}
+
+impl Combiner {
+    pub fn new(mode: CombineMode) -> Self {
+        let inputs = Vec::new();
+        let block_cache = BlockCache::new();
+        Self { inputs, mode, block_cache }
+    }
+
+    pub fn with_inputs(mode: CombineMode, n_inputs: usize) -> Self {
+        let inputs = vec![None; n_inputs];
+        let block_cache = BlockCache::new();
+        Self { inputs, mode, block_cache }
+    }
+
+    pub fn set_mode(&mut self, mode: CombineMode) {
+        self.mode = mode;
+    }
+
+    pub fn get_mode(&self) -> CombineMode {
+        self.mode
+    }
+
+    pub fn add_input(&mut self, input: Option<Rc<dyn SynthModule>>) {
+        self.inputs.push(input);
+    }
+
+    pub fn set_input_in(&mut self, input_index: usize, input: Option<Rc<dyn SynthModule>>) -> SynthResult<()> {
+        match self.inputs.get_mut(input_index) {
+            Some(existing_input) => *existing_input = input,
+            None => {
+                let msg = "Failed to set combiner input because index was out of bounds";
+                return Err(SynthError::new(msg));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn remove_input(&mut self, input_index: usize) -> SynthResult<()> {
+        if input_index >= self.inputs.len() {
+            let msg = "Tried to remove combiner input that was out of bounds";
+            return Err(SynthError::new(msg));
+        }
+        self.inputs.remove(input_index);
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.inputs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inputs.is_empty()
+    }
+
+    fn combine(&self, accumulated: f32, datum: f32) -> f32 {
+        match self.mode {
+            CombineMode::Sum => accumulated + datum,
+            CombineMode::Difference => accumulated - datum,
+            CombineMode::Product => accumulated * datum,
+            CombineMode::Min => accumulated.min(datum),
+            CombineMode::Max => accumulated.max(datum)
+        }
+    }
+}
+
+impl SynthModule for Combiner {
+    fn fill_output_buffer(&self, buffer: &mut [f32], output_info: &OutputInfo) {
+        self.block_cache.fill(buffer, output_info, |buffer| {
+            let mut connected_inputs = self.inputs.iter().flatten();
+
+            // The first input is the starting point for everything else
+            match connected_inputs.next() {
+                Some(first_input) => first_input.fill_output_buffer(buffer, output_info),
+                None => {
+                    buffer.fill(0.0);
+                    return;
+                }
+            }
+
+            let mut input_buffer = vec![0.0; buffer.len()];
+            for input in connected_inputs {
+                input.fill_output_buffer(&mut input_buffer, output_info);
+                for (datum, input_datum) in buffer.iter_mut().zip(input_buffer.iter()) {
+                    *datum = self.combine(*datum, *input_datum);
+                }
+            }
+        });
+    }
+}
+
+/// What a `Utility` does to its input
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum UtilityMode {
+    /// Passes the signal through so only the offset is applied
+    Offset,
+    /// Full wave rectification
+    Abs,
+    /// Half wave rectification. Anything below 0.0 becomes 0.0
+    Rectify,
+    /// Flips the signal upside down
+    Invert,
+    /// Keeps the signal between the minimum and maximum
+    Clamp
+}
+
+const UTILITY_MODE_NAMES: [(&str, UtilityMode); 5] = [
+    ("Offset", UtilityMode::Offset),
+    ("Abs", UtilityMode::Abs),
+    ("Rectify", UtilityMode::Rectify),
+    ("Invert", UtilityMode::Invert),
+    ("Clamp", UtilityMode::Clamp)
+];
+
+/// Applies a simple operation to a signal, then adds an offset to it
+#[derive(Clone)]
+pub struct Utility {
+    signal_in: Option<Rc<dyn SynthModule>>,
+    mode: UtilityMode,
+    offset: SmoothedValue,
+    // Only used for clamping
+    min: f32,
+    max: f32,
+    block_cache: BlockCache
+}
+
+impl Utility {
+    pub fn new(mode: UtilityMode) -> Self {
+        let signal_in = None;
+        let offset = SmoothedValue::new(0.0);
+        let min = -1.0;
+        let max = 1.0;
+        let block_cache = BlockCache::new();
+        Self { signal_in, mode, offset, min, max, block_cache }
+    }
+
+    pub fn set_signal_in(&mut self, signal_in: Option<Rc<dyn SynthModule>>) {
+        self.signal_in = signal_in;
+    }
+
+    pub fn set_mode(&mut self, mode: UtilityMode) {
+        self.mode = mode;
+    }
+
+    pub fn get_mode(&self) -> UtilityMode {
+        self.mode
+    }
+
+    pub fn set_offset(&mut self, offset: f32) {
+        self.offset.set(offset);
+    }
+
+    pub fn get_offset(&self) -> f32 {
+        self.offset.get()
+    }
+
+    /// Sets how long in milliseconds an offset change takes to ramp in
+    pub fn set_smoothing_time(&mut self, smoothing_time: f32) {
+        self.offset.set_ramp_time(smoothing_time);
+    }
+
+    pub fn get_smoothing_time(&self) -> f32 {
+        self.offset.get_ramp_time()
+    }
+
+    pub fn set_range(&mut self, min: f32, max: f32) -> SynthResult<()> {
+        if min > max {
+            let msg = format!("Clamp minimum {} is greater than maximum {}", min, max);
+            return Err(SynthError::new(&msg));
+        }
+        self.min = min;
+        self.max = max;
+        Ok(())
+    }
+
+    pub fn get_min(&self) -> f32 {
+        self.min
+    }
+
+    pub fn get_max(&self) -> f32 {
+        self.max
+    }
+
+    fn apply(&self, datum: f32, offset: f32) -> f32 {
+        let processed = match self.mode {
+            UtilityMode::Offset => datum,
+            UtilityMode::Abs => datum.abs(),
+            UtilityMode::Rectify => datum.max(0.0),
+            UtilityMode::Invert => -datum,
+            UtilityMode::Clamp => datum.clamp(self.min, self.max)
+        };
+        processed + offset
+    }
+}
+
+impl SynthModule for Utility {
+    fn fill_output_buffer(&self, buffer: &mut [f32], output_info: &OutputInfo) {
+        self.block_cache.fill(buffer, output_info, |buffer| {
+            // An unconnected input is treated as 0.0 so this can be used as a constant
+            match &self.signal_in {
+                Some(signal_in) => signal_in.fill_output_buffer(buffer, output_info),
+                None => buffer.fill(0.0)
+            }
+
+            for datum in buffer.iter_mut() {
+                *datum = self.apply(*datum, self.offset.next(output_info.sample_rate));
+            }
+        });
+    }
+}
+
+/// Fades between two signals. A position of 0.0 is entirely the first signal
+/// and 1.0 is entirely the second.
+#[derive(Clone)]
+pub struct Crossfade {
+    a_in: Option<Rc<dyn SynthModule>>,
+    b_in: Option<Rc<dyn SynthModule>>,
+    control_in: Option<Rc<dyn SynthModule>>,
+    /// Position the control signal is added to
+    position: SmoothedValue,
+    block_cache: BlockCache
+}
+
+impl Crossfade {
+    pub fn new() -> Self {
+        let a_in = None;
+        let b_in = None;
+        let control_in = None;
+        let position = SmoothedValue::new(0.5);
+        let block_cache = BlockCache::new();
+        Self { a_in, b_in, control_in, position, block_cache }
+    }
+
+    pub fn set_a_in(&mut self, a_in: Option<Rc<dyn SynthModule>>) {
+        self.a_in = a_in;
+    }
+
+    pub fn set_b_in(&mut self, b_in: Option<Rc<dyn SynthModule>>) {
+        self.b_in = b_in;
+    }
+
+    pub fn set_control_in(&mut self, control_in: Option<Rc<dyn SynthModule>>) {
+        self.control_in = control_in;
+    }
+
+    pub fn set_position(&mut self, position: f32) {
+        self.position.set(position.clamp(0.0, 1.0));
+    }
+
+    pub fn get_position(&self) -> f32 {
+        self.position.get()
+    }
+
+    /// Sets how long in milliseconds a position change takes to ramp in
+    pub fn set_smoothing_time(&mut self, smoothing_time: f32) {
+        self.position.set_ramp_time(smoothing_time);
+    }
+
+    pub fn get_smoothing_time(&self) -> f32 {
+        self.position.get_ramp_time()
+    }
+}
+
+impl Default for Crossfade {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SynthModule for Crossfade {
+    fn fill_output_buffer(&self, buffer: &mut [f32], output_info: &OutputInfo) {
+        self.block_cache.fill(buffer, output_info, |buffer| {
+            let buffer_len = buffer.len();
+
+            let mut a = vec![0.0; buffer_len];
+            if let Some(a_in) = &self.a_in {
+                a_in.fill_output_buffer(&mut a, output_info);
+            }
+
+            let mut b = vec![0.0; buffer_len];
+            if let Some(b_in) = &self.b_in {
+                b_in.fill_output_buffer(&mut b, output_info);
+            }
+
+            let mut control = vec![0.0; buffer_len];
+            if let Some(control_in) = &self.control_in {
+                control_in.fill_output_buffer(&mut control, output_info);
+            }
+
+            for i in 0..buffer_len {
+                let position = (self.position.next(output_info.sample_rate) + control[i]).clamp(0.0, 1.0);
+                buffer[i] = a[i] * (1.0 - position) + b[i] * position;
+            }
+        });
+    }
+}
+
+impl PatchModule for Combiner {
+    fn get_input_names(&self) -> Vec<String> {
+        (0..self.inputs.len()).map(|input_index| format!("in {}", input_index)).collect()
+    }
+
+    fn set_input(&mut self, input: usize, module: Option<Rc<dyn SynthModule>>) -> SynthResult<()> {
+        if input >= self.inputs.len() {
+            return no_such_input(input);
+        }
+        self.set_input_in(input, module)
+    }
+}
+
+impl PatchModule for Utility {
+    fn get_input_names(&self) -> Vec<String> {
+        input_names(&["signal"])
+    }
+
+    fn set_input(&mut self, input: usize, module: Option<Rc<dyn SynthModule>>) -> SynthResult<()> {
+        match input {
+            0 => self.set_signal_in(module),
+            _ => return no_such_input(input)
+        }
+        Ok(())
+    }
+}
+
+impl PatchModule for Crossfade {
+    fn get_input_names(&self) -> Vec<String> {
+        input_names(&["a", "b", "control"])
+    }
+
+    fn set_input(&mut self, input: usize, module: Option<Rc<dyn SynthModule>>) -> SynthResult<()> {
+        match input {
+            0 => self.set_a_in(module),
+            1 => self.set_b_in(module),
+            2 => self.set_control_in(module),
+            _ => return no_such_input(input)
+        }
+        Ok(())
+    }
+}
+
+impl Parameters for Combiner {
+    fn get_parameter_info(&self) -> Vec<ParameterInfo> {
+        vec![ParameterInfo::choice("mode", "Mode", &COMBINE_MODE_NAMES, CombineMode::Sum)]
+    }
+
+    fn get_parameter(&self, id: &str) -> Option<f32> {
+        match id {
+            "mode" => Some(choice_index(&COMBINE_MODE_NAMES, self.mode)),
+            _ => None
+        }
+    }
+
+    fn set_parameter(&mut self, id: &str, value: f32) -> SynthResult<()> {
+        let value = clamp_parameter(self, id, value)?;
+        match id {
+            "mode" => self.set_mode(choice_value(&COMBINE_MODE_NAMES, value)),
+            _ => return no_such_parameter(id)
+        }
+        Ok(())
+    }
+}
+
+impl Parameters for Utility {
+    fn get_parameter_info(&self) -> Vec<ParameterInfo> {
+        vec![
+            ParameterInfo::choice("mode", "Mode", &UTILITY_MODE_NAMES, UtilityMode::Offset),
+            ParameterInfo::new("offset", "Offset", "", -10.0, 10.0, 0.0, ParameterScale::Linear),
+            ParameterInfo::new("min", "Minimum", "", -10.0, 10.0, -1.0, ParameterScale::Linear),
+            ParameterInfo::new("max", "Maximum", "", -10.0, 10.0, 1.0, ParameterScale::Linear)
+        ]
+    }
+
+    fn get_parameter(&self, id: &str) -> Option<f32> {
+        match id {
+            "mode" => Some(choice_index(&UTILITY_MODE_NAMES, self.mode)),
+            "offset" => Some(self.offset.get()),
+            "min" => Some(self.min),
+            "max" => Some(self.max),
+            _ => None
+        }
+    }
+
+    /// Moving the minimum past the maximum pushes the maximum along with it, and the other way around
+    fn set_parameter(&mut self, id: &str, value: f32) -> SynthResult<()> {
+        let value = clamp_parameter(self, id, value)?;
+        match id {
+            "mode" => self.set_mode(choice_value(&UTILITY_MODE_NAMES, value)),
+            "offset" => self.set_offset(value),
+            "min" => self.set_range(value, self.max.max(value))?,
+            "max" => self.set_range(self.min.min(value), value)?,
+            _ => return no_such_parameter(id)
+        }
+        Ok(())
+    }
+}
+
+impl Parameters for Crossfade {
+    fn get_parameter_info(&self) -> Vec<ParameterInfo> {
+        vec![ParameterInfo::new("position", "Position", "", 0.0, 1.0, 0.5, ParameterScale::Linear)]
+    }
+
+    fn get_parameter(&self, id: &str) -> Option<f32> {
+        match id {
+            "position" => Some(self.position.get()),
+            _ => None
+        }
+    }
+
+    fn set_parameter(&mut self, id: &str, value: f32) -> SynthResult<()> {
+        let value = clamp_parameter(self, id, value)?;
+        match id {
+            "position" => self.set_position(value),
+            _ => return no_such_parameter(id)
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::*;
+    use crate::module::sample_buffer::SampleBuffer;
+    use crate::clock;
+
+    const SAMPLE_RATE: usize = 4;
+
+    fn get_output(module: &dyn SynthModule) -> Vec<f32> {
+        let mut clock = clock::SampleClock::new(SAMPLE_RATE);
+        let clock_values = clock.get_range(SAMPLE_RATE);
+        let output_info = OutputInfo::new_basic(SAMPLE_RATE, clock_values);
+
+        let mut output_buffer = vec![0_f32; SAMPLE_RATE];
+        module.fill_output_buffer(&mut output_buffer, &output_info);
+        output_buffer
+    }
+
+    fn get_signal(samples: [f32; SAMPLE_RATE]) -> Option<Rc<dyn SynthModule>> {
+        Some(Rc::new(SampleBuffer::new(samples.to_vec())))
+    }
+
+    fn assert_output_eq(got: &[f32], expected: &[f32]) {
+        for (got_datum, expected_datum) in got.iter().zip(expected.iter()) {
+            assert!(
+                float_eq(*got_datum, *expected_datum, 0.0001),
+                "Output does not match expected:\n\tGot: {:?}\n\tExpected: {:?}", got, expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_combine_modes() {
+        const A: [f32; SAMPLE_RATE] = [1.0, -1.0, 0.5, 0.0];
+        const B: [f32; SAMPLE_RATE] = [0.5, 0.5, -0.5, 2.0];
+        let cases = [
+            (CombineMode::Sum, [1.5, -0.5, 0.0, 2.0]),
+            (CombineMode::Difference, [0.5, -1.5, 1.0, -2.0]),
+            (CombineMode::Product, [0.5, -0.5, -0.25, 0.0]),
+            (CombineMode::Min, [0.5, -1.0, -0.5, 0.0]),
+            (CombineMode::Max, [1.0, 0.5, 0.5, 2.0])
+        ];
+        for (mode, expected) in cases.iter() {
+            let mut combiner = Combiner::with_inputs(*mode, 3);
+            combiner.set_input_in(0, get_signal(A)).unwrap();
+            // Input 1 is left unconnected
+            combiner.set_input_in(2, get_signal(B)).unwrap();
+            let output = get_output(&combiner);
+            assert_output_eq(&output, expected);
+        }
+    }
+
+    #[test]
+    fn test_utility_modes() {
+        const SIGNAL: [f32; SAMPLE_RATE] = [1.5, -1.5, 0.5, -0.5];
+        let cases = [
+            (UtilityMode::Offset, [2.0, -1.0, 1.0, 0.0]),
+            (UtilityMode::Abs, [2.0, 2.0, 1.0, 1.0]),
+            (UtilityMode::Rectify, [2.0, 0.5, 1.0, 0.5]),
+            (UtilityMode::Invert, [-1.0, 2.0, 0.0, 1.0]),
+            (UtilityMode::Clamp, [1.5, -0.5, 1.0, 0.0])
+        ];
+        for (mode, expected) in cases.iter() {
+            let mut utility = Utility::new(*mode);
+            utility.set_offset(0.5);
+            utility.set_signal_in(get_signal(SIGNAL));
+            let output = get_output(&utility);
+            assert_output_eq(&output, expected);
+        }
+    }
+
+    #[test]
+    fn test_crossfade() {
+        let mut crossfade = Crossfade::new();
+        crossfade.set_a_in(get_signal([1.0; SAMPLE_RATE]));
+        crossfade.set_b_in(get_signal([-1.0; SAMPLE_RATE]));
+        crossfade.set_position(0.0);
+        crossfade.set_control_in(get_signal([0.0, 0.25, 1.0, 2.0]));
+        let output = get_output(&crossfade);
+        assert_output_eq(&output, &[1.0, 0.5, -1.0, -1.0]);
+    }
+}
@@ -3,10 +3,11 @@ pub mod midi_note;
 use crate::midi;
 use crate::midi::data::NoteDelta;
 use crate::{SynthError, SynthResult};
+use crate::patch_file::Table;
 
 use std::collections::HashSet;
-use std::time::Instant;
 use std::cell::{Cell, RefCell};
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, Clone, Copy, Hash)]
 struct TimestampDuration {
@@ -14,20 +15,22 @@ struct TimestampDuration {
     end_microseconds: usize
 }
 
+/// Holds the notes read for the block being rendered so every output sharing a `MidiModuleBase`
+/// gets the same notes
 #[derive(Debug, Clone)]
 struct MidiCache {
-    timestamp: Instant,
+    block_id: Option<usize>,
     timestamp_duration: TimestampDuration,
     cached_note_delta: Option<NoteDelta>,
 }
 
 impl MidiCache {
     fn new() -> Self {
-        let timestamp = Instant::now();
+        let block_id = None;
         let timestamp_duration = TimestampDuration{ start_microseconds: 0, end_microseconds: 0 };
         let cached_note_delta = None;
         Self {
-            timestamp,
+            block_id,
             timestamp_duration,
             cached_note_delta
         }
@@ -37,17 +40,17 @@ impl MidiCache {
         self.cached_note_delta = None;
     }
 
-    fn set_note_delta(&mut self, time: &Instant, duration: &TimestampDuration, delta: &NoteDelta) {
-        if *time != self.timestamp {
+    fn set_note_delta(&mut self, block_id: usize, duration: &TimestampDuration, delta: &NoteDelta) {
+        if Some(block_id) != self.block_id {
             self.invalidate();
-            self.timestamp = *time;
+            self.block_id = Some(block_id);
             self.timestamp_duration = *duration;
         }
         self.cached_note_delta = Some(delta.clone());
     }
 
-    fn try_get_note_delta(&self, time: &Instant) -> Option<&NoteDelta> {
-        if *time == self.timestamp {
+    fn try_get_note_delta(&self, block_id: usize) -> Option<&NoteDelta> {
+        if Some(block_id) == self.block_id {
             return self.cached_note_delta.as_ref();
         }
         None
@@ -56,6 +59,8 @@ impl MidiCache {
 
 pub struct MidiModuleBase {
     data: midi::data::MidiData,
+    /// The file the data was read from
+    path: PathBuf,
     track: usize,
     channel: Option<usize>,
     playing: bool,
@@ -64,14 +69,15 @@ pub struct MidiModuleBase {
 }
 
 impl MidiModuleBase {
-    pub fn open<P: AsRef<std::path::Path>>(path: P) -> SynthResult<Self> {
-        let data = match midi::data::MidiData::from_file(path) {
+    pub fn open<P: AsRef<Path>>(path: P) -> SynthResult<Self> {
+        let data = match midi::data::MidiData::from_file(path.as_ref()) {
             Ok(data) => data,
             Err(err) => {
                 let msg = format!("Failed to create MIDI file module: {}", err);
                 return Err(SynthError::new(&msg));
             }
         };
+        let path = path.as_ref().to_path_buf();
         let track = 0;
         let channel = None;
         let playing = true;
@@ -80,6 +86,7 @@ impl MidiModuleBase {
 
         Ok(Self {
             data,
+            path,
             track,
             channel,
             playing,
@@ -88,6 +95,36 @@ impl MidiModuleBase {
         })
     }
 
+    /// Opens the MIDI file named in a patch file's settings and picks its track and channel
+    pub fn load_settings(settings: &Table) -> SynthResult<Self> {
+        settings.check_keys(&["file", "track", "channel"])?;
+        let path = match settings.get_str("file")? {
+            Some(path) => path,
+            None => return Err(SynthError::new("MIDI module is missing \"file\""))
+        };
+        let mut midi_module = Self::open(path)?;
+        if let Some(track) = settings.get_usize("track")? {
+            midi_module.set_track(track)?;
+        }
+        midi_module.set_channel(settings.get_usize("channel")?);
+        Ok(midi_module)
+    }
+
+    /// The settings written to a patch file. The file's path is saved rather than its data
+    pub fn save_settings(&self) -> Table {
+        let mut settings = Table::new();
+        settings.set("file", self.path.to_string_lossy().into_owned());
+        settings.set("track", self.track);
+        if let Some(channel) = self.channel {
+            settings.set("channel", channel);
+        }
+        settings
+    }
+
+    pub fn get_path(&self) -> &Path {
+        &self.path
+    }
+
     pub fn set_track(&mut self, track_number: usize) -> SynthResult<()> {
         let track_len = self.data.get_tracks().len();
         if self.data.get_tracks().len() <= track_number {
@@ -148,11 +185,13 @@ impl MidiModuleBase {
         }
     }
 
+    /// Reads the notes that turn on or off during a block. Reading the same block again gives
+    /// the same notes without moving the time forward
     pub fn read_notes_on_off_delta(
-        &self, n_microseconds: usize, timestamp: &Instant
+        &self, n_microseconds: usize, block_id: usize
     ) -> SynthResult<NoteDelta> {
         let mut cache = self.cache.borrow_mut();
-        if let Some(cached_deltas) = cache.try_get_note_delta(timestamp) {
+        if let Some(cached_deltas) = cache.try_get_note_delta(block_id) {
             // We already got these deltas earlier, just send them again
             return Ok(cached_deltas.clone());
         }
@@ -169,7 +208,7 @@ impl MidiModuleBase {
         match note_delta_result {
             Ok(notes_delta) => {
                 let duration = TimestampDuration { start_microseconds, end_microseconds };
-                cache.set_note_delta(timestamp, &duration, &notes_delta);
+                cache.set_note_delta(block_id, &duration, &notes_delta);
                 self.microseconds_read.set(start_microseconds + n_microseconds);
 
                 Ok(notes_delta)
@@ -180,4 +219,31 @@ impl MidiModuleBase {
             }
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::test_util;
+
+    const BLOCK_MICROSECONDS: usize = 5_000_000;
+
+    #[test]
+    fn test_read_same_block_twice() {
+        let mut midi_module_base = MidiModuleBase::open(test_util::get_test_midi_file_path()).unwrap();
+        midi_module_base.set_track(1).unwrap();
+        midi_module_base.set_channel(Some(0));
+
+        // Everything reading the same block gets the same notes and the time only moves forward once
+        let first_delta = midi_module_base.read_notes_on_off_delta(BLOCK_MICROSECONDS, 1).unwrap();
+        assert_ne!(first_delta.delta.len(), 0, "Expected to get notes back");
+        assert_eq!(midi_module_base.get_time(), BLOCK_MICROSECONDS);
+        let second_delta = midi_module_base.read_notes_on_off_delta(BLOCK_MICROSECONDS, 1).unwrap();
+        assert_eq!(first_delta, second_delta, "Reading the same block twice gave different notes");
+        assert_eq!(midi_module_base.get_time(), BLOCK_MICROSECONDS);
+
+        // A new block carries on from the end of the last one
+        let third_delta = midi_module_base.read_notes_on_off_delta(BLOCK_MICROSECONDS, 2).unwrap();
+        assert_ne!(first_delta, third_delta, "Expected a new block to read new notes");
+        assert_eq!(midi_module_base.get_time(), 2 * BLOCK_MICROSECONDS);
+    }
+}
@@ -1,25 +1,111 @@
-use super::super::OutputInfo;
+use super::super::{OutputInfo, MultiOutputModule, PatchModule, no_such_input};
 use super::super::midi::MidiModuleBase;
-use crate::SynthResult;
+use super::super::port::PortBuffers;
+use super::super::parameter::{Parameters, ParameterInfo, clamp_parameter, no_such_parameter, choice_index, choice_value};
+use crate::{SynthError, SynthResult};
+use crate::patch_file::{Table, ModuleSettings};
 use crate::midi;
 use crate::midi::data::NoteDelta;
 use crate::module::SynthModule;
-use crate::note::{Note, NoteInterval};
+use crate::note::{self, Note, NoteInterval, MAX_VELOCITY};
+use crate::tuning::{self, Tuning};
 
-use std::collections::HashSet;
+use std::collections::{HashSet, HashMap};
 use std::rc::Rc;
-use std::time::Instant;
-use std::cell::{RefCell, Ref};
+use std::cell::{Cell, RefCell, Ref};
 
+/// Which property of the playing note a `MidiNoteOutput` puts out.
+/// Each one is also an output port named the same thing in lower case
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum MidiNoteSignal {
+    /// The pitch of the note, where C4 is 0.0 and each octave is 1.0. Between notes it holds the last pitch
+    Pitch,
+    /// The velocity of the note between 0.0 and 1.0
+    Velocity,
+    /// 1.0 while a note is on, otherwise 0.0
+    Gate
+}
+
+/// Names of the output ports, indexed by `MidiNoteSignal`
+const PORT_NAMES: [&str; 3] = ["pitch", "velocity", "gate"];
+
+const SIGNAL_NAMES: [(&str, MidiNoteSignal); 3] = [
+    ("Pitch", MidiNoteSignal::Pitch),
+    ("Velocity", MidiNoteSignal::Velocity),
+    ("Gate", MidiNoteSignal::Gate)
+];
+
+/// Puts out the notes from a `MidiModuleBase`. The main output is whichever signal it's been
+/// set to but every signal can be read from its own port at the same time
 pub struct MidiNoteOutput {
     midi_source: Rc<MidiModuleBase>,
-    active_notes: RefCell<HashSet<u8>>
+    signal: MidiNoteSignal,
+    /// Tuning used for pitches. Uses the global tuning if this is `None`
+    tuning: Option<Rc<Tuning>>,
+    active_notes: RefCell<HashSet<u8>>,
+    active_velocities: RefCell<HashMap<u8, u8>>,
+    /// Pitch of the last note that was played. Held between notes so the pitch doesn't jump to C4
+    last_pitch: Cell<f32>,
+    port_buffers: RefCell<PortBuffers>
 }
 
 impl MidiNoteOutput {
     pub fn new(midi_source: Rc<MidiModuleBase>) -> Self {
+        Self::with_signal(midi_source, MidiNoteSignal::Pitch)
+    }
+
+    /// Creates an output for a particular property of the notes. Several outputs can share one
+    /// `MidiModuleBase` e.g. one for pitch and one for velocity
+    pub fn with_signal(midi_source: Rc<MidiModuleBase>, signal: MidiNoteSignal) -> Self {
         let on_notes = RefCell::new(HashSet::new());
-        Self { midi_source, active_notes: on_notes }
+        let active_velocities = RefCell::new(HashMap::new());
+        let tuning = None;
+        let last_pitch = Cell::new(0.0);
+        let port_buffers = RefCell::new(PortBuffers::new());
+        Self { midi_source, signal, tuning, active_notes: on_notes, active_velocities, last_pitch, port_buffers }
+    }
+
+    /// Makes an output from its settings in a patch file. "midi" names the MIDI source it reads
+    /// from, which is one of the sources loaded from the file
+    pub fn load_settings(settings: &Table, midi_sources: &HashMap<String, Rc<MidiModuleBase>>) -> SynthResult<Self> {
+        settings.check_keys(&["midi", "signal"])?;
+        let midi_source = match settings.get_str("midi")? {
+            Some(name) => match midi_sources.get(name) {
+                Some(midi_source) => midi_source.clone(),
+                None => {
+                    let msg = format!("There's no MIDI source named \"{}\"", name);
+                    return Err(SynthError::new(&msg));
+                }
+            },
+            None => return Err(SynthError::new("MIDI output is missing \"midi\""))
+        };
+        let signal = settings.get_choice("signal", &SIGNAL_NAMES)?;
+        Ok(Self::with_signal(midi_source, signal.unwrap_or(MidiNoteSignal::Pitch)))
+    }
+
+    pub fn set_tuning(&mut self, tuning: Option<Rc<Tuning>>) {
+        self.tuning = tuning;
+    }
+
+    /// Gets the tuning this output uses, which is the global tuning if it hasn't been given one
+    pub fn get_tuning(&self) -> Rc<Tuning> {
+        match &self.tuning {
+            Some(tuning) => tuning.clone(),
+            None => tuning::get_global_tuning()
+        }
+    }
+
+    pub fn set_signal(&mut self, signal: MidiNoteSignal) {
+        self.signal = signal;
+    }
+
+    pub fn get_signal(&self) -> MidiNoteSignal {
+        self.signal
+    }
+
+    /// Gets the velocity a note that's currently on was played with
+    fn get_active_velocity(&self, note_number: u8) -> u8 {
+        self.active_velocities.borrow().get(&note_number).cloned().unwrap_or(MAX_VELOCITY)
     }
 
     // Gets all notes that are currently on
@@ -29,9 +115,9 @@ impl MidiNoteOutput {
     
     /// Gets changes in note state since the last time this was called
     fn read_notes_on_off_delta(
-        &self, n_microseconds: usize, timestamp: &Instant
+        &self, n_microseconds: usize, block_id: usize
     ) -> SynthResult<NoteDelta> {
-        self.midi_source.read_notes_on_off_delta(n_microseconds, timestamp)
+        self.midi_source.read_notes_on_off_delta(n_microseconds, block_id)
     }
 
     fn get_active_notes(&self) -> Ref<HashSet<u8>> {
@@ -50,7 +136,7 @@ impl MidiNoteOutput {
 
         let note_delta = match self.midi_source.read_notes_on_off_delta(
             sample_period_microseconds,
-            &output_info.timestamp
+            output_info.block_id
         ) {
             Ok(delta) => delta,
             Err(err) => {
@@ -66,7 +152,8 @@ impl MidiNoteOutput {
             let mut intervals = Vec::with_capacity(active_midi_notes.len());
             for active_midi_note in active_midi_notes.iter().cloned() {
                 let active_note = Note::from_midi_note(active_midi_note);
-                let interval = NoteInterval::new(active_note, None, None);
+                let velocity = self.get_active_velocity(active_midi_note);
+                let interval = NoteInterval::with_velocity(active_note, None, None, velocity);
                 intervals.push(interval);
             }
             return intervals;
@@ -79,7 +166,8 @@ impl MidiNoteOutput {
             // They also have no end sample initially though one might be added if a note off event is seen
             // in this sample period.
             let note = Note::from_midi_note(active_note);
-            let interval = NoteInterval::new(note, None, None);
+            let velocity = self.get_active_velocity(active_note);
+            let interval = NoteInterval::with_velocity(note, None, None, velocity);
             intervals.push(interval);
         }
 
@@ -101,9 +189,10 @@ impl MidiNoteOutput {
                         "Activated a note we were already playing"
                     );
                     active_notes.insert(note_number);
+                    self.active_velocities.borrow_mut().insert(note_number, delta.get_velocity());
 
                     let note = Note::from_midi_note(delta.get_note_number());
-                    let interval = NoteInterval::new(note, Some(sample_num), None);
+                    let interval = NoteInterval::with_velocity(note, Some(sample_num), None, delta.get_velocity());
                     intervals.push(interval);
                 }
                 midi::data::NoteEventType::Off => {
@@ -111,12 +200,14 @@ impl MidiNoteOutput {
                     // This should always find and interval to end. If it doesn't then something is wrong.
                     let successfully_removed = self.active_notes.borrow_mut().remove(&delta.get_note_number());
                     debug_assert!(successfully_removed, "Tried to remove an active note that didn't exist");
+                    self.active_velocities.borrow_mut().remove(&delta.get_note_number());
 
                     let note = Note::from_midi_note(delta.get_note_number());
 
                     for interval in intervals.iter_mut() {
                         if interval.note == note && interval.end.is_none() {
                             interval.end = Some(sample_num);
+                            interval.release_velocity = Some(delta.get_velocity());
                             break;
                         }
                     }
@@ -125,30 +216,101 @@ impl MidiNoteOutput {
         }
         intervals
     }
+
+    /// Renders note intervals into the output port buffers. The buffers should start out zeroed.
+    /// The first interval is output until it's done then the second, etc. Between notes the gate and
+    /// velocity are 0.0 and the pitch holds where the last note left it. Keys the tuning doesn't map are
+    /// skipped entirely.
+    fn render_intervals(&self, intervals: Vec<NoteInterval>, buffers: &mut [Vec<f32>]) {
+        let tuning = self.get_tuning();
+        let buffer_len = buffers[MidiNoteSignal::Pitch as usize].len();
+        let mut current_sample = 0_usize;
+        for interval in intervals {
+            let freq = match tuning.get_note_freq(&interval.note) {
+                Some(freq) => freq,
+                None => continue
+            };
+            let start = interval.start.unwrap_or(0).clamp(current_sample, buffer_len);
+            let end = interval.end.unwrap_or(buffer_len).clamp(start, buffer_len);
+
+            let pitch_buffer = &mut buffers[MidiNoteSignal::Pitch as usize];
+            pitch_buffer[current_sample..start].fill(self.last_pitch.get());
+            self.last_pitch.set(note::freq_to_pitch(freq));
+            pitch_buffer[start..end].fill(self.last_pitch.get());
+            buffers[MidiNoteSignal::Velocity as usize][start..end].fill(interval.get_velocity_normalized());
+            buffers[MidiNoteSignal::Gate as usize][start..end].fill(1.0);
+            current_sample = end;
+        }
+        buffers[MidiNoteSignal::Pitch as usize][current_sample..].fill(self.last_pitch.get());
+    }
 }
 
 impl SynthModule for MidiNoteOutput {
     fn fill_output_buffer(&self, buffer: &mut [f32], output_info: &OutputInfo) {
-        // Put whatever the first interval is as the output until it's done then move on to the second, etc.
-        let fill_with_interval = |buffer: &mut [f32], interval: NoteInterval, current_sample: usize| {
-            let end = interval.end.unwrap_or(buffer.len());
-            let signal_out = interval.note.to_freq_normalized();
-            buffer[current_sample..end].fill(signal_out);
-        };
+        self.fill_port_buffer(self.signal as usize, buffer, output_info);
+    }
+}
 
-        let intervals = self.read_note_intervals(buffer.len(), output_info);
-        let mut current_sample = 0_usize;
-        for interval in intervals {
-            if interval.start.unwrap_or(0) <= current_sample {
-                fill_with_interval(buffer, interval, current_sample)
-            }
-            else {
-                buffer[current_sample..interval.start.unwrap()].fill(0.0);
-                fill_with_interval(buffer, interval, interval.start.unwrap());
-            }
-            current_sample = interval.end.unwrap_or(buffer.len());
+impl MultiOutputModule for MidiNoteOutput {
+    fn get_output_names(&self) -> Vec<String> {
+        PORT_NAMES.iter().map(|name| name.to_string()).collect()
+    }
+
+    fn fill_port_buffer(&self, port: usize, buffer: &mut [f32], output_info: &OutputInfo) {
+        let mut port_buffers = self.port_buffers.borrow_mut();
+        if !port_buffers.is_current(buffer.len(), output_info) {
+            // Reading the intervals moves the MIDI along so it's only done once for every port
+            let intervals = self.read_note_intervals(buffer.len(), output_info);
+            let buffers = port_buffers.start_block(PORT_NAMES.len(), buffer.len(), output_info);
+            self.render_intervals(intervals, buffers);
+        }
+        port_buffers.copy_port(port, buffer);
+    }
+}
+
+impl PatchModule for MidiNoteOutput {
+    fn get_input_names(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    fn set_input(&mut self, input: usize, _module: Option<Rc<dyn SynthModule>>) -> SynthResult<()> {
+        no_such_input(input)
+    }
+
+    fn as_multi_output(&self) -> Option<&dyn MultiOutputModule> {
+        Some(self)
+    }
+
+    /// Custom tunings aren't saved. A loaded output uses the global tuning. The MIDI source is
+    /// saved on its own so outputs that share it still share it once they're loaded
+    fn save_settings(&self) -> Option<ModuleSettings> {
+        let mut settings = Table::new();
+        settings.set_choice("signal", self.signal, &SIGNAL_NAMES);
+        Some(ModuleSettings::new("MidiNoteOutput", settings).with_midi_source(self.midi_source.clone()))
+    }
+}
+
+/// The MIDI file, track and channel belong to the `MidiModuleBase`, which can be shared, so they aren't parameters
+impl Parameters for MidiNoteOutput {
+    fn get_parameter_info(&self) -> Vec<ParameterInfo> {
+        vec![ParameterInfo::choice("signal", "Signal", &SIGNAL_NAMES, MidiNoteSignal::Pitch)]
+    }
+
+    fn get_parameter(&self, id: &str) -> Option<f32> {
+        match id {
+            "signal" => Some(choice_index(&SIGNAL_NAMES, self.signal)),
+            _ => None
         }
     }
+
+    fn set_parameter(&mut self, id: &str, value: f32) -> SynthResult<()> {
+        let value = clamp_parameter(self, id, value)?;
+        match id {
+            "signal" => self.set_signal(choice_value(&SIGNAL_NAMES, value)),
+            _ => return no_such_parameter(id)
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -157,7 +319,6 @@ mod tests {
     use crate::util::test_util;
 
     use core::panic;
-    use std::time;
 
     fn get_test_midi_module() -> MidiNoteOutput {
         let path = test_util::get_test_midi_file_path();
@@ -182,7 +343,7 @@ mod tests {
         midi_source.set_channel(Some(0));
         drop(midi_source);
 
-        let delta = match midi_module.read_notes_on_off_delta(10_000_000, &time::Instant::now()) {
+        let delta = match midi_module.read_notes_on_off_delta(10_000_000, 0) {
             Ok(delta) => delta,
             Err(err) => {
                 panic!("Failed to get note delta: {}", err);
@@ -227,6 +388,106 @@ mod tests {
         );
     }
 
+    #[test]
+    fn read_note_velocities() {
+        const SAMPLE_RATE: usize = 1;
+        let mut midi_module = get_test_midi_module();
+        Rc::get_mut(&mut midi_module.midi_source).unwrap().set_channel(Some(0));
+
+        // Ten seconds worth of notes
+        let mut clock = crate::clock::SampleClock::new(SAMPLE_RATE);
+        let output_info = OutputInfo::new_basic(SAMPLE_RATE, clock.get_range(10));
+        let intervals = midi_module.read_note_intervals(10, &output_info);
+        assert!(intervals.iter().any(|interval| interval.start.is_some()), "Expected some notes to start");
+        for interval in intervals.iter() {
+            assert!(interval.velocity > 0, "Expected notes to have been played with a velocity");
+            assert_eq!(
+                interval.end.is_some(), interval.release_velocity.is_some(),
+                "Expected notes that ended to have a release velocity"
+            );
+        }
+    }
+
+    #[test]
+    fn read_ports_in_same_block() {
+        const SAMPLE_RATE: usize = 1;
+        let mut midi_module = get_test_midi_module();
+        Rc::get_mut(&mut midi_module.midi_source).unwrap().set_channel(Some(0));
+        assert_eq!(midi_module.get_output_index("gate"), Some(MidiNoteSignal::Gate as usize));
+
+        // Both ports have to come from the same notes even though the MIDI is only read once
+        let mut clock = crate::clock::SampleClock::new(SAMPLE_RATE);
+        let output_info = OutputInfo::new_basic(SAMPLE_RATE, clock.get_range(10));
+        let mut gate = vec![0.0; 10];
+        midi_module.fill_port_buffer(MidiNoteSignal::Gate as usize, &mut gate, &output_info);
+        let mut velocity = vec![0.0; 10];
+        midi_module.fill_port_buffer(MidiNoteSignal::Velocity as usize, &mut velocity, &output_info);
+        assert!(gate.contains(&1.0), "Expected some notes to be on");
+        for (gate_datum, velocity_datum) in gate.iter().zip(velocity.iter()) {
+            assert_eq!(
+                *gate_datum == 1.0, *velocity_datum > 0.0,
+                "Expected gate and velocity to line up.\n\tGate: {:?}\n\tVelocity: {:?}", gate, velocity
+            );
+        }
+    }
+
+    #[test]
+    fn unmapped_keys_and_gaps() {
+        // Twelve tone equal temperament with C#4 left unmapped
+        let kbm = "! no C#
+12
+0
+127
+60
+69
+440.0
+12
+0
+x
+2
+3
+4
+5
+6
+7
+8
+9
+10
+11
+";
+        let mut tuning = Tuning::new();
+        tuning.set_keyboard_mapping(tuning::KeyboardMapping::from_kbm_str(kbm).unwrap()).unwrap();
+        assert!(tuning.get_freq(61).is_none());
+        let mut midi_module = get_test_midi_module();
+        midi_module.set_tuning(Some(Rc::new(tuning)));
+
+        let e4_pitch = 4.0 / 12.0;
+        let d4_pitch = 2.0 / 12.0;
+        let intervals = vec![
+            NoteInterval::with_velocity(Note::from_midi_note(64), Some(0), Some(2), MAX_VELOCITY),
+            NoteInterval::with_velocity(Note::from_midi_note(61), Some(2), Some(4), MAX_VELOCITY),
+            NoteInterval::with_velocity(Note::from_midi_note(62), Some(6), Some(8), MAX_VELOCITY)
+        ];
+        let mut buffers = vec![vec![0.0; 10]; PORT_NAMES.len()];
+        midi_module.render_intervals(intervals, &mut buffers);
+
+        // The unmapped key doesn't play at all and the pitch holds through every gap
+        const EXPECTED_GATE: [f32; 10] = [1.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 1.0, 0.0, 0.0];
+        let mut expected_pitch = [e4_pitch; 10];
+        expected_pitch[6..].fill(d4_pitch);
+        assert_eq!(buffers[MidiNoteSignal::Gate as usize], EXPECTED_GATE);
+        assert_eq!(buffers[MidiNoteSignal::Velocity as usize].iter().filter(|velocity| **velocity > 0.0).count(), 4);
+        for (got, expected) in buffers[MidiNoteSignal::Pitch as usize].iter().zip(expected_pitch.iter()) {
+            assert!((got - expected).abs() < 0.0001, "Pitch does not match expected: {:?}", buffers[MidiNoteSignal::Pitch as usize]);
+        }
+
+        // The pitch keeps holding into the next block
+        let mut buffers = vec![vec![0.0; 4]; PORT_NAMES.len()];
+        midi_module.render_intervals(Vec::new(), &mut buffers);
+        assert!(buffers[MidiNoteSignal::Pitch as usize].iter().all(|pitch| (pitch - d4_pitch).abs() < 0.0001));
+        assert!(buffers[MidiNoteSignal::Gate as usize].iter().all(|gate| *gate == 0.0));
+    }
+
     #[test]
     fn get_active_notes() {
         let on_notes: HashSet<u8> = HashSet::from([1, 4, 3, 5, 2]);
@@ -2,22 +2,26 @@ use std::rc::Rc;
 
 use crate::prelude::*;
 use crate::{SynthError, SynthResult};
-use super::{SynthModule, OutputInfo, CompressionMode, compress_audio};
+use super::{SynthModule, PatchModule, OutputInfo, SmoothedValue, CompressionMode, compress_audio, no_such_input, COMPRESSION_MODE_NAMES};
+use crate::patch_file::{Table, Value, ModuleSettings};
+use super::limiter::{Limiter, LIMITER_PARAMETER_PREFIX};
+use super::parameter::{Parameters, ParameterInfo, ParameterScale, clamp_parameter, no_such_parameter, choice_index, choice_value};
+use super::render_cache::BlockCache;
 
 pub struct MixerInput {
     input: Option<Rc<dyn SynthModule>>,
-    level: f32
+    level: SmoothedValue
 }
 
 impl MixerInput {
     pub fn new() -> Self {
         let input = None;
-        let level = 1_f32;
+        let level = SmoothedValue::new(1.0);
         Self { input, level }
     }
 
     pub fn with_input(input: Option<Rc<dyn SynthModule>>) -> Self {
-        let level = 1_f32;
+        let level = SmoothedValue::new(1.0);
         Self { input, level }
     }
 
@@ -26,20 +30,46 @@ impl MixerInput {
     }
 
     pub fn set_level(&mut self, level: f32) {
-        self.level = level;
+        self.level.set(level);
+    }
+
+    pub fn get_level(&self) -> f32 {
+        self.level.get()
+    }
+
+    /// Sets how long in milliseconds a level change takes to ramp in
+    pub fn set_smoothing_time(&mut self, smoothing_time: f32) {
+        self.level.set_ramp_time(smoothing_time);
+    }
+
+    pub fn get_smoothing_time(&self) -> f32 {
+        self.level.get_ramp_time()
+    }
+}
+
+impl Default for MixerInput {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
+/// Put in front of an input's index in the id of its level parameter
+const LEVEL_PARAMETER_PREFIX: &str = "level_";
+
 pub struct Mixer {
     inputs: Vec<MixerInput>,
-    compression_mode: CompressionMode
+    compression_mode: CompressionMode,
+    limiter: Limiter,
+    block_cache: BlockCache
 }
 
 impl Mixer {
     pub fn new() -> Self {
         let inputs = Vec::new();
         let compression_mode = CompressionMode::None;
-        Self { inputs, compression_mode }
+        let limiter = Limiter::new();
+        let block_cache = BlockCache::new();
+        Self { inputs, compression_mode, limiter, block_cache }
     }
 
     pub fn with_inputs(n_inputs: usize) -> Self {
@@ -48,7 +78,44 @@ impl Mixer {
             inputs.push(MixerInput::new());
         }
         let compression_mode = CompressionMode::None;
-        Self { inputs, compression_mode }
+        let limiter = Limiter::new();
+        let block_cache = BlockCache::new();
+        Self { inputs, compression_mode, limiter, block_cache }
+    }
+
+    /// Makes a mixer from its settings in a patch file. It gets an input for every level
+    pub fn load_settings(settings: &Table) -> SynthResult<Self> {
+        settings.check_keys(&["levels", "compression", "limiter"])?;
+        let mut mixer = Self::new();
+        for level in settings.get_array_of("levels", "a number", Value::as_float)?.unwrap_or_default() {
+            let mut input = MixerInput::new();
+            input.set_level(level);
+            mixer.add_input(input);
+        }
+        if let Some(compression_mode) = settings.get_choice("compression", &COMPRESSION_MODE_NAMES)? {
+            mixer.set_compression_mode(compression_mode);
+        }
+        if let Some(limiter_settings) = settings.get_table("limiter")? {
+            mixer.limiter.apply_settings(limiter_settings)?;
+        }
+        Ok(mixer)
+    }
+
+    pub fn set_compression_mode(&mut self, compression_mode: CompressionMode) {
+        self.compression_mode = compression_mode;
+    }
+
+    pub fn get_compression_mode(&self) -> CompressionMode {
+        self.compression_mode
+    }
+
+    /// Gets the limiter used when the compression mode is `CompressionMode::LookaheadLimit`
+    pub fn get_limiter(&self) -> &Limiter {
+        &self.limiter
+    }
+
+    pub fn get_limiter_mut(&mut self) -> &mut Limiter {
+        &mut self.limiter
     }
 
     pub fn add_input(&mut self, input: MixerInput) {
@@ -71,43 +138,115 @@ impl Mixer {
     pub fn iter_inputs_mut(&mut self) -> std::slice::IterMut<MixerInput> {
         self.inputs.iter_mut()
     }
+
+    fn get_level_input_index(id: &str) -> Option<usize> {
+        id.strip_prefix(LEVEL_PARAMETER_PREFIX)?.parse().ok()
+    }
 }
 
 impl SynthModule for Mixer {
     fn fill_output_buffer(&self, data: &mut [f32], output_info: &OutputInfo) {
-        let data_len = data.len();
-        let input_len = self.inputs.len();
+        self.block_cache.fill(data, output_info, |data| {
+            let data_len = data.len();
+            let input_len = self.inputs.len();
 
-        for datum in data.iter_mut() {
-            *datum = 0.0;
-        }
-
-        // Merge all inputs into `data`
-        let mut data_buffer = Vec::with_capacity(data_len);
-        data_buffer.resize(data_len, 0.0);
-        for i in 0..input_len {
-            let input = &self.inputs[i];
-            if let Some(signal_input) = &input.input {
-                signal_input.fill_output_buffer(&mut data_buffer, output_info);
-            }
-            else {
-                continue;
+            for datum in data.iter_mut() {
+                *datum = 0.0;
             }
 
-            // Apply the level if we need to
-            if !float_eq(input.level, 1.0, 0.000001) {
-                for datum in data_buffer.iter_mut() {
-                    *datum *= input.level;
+            // Merge all inputs into `data`
+            let mut data_buffer = Vec::with_capacity(data_len);
+            data_buffer.resize(data_len, 0.0);
+            for i in 0..input_len {
+                let input = &self.inputs[i];
+                if let Some(signal_input) = &input.input {
+                    signal_input.fill_output_buffer(&mut data_buffer, output_info);
+                }
+                else {
+                    continue;
+                }
+
+                // Apply the level if we need to
+                if input.level.is_smoothing() || !float_eq(input.level.get(), 1.0, 0.000001) {
+                    for datum in data_buffer.iter_mut() {
+                        *datum *= input.level.next(output_info.sample_rate);
+                    }
                 }
-            }
             
-            for i in 0..data_len {
-                data[i] += data_buffer[i];
+                for i in 0..data_len {
+                    data[i] += data_buffer[i];
+                }
             }
+
+            // Apply compression if needed
+            match self.compression_mode {
+                CompressionMode::LookaheadLimit => self.limiter.process(data, output_info.sample_rate),
+                _ => compress_audio(data, self.compression_mode)
+            }
+        });
+    }
+}
+
+impl PatchModule for Mixer {
+    fn get_input_names(&self) -> Vec<String> {
+        (0..self.inputs.len()).map(|input_index| format!("in {}", input_index)).collect()
+    }
+
+    fn set_input(&mut self, input: usize, module: Option<Rc<dyn SynthModule>>) -> SynthResult<()> {
+        match self.inputs.get_mut(input) {
+            Some(mixer_input) => mixer_input.set_input(module),
+            None => return no_such_input(input)
+        }
+        Ok(())
+    }
+
+    fn save_settings(&self) -> Option<ModuleSettings> {
+        let mut settings = Table::new();
+        let levels: Vec<f32> = self.inputs.iter().map(|input| input.get_level()).collect();
+        settings.set("levels", levels);
+        settings.set_choice("compression", self.compression_mode, &COMPRESSION_MODE_NAMES);
+        settings.set("limiter", self.limiter.save_settings());
+        Some(ModuleSettings::new("Mixer", settings))
+    }
+}
+
+/// Every input has a level parameter, e.g. "level_0". The limiter's parameters start with "limiter_"
+impl Parameters for Mixer {
+    fn get_parameter_info(&self) -> Vec<ParameterInfo> {
+        let mut info: Vec<ParameterInfo> = (0..self.inputs.len()).map(|input_index| {
+            let id = format!("{}{}", LEVEL_PARAMETER_PREFIX, input_index);
+            let name = format!("Input {} Level", input_index);
+            ParameterInfo::new(&id, &name, "", 0.0, 2.0, 1.0, ParameterScale::Linear)
+        }).collect();
+        info.push(ParameterInfo::choice("compression", "Compression", &COMPRESSION_MODE_NAMES, CompressionMode::None));
+        info.extend(self.limiter.get_owner_parameter_info());
+        info
+    }
+
+    fn get_parameter(&self, id: &str) -> Option<f32> {
+        if id == "compression" {
+            return Some(choice_index(&COMPRESSION_MODE_NAMES, self.compression_mode));
         }
+        if let Some(limiter_id) = id.strip_prefix(LIMITER_PARAMETER_PREFIX) {
+            return self.limiter.get_parameter(limiter_id);
+        }
+        Self::get_level_input_index(id).and_then(|input_index| self.inputs.get(input_index)).map(MixerInput::get_level)
+    }
 
-        // Apply compression if needed
-        compress_audio(data, self.compression_mode)
+    fn set_parameter(&mut self, id: &str, value: f32) -> SynthResult<()> {
+        let value = clamp_parameter(self, id, value)?;
+        if id == "compression" {
+            self.set_compression_mode(choice_value(&COMPRESSION_MODE_NAMES, value));
+            return Ok(());
+        }
+        if let Some(limiter_id) = id.strip_prefix(LIMITER_PARAMETER_PREFIX) {
+            return self.limiter.set_parameter(limiter_id, value);
+        }
+        match Self::get_level_input_index(id).and_then(|input_index| self.inputs.get_mut(input_index)) {
+            Some(input) => input.set_level(value),
+            None => return no_such_parameter(id)
+        }
+        Ok(())
     }
 }
 
@@ -115,6 +254,7 @@ impl SynthModule for Mixer {
 mod tests {
     use super::*;
     use super::super::oscillator;
+    use super::super::sample_buffer::SampleBuffer;
     use crate::clock;
 
     fn get_square_and_25_pulse_mixer_inputs() -> (MixerInput, MixerInput) {
@@ -169,7 +309,7 @@ mod tests {
         mixer.compression_mode = CompressionMode::None;
 
         let (mut mixer_input_1, mixer_input_2) = get_square_and_25_pulse_mixer_inputs();
-        mixer_input_1.level = 0.5;
+        mixer_input_1.set_level(0.5);
 
         mixer.add_input(mixer_input_1);
         mixer.add_input(mixer_input_2);
@@ -197,7 +337,7 @@ mod tests {
         mixer.compression_mode = CompressionMode::Compress;
 
         let (mut mixer_input_1, mixer_input_2) = get_square_and_25_pulse_mixer_inputs();
-        mixer_input_1.level = 0.5;
+        mixer_input_1.set_level(0.5);
 
         mixer.add_input(mixer_input_1);
         mixer.add_input(mixer_input_2);
@@ -243,4 +383,34 @@ mod tests {
             );
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_lookahead_limit_mixing() {
+        const SAMPLE_RATE: usize = 10_usize;
+        let mut mixer = Mixer::new();
+        mixer.set_compression_mode(CompressionMode::LookaheadLimit);
+        mixer.get_limiter_mut().set_lookahead_time(100.0);
+        let ceiling = db_to_amplitude(mixer.get_limiter().get_ceiling());
+
+        let loud_samples = vec![1.0; SAMPLE_RATE];
+        mixer.add_input(MixerInput::with_input(Some(Rc::new(SampleBuffer::new(loud_samples.clone())))));
+        mixer.add_input(MixerInput::with_input(Some(Rc::new(SampleBuffer::new(loud_samples)))));
+
+        // Render a few blocks so the delayed audio has made it through the limiter
+        let mut output_buffer = vec![0.0; SAMPLE_RATE];
+        for _ in 0..3 {
+            let clock_values = get_clock_values(SAMPLE_RATE, SAMPLE_RATE);
+            let output_info = OutputInfo::new_basic(SAMPLE_RATE, clock_values);
+            mixer.fill_output_buffer(&mut output_buffer, &output_info);
+
+            for datum in output_buffer.iter() {
+                assert!(datum.abs() <= ceiling + 0.00001, "Output went over the limiter ceiling: {:?}", output_buffer);
+            }
+        }
+
+        // A steady signal over the ceiling should be turned down to exactly the ceiling
+        for datum in output_buffer.iter() {
+            assert!(float_eq(*datum, ceiling, 0.0001), "Output was not limited to the ceiling: {:?}", output_buffer);
+        }
+    }
+}
@@ -0,0 +1,518 @@
+use std::rc::Rc;
+use std::cell::Cell;
+
+use super::{SynthModule, PatchModule, OutputInfo, no_such_input, input_names};
+use super::envelope::EnvelopeCurve;
+use crate::{SynthError, SynthResult};
+use super::parameter::{Parameters, ParameterInfo, ParameterScale, clamp_parameter, no_such_parameter, choice_index, choice_value};
+use super::render_cache::BlockCache;
+
+const MILLISECONDS_PER_SECOND: f32 = 1000.0;
+const SECONDS_PER_MINUTE: f32 = 60.0;
+
+/// One point of a `MultiSegmentEnvelope`. The envelope moves from wherever it is to `level`
+/// over `time` along `curve`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Breakpoint {
+    /// How long it takes to reach this breakpoint from the previous one. In milliseconds,
+    /// or in beats if the envelope is synced to a tempo
+    pub time: f32,
+    pub level: f32,
+    pub curve: EnvelopeCurve
+}
+
+impl Breakpoint {
+    pub const fn new(time: f32, level: f32, curve: EnvelopeCurve) -> Self {
+        Self { time, level, curve }
+    }
+}
+
+/// How a `MultiSegmentEnvelope` is played
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum MultiSegmentMode {
+    /// The shape plays once each time the envelope is triggered. Sustain and loop points are
+    /// honored while the trigger is held
+    Triggered,
+    /// The whole shape cycles forever. A trigger restarts it from the first breakpoint.
+    /// Sustain and loop points are ignored
+    FreeRunning
+}
+
+const MODE_NAMES: [(&str, MultiSegmentMode); 2] = [
+    ("Triggered", MultiSegmentMode::Triggered),
+    ("FreeRunning", MultiSegmentMode::FreeRunning)
+];
+
+/// An envelope made of any number of breakpoints. Like `Envelope` it's driven by a trigger
+/// input, but it can have as many stages as needed, a sustain point and a loop.
+pub struct MultiSegmentEnvelope {
+    breakpoints: Vec<Breakpoint>,
+    sustain_point: Option<usize>,
+    loop_points: Option<(usize, usize)>,
+    mode: MultiSegmentMode,
+    /// If set, breakpoint times are in beats at this many beats per minute
+    tempo: Option<f32>,
+
+    segment: Cell<usize>,
+    segment_samples_elapsed: Cell<usize>,
+    segment_start_value: Cell<f32>,
+    previous_value: Cell<f32>,
+    running: Cell<bool>,
+    sustaining: Cell<bool>,
+
+    trigger: Option<Rc<dyn SynthModule>>,
+    trigger_tolerance: f32, // Minimum value at which envelope is triggered
+    triggered: Cell<bool>,
+    block_cache: BlockCache
+}
+
+impl MultiSegmentEnvelope {
+    pub fn new() -> Self {
+        let breakpoints = Vec::new();
+        let sustain_point = None;
+        let loop_points = None;
+        let mode = MultiSegmentMode::Triggered;
+        let tempo = None;
+
+        let segment = Cell::new(0);
+        let segment_samples_elapsed = Cell::new(0);
+        let segment_start_value = Cell::new(0.0);
+        let previous_value = Cell::new(0.0);
+        let running = Cell::new(false);
+        let sustaining = Cell::new(false);
+
+        let trigger = None;
+        let trigger_tolerance = 0.5;
+        let triggered = Cell::new(false);
+
+        let block_cache = BlockCache::new();
+
+        Self {
+            breakpoints, sustain_point, loop_points, mode, tempo,
+            segment, segment_samples_elapsed, segment_start_value, previous_value, running, sustaining,
+            trigger, trigger_tolerance, triggered, block_cache
+        }
+    }
+
+    pub fn with_breakpoints(breakpoints: Vec<Breakpoint>) -> Self {
+        let mut envelope = Self::new();
+        envelope.breakpoints = breakpoints;
+        envelope
+    }
+
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) {
+        self.breakpoints.push(breakpoint);
+    }
+
+    pub fn get_breakpoint(&self, index: usize) -> Option<&Breakpoint> {
+        self.breakpoints.get(index)
+    }
+
+    pub fn get_breakpoint_mut(&mut self, index: usize) -> Option<&mut Breakpoint> {
+        self.breakpoints.get_mut(index)
+    }
+
+    /// Removes a breakpoint. Sustain and loop points that refer to it are cleared. Points after it
+    /// are moved back so they still refer to the same breakpoints.
+    pub fn remove_breakpoint(&mut self, index: usize) -> SynthResult<()> {
+        if index >= self.breakpoints.len() {
+            let msg = "Failed to remove breakpoint because index is out of bounds";
+            return Err(SynthError::new(msg));
+        }
+        self.breakpoints.remove(index);
+
+        let shift_point = |point: usize| if point > index { point - 1 } else { point };
+        self.sustain_point = self.sustain_point
+            .filter(|sustain_point| *sustain_point != index)
+            .map(shift_point);
+        self.loop_points = self.loop_points
+            .filter(|(loop_start, loop_end)| *loop_start != index && *loop_end != index)
+            .map(|(loop_start, loop_end)| (shift_point(loop_start), shift_point(loop_end)));
+        self.segment.set(0);
+        Ok(())
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Breakpoint> {
+        self.breakpoints.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> std::slice::IterMut<'_, Breakpoint> {
+        self.breakpoints.iter_mut()
+    }
+
+    pub fn len(&self) -> usize {
+        self.breakpoints.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.breakpoints.is_empty()
+    }
+
+    /// Sets a breakpoint the envelope will wait at while the trigger is held
+    pub fn set_sustain_point(&mut self, sustain_point: Option<usize>) -> SynthResult<()> {
+        if let Some(index) = sustain_point {
+            if index >= self.breakpoints.len() {
+                let msg = format!("Sustain point {} is out of bounds", index);
+                return Err(SynthError::new(&msg));
+            }
+        }
+        self.sustain_point = sustain_point;
+        Ok(())
+    }
+
+    pub fn get_sustain_point(&self) -> Option<usize> {
+        self.sustain_point
+    }
+
+    /// Sets a range of breakpoints that will repeat while the trigger is held. When the envelope
+    /// reaches the end breakpoint it heads back to the start breakpoint, taking the start
+    /// breakpoint's time and curve to get there
+    pub fn set_loop_points(&mut self, loop_points: Option<(usize, usize)>) -> SynthResult<()> {
+        if let Some((loop_start, loop_end)) = loop_points {
+            if loop_start >= loop_end || loop_end >= self.breakpoints.len() {
+                let msg = format!("Invalid loop points: {} to {}", loop_start, loop_end);
+                return Err(SynthError::new(&msg));
+            }
+        }
+        self.loop_points = loop_points;
+        Ok(())
+    }
+
+    pub fn get_loop_points(&self) -> Option<(usize, usize)> {
+        self.loop_points
+    }
+
+    pub fn set_mode(&mut self, mode: MultiSegmentMode) {
+        self.mode = mode;
+    }
+
+    pub fn get_mode(&self) -> MultiSegmentMode {
+        self.mode
+    }
+
+    /// Syncs the envelope to a tempo in beats per minute. Breakpoint times will be treated as beats.
+    /// `None` goes back to milliseconds.
+    pub fn set_tempo(&mut self, tempo: Option<f32>) {
+        self.tempo = tempo;
+    }
+
+    pub fn get_tempo(&self) -> Option<f32> {
+        self.tempo
+    }
+
+    pub fn set_trigger(&mut self, trigger: Option<Rc<dyn SynthModule>>) {
+        self.trigger = trigger;
+    }
+
+    pub fn set_trigger_tolerance(&mut self, trigger_tolerance: f32) {
+        self.trigger_tolerance = trigger_tolerance;
+    }
+
+    pub fn get_trigger_tolerance(&self) -> f32 {
+        self.trigger_tolerance
+    }
+
+    pub fn trigger(&self) {
+        self.triggered.set(true);
+        self.running.set(true);
+        self.enter_segment(0);
+    }
+
+    pub fn release(&self) {
+        self.triggered.set(false);
+        if self.mode == MultiSegmentMode::FreeRunning {
+            return;
+        }
+
+        // Skip ahead to whatever comes after the sustain or loop if we haven't passed it yet
+        let release_point = match (self.sustain_point, self.loop_points) {
+            (Some(sustain_point), Some((_, loop_end))) => Some(sustain_point.max(loop_end)),
+            (Some(sustain_point), None) => Some(sustain_point),
+            (None, Some((_, loop_end))) => Some(loop_end),
+            (None, None) => None
+        };
+        if let Some(release_point) = release_point {
+            let segment = self.segment.get();
+            if self.sustaining.get() || segment <= release_point {
+                self.enter_segment(release_point + 1);
+            }
+        }
+    }
+
+    /// Starts moving towards a breakpoint from the current value
+    fn enter_segment(&self, segment: usize) {
+        self.sustaining.set(false);
+        self.segment.set(segment);
+        self.segment_samples_elapsed.set(0);
+        self.segment_start_value.set(self.previous_value.get());
+        if segment >= self.breakpoints.len() {
+            self.running.set(false);
+        }
+    }
+
+    /// Called once a breakpoint has been reached to work out where to go next
+    fn finish_segment(&self, segment: usize) {
+        let held = self.triggered.get() && self.mode == MultiSegmentMode::Triggered;
+        if held {
+            if let Some((loop_start, loop_end)) = self.loop_points {
+                if segment == loop_end {
+                    self.enter_segment(loop_start);
+                    return;
+                }
+            }
+            if self.sustain_point == Some(segment) {
+                self.sustaining.set(true);
+                return;
+            }
+        }
+
+        let next_segment = segment + 1;
+        if next_segment >= self.breakpoints.len() && self.mode == MultiSegmentMode::FreeRunning {
+            self.enter_segment(0);
+        }
+        else {
+            self.enter_segment(next_segment);
+        }
+    }
+
+    fn time_to_samples(&self, time: f32, sample_rate: usize) -> f32 {
+        let seconds = match self.tempo {
+            Some(tempo) => time * SECONDS_PER_MINUTE / tempo,
+            None => time / MILLISECONDS_PER_SECOND
+        };
+        seconds * sample_rate as f32
+    }
+
+    pub fn get(&self, sample_rate: usize) -> f32 {
+        let running = self.running.get() || self.mode == MultiSegmentMode::FreeRunning;
+        if !running || self.sustaining.get() || self.breakpoints.is_empty() {
+            return self.previous_value.get();
+        }
+
+        let segment = self.segment.get();
+        let breakpoint = match self.breakpoints.get(segment) {
+            Some(breakpoint) => *breakpoint,
+            None => {
+                // Breakpoints were changed out from under us. Start over
+                self.enter_segment(0);
+                self.breakpoints[0]
+            }
+        };
+
+        let elapsed = self.segment_samples_elapsed.get() + 1;
+        self.segment_samples_elapsed.set(elapsed);
+        let duration = self.time_to_samples(breakpoint.time, sample_rate);
+        let progress = if duration > 0.0 { (elapsed as f32 / duration).min(1.0) } else { 1.0 };
+
+        let start = self.segment_start_value.get();
+        let envelope_value = start + (breakpoint.level - start) * breakpoint.curve.apply(progress);
+        debug_assert!(envelope_value.is_finite());
+        self.previous_value.set(envelope_value);
+
+        if progress >= 1.0 {
+            self.previous_value.set(breakpoint.level);
+            self.finish_segment(self.segment.get());
+            return breakpoint.level;
+        }
+        envelope_value
+    }
+}
+
+impl Default for MultiSegmentEnvelope {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SynthModule for MultiSegmentEnvelope {
+    fn fill_output_buffer(&self, data: &mut [f32], output_info: &OutputInfo) {
+        self.block_cache.fill(data, output_info, |data| {
+            let data_size = data.len();
+            let mut trigger_data = vec![0.0; data_size];
+
+            if let Some(trigger) = &self.trigger {
+                trigger.fill_output_buffer(&mut trigger_data, output_info);
+            }
+            else if self.mode == MultiSegmentMode::Triggered {
+                data.fill(0.0);
+                return;
+            }
+
+            for (i, datum) in data.iter_mut().enumerate() {
+                let triggered = trigger_data[i] > self.trigger_tolerance;
+                if triggered != self.triggered.get() {
+                    // Triggered state has changed. We should either start over or release
+                    if triggered {
+                        self.trigger();
+                    }
+                    else {
+                        self.release();
+                    }
+                }
+                *datum = self.get(output_info.sample_rate);
+            }
+        });
+    }
+}
+
+impl PatchModule for MultiSegmentEnvelope {
+    fn get_input_names(&self) -> Vec<String> {
+        input_names(&["trigger"])
+    }
+
+    fn set_input(&mut self, input: usize, module: Option<Rc<dyn SynthModule>>) -> SynthResult<()> {
+        match input {
+            0 => self.set_trigger(module),
+            _ => return no_such_input(input)
+        }
+        Ok(())
+    }
+}
+
+/// The breakpoints, sustain point and loop points aren't parameters. A tempo of 0 isn't synced to a tempo
+impl Parameters for MultiSegmentEnvelope {
+    fn get_parameter_info(&self) -> Vec<ParameterInfo> {
+        vec![
+            ParameterInfo::choice("mode", "Mode", &MODE_NAMES, MultiSegmentMode::Triggered),
+            ParameterInfo::new("tempo", "Tempo", "BPM", 0.0, 300.0, 0.0, ParameterScale::Linear),
+            ParameterInfo::new("trigger_tolerance", "Trigger Tolerance", "", 0.0, 1.0, 0.5, ParameterScale::Linear)
+        ]
+    }
+
+    fn get_parameter(&self, id: &str) -> Option<f32> {
+        match id {
+            "mode" => Some(choice_index(&MODE_NAMES, self.mode)),
+            "tempo" => Some(self.tempo.unwrap_or(0.0)),
+            "trigger_tolerance" => Some(self.trigger_tolerance),
+            _ => None
+        }
+    }
+
+    fn set_parameter(&mut self, id: &str, value: f32) -> SynthResult<()> {
+        let value = clamp_parameter(self, id, value)?;
+        match id {
+            "mode" => self.set_mode(choice_value(&MODE_NAMES, value)),
+            "tempo" => self.set_tempo(Some(value).filter(|tempo| *tempo > 0.0)),
+            "trigger_tolerance" => self.set_trigger_tolerance(value),
+            _ => return no_such_parameter(id)
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::*;
+    use crate::clock;
+    use crate::module::sample_buffer::SampleBuffer;
+
+    const SAMPLE_RATE: usize = 4;
+
+    fn get_envelope_output(envelope: &mut MultiSegmentEnvelope, trigger_data: &[f32]) -> Vec<f32> {
+        let mut clock = clock::SampleClock::new(SAMPLE_RATE);
+        let clock_values = clock.get_range(trigger_data.len());
+        let output_info = OutputInfo::new_basic(SAMPLE_RATE, clock_values);
+        envelope.set_trigger(Some(Rc::new(SampleBuffer::new(trigger_data.to_vec()))));
+
+        let mut data = vec![0.0; trigger_data.len()];
+        envelope.fill_output_buffer(&mut data, &output_info);
+        data
+    }
+
+    fn assert_envelope_eq(got: &[f32], expected: &[f32]) {
+        for (got_datum, expected_datum) in got.iter().zip(expected.iter()) {
+            assert!(
+                float_eq(*got_datum, *expected_datum, 0.0001),
+                "Envelope output does not match expected:\n\tGot: {:?}\n\tExpected: {:?}", got, expected
+            );
+        }
+    }
+
+    fn create_test_envelope() -> MultiSegmentEnvelope {
+        MultiSegmentEnvelope::with_breakpoints(vec![
+            Breakpoint::new(500.0, 1.0, EnvelopeCurve::Linear),
+            Breakpoint::new(500.0, 0.5, EnvelopeCurve::Linear),
+            Breakpoint::new(250.0, 0.75, EnvelopeCurve::Linear),
+            Breakpoint::new(500.0, 0.0, EnvelopeCurve::Linear),
+        ])
+    }
+
+    #[test]
+    fn test_one_shot() {
+        const EXPECTED_DATA: [f32; 10] = [0.5, 1.0, 0.75, 0.5, 0.75, 0.375, 0.0, 0.0, 0.0, 0.0];
+        let mut envelope = create_test_envelope();
+        let data = get_envelope_output(&mut envelope, &[1.0; 10]);
+        assert_envelope_eq(&data, &EXPECTED_DATA);
+    }
+
+    #[test]
+    fn test_sustain_point() {
+        const TRIGGER_DATA: [f32; 10] = [1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 0.0, 0.0, 0.0, 0.0];
+        const EXPECTED_DATA: [f32; 10] = [0.5, 1.0, 0.75, 0.5, 0.5, 0.5, 0.75, 0.375, 0.0, 0.0];
+        let mut envelope = create_test_envelope();
+        envelope.set_sustain_point(Some(1)).unwrap();
+        let data = get_envelope_output(&mut envelope, &TRIGGER_DATA);
+        assert_envelope_eq(&data, &EXPECTED_DATA);
+    }
+
+    #[test]
+    fn test_loop_points() {
+        const TRIGGER_DATA: [f32; 10] = [1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 0.0, 0.0, 0.0];
+        const EXPECTED_DATA: [f32; 10] = [0.5, 1.0, 0.75, 0.5, 0.75, 0.625, 0.5, 0.25, 0.0, 0.0];
+        let mut envelope = create_test_envelope();
+        envelope.set_loop_points(Some((1, 2))).unwrap();
+        let data = get_envelope_output(&mut envelope, &TRIGGER_DATA);
+        assert_envelope_eq(&data, &EXPECTED_DATA);
+    }
+
+    #[test]
+    fn test_free_running_tempo_sync() {
+        // At 120bpm a beat is half a second, or two samples
+        const EXPECTED_DATA: [f32; 8] = [0.5, 1.0, 0.5, 0.0, 0.5, 1.0, 0.5, 0.0];
+        let mut envelope = MultiSegmentEnvelope::with_breakpoints(vec![
+            Breakpoint::new(1.0, 1.0, EnvelopeCurve::Linear),
+            Breakpoint::new(1.0, 0.0, EnvelopeCurve::Linear),
+        ]);
+        envelope.set_mode(MultiSegmentMode::FreeRunning);
+        envelope.set_tempo(Some(120.0));
+        let data = get_envelope_output(&mut envelope, &[0.0; 8]);
+        assert_envelope_eq(&data, &EXPECTED_DATA);
+    }
+
+    #[test]
+    fn test_invalid_points() {
+        let mut envelope = create_test_envelope();
+        assert!(envelope.set_sustain_point(Some(4)).is_err());
+        assert!(envelope.set_loop_points(Some((2, 1))).is_err());
+        assert!(envelope.set_loop_points(Some((1, 4))).is_err());
+    }
+
+    #[test]
+    fn test_remove_breakpoint() {
+        let mut envelope = create_test_envelope();
+        envelope.set_sustain_point(Some(2)).unwrap();
+        envelope.set_loop_points(Some((1, 3))).unwrap();
+        envelope.remove_breakpoint(1).unwrap();
+        // The sustain point still refers to the same breakpoint. The loop started at the removed one
+        assert_eq!(envelope.get_sustain_point(), Some(1));
+        assert_eq!(envelope.get_breakpoint(1).unwrap().level, 0.75);
+        assert_eq!(envelope.get_loop_points(), None);
+
+        let mut envelope = create_test_envelope();
+        envelope.set_sustain_point(Some(1)).unwrap();
+        envelope.set_loop_points(Some((0, 3))).unwrap();
+        envelope.remove_breakpoint(1).unwrap();
+        assert_eq!(envelope.get_sustain_point(), None);
+        assert_eq!(envelope.get_loop_points(), Some((0, 2)));
+
+        // Removing a breakpoint after the points leaves them alone
+        let mut envelope = create_test_envelope();
+        envelope.set_sustain_point(Some(1)).unwrap();
+        envelope.set_loop_points(Some((0, 1))).unwrap();
+        envelope.remove_breakpoint(3).unwrap();
+        assert_eq!(envelope.get_sustain_point(), Some(1));
+        assert_eq!(envelope.get_loop_points(), Some((0, 1)));
+        assert!(envelope.remove_breakpoint(3).is_err());
+    }
+}
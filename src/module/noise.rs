@@ -1,13 +1,20 @@
 extern crate rand;
 
-use super::{SynthModule, OutputInfo};
+use std::rc::Rc;
+
+use super::{SynthModule, PatchModule, OutputInfo, no_such_input};
+use crate::SynthResult;
+use super::parameter::{Parameters, ParameterInfo, no_such_parameter};
+use super::render_cache::BlockCache;
 
 pub struct NoiseGenerator {
+    block_cache: BlockCache
 }
 
 impl NoiseGenerator {
     pub fn new() -> NoiseGenerator {
-        NoiseGenerator {}
+        let block_cache = BlockCache::new();
+        NoiseGenerator { block_cache }
     }
 
     pub fn get<T>(&self) -> T 
@@ -17,9 +24,35 @@ impl NoiseGenerator {
 }
 
 impl SynthModule for NoiseGenerator {
-    fn fill_output_buffer(&self, data: &mut [f32], _output_info: &OutputInfo) {
-        for datum in data.iter_mut() {
-            *datum = self.get();
-        }
+    fn fill_output_buffer(&self, data: &mut [f32], output_info: &OutputInfo) {
+        self.block_cache.fill(data, output_info, |data| {
+            for datum in data.iter_mut() {
+                *datum = self.get();
+            }
+        });
+    }
+}
+
+impl PatchModule for NoiseGenerator {
+    fn get_input_names(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    fn set_input(&mut self, input: usize, _module: Option<Rc<dyn SynthModule>>) -> SynthResult<()> {
+        no_such_input(input)
+    }
+}
+
+impl Parameters for NoiseGenerator {
+    fn get_parameter_info(&self) -> Vec<ParameterInfo> {
+        Vec::new()
+    }
+
+    fn get_parameter(&self, _id: &str) -> Option<f32> {
+        None
     }
-}
\ No newline at end of file
+
+    fn set_parameter(&mut self, id: &str, _value: f32) -> SynthResult<()> {
+        no_such_parameter(id)
+    }
+}
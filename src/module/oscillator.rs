@@ -1,9 +1,13 @@
 use std::rc::Rc;
+use std::cell::Cell;
 
 use crate::note;
 use crate::clock;
-use crate::note::Note;
-use super::{SynthModule, OutputInfo};
+use super::{SynthModule, PatchModule, OutputInfo, SmoothedValue, no_such_input, input_names};
+use crate::SynthResult;
+use super::parameter::{Parameters, ParameterInfo, ParameterScale, clamp_parameter, no_such_parameter, choice_index, choice_value};
+use crate::patch_file::{Table, ModuleSettings};
+use super::render_cache::BlockCache;
 
 const PI: f32 = std::f64::consts::PI as f32;
 const TAU: f32 = PI * 2.0;
@@ -19,36 +23,67 @@ pub enum Waveform {
     Pulse
 }
 
+const WAVEFORM_NAMES: [(&str, Waveform); 5] = [
+    ("Sine", Waveform::Sine),
+    ("Triangle", Waveform::Triangle),
+    ("Saw", Waveform::Saw),
+    ("Ramp", Waveform::Ramp),
+    ("Pulse", Waveform::Pulse)
+];
+
 /// Represents a Oscillator capable of outputting values
 #[derive(Clone)]
 pub struct Oscillator {
     /// Basic waveform that will be played
     waveform: Waveform,
     /// frequency in Hz that the wave will be played at
-    frequency: f32,
+    frequency: SmoothedValue,
     /// Width of the pulse. Only used for pulse waveforms. 50% is square, 0% and 100% are silent
-    pulse_width: f32,
+    pulse_width: SmoothedValue,
     /// Linear freq modulation input
     linear_freq_input: Option<Rc<dyn SynthModule>>,
-    /// Exponential freq modulation input
+    /// Exponential freq modulation input. Takes a pitch signal that goes up by 1.0 per octave
     exponential_freq_input: Option<Rc<dyn SynthModule>>,
+    /// How far through its cycle the wave is, between 0.0 and 1.0
+    phase: Cell<f32>,
+    block_cache: BlockCache
 }
 
 impl Oscillator {
-    /// Creates a basic sine wave oscillator stream with a default `OscillatorState`
+    /// Creates a sine oscillator at C4 so a pitch signal of 0.0 plays C4
     pub fn new() -> Self {
         let waveform = Waveform::Sine;
-        let frequency = note::FREQ_C;
-        let pulse_width = 0.5;
+        let frequency = SmoothedValue::new(note::PITCH_REFERENCE_FREQ);
+        let pulse_width = SmoothedValue::new(0.5);
         let linear_freq_input = None;
         let exponential_freq_input = None;
+        let phase = Cell::new(0.0);
+        let block_cache = BlockCache::new();
         Oscillator {
             waveform,
             frequency,
             pulse_width,
             linear_freq_input,
-            exponential_freq_input
+            exponential_freq_input,
+            phase,
+            block_cache
+        }
+    }
+
+    /// Makes an oscillator from its settings in a patch file
+    pub fn load_settings(settings: &Table) -> SynthResult<Self> {
+        settings.check_keys(&["waveform", "frequency", "pulse_width"])?;
+        let mut oscillator = Self::new();
+        if let Some(waveform) = settings.get_choice("waveform", &WAVEFORM_NAMES)? {
+            oscillator.set_waveform(waveform);
         }
+        if let Some(frequency) = settings.get_float("frequency")? {
+            oscillator.set_frequency(frequency);
+        }
+        if let Some(pulse_width) = settings.get_float("pulse_width")? {
+            oscillator.set_pulse_width(pulse_width);
+        }
+        Ok(oscillator)
     }
 
     pub fn set_waveform(&mut self, waveform: Waveform) {
@@ -60,19 +95,29 @@ impl Oscillator {
     }
 
     pub fn set_frequency(&mut self, freq: f32) {
-        self.frequency = freq
+        self.frequency.set(freq)
     }
 
     pub fn get_frequency(&self) -> f32 {
-        self.frequency
+        self.frequency.get()
     }
 
     pub fn set_pulse_width(&mut self, pulse_width: f32) {
-        self.pulse_width = pulse_width;
+        self.pulse_width.set(pulse_width);
     }
 
     pub fn get_pulse_width(&self) -> f32 {
-        self.pulse_width
+        self.pulse_width.get()
+    }
+
+    /// Sets how long in milliseconds frequency and pulse width changes take to ramp in
+    pub fn set_smoothing_time(&mut self, smoothing_time: f32) {
+        self.frequency.set_ramp_time(smoothing_time);
+        self.pulse_width.set_ramp_time(smoothing_time);
+    }
+
+    pub fn get_smoothing_time(&self) -> f32 {
+        self.frequency.get_ramp_time()
     }
 
     pub fn set_linear_freq_input(
@@ -81,68 +126,49 @@ impl Oscillator {
         self.linear_freq_input = input;
     }
 
+    /// Sets an input that moves the frequency up by an octave for every 1.0, e.g. the output of a
+    /// `MidiNoteOutput`. The frequency that an input of 0.0 gives is the oscillator's frequency
     pub fn set_exponential_freq_input(
         &mut self, input: Option<Rc<dyn SynthModule>>
     ) {
         self.exponential_freq_input = input;
     }
 
-    fn fill_sine(&self, buffer: &mut [f32], sample_range: &clock::SampleRange, freq_values: &[f32]) {
-        let buffer_len = buffer.len();
-        let sample_rate = sample_range.get_sample_rate();
-        debug_assert!(buffer_len == sample_range.get_n_samples() && buffer_len == freq_values.len());
-        let mut sample_iter = sample_range.iter();
-        for i in 0..buffer_len {
-            let sample_number = sample_iter.next().expect("Ran out of samples??") as f32;
-            let freq_value = freq_values[i];
-            let sample_rate = sample_rate as f32;
-
-            // TODO: the looping nature of the sample counter is not working here. We need to have some accumulator
-            // that resets when the output of this sin call is sufficiently close to 0.
-            buffer[i] = (freq_value * sample_number * TAU / sample_rate).sin();
+    fn fill_sine(&self, buffer: &mut [f32], phases: &[f32]) {
+        debug_assert!(buffer.len() == phases.len());
+        for (datum, phase) in buffer.iter_mut().zip(phases.iter()) {
+            *datum = (phase * TAU).sin();
         }
     }
 
-    fn fill_ramp(&self, buffer: &mut [f32], sample_range: &clock::SampleRange, freq_values: &[f32]) {
-        let buffer_len = buffer.len();
-        let sample_rate = sample_range.get_sample_rate();
-        debug_assert!(buffer_len == sample_range.get_n_samples() && buffer_len == freq_values.len());
-        let mut sample_iter = sample_range.iter();
-        for i in 0..buffer_len {
-            let sample_number = sample_iter.next().expect("Ran out of samples??") as f32;
-            let freq_value = freq_values[i];
-            let sample_rate = sample_rate as f32;
-
-            buffer[i] = (freq_value * sample_number * 2_f32 / sample_rate) % 2_f32 - 1_f32;
+    fn fill_triangle(&self, buffer: &mut [f32], phases: &[f32]) {
+        debug_assert!(buffer.len() == phases.len());
+        for (datum, phase) in buffer.iter_mut().zip(phases.iter()) {
+            // Shifted a quarter cycle so it starts at 0.0 and rises like the sine does
+            let shifted_phase = (phase + 0.25_f32) % 1_f32;
+            *datum = 1_f32 - 4_f32 * (shifted_phase - 0.5_f32).abs();
         }
     }
 
-    fn fill_saw(&self, buffer: &mut [f32], sample_range: &clock::SampleRange, freq_values: &[f32]) {
-        let buffer_len = buffer.len();
-        let sample_rate = sample_range.get_sample_rate();
-        debug_assert!(buffer_len == sample_range.get_n_samples() && buffer_len == freq_values.len());
-        let mut sample_iter = sample_range.iter();
-        for i in 0..buffer_len {
-            let sample_number = sample_iter.next().expect("Ran out of samples??") as f32;
-            let freq_value = freq_values[i];
-            let sample_rate = sample_rate as f32;
-
-            buffer[i] = (freq_value * sample_number * -2_f32 / sample_rate) % 2_f32 + 1_f32;
+    fn fill_ramp(&self, buffer: &mut [f32], phases: &[f32]) {
+        debug_assert!(buffer.len() == phases.len());
+        for (datum, phase) in buffer.iter_mut().zip(phases.iter()) {
+            *datum = phase * 2_f32 - 1_f32;
         }
     }
 
-    fn fill_pulse(&self, buffer: &mut [f32], sample_range: &clock::SampleRange, freq_values: &[f32]) {
-        let buffer_len = buffer.len();
-        let sample_rate = sample_range.get_sample_rate();
-        debug_assert!(buffer_len == sample_range.get_n_samples() && buffer_len == freq_values.len());
-        let mut sample_iter = sample_range.iter();
-        for i in 0..buffer_len {
-            let sample_number = sample_iter.next().expect("Ran out of samples??") as f32;
-            let freq_value = freq_values[i];
-            let sample_rate = sample_rate as f32;
-
-            let duration_offset = (sample_number * freq_value / sample_rate) % 1_f32;
-            buffer[i] = if duration_offset > self.pulse_width { 1_f32 } else { -1_f32 };
+    fn fill_saw(&self, buffer: &mut [f32], phases: &[f32]) {
+        debug_assert!(buffer.len() == phases.len());
+        for (datum, phase) in buffer.iter_mut().zip(phases.iter()) {
+            *datum = 1_f32 - phase * 2_f32;
+        }
+    }
+
+    fn fill_pulse(&self, buffer: &mut [f32], phases: &[f32], sample_rate: usize) {
+        debug_assert!(buffer.len() == phases.len());
+        for (datum, phase) in buffer.iter_mut().zip(phases.iter()) {
+            let pulse_width = self.pulse_width.next(sample_rate);
+            *datum = if *phase > pulse_width { 1_f32 } else { -1_f32 };
         }
     }
 
@@ -151,39 +177,103 @@ impl Oscillator {
     ) {
         let buffer_len = buffer.len();
 
-        // Compute frequency per sample
-        let mut freq_values = vec![self.frequency; buffer_len];
-        debug_assert!(freq_values.len() == linear_freq_mod.len() && freq_values.len() == expo_freq_mod.len());
-        for i in 0..freq_values.len() {
-            let expo_mod = Note::normalized_to_coefficient(expo_freq_mod[i]);
-            freq_values[i] = freq_values[i] * expo_mod + linear_freq_mod[i];
+        let sample_rate = sample_range.get_sample_rate();
+
+        // Work out where in the cycle each sample is. The phase moves forward by the frequency
+        // at each sample, so changing the frequency bends the wave instead of making it jump
+        let mut phases = vec![0.0; buffer_len];
+        debug_assert!(phases.len() == linear_freq_mod.len() && phases.len() == expo_freq_mod.len());
+        let mut phase = self.phase.get();
+        for i in 0..phases.len() {
+            let expo_mod = 2_f32.powf(expo_freq_mod[i]);
+            let freq_value = self.frequency.next(sample_rate) * expo_mod + linear_freq_mod[i];
+            phases[i] = phase;
+            phase = (phase + freq_value / sample_rate as f32).rem_euclid(1_f32);
         }
+        self.phase.set(phase);
 
         match self.waveform {
-            Waveform::Sine     => self.fill_sine(buffer, sample_range, &freq_values),
-            Waveform::Ramp     => self.fill_ramp(buffer, sample_range, &freq_values),
-            Waveform::Saw      => self.fill_saw(buffer, sample_range, &freq_values),
-            Waveform::Pulse    => self.fill_pulse(buffer, sample_range, &freq_values),
-            Waveform::Triangle => todo!()
+            Waveform::Sine     => self.fill_sine(buffer, &phases),
+            Waveform::Ramp     => self.fill_ramp(buffer, &phases),
+            Waveform::Saw      => self.fill_saw(buffer, &phases),
+            Waveform::Pulse    => self.fill_pulse(buffer, &phases, sample_rate),
+            Waveform::Triangle => self.fill_triangle(buffer, &phases)
         }
     }
 }
 
 impl SynthModule for Oscillator {
     fn fill_output_buffer(&self, data: &mut [f32], output_info: &OutputInfo) {
-        let buffer_len = data.len();
+        self.block_cache.fill(data, output_info, |data| {
+            let buffer_len = data.len();
+
+            let mut linear_freq_input_buffer = vec![0.0; buffer_len];
+            if let Some(linear_freq_input) = &self.linear_freq_input {
+                linear_freq_input.fill_output_buffer(linear_freq_input_buffer.as_mut_slice(), output_info);
+            };
+
+            let mut expo_freq_input_buffer = vec![0.0; buffer_len];
+            if let Some(expo_freq_input) = &self.exponential_freq_input {
+                expo_freq_input.fill_output_buffer(expo_freq_input_buffer.as_mut_slice(), output_info);
+            }
+
+            self.fill(data, &output_info.current_sample_range, &linear_freq_input_buffer, &expo_freq_input_buffer);
+        });
+    }
+}
+
+impl PatchModule for Oscillator {
+    fn get_input_names(&self) -> Vec<String> {
+        input_names(&["linear freq", "exponential freq"])
+    }
+
+    fn set_input(&mut self, input: usize, module: Option<Rc<dyn SynthModule>>) -> SynthResult<()> {
+        match input {
+            0 => self.set_linear_freq_input(module),
+            1 => self.set_exponential_freq_input(module),
+            _ => return no_such_input(input)
+        }
+        Ok(())
+    }
+
+    fn save_settings(&self) -> Option<ModuleSettings> {
+        let mut settings = Table::new();
+        settings.set_choice("waveform", self.waveform, &WAVEFORM_NAMES);
+        settings.set("frequency", self.frequency.get());
+        settings.set("pulse_width", self.pulse_width.get());
+        Some(ModuleSettings::new("Oscillator", settings))
+    }
+}
 
-        let mut linear_freq_input_buffer = vec![0.0; buffer_len];
-        if let Some(linear_freq_input) = &self.linear_freq_input {
-            linear_freq_input.fill_output_buffer(linear_freq_input_buffer.as_mut_slice(), output_info);
-        };
+impl Parameters for Oscillator {
+    fn get_parameter_info(&self) -> Vec<ParameterInfo> {
+        vec![
+            ParameterInfo::choice("waveform", "Waveform", &WAVEFORM_NAMES, Waveform::Sine),
+            ParameterInfo::new(
+                "frequency", "Frequency", "Hz", 0.0, 20_000.0, note::PITCH_REFERENCE_FREQ, ParameterScale::Logarithmic
+            ),
+            ParameterInfo::new("pulse_width", "Pulse Width", "", 0.0, 1.0, 0.5, ParameterScale::Linear)
+        ]
+    }
 
-        let mut expo_freq_input_buffer = vec![0.0; buffer_len];
-        if let Some(expo_freq_input) = &self.exponential_freq_input {
-            expo_freq_input.fill_output_buffer(expo_freq_input_buffer.as_mut_slice(), output_info);
+    fn get_parameter(&self, id: &str) -> Option<f32> {
+        match id {
+            "waveform" => Some(choice_index(&WAVEFORM_NAMES, self.waveform)),
+            "frequency" => Some(self.frequency.get()),
+            "pulse_width" => Some(self.pulse_width.get()),
+            _ => None
         }
+    }
 
-        self.fill(data, &output_info.current_sample_range, &linear_freq_input_buffer, &expo_freq_input_buffer);
+    fn set_parameter(&mut self, id: &str, value: f32) -> SynthResult<()> {
+        let value = clamp_parameter(self, id, value)?;
+        match id {
+            "waveform" => self.set_waveform(choice_value(&WAVEFORM_NAMES, value)),
+            "frequency" => self.set_frequency(value),
+            "pulse_width" => self.set_pulse_width(value),
+            _ => return no_such_parameter(id)
+        }
+        Ok(())
     }
 }
 
@@ -227,6 +317,61 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_frequency_change_continuity() {
+        const SAMPLE_RATE: usize = 1000;
+        const BLOCK_SIZE: usize = 100;
+        const START_FREQUENCY: f32 = 10.0;
+        const END_FREQUENCY: f32 = 13.7;
+        // A sine can't move further than this in one sample at the highest frequency
+        const MAX_STEP: f32 = TAU * END_FREQUENCY / SAMPLE_RATE as f32 + 0.001;
+
+        let mut osc = Oscillator::new();
+        osc.set_frequency(START_FREQUENCY);
+        let mut clock = clock::SampleClock::new(SAMPLE_RATE);
+
+        // Change the frequency part way through a second, where the sample number is large
+        let mut data = vec![0_f32; BLOCK_SIZE];
+        for block in 0..20 {
+            if block == 5 {
+                osc.set_frequency(END_FREQUENCY);
+            }
+            let previous_sample = data[BLOCK_SIZE - 1];
+            let output_info = OutputInfo::new_basic(SAMPLE_RATE, clock.get_range(BLOCK_SIZE));
+            osc.fill_output_buffer(&mut data, &output_info);
+            if block == 0 {
+                continue;
+            }
+
+            let mut previous_sample = previous_sample;
+            for (i, datum) in data.iter().enumerate() {
+                assert!(
+                    (datum - previous_sample).abs() <= MAX_STEP,
+                    "Output jumped from {} to {} at sample {} of block {}", previous_sample, datum, i, block
+                );
+                previous_sample = *datum;
+            }
+        }
+    }
+
+    #[test]
+    fn test_triangle() {
+        const EXPECTED_DATA: &[f32] = &[0.0, 0.5, 1.0, 0.5, 0.0, -0.5, -1.0, -0.5];
+        let mut osc = Oscillator::new();
+        osc.set_waveform(Waveform::Triangle);
+        osc.set_frequency(1_f32);
+        let data = get_osc_data(&mut osc, 8, 8);
+
+        for i in 0..8 {
+            if !float_eq(EXPECTED_DATA[i], data[i], 0.001) {
+                panic!(
+                    "Oscillator output differs from expected:\n\tExpected: {:?},\n\tGot: {:?}",
+                    EXPECTED_DATA, data
+                );
+            }
+        }
+    }
+
     #[test]
     fn test_ramp() {
         const EXPECTED_DATA: &[f32] = &[-0.5, 0.0, 0.5, -1.0];
@@ -1,63 +1,223 @@
 use std::rc::Rc;
 
-use super::{SynthModule, OutputInfo};
+use super::{SynthModule, OutputInfo, SmoothedValue, CompressionMode, compress_audio, COMPRESSION_MODE_NAMES};
+use crate::SynthResult;
+use crate::patch_file::Table;
+use super::limiter::{Limiter, LIMITER_PARAMETER_PREFIX};
+use super::parameter::{Parameters, ParameterInfo, ParameterScale, clamp_parameter, no_such_parameter, choice_index, choice_value};
+use super::render_cache::BlockCache;
 
 /// A structure representing controls that would typically be on a output module
 /// of a modular synth.
 pub struct Output {
-    volume: f32,
+    volume: SmoothedValue,
     panning: f32,
-    audio_input: Option<Rc<dyn SynthModule>>
+    compression_mode: CompressionMode,
+    limiter: Limiter,
+    audio_input: Option<Rc<dyn SynthModule>>,
+    block_cache: BlockCache
 }
 
 impl Output {
     pub fn new() -> Self {
-        let volume = 1.0;
+        let volume = SmoothedValue::new(1.0);
         let panning = 0.5;
+        let compression_mode = CompressionMode::None;
+        let limiter = Limiter::new();
         let audio_input = None;
 
-        Self { volume, panning, audio_input }
+        let block_cache = BlockCache::new();
+        Self { volume, panning, compression_mode, limiter, audio_input, block_cache }
     }
 
     pub fn set_volume(&mut self, volume: f32) {
-        self.volume = volume;
+        self.volume.set(volume);
+    }
+
+    pub fn get_volume(&self) -> f32 {
+        self.volume.get()
+    }
+
+    /// Sets how long in milliseconds a volume change takes to ramp in
+    pub fn set_smoothing_time(&mut self, smoothing_time: f32) {
+        self.volume.set_ramp_time(smoothing_time);
+    }
+
+    pub fn get_smoothing_time(&self) -> f32 {
+        self.volume.get_ramp_time()
     }
 
     pub fn set_panning(&mut self, panning: f32) {
         self.panning = panning;
     }
 
+    pub fn get_panning(&self) -> f32 {
+        self.panning
+    }
+
+    pub fn set_compression_mode(&mut self, compression_mode: CompressionMode) {
+        self.compression_mode = compression_mode;
+    }
+
+    pub fn get_compression_mode(&self) -> CompressionMode {
+        self.compression_mode
+    }
+
+    /// Gets the limiter used when the compression mode is `CompressionMode::LookaheadLimit`
+    pub fn get_limiter(&self) -> &Limiter {
+        &self.limiter
+    }
+
+    pub fn get_limiter_mut(&mut self) -> &mut Limiter {
+        &mut self.limiter
+    }
+
     pub fn set_audio_input(&mut self, audio_input: Option<Rc<dyn SynthModule>>) {
         self.audio_input = audio_input;
     }
+
+    /// Gets the output's settings to write in a patch file
+    pub fn save_settings(&self) -> Table {
+        let mut settings = Table::new();
+        settings.set("volume", self.volume.get());
+        settings.set("panning", self.panning);
+        settings.set_choice("compression", self.compression_mode, &COMPRESSION_MODE_NAMES);
+        settings.set("limiter", self.limiter.save_settings());
+        settings
+    }
+
+    /// Changes the output to match its settings in a patch file
+    pub fn apply_settings(&mut self, settings: &Table) -> SynthResult<()> {
+        settings.check_keys(&["volume", "panning", "compression", "limiter"])?;
+        if let Some(volume) = settings.get_float("volume")? {
+            self.set_volume(volume);
+        }
+        if let Some(panning) = settings.get_float("panning")? {
+            self.set_panning(panning);
+        }
+        if let Some(compression_mode) = settings.get_choice("compression", &COMPRESSION_MODE_NAMES)? {
+            self.set_compression_mode(compression_mode);
+        }
+        if let Some(limiter_settings) = settings.get_table("limiter")? {
+            self.limiter.apply_settings(limiter_settings)?;
+        }
+        Ok(())
+    }
+}
+
+/// The limiter's parameters start with "limiter_"
+impl Parameters for Output {
+    fn get_parameter_info(&self) -> Vec<ParameterInfo> {
+        let mut info = vec![
+            ParameterInfo::new("volume", "Volume", "", 0.0, 1.0, 1.0, ParameterScale::Linear),
+            ParameterInfo::new("panning", "Panning", "", 0.0, 1.0, 0.5, ParameterScale::Linear),
+            ParameterInfo::choice("compression", "Compression", &COMPRESSION_MODE_NAMES, CompressionMode::None)
+        ];
+        info.extend(self.limiter.get_owner_parameter_info());
+        info
+    }
+
+    fn get_parameter(&self, id: &str) -> Option<f32> {
+        match id {
+            "volume" => Some(self.volume.get()),
+            "panning" => Some(self.panning),
+            "compression" => Some(choice_index(&COMPRESSION_MODE_NAMES, self.compression_mode)),
+            _ => self.limiter.get_parameter(id.strip_prefix(LIMITER_PARAMETER_PREFIX)?)
+        }
+    }
+
+    fn set_parameter(&mut self, id: &str, value: f32) -> SynthResult<()> {
+        let value = clamp_parameter(self, id, value)?;
+        match id {
+            "volume" => self.set_volume(value),
+            "panning" => self.set_panning(value),
+            "compression" => self.set_compression_mode(choice_value(&COMPRESSION_MODE_NAMES, value)),
+            _ => match id.strip_prefix(LIMITER_PARAMETER_PREFIX) {
+                Some(limiter_id) => return self.limiter.set_parameter(limiter_id, value),
+                None => return no_such_parameter(id)
+            }
+        }
+        Ok(())
+    }
 }
 
 impl SynthModule for Output {
     fn fill_output_buffer(&self, data: &mut [f32], output_info: &OutputInfo) {
-        let channel_count_usize = output_info.channel_count as usize;
-        let total_buffer_len = data.len();
-        debug_assert!(
-            total_buffer_len % channel_count_usize == 0,
-            "Expected buffer length to have same number of slots for each channel"
-        );
-        // We will take just one channel's samples and multiplex them to do the panning
-        // NOTE: this assumes we have no modules that operate on stereo signals
-        let mono_channel_len = total_buffer_len / channel_count_usize;
-        let mut mono_channel_buffer = vec![0.0; mono_channel_len];
-
-        // Get the audio for the one channel
-        if let Some(audio_input) = &self.audio_input {
-            audio_input.fill_output_buffer(&mut mono_channel_buffer, output_info);
-        };
-
-        // fill the final buffer with multi-channel data
-        let output_chunk_iter = data.chunks_mut(channel_count_usize);
-        let input_sample_iter = mono_channel_buffer.iter();
-        for (output_chunk, input_sample) in output_chunk_iter.zip(input_sample_iter) {
-            // TODO: panning
-            for output_sample in output_chunk.iter_mut() {
-                *output_sample = input_sample * self.volume;
+        self.block_cache.fill(data, output_info, |data| {
+            let channel_count_usize = output_info.channel_count as usize;
+            let total_buffer_len = data.len();
+            debug_assert!(
+                total_buffer_len % channel_count_usize == 0,
+                "Expected buffer length to have same number of slots for each channel"
+            );
+            // We will take just one channel's samples and multiplex them to do the panning
+            // NOTE: this assumes we have no modules that operate on stereo signals
+            let mono_channel_len = total_buffer_len / channel_count_usize;
+            let mut mono_channel_buffer = vec![0.0; mono_channel_len];
+
+            // Get the audio for the one channel
+            if let Some(audio_input) = &self.audio_input {
+                audio_input.fill_output_buffer(&mut mono_channel_buffer, output_info);
+            };
+
+            // Apply volume then keep the result in range
+            for sample in mono_channel_buffer.iter_mut() {
+                *sample *= self.volume.next(output_info.sample_rate);
+            }
+            match self.compression_mode {
+                CompressionMode::LookaheadLimit => self.limiter.process(&mut mono_channel_buffer, output_info.sample_rate),
+                _ => compress_audio(&mut mono_channel_buffer, self.compression_mode)
             }
+
+            // fill the final buffer with multi-channel data
+            let output_chunk_iter = data.chunks_mut(channel_count_usize);
+            let input_sample_iter = mono_channel_buffer.iter();
+            for (output_chunk, input_sample) in output_chunk_iter.zip(input_sample_iter) {
+                // TODO: panning
+                for output_sample in output_chunk.iter_mut() {
+                    *output_sample = *input_sample;
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module::sample_buffer::SampleBuffer;
+    use crate::clock;
+
+    const SAMPLE_RATE: usize = 1000;
+    const BLOCK_SIZE: usize = 20;
+
+    fn get_output(output: &Output) -> Vec<f32> {
+        let mut clock = clock::SampleClock::new(SAMPLE_RATE);
+        let clock_values = clock.get_range(BLOCK_SIZE);
+        let output_info = OutputInfo::new_basic(SAMPLE_RATE, clock_values);
+
+        let mut output_buffer = vec![0_f32; BLOCK_SIZE];
+        output.fill_output_buffer(&mut output_buffer, &output_info);
+        output_buffer
+    }
+
+    #[test]
+    fn test_volume_smoothing() {
+        let mut output = Output::new();
+        output.set_audio_input(Some(Rc::new(SampleBuffer::new(vec![0.5; BLOCK_SIZE]))));
+        output.set_volume(0.5);
+        assert!(get_output(&output).iter().all(|sample| *sample == 0.25));
+
+        // Turning the volume up mid playback ramps over 10ms, which is 10 samples here
+        output.set_volume(1.0);
+        let samples = get_output(&output);
+        for i in 1..10 {
+            assert!(samples[i] > samples[i - 1]);
         }
+        assert!(samples[10..].iter().all(|sample| *sample == 0.5));
+
+        output.set_smoothing_time(0.0);
+        output.set_volume(0.0);
+        assert!(get_output(&output).iter().all(|sample| *sample == 0.0));
     }
-}
\ No newline at end of file
+}
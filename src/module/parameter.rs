@@ -0,0 +1,290 @@
+use crate::{SynthError, SynthResult};
+
+/// How much of a logarithmic range each part of a control covers. Each third of the control
+/// covers about ten times as much of the range as the third before it
+const LOG_SCALE_SPAN: f32 = 1000.0;
+
+/// How a parameter's range is spread over a control like a knob, a slider or a MIDI CC
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum ParameterScale {
+    /// Equal steps of the control change the value by equal amounts
+    Linear,
+    /// Small values get more of the control than big ones. Good for times and frequencies
+    Logarithmic,
+    /// Only whole numbers. Used for counts, switches and choices between modes
+    Stepped
+}
+
+/// Describes one of a module's parameters
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParameterInfo {
+    /// Picks the parameter out when getting and setting it
+    pub id: String,
+    /// Name to show in a UI
+    pub name: String,
+    /// Unit the value is in, e.g. "ms", "dB" or "Hz". Empty if it doesn't have one
+    pub unit: &'static str,
+    pub min: f32,
+    pub max: f32,
+    pub default: f32,
+    pub scale: ParameterScale,
+    /// Names of the values of a stepped parameter that picks between modes, starting from 0.
+    /// Empty for every other parameter
+    pub choices: Vec<&'static str>
+}
+
+impl ParameterInfo {
+    pub fn new(
+        id: &str, name: &str, unit: &'static str, min: f32, max: f32, default: f32, scale: ParameterScale
+    ) -> Self {
+        let id = id.to_owned();
+        let name = name.to_owned();
+        let choices = Vec::new();
+        Self { id, name, unit, min, max, default, scale, choices }
+    }
+
+    /// A parameter whose value is the index of one of some named choices
+    pub fn choice<T: PartialEq>(id: &str, name: &str, choices: &[(&'static str, T)], default: T) -> Self {
+        let max = choices.len().saturating_sub(1) as f32;
+        let mut info = Self::new(id, name, "", 0.0, max, choice_index(choices, default), ParameterScale::Stepped);
+        info.choices = choices.iter().map(|(choice_name, _)| *choice_name).collect();
+        info
+    }
+
+    /// A parameter that's either off (0.0) or on (1.0)
+    pub fn toggle(id: &str, name: &str, default: bool) -> Self {
+        let mut info = Self::new(id, name, "", 0.0, 1.0, toggle_value(default), ParameterScale::Stepped);
+        info.choices = vec!["Off", "On"];
+        info
+    }
+
+    /// Moves a value into the parameter's range, rounding it if the parameter is stepped
+    pub fn clamp(&self, value: f32) -> f32 {
+        let value = value.clamp(self.min, self.max);
+        match self.scale {
+            ParameterScale::Stepped => value.round(),
+            _ => value
+        }
+    }
+
+    /// Gets how far along the range a value is, from 0.0 at the minimum to 1.0 at the maximum
+    pub fn to_normalized(&self, value: f32) -> f32 {
+        let range = self.max - self.min;
+        if range <= 0.0 {
+            return 0.0;
+        }
+        let linear = (self.clamp(value) - self.min) / range;
+        match self.scale {
+            ParameterScale::Logarithmic => (1.0 + linear * (LOG_SCALE_SPAN - 1.0)).ln() / LOG_SCALE_SPAN.ln(),
+            _ => linear
+        }
+    }
+
+    /// Gets the value that's some way along the range, from 0.0 at the minimum to 1.0 at the maximum
+    pub fn from_normalized(&self, normalized: f32) -> f32 {
+        let normalized = normalized.clamp(0.0, 1.0);
+        let linear = match self.scale {
+            ParameterScale::Logarithmic => (LOG_SCALE_SPAN.powf(normalized) - 1.0) / (LOG_SCALE_SPAN - 1.0),
+            _ => normalized
+        };
+        self.clamp(self.min + linear * (self.max - self.min))
+    }
+
+    /// Gets the name of the choice a value picks, if the parameter picks between named choices
+    pub fn get_choice_name(&self, value: f32) -> Option<&'static str> {
+        self.choices.get(self.clamp(value) as usize).copied()
+    }
+}
+
+/// Trait for modules with settings that can be listed, read and changed by id. Lets generic UIs,
+/// automation and MIDI learn work with any module without knowing what kind of module it is.
+/// Choices between modes and on/off switches are stepped parameters, so every value is an `f32`
+pub trait Parameters {
+    /// Describes every parameter the module has
+    fn get_parameter_info(&self) -> Vec<ParameterInfo>;
+
+    /// Gets a parameter's value, or `None` if the module has no parameter with that id
+    fn get_parameter(&self, id: &str) -> Option<f32>;
+
+    /// Sets a parameter. The value is clamped to the parameter's range and stepped parameters are rounded
+    fn set_parameter(&mut self, id: &str, value: f32) -> SynthResult<()>;
+
+    /// Describes the parameter with the given id
+    fn find_parameter_info(&self, id: &str) -> Option<ParameterInfo> {
+        self.get_parameter_info().into_iter().find(|info| info.id == id)
+    }
+
+    /// Sets a parameter from the position of a control between 0.0 and 1.0, following the parameter's scale
+    fn set_parameter_normalized(&mut self, id: &str, normalized: f32) -> SynthResult<()> {
+        match self.find_parameter_info(id) {
+            Some(info) => self.set_parameter(id, info.from_normalized(normalized)),
+            None => no_such_parameter(id)
+        }
+    }
+
+    /// Copies every parameter that this module shares with another, e.g. to set up a voice like
+    /// one that already exists. Connections aren't copied
+    fn copy_parameters_from(&mut self, other: &dyn Parameters) -> SynthResult<()> {
+        for info in self.get_parameter_info() {
+            if let Some(value) = other.get_parameter(&info.id) {
+                self.set_parameter(&info.id, value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Makes the error for a module being asked for a parameter it doesn't have
+pub(super) fn no_such_parameter<T>(id: &str) -> SynthResult<T> {
+    let msg = format!("Module has no parameter \"{}\"", id);
+    Err(SynthError::new(&msg))
+}
+
+/// Clamps a value to the range of the parameter with the given id. This is the first thing
+/// `Parameters::set_parameter` does, so unknown ids fail before anything changes
+pub(super) fn clamp_parameter<P: Parameters + ?Sized>(parameters: &P, id: &str, value: f32) -> SynthResult<f32> {
+    match parameters.find_parameter_info(id) {
+        Some(info) => Ok(info.clamp(value)),
+        None => no_such_parameter(id)
+    }
+}
+
+/// Gets the value of a choice parameter from the names table its choices came from
+pub(super) fn choice_index<T: PartialEq>(choices: &[(&str, T)], choice: T) -> f32 {
+    choices.iter().position(|(_, named_choice)| *named_choice == choice).unwrap_or(0) as f32
+}
+
+/// Gets the choice a choice parameter's value picks. The value should already be clamped
+pub(super) fn choice_value<T: Copy>(choices: &[(&str, T)], value: f32) -> T {
+    choices[(value as usize).min(choices.len() - 1)].1
+}
+
+pub(super) fn toggle_value(on: bool) -> f32 {
+    if on {
+        1.0
+    }
+    else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+    use crate::module::*;
+    use crate::util::test_util;
+
+    fn create_modules() -> Vec<(&'static str, Box<dyn Parameters>)> {
+        let midi_source = Rc::new(MidiModuleBase::open(test_util::get_test_midi_file_path()).unwrap());
+        vec![
+            ("Oscillator", Box::new(Oscillator::new())),
+            ("Attenuverter", Box::new(Attenuverter::new())),
+            ("Bitcrusher", Box::new(Bitcrusher::new())),
+            ("Compressor", Box::new(Compressor::new())),
+            ("Envelope", Box::new(Envelope::new())),
+            ("EnvelopeFollower", Box::new(EnvelopeFollower::new())),
+            ("Gate", Box::new(Gate::new())),
+            ("Vca", Box::new(Vca::new())),
+            ("Slew", Box::new(Slew::new())),
+            ("MultiSegmentEnvelope", Box::new(MultiSegmentEnvelope::new())),
+            ("Mixer", Box::new(Mixer::with_inputs(2))),
+            ("Combiner", Box::new(Combiner::new(CombineMode::Sum))),
+            ("Utility", Box::new(Utility::new(UtilityMode::Offset))),
+            ("Crossfade", Box::new(Crossfade::new())),
+            ("Quantizer", Box::new(Quantizer::new())),
+            ("Sequencer", Box::new(Sequencer::new())),
+            ("MidiNoteOutput", Box::new(MidiNoteOutput::new(midi_source))),
+            ("Output", Box::new(Output::new())),
+            ("Limiter", Box::new(Limiter::new()))
+        ]
+    }
+
+    #[test]
+    fn test_normalized() {
+        let linear = ParameterInfo::new("level", "Level", "", -1.0, 1.0, 0.0, ParameterScale::Linear);
+        assert_eq!(linear.to_normalized(0.0), 0.5);
+        assert_eq!(linear.from_normalized(0.75), 0.5);
+        assert_eq!(linear.to_normalized(5.0), 1.0, "Values past the range should be clamped");
+
+        let log = ParameterInfo::new("time", "Time", "ms", 0.0, 1000.0, 0.0, ParameterScale::Logarithmic);
+        assert_eq!(log.from_normalized(0.0), 0.0);
+        assert!((log.from_normalized(1.0) - 1000.0).abs() < 0.01);
+        assert!(log.from_normalized(0.5) < 50.0, "Small values should get more of a logarithmic control");
+        for value in [0.0, 1.0, 10.0, 250.0, 999.0].iter() {
+            let round_trip = log.from_normalized(log.to_normalized(*value));
+            assert!((round_trip - value).abs() < 0.01, "Expected {} but got {}", value, round_trip);
+        }
+
+        let stepped = ParameterInfo::new("count", "Count", "", 1.0, 4.0, 1.0, ParameterScale::Stepped);
+        assert_eq!(stepped.from_normalized(0.4), 2.0);
+        assert_eq!(stepped.clamp(3.7), 4.0);
+    }
+
+    #[test]
+    fn test_choices() {
+        let choices = [("Up", 1), ("Down", -1), ("Still", 0)];
+        let info = ParameterInfo::choice("direction", "Direction", &choices, -1);
+        assert_eq!((info.min, info.max, info.default), (0.0, 2.0, 1.0));
+        assert_eq!(info.get_choice_name(2.0), Some("Still"));
+        assert_eq!(choice_value(&choices, info.clamp(7.0)), 0);
+        assert_eq!(choice_index(&choices, 1), 0.0);
+
+        let toggle = ParameterInfo::toggle("looping", "Looping", true);
+        assert_eq!(toggle.get_choice_name(toggle.default), Some("On"));
+    }
+
+    #[test]
+    fn test_module_parameters() {
+        for (module_name, mut module) in create_modules() {
+            // Check every default before changing anything since some parameters move others
+            for info in module.get_parameter_info() {
+                assert_eq!(
+                    module.get_parameter(&info.id), Some(info.default),
+                    "{} parameter \"{}\" doesn't start at its default", module_name, info.id
+                );
+                assert!(
+                    info.min <= info.default && info.default <= info.max,
+                    "{} parameter \"{}\" has a default outside its range", module_name, info.id
+                );
+            }
+
+            for info in module.get_parameter_info() {
+                module.set_parameter(&info.id, info.max).unwrap();
+                assert_eq!(
+                    module.get_parameter(&info.id), Some(info.max),
+                    "{} parameter \"{}\" wasn't set", module_name, info.id
+                );
+                module.set_parameter(&info.id, info.max + 100.0).unwrap();
+                assert_eq!(
+                    module.get_parameter(&info.id), Some(info.max),
+                    "{} parameter \"{}\" wasn't clamped", module_name, info.id
+                );
+            }
+            assert!(module.get_parameter("no such parameter").is_none());
+            assert!(module.set_parameter("no such parameter", 0.0).is_err());
+        }
+    }
+
+    #[test]
+    fn test_copy_parameters() {
+        let mut envelope = Envelope::new();
+        envelope.set_attack_time(250.0);
+        envelope.set_decay_curve(EnvelopeCurve::Exponential);
+        envelope.set_release_curve(EnvelopeCurve::Custom(-2.0));
+        envelope.set_looping(true);
+
+        let mut copy = Envelope::new();
+        copy.copy_parameters_from(&envelope).unwrap();
+        assert_eq!(copy.get_attack_time(), 250.0);
+        assert_eq!(copy.get_decay_curve(), EnvelopeCurve::Exponential);
+        assert_eq!(copy.get_release_curve(), EnvelopeCurve::Custom(-2.0));
+        assert!(copy.is_looping());
+
+        // Modules of different kinds share the parameters with the same ids
+        let mut gate = Gate::new();
+        gate.copy_parameters_from(&envelope).unwrap();
+        assert_eq!(gate.get_attack_time(), 250.0);
+        assert_eq!(gate.get_release_time(), 0.0);
+    }
+}
@@ -0,0 +1,768 @@
+use std::rc::Rc;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+
+use super::{SynthModule, PatchModule, OutputInfo};
+use crate::{SynthError, SynthResult};
+
+/// Identifies a node in a `Patch`. IDs aren't reused after a node is removed
+pub type NodeId = usize;
+
+/// The name of the only output of modules that don't have several
+const DEFAULT_OUTPUT_NAME: &str = "out";
+
+/// How a connection passes a signal along
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum ConnectionKind {
+    /// The input reads the output from the same block. These can't make cycles
+    Direct,
+    /// The input reads the output from the block before, so it can loop back to an earlier node
+    BlockFeedback,
+    /// The input reads the output from the sample before. Every node in a loop closed by one of
+    /// these renders a sample at a time, so keep those loops small
+    SampleFeedback
+}
+
+/// A connection from one node's output to another node's input
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Connection {
+    pub from: NodeId,
+    pub output: usize,
+    pub to: NodeId,
+    pub input: usize,
+    pub kind: ConnectionKind
+}
+
+struct PatchNode {
+    name: String,
+    module: Box<dyn PatchModule>
+}
+
+impl PatchNode {
+    fn get_output_names(&self) -> Vec<String> {
+        match self.module.as_multi_output() {
+            Some(module) => module.get_output_names(),
+            None => vec![String::from(DEFAULT_OUTPUT_NAME)]
+        }
+    }
+
+    /// Renders every output of the node into the given buffers
+    fn render(&self, buffers: &mut Vec<Vec<f32>>, block_size: usize, output_info: &OutputInfo) {
+        self.clear_buffers(buffers, block_size);
+
+        match self.module.as_multi_output() {
+            Some(module) => {
+                for (port, buffer) in buffers.iter_mut().enumerate() {
+                    module.fill_port_buffer(port, buffer, output_info);
+                }
+            }
+            None => self.module.fill_output_buffer(&mut buffers[0], output_info)
+        }
+    }
+
+    /// Gets a silent buffer of the given size for every output of the node
+    fn clear_buffers(&self, buffers: &mut Vec<Vec<f32>>, block_size: usize) {
+        let output_count = self.get_output_names().len();
+        buffers.resize(output_count, Vec::new());
+        for buffer in buffers.iter_mut() {
+            buffer.clear();
+            buffer.resize(block_size, 0.0);
+        }
+    }
+}
+
+/// Every node's outputs. The inputs that nodes are connected to read from these
+struct NodeBuffers {
+    /// Outputs for the block being rendered
+    current: HashMap<NodeId, Vec<Vec<f32>>>,
+    /// Outputs for the block before, for feedback connections to read
+    previous: HashMap<NodeId, Vec<Vec<f32>>>,
+    /// The sample being rendered while some of the nodes are rendering a sample at a time
+    sample: Option<usize>
+}
+
+impl NodeBuffers {
+    fn new() -> Self {
+        let current = HashMap::new();
+        let previous = HashMap::new();
+        let sample = None;
+        Self { current, previous, sample }
+    }
+
+    fn get_current(&self, node: NodeId, output: usize) -> Option<&[f32]> {
+        self.current.get(&node).and_then(|outputs| outputs.get(output)).map(|output| output.as_slice())
+    }
+
+    fn get_previous(&self, node: NodeId, output: usize) -> Option<&[f32]> {
+        self.previous.get(&node).and_then(|outputs| outputs.get(output)).map(|output| output.as_slice())
+    }
+}
+
+/// Copies a signal into a buffer. Anything the signal doesn't cover is silent
+fn copy_signal(signal: Option<&[f32]>, buffer: &mut [f32]) {
+    let signal = signal.unwrap_or(&[]);
+    let copy_len = signal.len().min(buffer.len());
+    buffer[..copy_len].copy_from_slice(&signal[..copy_len]);
+    buffer[copy_len..].fill(0.0);
+}
+
+/// Fills a buffer with one sample of a signal
+fn fill_sample(signal: Option<&[f32]>, sample: usize, buffer: &mut [f32]) {
+    let value = signal.and_then(|signal| signal.get(sample)).copied().unwrap_or(0.0);
+    buffer.fill(value);
+}
+
+/// Reads one output of a node. The patch connects these to node inputs instead of the nodes
+/// themselves so every node renders once a block no matter how many inputs it feeds
+struct NodeOutput {
+    buffers: Rc<RefCell<NodeBuffers>>,
+    node: NodeId,
+    output: usize,
+    kind: ConnectionKind
+}
+
+impl SynthModule for NodeOutput {
+    fn fill_output_buffer(&self, buffer: &mut [f32], _output_info: &OutputInfo) {
+        let buffers = self.buffers.borrow();
+        let current = buffers.get_current(self.node, self.output);
+        let previous = buffers.get_previous(self.node, self.output);
+        match (self.kind, buffers.sample) {
+            (ConnectionKind::Direct, None) => copy_signal(current, buffer),
+            (ConnectionKind::Direct, Some(sample)) => fill_sample(current, sample, buffer),
+            (ConnectionKind::BlockFeedback, None) => copy_signal(previous, buffer),
+            (ConnectionKind::BlockFeedback, Some(sample)) => fill_sample(previous, sample, buffer),
+            (ConnectionKind::SampleFeedback, None) => {
+                // The output isn't in a loop with this input so it's already rendered the whole block
+                if let Some((first_sample, rest)) = buffer.split_first_mut() {
+                    *first_sample = previous.and_then(|previous| previous.last()).copied().unwrap_or(0.0);
+                    copy_signal(current, rest);
+                }
+            }
+            (ConnectionKind::SampleFeedback, Some(0)) => {
+                let last_sample = previous.map_or(0, |previous| previous.len().saturating_sub(1));
+                fill_sample(previous, last_sample, buffer);
+            }
+            (ConnectionKind::SampleFeedback, Some(sample)) => fill_sample(current, sample - 1, buffer)
+        }
+    }
+}
+
+/// Reads whichever node output is the patch's output. Silent if the patch has no output
+struct PatchOutput {
+    buffers: Rc<RefCell<NodeBuffers>>,
+    output: Rc<Cell<Option<(NodeId, usize)>>>
+}
+
+impl SynthModule for PatchOutput {
+    fn fill_output_buffer(&self, buffer: &mut [f32], _output_info: &OutputInfo) {
+        match self.output.get() {
+            Some((node, output)) => copy_signal(self.buffers.borrow().get_current(node, output), buffer),
+            None => buffer.fill(0.0)
+        }
+    }
+}
+
+/// One step of rendering a block
+enum RenderStep {
+    /// Renders a node a block at a time
+    Node(NodeId),
+    /// Renders a loop of nodes a sample at a time
+    Samples(Vec<NodeId>)
+}
+
+/// A graph of modules that can be looked at and rewired after it's built. The patch owns its
+/// nodes and connects them by name, so there's no need to hold on to `Rc`s to change the wiring.
+/// Nodes render once a block in an order where everything a node reads from renders first.
+/// Signals can only loop back through feedback connections
+pub struct Patch {
+    nodes: BTreeMap<NodeId, PatchNode>,
+    next_node_id: NodeId,
+    connections: Vec<Connection>,
+    render_order: Vec<NodeId>,
+    render_steps: Vec<RenderStep>,
+    buffers: Rc<RefCell<NodeBuffers>>,
+    output: Rc<Cell<Option<(NodeId, usize)>>>,
+    /// Block id and size of the last rendered block so reading the patch twice in a block only renders it once
+    rendered_block: Cell<Option<(usize, usize)>>
+}
+
+impl Patch {
+    pub fn new() -> Self {
+        let nodes = BTreeMap::new();
+        let next_node_id = 0;
+        let connections = Vec::new();
+        let render_order = Vec::new();
+        let render_steps = Vec::new();
+        let buffers = Rc::new(RefCell::new(NodeBuffers::new()));
+        let output = Rc::new(Cell::new(None));
+        let rendered_block = Cell::new(None);
+        Self { nodes, next_node_id, connections, render_order, render_steps, buffers, output, rendered_block }
+    }
+
+    /// Adds a module to the patch. Every node needs a different name
+    pub fn add_node<M: PatchModule + 'static>(&mut self, name: &str, module: M) -> SynthResult<NodeId> {
+        self.add_boxed_node(name, Box::new(module))
+    }
+
+    /// Adds a module that's already boxed, e.g. one loaded from a patch file
+    pub fn add_boxed_node(&mut self, name: &str, module: Box<dyn PatchModule>) -> SynthResult<NodeId> {
+        if self.get_node_id(name).is_some() {
+            let msg = format!("Patch already has a node named \"{}\"", name);
+            return Err(SynthError::new(&msg));
+        }
+
+        let node_id = self.next_node_id;
+        self.next_node_id += 1;
+        let node = PatchNode { name: name.to_string(), module };
+        self.nodes.insert(node_id, node);
+        self.update_render_order();
+        Ok(node_id)
+    }
+
+    /// Removes a node along with every connection to and from it
+    pub fn remove_node(&mut self, node: NodeId) -> SynthResult<()> {
+        self.get_node(node)?;
+
+        let node_connections: Vec<Connection> = self.connections.iter()
+            .filter(|connection| connection.from == node || connection.to == node)
+            .copied()
+            .collect();
+        for connection in node_connections {
+            self.remove_connection(connection.to, connection.input)?;
+        }
+        if self.output.get().is_some_and(|(output_node, _)| output_node == node) {
+            self.output.set(None);
+        }
+
+        self.nodes.remove(&node);
+        let mut buffers = self.buffers.borrow_mut();
+        buffers.current.remove(&node);
+        buffers.previous.remove(&node);
+        drop(buffers);
+        self.update_render_order();
+        Ok(())
+    }
+
+    pub fn get_node_id(&self, name: &str) -> Option<NodeId> {
+        self.nodes.iter().find(|(_, node)| node.name == name).map(|(node_id, _)| *node_id)
+    }
+
+    pub fn get_node_name(&self, node: NodeId) -> Option<&str> {
+        self.nodes.get(&node).map(|node| node.name.as_str())
+    }
+
+    /// Gets the ID of every node, in the order they were added
+    pub fn get_node_ids(&self) -> Vec<NodeId> {
+        self.nodes.keys().copied().collect()
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn get_module(&self, node: NodeId) -> Option<&dyn PatchModule> {
+        self.nodes.get(&node).map(|node| node.module.as_ref())
+    }
+
+    /// Gets a node's module to change its settings. Its inputs should be changed with `connect`
+    /// and `disconnect` so the patch knows about them
+    pub fn get_module_mut(&mut self, node: NodeId) -> Option<&mut dyn PatchModule> {
+        match self.nodes.get_mut(&node) {
+            Some(node) => Some(node.module.as_mut()),
+            None => None
+        }
+    }
+
+    /// Gets the names of a node's outputs. Modules that only have one output call it "out"
+    pub fn get_output_names(&self, node: NodeId) -> SynthResult<Vec<String>> {
+        Ok(self.get_node(node)?.get_output_names())
+    }
+
+    pub fn get_input_names(&self, node: NodeId) -> SynthResult<Vec<String>> {
+        Ok(self.get_node(node)?.module.get_input_names())
+    }
+
+    /// Connects an output of one node to an input of another. Anything that was already connected
+    /// to the input gets disconnected. Fails if it would make the patch loop back on itself
+    pub fn connect(&mut self, from: NodeId, output: &str, to: NodeId, input: &str) -> SynthResult<()> {
+        self.add_connection(from, output, to, input, ConnectionKind::Direct)
+    }
+
+    /// Connects an output to an input so the input gets the output from the block before.
+    /// These can loop back to any node, including the one they come from
+    pub fn connect_feedback(&mut self, from: NodeId, output: &str, to: NodeId, input: &str) -> SynthResult<()> {
+        self.add_connection(from, output, to, input, ConnectionKind::BlockFeedback)
+    }
+
+    /// Connects an output to an input so the input gets the output from the sample before.
+    /// Every node in the loop this closes renders a sample at a time, which is a lot slower
+    pub fn connect_sample_feedback(
+        &mut self, from: NodeId, output: &str, to: NodeId, input: &str
+    ) -> SynthResult<()> {
+        self.add_connection(from, output, to, input, ConnectionKind::SampleFeedback)
+    }
+
+    /// Disconnects whatever is connected to a node's input. Does nothing if it isn't connected
+    pub fn disconnect(&mut self, to: NodeId, input: &str) -> SynthResult<()> {
+        let input = self.find_input(to, input)?;
+        self.remove_connection(to, input)?;
+        self.update_render_order();
+        Ok(())
+    }
+
+    pub fn get_connections(&self) -> &[Connection] {
+        &self.connections
+    }
+
+    /// Gets the connection into a node's input if there is one
+    pub fn get_connection(&self, to: NodeId, input: &str) -> SynthResult<Option<Connection>> {
+        let input = self.find_input(to, input)?;
+        let connection = self.connections.iter()
+            .find(|connection| connection.to == to && connection.input == input)
+            .copied();
+        Ok(connection)
+    }
+
+    /// Sets which node output is the output of the whole patch
+    pub fn set_output(&mut self, node: NodeId, output: &str) -> SynthResult<()> {
+        let output = self.find_output(node, output)?;
+        self.output.set(Some((node, output)));
+        Ok(())
+    }
+
+    pub fn clear_output(&mut self) {
+        self.output.set(None);
+    }
+
+    /// Gets the node and output index that's the output of the patch
+    pub fn get_output(&self) -> Option<(NodeId, usize)> {
+        self.output.get()
+    }
+
+    /// Gets a module that plays the patch's output from the last call to `render`. It follows
+    /// along if the output is changed afterwards
+    pub fn get_output_module(&self) -> Rc<dyn SynthModule> {
+        let output_module = PatchOutput { buffers: self.buffers.clone(), output: self.output.clone() };
+        Rc::new(output_module)
+    }
+
+    /// Gets the order the nodes render in
+    pub fn get_render_order(&self) -> &[NodeId] {
+        &self.render_order
+    }
+
+    /// Checks if a node is in a loop closed by a sample feedback connection, so renders a sample at a time
+    pub fn is_rendered_per_sample(&self, node: NodeId) -> bool {
+        self.render_steps.iter().any(|step| match step {
+            RenderStep::Node(_) => false,
+            RenderStep::Samples(nodes) => nodes.contains(&node)
+        })
+    }
+
+    /// Renders a block for every node in the patch
+    pub fn render(&self, block_size: usize, output_info: &OutputInfo) {
+        self.rendered_block.set(Some((output_info.block_id, block_size)));
+        {
+            // The last block becomes the one feedback connections read from
+            let mut buffers = self.buffers.borrow_mut();
+            let buffers = &mut *buffers;
+            std::mem::swap(&mut buffers.current, &mut buffers.previous);
+        }
+
+        for step in self.render_steps.iter() {
+            match step {
+                RenderStep::Node(node_id) => {
+                    // Take the node's buffers out while it renders so its inputs can read everyone else's
+                    let mut outputs = self.buffers.borrow_mut().current.remove(node_id).unwrap_or_default();
+                    self.nodes[node_id].render(&mut outputs, block_size, output_info);
+                    self.buffers.borrow_mut().current.insert(*node_id, outputs);
+                }
+                RenderStep::Samples(nodes) => self.render_samples(nodes, block_size, output_info)
+            }
+        }
+    }
+
+    /// Renders a loop of nodes one sample at a time so each node can read the others' last sample
+    fn render_samples(&self, nodes: &[NodeId], block_size: usize, output_info: &OutputInfo) {
+        for node_id in nodes {
+            let mut buffers = self.buffers.borrow_mut();
+            let outputs = buffers.current.entry(*node_id).or_default();
+            self.nodes[node_id].clear_buffers(outputs, block_size);
+        }
+
+        let mut sample_outputs = Vec::new();
+        for sample in 0..block_size {
+            self.buffers.borrow_mut().sample = Some(sample);
+            let sample_info = output_info.get_sub_block(sample, 1);
+            for node_id in nodes {
+                self.nodes[node_id].render(&mut sample_outputs, 1, &sample_info);
+                let mut buffers = self.buffers.borrow_mut();
+                let outputs = buffers.current.get_mut(node_id).unwrap();
+                for (output, sample_output) in outputs.iter_mut().zip(sample_outputs.iter()) {
+                    output[sample] = sample_output[0];
+                }
+            }
+        }
+        self.buffers.borrow_mut().sample = None;
+    }
+
+    fn add_connection(
+        &mut self, from: NodeId, output: &str, to: NodeId, input: &str, kind: ConnectionKind
+    ) -> SynthResult<()> {
+        let output = self.find_output(from, output)?;
+        let input = self.find_input(to, input)?;
+        if kind == ConnectionKind::Direct && (from == to || self.is_upstream(to, from, false)) {
+            let msg = format!(
+                "Connecting \"{}\" to \"{}\" would make a cycle. Use a feedback connection to loop back",
+                self.nodes[&from].name, self.nodes[&to].name
+            );
+            return Err(SynthError::new(&msg));
+        }
+
+        let node_output = NodeOutput { buffers: self.buffers.clone(), node: from, output, kind };
+        self.nodes.get_mut(&to).unwrap().module.set_input(input, Some(Rc::new(node_output)))?;
+        self.connections.retain(|connection| connection.to != to || connection.input != input);
+        self.connections.push(Connection { from, output, to, input, kind });
+        self.update_render_order();
+        Ok(())
+    }
+
+    fn get_node(&self, node: NodeId) -> SynthResult<&PatchNode> {
+        match self.nodes.get(&node) {
+            Some(node) => Ok(node),
+            None => {
+                let msg = format!("Patch has no node {}", node);
+                Err(SynthError::new(&msg))
+            }
+        }
+    }
+
+    fn find_output(&self, node: NodeId, name: &str) -> SynthResult<usize> {
+        let patch_node = self.get_node(node)?;
+        match patch_node.get_output_names().iter().position(|output_name| output_name == name) {
+            Some(output) => Ok(output),
+            None => {
+                let msg = format!("Node \"{}\" has no output named \"{}\"", patch_node.name, name);
+                Err(SynthError::new(&msg))
+            }
+        }
+    }
+
+    fn find_input(&self, node: NodeId, name: &str) -> SynthResult<usize> {
+        let patch_node = self.get_node(node)?;
+        match patch_node.module.get_input_index(name) {
+            Some(input) => Ok(input),
+            None => {
+                let msg = format!("Node \"{}\" has no input named \"{}\"", patch_node.name, name);
+                Err(SynthError::new(&msg))
+            }
+        }
+    }
+
+    /// Disconnects an input and forgets its connection without updating the render order
+    fn remove_connection(&mut self, to: NodeId, input: usize) -> SynthResult<()> {
+        let connection_count = self.connections.len();
+        self.connections.retain(|connection| connection.to != to || connection.input != input);
+        if self.connections.len() != connection_count {
+            self.nodes.get_mut(&to).unwrap().module.set_input(input, None)?;
+        }
+        Ok(())
+    }
+
+    /// Checks if following connections from `from` ever reaches `to`. Sample feedback connections
+    /// are only followed if `sample_feedback` is set. Block feedback connections never are
+    fn is_upstream(&self, from: NodeId, to: NodeId, sample_feedback: bool) -> bool {
+        self.get_downstream(from, sample_feedback).contains(&to)
+    }
+
+    /// Gets every node that can be reached by following connections out of a node
+    fn get_downstream(&self, node: NodeId, sample_feedback: bool) -> BTreeSet<NodeId> {
+        let mut downstream = BTreeSet::new();
+        let mut to_visit = vec![node];
+        while let Some(node) = to_visit.pop() {
+            let outgoing = self.connections.iter()
+                .filter(|connection| connection.from == node && Self::follows(connection, sample_feedback));
+            for connection in outgoing {
+                if downstream.insert(connection.to) {
+                    to_visit.push(connection.to);
+                }
+            }
+        }
+        downstream
+    }
+
+    /// Checks if a connection means its input has to render after its output
+    fn follows(connection: &Connection, sample_feedback: bool) -> bool {
+        match connection.kind {
+            ConnectionKind::Direct => true,
+            ConnectionKind::BlockFeedback => false,
+            ConnectionKind::SampleFeedback => sample_feedback
+        }
+    }
+
+    /// Works out the order the nodes render in, and which of them render a sample at a time
+    fn update_render_order(&mut self) {
+        // The wiring changed so the next read has to render again
+        self.rendered_block.set(None);
+        let single_nodes = self.nodes.keys().map(|node| vec![*node]).collect();
+        let node_order: Vec<NodeId> = self.sort_groups(single_nodes, false).into_iter().flatten().collect();
+
+        // A loop closed by sample feedback is every node that can both reach and be reached from
+        // a node in it. Each loop renders together, in the order its direct connections need
+        let downstream: HashMap<NodeId, BTreeSet<NodeId>> = node_order.iter()
+            .map(|node| (*node, self.get_downstream(*node, true)))
+            .collect();
+        let is_looped = |node: &NodeId| downstream[node].contains(node);
+        let mut groups = Vec::new();
+        let mut grouped = BTreeSet::new();
+        for node in node_order.iter() {
+            if grouped.contains(node) {
+                continue;
+            }
+            let group: Vec<NodeId> = if is_looped(node) {
+                node_order.iter()
+                    .filter(|other| downstream[node].contains(other) && downstream[other].contains(node))
+                    .copied()
+                    .collect()
+            }
+            else {
+                vec![*node]
+            };
+            grouped.extend(group.iter().copied());
+            groups.push(group);
+        }
+
+        let groups = self.sort_groups(groups, true);
+        self.render_order = groups.iter().flatten().copied().collect();
+        self.render_steps = groups.into_iter()
+            .map(|group| {
+                if is_looped(&group[0]) {
+                    RenderStep::Samples(group)
+                }
+                else {
+                    RenderStep::Node(group[0])
+                }
+            })
+            .collect();
+    }
+
+    /// Sorts groups of nodes so every group comes after the groups connected to its inputs.
+    /// Groups that don't depend on each other stay in the order they were given
+    fn sort_groups(&self, groups: Vec<Vec<NodeId>>, sample_feedback: bool) -> Vec<Vec<NodeId>> {
+        let group_of: HashMap<NodeId, usize> = groups.iter().enumerate()
+            .flat_map(|(group_index, group)| group.iter().map(move |node| (*node, group_index)))
+            .collect();
+        let between_groups = |connection: &&Connection| {
+            Self::follows(connection, sample_feedback) && group_of[&connection.from] != group_of[&connection.to]
+        };
+
+        let mut input_counts = vec![0_usize; groups.len()];
+        for connection in self.connections.iter().filter(between_groups) {
+            input_counts[group_of[&connection.to]] += 1;
+        }
+
+        let mut ready: VecDeque<usize> = (0..groups.len())
+            .filter(|group_index| input_counts[*group_index] == 0)
+            .collect();
+        let mut sorted = Vec::with_capacity(groups.len());
+        while let Some(group_index) = ready.pop_front() {
+            sorted.push(group_index);
+            let outgoing = self.connections.iter()
+                .filter(between_groups)
+                .filter(|connection| group_of[&connection.from] == group_index);
+            for connection in outgoing {
+                let to = group_of[&connection.to];
+                input_counts[to] -= 1;
+                if input_counts[to] == 0 {
+                    ready.push_back(to);
+                }
+            }
+        }
+        debug_assert!(sorted.len() == groups.len(), "Patch has a cycle");
+
+        let mut groups: Vec<Option<Vec<NodeId>>> = groups.into_iter().map(Some).collect();
+        sorted.into_iter().filter_map(|group_index| groups[group_index].take()).collect()
+    }
+}
+
+impl Default for Patch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SynthModule for Patch {
+    fn fill_output_buffer(&self, buffer: &mut [f32], output_info: &OutputInfo) {
+        if self.rendered_block.get() != Some((output_info.block_id, buffer.len())) {
+            self.render(buffer.len(), output_info);
+        }
+        self.get_output_module().fill_output_buffer(buffer, output_info);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module::{Combiner, CombineMode, Utility, UtilityMode};
+    use crate::module::sample_buffer::SampleBuffer;
+    use crate::clock;
+
+    const SAMPLE_RATE: usize = 4;
+
+    fn render_patch(patch: &Patch) -> Vec<f32> {
+        let mut clock = clock::SampleClock::new(SAMPLE_RATE);
+        let output_info = OutputInfo::new_basic(SAMPLE_RATE, clock.get_range(SAMPLE_RATE));
+        let mut output = vec![0_f32; SAMPLE_RATE];
+        patch.fill_output_buffer(&mut output, &output_info);
+        output
+    }
+
+    #[test]
+    fn test_connect_and_render() {
+        let mut patch = Patch::new();
+        // Add the combiner first so it has to be moved after the source to render
+        let combiner = patch.add_node("sum", Combiner::with_inputs(CombineMode::Sum, 2)).unwrap();
+        let source = patch.add_node("source", SampleBuffer::new(vec![0.0, 0.25, 0.5, 1.0])).unwrap();
+        patch.connect(source, "out", combiner, "in 0").unwrap();
+        patch.connect(source, "out", combiner, "in 1").unwrap();
+        patch.set_output(combiner, "out").unwrap();
+
+        assert_eq!(patch.get_render_order(), &[source, combiner]);
+        assert_eq!(patch.get_connections().len(), 2);
+        assert_eq!(render_patch(&patch), vec![0.0, 0.5, 1.0, 2.0]);
+
+        // Connecting the same input again replaces the old connection
+        let negated = patch.add_node("negated", Utility::new(UtilityMode::Invert)).unwrap();
+        patch.connect(source, "out", negated, "signal").unwrap();
+        patch.connect(negated, "out", combiner, "in 1").unwrap();
+        assert_eq!(patch.get_connections().len(), 3);
+        assert_eq!(patch.get_render_order(), &[source, negated, combiner]);
+        assert_eq!(render_patch(&patch), vec![0.0; SAMPLE_RATE]);
+    }
+
+    #[test]
+    fn test_cycles() {
+        let mut patch = Patch::new();
+        let first = patch.add_node("first", Utility::new(UtilityMode::Invert)).unwrap();
+        let second = patch.add_node("second", Utility::new(UtilityMode::Invert)).unwrap();
+        let third = patch.add_node("third", Utility::new(UtilityMode::Invert)).unwrap();
+        patch.connect(first, "out", second, "signal").unwrap();
+        patch.connect(second, "out", third, "signal").unwrap();
+
+        assert!(patch.connect(third, "out", first, "signal").is_err(), "Expected a cycle through three nodes to fail");
+        assert!(patch.connect(first, "out", first, "signal").is_err(), "Expected a node connected to itself to fail");
+        assert_eq!(patch.get_connections().len(), 2, "Failed connections shouldn't be kept");
+        assert_eq!(patch.get_render_order(), &[first, second, third]);
+    }
+
+    #[test]
+    fn test_disconnect_and_remove() {
+        let mut patch = Patch::new();
+        let source = patch.add_node("source", SampleBuffer::new(vec![1.0; SAMPLE_RATE])).unwrap();
+        let utility = patch.add_node("utility", Utility::new(UtilityMode::Invert)).unwrap();
+        patch.connect(source, "out", utility, "signal").unwrap();
+        patch.set_output(utility, "out").unwrap();
+        assert_eq!(render_patch(&patch), vec![-1.0; SAMPLE_RATE]);
+
+        patch.disconnect(utility, "signal").unwrap();
+        assert!(patch.get_connections().is_empty());
+        assert_eq!(render_patch(&patch), vec![0.0; SAMPLE_RATE]);
+
+        patch.connect(source, "out", utility, "signal").unwrap();
+        patch.remove_node(source).unwrap();
+        assert!(patch.get_connections().is_empty());
+        assert_eq!(patch.get_node_id("source"), None);
+        assert_eq!(render_patch(&patch), vec![0.0; SAMPLE_RATE]);
+
+        patch.remove_node(utility).unwrap();
+        assert_eq!(patch.get_output(), None);
+        assert_eq!(patch.node_count(), 0);
+    }
+
+    #[test]
+    fn test_block_feedback() {
+        // A sum that adds its own last block to a constant counts up a block at a time
+        let mut patch = Patch::new();
+        let source = patch.add_node("source", SampleBuffer::new(vec![1.0; SAMPLE_RATE])).unwrap();
+        let sum = patch.add_node("sum", Combiner::with_inputs(CombineMode::Sum, 2)).unwrap();
+        patch.connect(source, "out", sum, "in 0").unwrap();
+        patch.connect_feedback(sum, "out", sum, "in 1").unwrap();
+        patch.set_output(sum, "out").unwrap();
+        assert!(!patch.is_rendered_per_sample(sum));
+        for block in 1..4 {
+            assert_eq!(render_patch(&patch), vec![block as f32; SAMPLE_RATE]);
+        }
+
+        // Direct connections still can't loop back
+        let negated = patch.add_node("negated", Utility::new(UtilityMode::Invert)).unwrap();
+        patch.connect(sum, "out", negated, "signal").unwrap();
+        assert!(patch.connect(negated, "out", sum, "in 0").is_err());
+        patch.connect_feedback(negated, "out", sum, "in 0").unwrap();
+        assert_eq!(patch.get_render_order(), &[source, sum, negated]);
+    }
+
+    #[test]
+    fn test_sample_feedback() {
+        // The same sum with sample feedback counts up a sample at a time
+        let mut patch = Patch::new();
+        let source = patch.add_node("source", SampleBuffer::new(vec![1.0; SAMPLE_RATE])).unwrap();
+        let sum = patch.add_node("sum", Combiner::with_inputs(CombineMode::Sum, 2)).unwrap();
+        patch.connect(source, "out", sum, "in 0").unwrap();
+        patch.connect_sample_feedback(sum, "out", sum, "in 1").unwrap();
+        patch.set_output(sum, "out").unwrap();
+        assert!(patch.is_rendered_per_sample(sum));
+        assert!(!patch.is_rendered_per_sample(source));
+        assert_eq!(render_patch(&patch), vec![1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(render_patch(&patch), vec![5.0, 6.0, 7.0, 8.0]);
+
+        // Reading the patch twice in a block doesn't move the count on a second time
+        let mut clock = clock::SampleClock::new(SAMPLE_RATE);
+        let output_info = OutputInfo::new_basic(SAMPLE_RATE, clock.get_range(SAMPLE_RATE));
+        for _ in 0..2 {
+            let mut output = vec![0_f32; SAMPLE_RATE];
+            patch.fill_output_buffer(&mut output, &output_info);
+            assert_eq!(output, vec![9.0, 10.0, 11.0, 12.0]);
+        }
+        assert_eq!(render_patch(&patch), vec![13.0, 14.0, 15.0, 16.0]);
+
+        // Every node in a loop renders a sample at a time
+        let mut patch = Patch::new();
+        let source = patch.add_node("source", SampleBuffer::new(vec![1.0; SAMPLE_RATE])).unwrap();
+        let negated = patch.add_node("negated", Utility::new(UtilityMode::Invert)).unwrap();
+        let sum = patch.add_node("sum", Combiner::with_inputs(CombineMode::Sum, 2)).unwrap();
+        patch.connect(source, "out", sum, "in 0").unwrap();
+        patch.connect(sum, "out", negated, "signal").unwrap();
+        patch.connect_sample_feedback(negated, "out", sum, "in 1").unwrap();
+        patch.set_output(sum, "out").unwrap();
+        assert!(patch.is_rendered_per_sample(sum) && patch.is_rendered_per_sample(negated));
+        assert_eq!(patch.get_render_order(), &[source, sum, negated]);
+        assert_eq!(render_patch(&patch), vec![1.0, 0.0, 1.0, 0.0]);
+
+        // Without a loop it's a one sample delay
+        let mut patch = Patch::new();
+        let delayed = patch.add_node("delayed", Utility::new(UtilityMode::Offset)).unwrap();
+        let source = patch.add_node("source", SampleBuffer::new(vec![0.0, 0.25, 0.5, 1.0])).unwrap();
+        patch.connect_sample_feedback(source, "out", delayed, "signal").unwrap();
+        patch.set_output(delayed, "out").unwrap();
+        assert!(!patch.is_rendered_per_sample(delayed));
+        assert_eq!(patch.get_render_order(), &[source, delayed]);
+        assert_eq!(render_patch(&patch), vec![0.0, 0.0, 0.25, 0.5]);
+        assert_eq!(render_patch(&patch), vec![1.0, 0.0, 0.25, 0.5]);
+    }
+
+    #[test]
+    fn test_bad_names() {
+        let mut patch = Patch::new();
+        let source = patch.add_node("source", SampleBuffer::new(vec![1.0; SAMPLE_RATE])).unwrap();
+        let utility = patch.add_node("utility", Utility::new(UtilityMode::Invert)).unwrap();
+
+        assert!(patch.add_node("source", SampleBuffer::new(vec![1.0; SAMPLE_RATE])).is_err());
+        assert!(patch.connect(source, "nothing", utility, "signal").is_err());
+        assert!(patch.connect(source, "out", utility, "nothing").is_err());
+        assert!(patch.connect(source, "out", utility + 1, "signal").is_err());
+        assert!(patch.set_output(source, "nothing").is_err());
+        assert!(patch.remove_node(utility + 1).is_err());
+        assert_eq!(patch.get_node_name(utility), Some("utility"));
+        assert_eq!(patch.get_input_names(utility).unwrap(), vec![String::from("signal")]);
+    }
+}
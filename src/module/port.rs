@@ -0,0 +1,96 @@
+use std::rc::Rc;
+
+use super::{SynthModule, MultiOutputModule, OutputInfo};
+use crate::{SynthError, SynthResult};
+
+/// Connects to one output of a module that has several. This is what gets plugged into
+/// other modules' inputs
+#[derive(Clone)]
+pub struct OutputPort {
+    module: Rc<dyn MultiOutputModule>,
+    port: usize
+}
+
+impl OutputPort {
+    /// Connects to the output with the given name
+    pub fn new(module: Rc<dyn MultiOutputModule>, port_name: &str) -> SynthResult<Self> {
+        match module.get_output_index(port_name) {
+            Some(port) => Ok(Self { module, port }),
+            None => {
+                let msg = format!("Module has no output named \"{}\"", port_name);
+                Err(SynthError::new(&msg))
+            }
+        }
+    }
+
+    /// Connects to the output at the given index
+    pub fn with_index(module: Rc<dyn MultiOutputModule>, port: usize) -> SynthResult<Self> {
+        if port >= module.get_output_names().len() {
+            let msg = format!("Module has no output {}", port);
+            return Err(SynthError::new(&msg));
+        }
+        Ok(Self { module, port })
+    }
+
+    pub fn get_port(&self) -> usize {
+        self.port
+    }
+
+    pub fn get_port_name(&self) -> Option<String> {
+        self.module.get_output_names().into_iter().nth(self.port)
+    }
+}
+
+impl SynthModule for OutputPort {
+    fn fill_output_buffer(&self, buffer: &mut [f32], output_info: &OutputInfo) {
+        self.module.fill_port_buffer(self.port, buffer, output_info);
+    }
+}
+
+/// Holds every output of a multi output module for the most recent block. Modules render all of
+/// their outputs into this at once, then each port copies its output out. That way the module's
+/// state only moves forward once a block no matter how many of its ports get read
+pub(super) struct PortBuffers {
+    block_id: Option<usize>,
+    buffers: Vec<Vec<f32>>
+}
+
+impl PortBuffers {
+    pub(super) fn new() -> Self {
+        let block_id = None;
+        let buffers = Vec::new();
+        Self { block_id, buffers }
+    }
+
+    /// Checks if the buffers already hold the block being asked for
+    pub(super) fn is_current(&self, block_size: usize, output_info: &OutputInfo) -> bool {
+        self.block_id == Some(output_info.block_id)
+            && self.buffers.first().is_some_and(|buffer| buffer.len() == block_size)
+    }
+
+    /// Gets zeroed buffers ready to render a new block into
+    pub(super) fn start_block(
+        &mut self, port_count: usize, block_size: usize, output_info: &OutputInfo
+    ) -> &mut [Vec<f32>] {
+        self.block_id = Some(output_info.block_id);
+        self.buffers.resize(port_count, Vec::new());
+        for buffer in self.buffers.iter_mut() {
+            buffer.clear();
+            buffer.resize(block_size, 0.0);
+        }
+        &mut self.buffers
+    }
+
+    /// Forgets the last block so it gets rendered again if it's asked for
+    pub(super) fn invalidate(&mut self) {
+        self.block_id = None;
+    }
+
+    /// Copies a port's output into a buffer. Ports that weren't rendered are silent
+    pub(super) fn copy_port(&self, port: usize, buffer: &mut [f32]) {
+        match self.buffers.get(port) {
+            Some(port_buffer) => buffer.copy_from_slice(port_buffer),
+            None => buffer.fill(0.0)
+        }
+    }
+}
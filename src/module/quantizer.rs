@@ -0,0 +1,414 @@
+use std::rc::Rc;
+use std::cell::{Cell, RefCell};
+
+use super::{SynthModule, MultiOutputModule, PatchModule, OutputInfo, no_such_input, input_names};
+use super::port::PortBuffers;
+use crate::note::{self, Note, Tone, Scale};
+use crate::tuning::{self, Tuning};
+use crate::SynthResult;
+use super::parameter::{Parameters, ParameterInfo, ParameterScale, clamp_parameter, no_such_parameter, choice_index, choice_value, toggle_value};
+
+const MILLISECONDS_PER_SECOND: f32 = 1000.0;
+const TONES_PER_OCTAVE: usize = 12;
+const MIDI_NOTE_COUNT: u8 = 128;
+
+/// The pitches a `Quantizer` can snap to for a particular tuning
+struct PitchTable {
+    tuning: Rc<Tuning>,
+    /// (MIDI key, log2 of frequency) for every allowed key, lowest first
+    pitches: Vec<(u8, f32)>
+}
+
+/// Which signal a `Quantizer` puts out.
+/// Each one is also an output port named the same thing in lower case
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum QuantizerSignal {
+    /// The quantized pitch
+    Pitch,
+    /// A short 1.0 pulse each time the quantized note changes
+    Trigger
+}
+
+/// Names of the output ports, indexed by `QuantizerSignal`
+const PORT_NAMES: [&str; 2] = ["pitch", "trigger"];
+
+const SIGNAL_NAMES: [(&str, QuantizerSignal); 2] = [
+    ("Pitch", QuantizerSignal::Pitch),
+    ("Trigger", QuantizerSignal::Trigger)
+];
+
+/// Ids and names of the parameters that allow each tone, indexed by `Tone`
+const TONE_PARAMETERS: [(&str, &str); TONES_PER_OCTAVE] = [
+    ("tone_a", "A"), ("tone_a_sharp", "A#"), ("tone_b", "B"), ("tone_c", "C"),
+    ("tone_c_sharp", "C#"), ("tone_d", "D"), ("tone_d_sharp", "D#"), ("tone_e", "E"),
+    ("tone_f", "F"), ("tone_f_sharp", "F#"), ("tone_g", "G"), ("tone_g_sharp", "G#")
+];
+
+/// Snaps a pitch signal to the nearest note that's allowed by a scale or a custom set of tones.
+/// The input is a pitch signal like the one `MidiNoteOutput` puts out. Notes are the
+/// pitches of the MIDI keys in the tuning, and a key is allowed if the tone it would normally
+/// play is. If no keys are allowed the input passes through untouched.
+/// The main output is whichever signal it's been set to but both can be read from their own ports.
+pub struct Quantizer {
+    signal_in: Option<Rc<dyn SynthModule>>,
+    signal: QuantizerSignal,
+    /// Which tones are allowed, indexed by `Tone`
+    tone_mask: [bool; TONES_PER_OCTAVE],
+    /// Tuning used for the notes. Uses the global tuning if this is `None`
+    tuning: Option<Rc<Tuning>>,
+    /// How long triggers last in milliseconds. Triggers are always at least one sample long
+    trigger_length: f32,
+
+    pitch_table: RefCell<Option<PitchTable>>,
+    // Last quantized MIDI key
+    previous_note: Cell<Option<u8>>,
+    trigger_samples_remaining: Cell<usize>,
+    port_buffers: RefCell<PortBuffers>
+}
+
+impl Quantizer {
+    pub fn new() -> Self {
+        Self::with_signal(QuantizerSignal::Pitch)
+    }
+
+    pub fn with_signal(signal: QuantizerSignal) -> Self {
+        let signal_in = None;
+        let tone_mask = [true; TONES_PER_OCTAVE];
+        let tuning = None;
+        let trigger_length = 5.0;
+        let pitch_table = RefCell::new(None);
+        let previous_note = Cell::new(None);
+        let trigger_samples_remaining = Cell::new(0);
+        let port_buffers = RefCell::new(PortBuffers::new());
+        Self {
+            signal_in, signal, tone_mask, tuning, trigger_length,
+            pitch_table, previous_note, trigger_samples_remaining, port_buffers
+        }
+    }
+
+    pub fn set_signal_in(&mut self, signal_in: Option<Rc<dyn SynthModule>>) {
+        self.signal_in = signal_in;
+    }
+
+    pub fn set_signal(&mut self, signal: QuantizerSignal) {
+        self.signal = signal;
+    }
+
+    pub fn get_signal(&self) -> QuantizerSignal {
+        self.signal
+    }
+
+    /// Allows only the tones in `scale` starting from `root`
+    pub fn set_scale(&mut self, root: Tone, scale: Scale) {
+        self.set_tones(&scale.get_tones(root));
+    }
+
+    /// Allows only the given tones
+    pub fn set_tones(&mut self, tones: &[Tone]) {
+        self.tone_mask = [false; TONES_PER_OCTAVE];
+        for tone in tones.iter() {
+            self.tone_mask[*tone as usize] = true;
+        }
+        *self.pitch_table.get_mut() = None;
+    }
+
+    pub fn set_tone_enabled(&mut self, tone: Tone, enabled: bool) {
+        self.set_tone_index_enabled(tone as usize, enabled);
+    }
+
+    fn set_tone_index_enabled(&mut self, tone_index: usize, enabled: bool) {
+        self.tone_mask[tone_index] = enabled;
+        *self.pitch_table.get_mut() = None;
+    }
+
+    pub fn is_tone_enabled(&self, tone: Tone) -> bool {
+        self.tone_mask[tone as usize]
+    }
+
+    pub fn set_tuning(&mut self, tuning: Option<Rc<Tuning>>) {
+        self.tuning = tuning;
+    }
+
+    /// Gets the tuning this quantizer uses, which is the global tuning if it hasn't been given one
+    pub fn get_tuning(&self) -> Rc<Tuning> {
+        match &self.tuning {
+            Some(tuning) => tuning.clone(),
+            None => tuning::get_global_tuning()
+        }
+    }
+
+    pub fn set_trigger_length(&mut self, trigger_length: f32) {
+        self.trigger_length = trigger_length.max(0.0);
+    }
+
+    pub fn get_trigger_length(&self) -> f32 {
+        self.trigger_length
+    }
+
+    /// Makes sure the pitch table is for the tuning we're using now
+    fn update_pitch_table(&self) {
+        let tuning = self.get_tuning();
+        let mut pitch_table = self.pitch_table.borrow_mut();
+        if let Some(table) = pitch_table.as_ref() {
+            if Rc::ptr_eq(&table.tuning, &tuning) {
+                return;
+            }
+        }
+
+        let mut pitches: Vec<(u8, f32)> = (0..MIDI_NOTE_COUNT).filter_map(|midi_note| {
+            let tone = Note::from_midi_note(midi_note).tone;
+            if !self.tone_mask[tone as usize] {
+                return None;
+            }
+            tuning.get_freq(midi_note).map(|freq| (midi_note, freq.log2()))
+        })
+        .collect();
+        pitches.sort_by(|a, b| a.1.total_cmp(&b.1));
+        *pitch_table = Some(PitchTable { tuning, pitches });
+    }
+
+    /// Finds the nearest allowed key to a frequency. Ties go to the lower key.
+    /// Returns `None` if no keys are allowed.
+    fn quantize(pitches: &[(u8, f32)], freq: f32) -> Option<(u8, f32)> {
+        let log_freq = freq.log2();
+        let upper_index = pitches.partition_point(|(_, pitch)| *pitch < log_freq);
+        let lower = if upper_index > 0 { pitches.get(upper_index - 1) } else { None };
+        let upper = pitches.get(upper_index);
+        let (midi_note, pitch) = match (lower, upper) {
+            (Some(lower), Some(upper)) => {
+                if log_freq - lower.1 <= upper.1 - log_freq { *lower } else { *upper }
+            },
+            (Some(nearest), None) | (None, Some(nearest)) => *nearest,
+            (None, None) => return None
+        };
+        Some((midi_note, 2_f32.powf(pitch)))
+    }
+
+    /// Renders every output for a block
+    fn render_block(&self, port_buffers: &mut PortBuffers, block_size: usize, output_info: &OutputInfo) {
+        let buffers = port_buffers.start_block(PORT_NAMES.len(), block_size, output_info);
+
+        // Get the pitch to quantize
+        let mut pitch = vec![0.0; block_size];
+        if let Some(signal_in) = &self.signal_in {
+            signal_in.fill_output_buffer(&mut pitch, output_info);
+        }
+        else {
+            return;
+        }
+
+        self.update_pitch_table();
+        let pitch_table = self.pitch_table.borrow();
+        let pitches = &pitch_table.as_ref().expect("Pitch table should have just been made").pitches;
+
+        let trigger_samples = (self.trigger_length * output_info.sample_rate as f32 / MILLISECONDS_PER_SECOND) as usize;
+        for (i, pitch_datum) in pitch.iter().enumerate() {
+            let freq = note::pitch_to_freq(*pitch_datum);
+            let quantized_note = Self::quantize(pitches, freq);
+
+            let quantized_pitch = match quantized_note {
+                Some((midi_note, quantized_freq)) => {
+                    if self.previous_note.get() != Some(midi_note) {
+                        self.previous_note.set(Some(midi_note));
+                        self.trigger_samples_remaining.set(trigger_samples.max(1));
+                    }
+                    note::freq_to_pitch(quantized_freq)
+                },
+                None => *pitch_datum
+            };
+
+            let trigger_samples_remaining = self.trigger_samples_remaining.get();
+            let trigger = if trigger_samples_remaining > 0 {
+                self.trigger_samples_remaining.set(trigger_samples_remaining - 1);
+                1.0
+            }
+            else {
+                0.0
+            };
+
+            buffers[QuantizerSignal::Pitch as usize][i] = quantized_pitch;
+            buffers[QuantizerSignal::Trigger as usize][i] = trigger;
+        }
+    }
+}
+
+impl Default for Quantizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SynthModule for Quantizer {
+    fn fill_output_buffer(&self, buffer: &mut [f32], output_info: &OutputInfo) {
+        self.fill_port_buffer(self.signal as usize, buffer, output_info);
+    }
+}
+
+impl MultiOutputModule for Quantizer {
+    fn get_output_names(&self) -> Vec<String> {
+        PORT_NAMES.iter().map(|name| name.to_string()).collect()
+    }
+
+    fn fill_port_buffer(&self, port: usize, buffer: &mut [f32], output_info: &OutputInfo) {
+        let mut port_buffers = self.port_buffers.borrow_mut();
+        if !port_buffers.is_current(buffer.len(), output_info) {
+            self.render_block(&mut port_buffers, buffer.len(), output_info);
+        }
+        port_buffers.copy_port(port, buffer);
+    }
+}
+
+impl PatchModule for Quantizer {
+    fn get_input_names(&self) -> Vec<String> {
+        input_names(&["signal"])
+    }
+
+    fn set_input(&mut self, input: usize, module: Option<Rc<dyn SynthModule>>) -> SynthResult<()> {
+        match input {
+            0 => self.set_signal_in(module),
+            _ => return no_such_input(input)
+        }
+        Ok(())
+    }
+
+    fn as_multi_output(&self) -> Option<&dyn MultiOutputModule> {
+        Some(self)
+    }
+}
+
+/// Each tone has a parameter that allows it, e.g. "tone_c_sharp"
+impl Parameters for Quantizer {
+    fn get_parameter_info(&self) -> Vec<ParameterInfo> {
+        let mut info = vec![
+            ParameterInfo::choice("signal", "Signal", &SIGNAL_NAMES, QuantizerSignal::Pitch),
+            ParameterInfo::new("trigger_length", "Trigger Length", "ms", 0.0, 100.0, 5.0, ParameterScale::Linear)
+        ];
+        info.extend(TONE_PARAMETERS.iter().map(|(id, name)| ParameterInfo::toggle(id, name, true)));
+        info
+    }
+
+    fn get_parameter(&self, id: &str) -> Option<f32> {
+        match id {
+            "signal" => Some(choice_index(&SIGNAL_NAMES, self.signal)),
+            "trigger_length" => Some(self.trigger_length),
+            _ => {
+                let tone_index = TONE_PARAMETERS.iter().position(|(tone_id, _)| *tone_id == id)?;
+                Some(toggle_value(self.tone_mask[tone_index]))
+            }
+        }
+    }
+
+    fn set_parameter(&mut self, id: &str, value: f32) -> SynthResult<()> {
+        let value = clamp_parameter(self, id, value)?;
+        match id {
+            "signal" => self.set_signal(choice_value(&SIGNAL_NAMES, value)),
+            "trigger_length" => self.set_trigger_length(value),
+            _ => match TONE_PARAMETERS.iter().position(|(tone_id, _)| *tone_id == id) {
+                Some(tone_index) => self.set_tone_index_enabled(tone_index, value > 0.0),
+                None => return no_such_parameter(id)
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::*;
+    use crate::module::sample_buffer::SampleBuffer;
+    use crate::module::OutputPort;
+    use crate::note::FREQ_A;
+    use crate::clock;
+
+    const SAMPLE_RATE: usize = 4;
+
+    /// Gets the pitch some number of semitones from A4
+    fn semitones_to_pitch(semitones: f32) -> f32 {
+        note::freq_to_pitch(FREQ_A) + note::semitones_to_pitch(semitones)
+    }
+
+    fn get_quantizer_output(quantizer: &mut Quantizer, semitones: [f32; SAMPLE_RATE]) -> Vec<f32> {
+        let pitch = semitones.iter().map(|semitone| semitones_to_pitch(*semitone)).collect();
+        quantizer.set_signal_in(Some(Rc::new(SampleBuffer::new(pitch))));
+
+        let mut clock = clock::SampleClock::new(SAMPLE_RATE);
+        let clock_values = clock.get_range(SAMPLE_RATE);
+        let output_info = OutputInfo::new_basic(SAMPLE_RATE, clock_values);
+
+        let mut output_buffer = vec![0_f32; SAMPLE_RATE];
+        quantizer.fill_output_buffer(&mut output_buffer, &output_info);
+        output_buffer
+    }
+
+    fn assert_pitches_eq(got: &[f32], expected_semitones: [f32; SAMPLE_RATE]) {
+        for (got_datum, expected_semitone) in got.iter().zip(expected_semitones.iter()) {
+            let expected_datum = semitones_to_pitch(*expected_semitone);
+            assert!(
+                float_eq(*got_datum, expected_datum, 0.00001),
+                "Quantizer output does not match expected:\n\tGot: {:?}\n\tExpected semitones: {:?}", got, expected_semitones
+            );
+        }
+    }
+
+    #[test]
+    fn test_chromatic() {
+        let mut quantizer = Quantizer::new();
+        let output = get_quantizer_output(&mut quantizer, [0.4, 0.6, -13.3, 7.5]);
+        assert_pitches_eq(&output, [0.0, 1.0, -13.0, 7.0]);
+    }
+
+    #[test]
+    fn test_scale() {
+        // A sharp isn't in C major so it should go to A or B, whichever is closer
+        let mut quantizer = Quantizer::new();
+        quantizer.set_scale(Tone::C, Scale::Major);
+        let output = get_quantizer_output(&mut quantizer, [0.9, 1.1, 3.0, -3.2]);
+        assert_pitches_eq(&output, [0.0, 2.0, 3.0, -4.0]);
+    }
+
+    #[test]
+    fn test_custom_tones() {
+        let mut quantizer = Quantizer::new();
+        quantizer.set_tones(&[Tone::E]);
+        let output = get_quantizer_output(&mut quantizer, [0.0, 2.0, 8.0, -6.0]);
+        assert_pitches_eq(&output, [-5.0, 7.0, 7.0, -5.0]);
+    }
+
+    #[test]
+    fn test_tuning() {
+        // Quarter tones. Every key is allowed since each one plays a tone the mask allows
+        let mut quantizer = Quantizer::new();
+        quantizer.set_tuning(Some(Rc::new(Tuning::equal_divisions(24, FREQ_A).unwrap())));
+        let output = get_quantizer_output(&mut quantizer, [0.4, 0.8, -0.2, 2.0]);
+        assert_pitches_eq(&output, [0.5, 1.0, 0.0, 2.0]);
+    }
+
+    #[test]
+    fn test_ports() {
+        // Reading the trigger then the pitch in one block mustn't quantize the input twice
+        let mut quantizer = Quantizer::new();
+        quantizer.set_trigger_length(0.0);
+        let pitch = [0.1, 0.2, 1.0, 0.9].iter().map(|semitone| semitones_to_pitch(*semitone)).collect();
+        quantizer.set_signal_in(Some(Rc::new(SampleBuffer::new(pitch))));
+        let quantizer = Rc::new(quantizer);
+        let trigger_port = OutputPort::new(quantizer.clone(), "trigger").unwrap();
+
+        let mut clock = clock::SampleClock::new(SAMPLE_RATE);
+        let output_info = OutputInfo::new_basic(SAMPLE_RATE, clock.get_range(SAMPLE_RATE));
+        let mut trigger = vec![0_f32; SAMPLE_RATE];
+        trigger_port.fill_output_buffer(&mut trigger, &output_info);
+        let mut pitch = vec![0_f32; SAMPLE_RATE];
+        quantizer.fill_output_buffer(&mut pitch, &output_info);
+        assert_eq!(trigger, vec![1.0, 0.0, 1.0, 0.0]);
+        assert_pitches_eq(&pitch, [0.0, 0.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn test_trigger_on_change() {
+        let mut quantizer = Quantizer::with_signal(QuantizerSignal::Trigger);
+        quantizer.set_trigger_length(0.0);
+        let output = get_quantizer_output(&mut quantizer, [0.1, 0.2, 1.0, 0.9]);
+        assert_eq!(output, vec![1.0, 0.0, 1.0, 0.0]);
+    }
+}
@@ -0,0 +1,106 @@
+use std::rc::Rc;
+use std::cell::RefCell;
+
+use super::{SynthModule, OutputInfo};
+use super::port::PortBuffers;
+
+/// A module's output for the most recent block. Modules are pulled by every input they're connected
+/// to, so every module in this crate renders through one of these. That way a module that feeds more
+/// than one input only renders once a block and stateful modules like `Envelope` only move forward once.
+pub(super) struct BlockCache {
+    output: RefCell<PortBuffers>
+}
+
+impl BlockCache {
+    pub(super) fn new() -> Self {
+        let output = RefCell::new(PortBuffers::new());
+        Self { output }
+    }
+
+    /// Fills `buffer` with this block's output. `render` is only called the first time a block is read
+    pub(super) fn fill<F: FnOnce(&mut [f32])>(&self, buffer: &mut [f32], output_info: &OutputInfo, render: F) {
+        let mut output = self.output.borrow_mut();
+        if !output.is_current(buffer.len(), output_info) {
+            let buffers = output.start_block(1, buffer.len(), output_info);
+            render(&mut buffers[0]);
+        }
+        output.copy_port(0, buffer);
+    }
+
+    /// Forgets the cached output so the next read renders again
+    pub(super) fn invalidate(&self) {
+        self.output.borrow_mut().invalidate();
+    }
+}
+
+/// A copy of a module doesn't share its output
+impl Clone for BlockCache {
+    fn clone(&self) -> Self {
+        Self::new()
+    }
+}
+
+/// Renders a module once per block and gives the same output to everything that reads it.
+/// Modules in this crate already do this themselves. Wrap modules from elsewhere in this before
+/// connecting them to more than one input so they don't render more than once a block.
+pub struct RenderCache {
+    module: Rc<dyn SynthModule>,
+    output: BlockCache
+}
+
+impl RenderCache {
+    pub fn new(module: Rc<dyn SynthModule>) -> Self {
+        let output = BlockCache::new();
+        Self { module, output }
+    }
+
+    pub fn get_module(&self) -> &Rc<dyn SynthModule> {
+        &self.module
+    }
+
+    /// Forgets the cached output so the module renders again the next time it's read
+    pub fn invalidate(&self) {
+        self.output.invalidate();
+    }
+}
+
+impl SynthModule for RenderCache {
+    fn fill_output_buffer(&self, buffer: &mut [f32], output_info: &OutputInfo) {
+        self.output.fill(buffer, output_info, |buffer| self.module.fill_output_buffer(buffer, output_info));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module::Envelope;
+    use crate::module::sample_buffer::SampleBuffer;
+    use crate::clock;
+
+    const SAMPLE_RATE: usize = 4;
+
+    #[test]
+    fn test_fan_out() {
+        // An envelope that's read twice a block should still take two seconds to attack
+        let mut envelope = Envelope::new();
+        envelope.set_attack_time(2000.0);
+        envelope.set_trigger(Some(Rc::new(SampleBuffer::new(vec![1.0; SAMPLE_RATE]))));
+        let cache = Rc::new(RenderCache::new(Rc::new(envelope)));
+
+        let mut clock = clock::SampleClock::new(SAMPLE_RATE);
+        let output_info = OutputInfo::new_basic(SAMPLE_RATE, clock.get_range(SAMPLE_RATE));
+        let mut first_output = vec![0_f32; SAMPLE_RATE];
+        cache.fill_output_buffer(&mut first_output, &output_info);
+        let mut second_output = vec![0_f32; SAMPLE_RATE];
+        cache.fill_output_buffer(&mut second_output, &output_info);
+        assert_eq!(first_output, second_output, "Reading the same block twice gave different output");
+
+        // The next block carries on from where the first left off
+        let output_info = OutputInfo::new_basic(SAMPLE_RATE, clock.get_range(SAMPLE_RATE));
+        cache.fill_output_buffer(&mut second_output, &output_info);
+        assert!(
+            second_output[0] > first_output[SAMPLE_RATE - 1],
+            "Expected the envelope to keep rising.\n\tFirst block: {:?}\n\tSecond block: {:?}", first_output, second_output
+        );
+    }
+}
@@ -1,4 +1,8 @@
-use super::{SynthModule, OutputInfo};
+use std::rc::Rc;
+
+use super::{SynthModule, PatchModule, OutputInfo, no_such_input};
+use crate::SynthResult;
+use super::parameter::{Parameters, ParameterInfo, no_such_parameter};
 
 pub struct SampleBuffer {
     samples: Vec<f32>,
@@ -18,3 +22,27 @@ impl SynthModule for SampleBuffer {
         }
     }
 }
+
+impl PatchModule for SampleBuffer {
+    fn get_input_names(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    fn set_input(&mut self, input: usize, _module: Option<Rc<dyn SynthModule>>) -> SynthResult<()> {
+        no_such_input(input)
+    }
+}
+
+impl Parameters for SampleBuffer {
+    fn get_parameter_info(&self) -> Vec<ParameterInfo> {
+        Vec::new()
+    }
+
+    fn get_parameter(&self, _id: &str) -> Option<f32> {
+        None
+    }
+
+    fn set_parameter(&mut self, id: &str, _value: f32) -> SynthResult<()> {
+        no_such_parameter(id)
+    }
+}
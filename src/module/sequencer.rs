@@ -1,13 +1,33 @@
+extern crate rand;
+
 use std::rc::Rc;
-use std::cell::Cell;
+use std::cell::{Cell, RefCell};
+
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
 
-use super::{SynthModule, OutputInfo, EdgeDetection};
+use super::{SynthModule, MultiOutputModule, PatchModule, OutputInfo, EdgeDetection, no_such_input, input_names};
+use super::port::{OutputPort, PortBuffers};
+use super::slew::{Slew, SlewShape, SHAPE_NAMES as SLIDE_SHAPE_NAMES};
 use crate::{SynthError, SynthResult};
+use crate::patch_file::{Table, Value, ModuleSettings};
+use crate::note::Note;
+use super::parameter::{Parameters, ParameterInfo, ParameterScale, clamp_parameter, no_such_parameter, choice_index, choice_value, toggle_value};
+
+const DEFAULT_PATTERN_NAME: &str = "default";
+
+const VALUE_PORT: usize = 0;
+const GATE_PORT: usize = 1;
+// Lanes come after every other port
+const FIRST_LANE_PORT: usize = 2;
 
 const DEFAULT_STEP_INFO: StepInfo = StepInfo {
     kind: SequencerStepKind::Normal,
     value: 0.0_f32,
-    slide: 0.0_f32
+    slide: 0.0_f32,
+    gate_length: 0.5_f32,
+    probability: 1.0_f32,
+    ratchets: 1
 };
 
 #[derive(Copy, Clone, PartialEq, Eq)]
@@ -20,70 +40,351 @@ pub enum SequencerStepKind {
 #[derive(Copy, Clone)]
 pub struct StepInfo {
     pub kind: SequencerStepKind,
+    /// Value output during this step. When the sequencer is driving a pitch this is a pitch signal,
+    /// where C4 is 0.0 and each octave is 1.0
     pub value: f32,
-    pub slide: f32
+    /// Time in milliseconds to glide from the previous step's value to this one. 0 jumps straight there
+    pub slide: f32,
+    /// How much of the step the gate is high for. 0.0 is never and 1.0 is the whole step, which
+    /// ties it to the next step. With ratchets this is how much of each ratchet the gate is high for
+    pub gate_length: f32,
+    /// Chance from 0.0 to 1.0 that the gate fires during this step. The value is output either way
+    pub probability: f32,
+    /// How many times the gate fires during this step. The step is split evenly between them
+    pub ratchets: usize
 }
 
-// TODO: Sequence direction e.g. forward, backward, forward/backward
-pub struct Sequencer {
+/// The order a `Sequencer` moves through its steps in
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum SequencerDirection {
+    /// First step to last step
+    Forward,
+    /// Last step to first step
+    Reverse,
+    /// First step to last step and back again. The end steps aren't repeated
+    Pendulum,
+    /// Jumps to a random step. Never reaches an end so it ignores cycling
+    Random,
+    /// Randomly moves one step forward or back, wrapping around at the ends.
+    /// Never reaches an end so it ignores cycling
+    RandomWalk
+}
+
+const STEP_KIND_NAMES: [(&str, SequencerStepKind); 3] = [
+    ("Normal", SequencerStepKind::Normal),
+    ("Skip", SequencerStepKind::Skip),
+    ("Repeat", SequencerStepKind::Repeat)
+];
+
+const DIRECTION_NAMES: [(&str, SequencerDirection); 5] = [
+    ("Forward", SequencerDirection::Forward),
+    ("Reverse", SequencerDirection::Reverse),
+    ("Pendulum", SequencerDirection::Pendulum),
+    ("Random", SequencerDirection::Random),
+    ("RandomWalk", SequencerDirection::RandomWalk)
+];
+
+const EDGE_DETECTION_NAMES: [(&str, EdgeDetection); 3] = [
+    ("Rising", EdgeDetection::Rising),
+    ("Falling", EdgeDetection::Falling),
+    ("Both", EdgeDetection::Both)
+];
+
+/// A named sequence of steps in a `Sequencer`'s pattern bank
+struct SequencerPattern {
+    name: String,
     steps: Vec<StepInfo>,
+    /// Extra values for each step, indexed by lane then step
+    lanes: Vec<Vec<f32>>
+}
+
+impl SequencerPattern {
+    fn new(name: &str, step_count: usize, lane_count: usize) -> Self {
+        let name = name.to_owned();
+        let steps = vec![DEFAULT_STEP_INFO; step_count];
+        let lanes = vec![vec![0.0; step_count]; lane_count];
+        Self { name, steps, lanes }
+    }
+
+    /// Makes a pattern from its table in a patch file. Each step setting is an array with a value
+    /// for every step. Settings that are left out use the default for every step
+    fn load_settings(settings: &Table) -> SynthResult<Self> {
+        settings.check_keys(&["name", "kinds", "values", "slides", "gate_lengths", "probabilities", "ratchets", "lanes"])?;
+        let name = match settings.get_str("name")? {
+            Some(name) => name,
+            None => return Err(SynthError::new("Sequencer pattern is missing \"name\""))
+        };
+        let as_kind = |value: &Value| {
+            let kind_name = value.as_str()?;
+            STEP_KIND_NAMES.iter().find(|(name, _)| *name == kind_name).map(|(_, kind)| *kind)
+        };
+        let as_ratchets = |value: &Value| value.as_integer().filter(|ratchets| *ratchets > 0).map(|ratchets| ratchets as usize);
+        let as_lane = |value: &Value| value.as_array()?.iter().map(Value::as_float).collect::<Option<Vec<f32>>>();
+
+        let kinds = settings.get_array_of("kinds", "\"Normal\", \"Skip\" or \"Repeat\"", as_kind)?;
+        let values = settings.get_array_of("values", "a number", Value::as_float)?;
+        let slides = settings.get_array_of("slides", "a number", Value::as_float)?;
+        let gate_lengths = settings.get_array_of("gate_lengths", "a number", Value::as_float)?;
+        let probabilities = settings.get_array_of("probabilities", "a number", Value::as_float)?;
+        let ratchets = settings.get_array_of("ratchets", "a whole number of one or more", as_ratchets)?;
+        let lanes = settings.get_array_of("lanes", "an array of numbers", as_lane)?.unwrap_or_default();
+
+        let step_counts: Vec<usize> = [
+            kinds.as_ref().map(Vec::len), values.as_ref().map(Vec::len), slides.as_ref().map(Vec::len),
+            gate_lengths.as_ref().map(Vec::len), probabilities.as_ref().map(Vec::len), ratchets.as_ref().map(Vec::len)
+        ].iter().flatten().copied().chain(lanes.iter().map(Vec::len)).collect();
+        let step_count = step_counts.first().copied().unwrap_or(0);
+        if step_counts.iter().any(|count| *count != step_count) {
+            let msg = format!("Every step setting in sequencer pattern \"{}\" should have the same number of steps", name);
+            return Err(SynthError::new(&msg));
+        }
+
+        let mut pattern = Self::new(name, step_count, 0);
+        let get_step = |values: &Option<Vec<f32>>, step_index: usize, default: f32| {
+            values.as_ref().map(|values| values[step_index]).unwrap_or(default)
+        };
+        for (step_index, step) in pattern.steps.iter_mut().enumerate() {
+            if let Some(kinds) = kinds.as_ref() {
+                step.kind = kinds[step_index];
+            }
+            step.value = get_step(&values, step_index, step.value);
+            step.slide = get_step(&slides, step_index, step.slide);
+            step.gate_length = get_step(&gate_lengths, step_index, step.gate_length);
+            step.probability = get_step(&probabilities, step_index, step.probability);
+            if let Some(ratchets) = ratchets.as_ref() {
+                step.ratchets = ratchets[step_index];
+            }
+        }
+        pattern.lanes = lanes;
+        Ok(pattern)
+    }
+
+    fn save_settings(&self) -> Table {
+        let step_kind_name = |kind: SequencerStepKind| {
+            STEP_KIND_NAMES.iter().find(|(_, named_kind)| *named_kind == kind).unwrap().0
+        };
+        let mut settings = Table::new();
+        settings.set("name", self.name.as_str());
+        settings.set("kinds", self.steps.iter().map(|step| step_kind_name(step.kind)).collect::<Vec<_>>());
+        settings.set("values", self.steps.iter().map(|step| step.value).collect::<Vec<_>>());
+        settings.set("slides", self.steps.iter().map(|step| step.slide).collect::<Vec<_>>());
+        settings.set("gate_lengths", self.steps.iter().map(|step| step.gate_length).collect::<Vec<_>>());
+        settings.set("probabilities", self.steps.iter().map(|step| step.probability).collect::<Vec<_>>());
+        settings.set("ratchets", self.steps.iter().map(|step| step.ratchets).collect::<Vec<_>>());
+        settings.set("lanes", self.lanes.clone());
+        settings
+    }
+}
+
+/// One part of a `Sequencer`'s song
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SongEntry {
+    /// Name of the pattern to play
+    pub pattern: String,
+    /// How many times to play it through before moving on
+    pub repeats: usize
+}
+
+impl SongEntry {
+    pub fn new(pattern: &str, repeats: usize) -> Self {
+        let pattern = pattern.to_owned();
+        Self { pattern, repeats }
+    }
+}
+
+/// Where a sequence goes after a step
+enum NextStep {
+    /// Carry on to this step
+    Step(usize),
+    /// The pattern has played through. It carries on from this step if it's cycling
+    Restart(usize),
+    /// The pattern has played through and it's not cycling
+    End
+}
+
+/// A step sequencer that moves to its next step each time its clock has an edge.
+/// The sequencer itself outputs the step values. It also has output ports for the value, the gate
+/// and each lane of extra values, named "value", "gate", "lane 0", "lane 1" etc.
+///
+/// Steps live in named patterns kept in a bank. Step editing works on whichever pattern is
+/// playing. Pattern changes are queued and happen when the playing pattern gets to its end,
+/// either from `queue_pattern`, the pattern select input or the song when song mode is on.
+pub struct Sequencer {
+    /// Every pattern the sequencer can play. They all have the same number of lanes
+    patterns: Vec<SequencerPattern>,
+    current_pattern: Cell<usize>,
+    queued_pattern: Cell<Option<usize>>,
+    /// Its value is rounded to the index of a pattern to queue whenever it changes
+    pattern_select_in: Option<Rc<dyn SynthModule>>,
+    last_pattern_select: Cell<Option<usize>>,
+
+    song: Vec<SongEntry>,
+    song_mode: bool,
+    song_position: Cell<usize>,
+    // How many times the current song entry has played through
+    song_repeats: Cell<usize>,
+
     playing: Cell<bool>,
     cycle: bool,
+    direction: SequencerDirection,
     current_step: Cell<usize>,
+    // Only used by the pendulum direction
+    ascending: Cell<bool>,
+    // Only used by the random directions, which count steps to decide when a pattern is over
+    steps_this_pass: Cell<usize>,
+    slew: Slew,
 
     clock: Option<Rc<dyn SynthModule>>,
     edge_detection: EdgeDetection,
-    edge_tolerance: f32
+    edge_tolerance: f32,
+    // The last clock sample we read. Kept between blocks so edges right at the start of one
+    // aren't missed, which matters most in feedback loops where blocks are one sample long
+    previous_clock_signal: Cell<Option<f32>>,
+
+    // Used to work out how long gates last
+    samples_since_step: Cell<usize>,
+    step_length: Cell<Option<usize>>,
+    // Whether the current step won its probability roll
+    step_fires: Cell<bool>,
+    rng: RefCell<StdRng>,
+    port_buffers: RefCell<PortBuffers>
 }
 
 impl Sequencer {
     pub fn new() -> Self {
-        let steps = Vec::new();
-        let playing = Cell::new(false);
-        let cycle = true;
-        let current_step = Cell::new(0_usize);
-
-        let clock = None;
-        let edge_detection = EdgeDetection::Falling;
-        let edge_tolerance = 0.8_f32;
-        Self { steps, playing, cycle, current_step, clock, edge_detection, edge_tolerance }
+        Self::with_steps(0)
     }
 
     pub fn with_steps(step_count: usize) -> Self {
-        let steps = vec![DEFAULT_STEP_INFO; step_count];
+        let patterns = vec![SequencerPattern::new(DEFAULT_PATTERN_NAME, step_count, 0)];
+        let current_pattern = Cell::new(0);
+        let queued_pattern = Cell::new(None);
+        let pattern_select_in = None;
+        let last_pattern_select = Cell::new(None);
+
+        let song = Vec::new();
+        let song_mode = false;
+        let song_position = Cell::new(0);
+        let song_repeats = Cell::new(0);
 
         let playing = Cell::new(false);
         let cycle = true;
+        let direction = SequencerDirection::Forward;
         let current_step = Cell::new(0_usize);
+        let ascending = Cell::new(true);
+        let steps_this_pass = Cell::new(0);
+        let slew = Slew::with_shape(SlewShape::Exponential);
 
         let clock = None;
         let edge_detection = EdgeDetection::Falling;
         let edge_tolerance = 0.8_f32;
-        Self { steps, playing, cycle, current_step, clock, edge_detection, edge_tolerance }
+        let previous_clock_signal = Cell::new(None);
+
+        let samples_since_step = Cell::new(0);
+        let step_length = Cell::new(None);
+        let step_fires = Cell::new(true);
+        let rng = RefCell::new(StdRng::from_entropy());
+        let port_buffers = RefCell::new(PortBuffers::new());
+        Self {
+            patterns, current_pattern, queued_pattern, pattern_select_in, last_pattern_select,
+            song, song_mode, song_position, song_repeats,
+            playing, cycle, direction, current_step, ascending, steps_this_pass, slew,
+            clock, edge_detection, edge_tolerance, previous_clock_signal,
+            samples_since_step, step_length, step_fires, rng, port_buffers
+        }
+    }
+
+    /// Makes a sequencer from its settings in a patch file. Every pattern in "patterns" needs
+    /// a unique name and they all need the same number of lanes
+    pub fn load_settings(settings: &Table) -> SynthResult<Self> {
+        settings.check_keys(&[
+            "direction", "cycle", "edge_detection", "edge_tolerance", "slide_shape",
+            "patterns", "current_pattern", "song", "song_mode"
+        ])?;
+        let mut sequencer = Self::new();
+        if let Some(pattern_tables) = settings.get_array_of("patterns", "a table", Value::as_table)? {
+            let mut patterns: Vec<SequencerPattern> = Vec::with_capacity(pattern_tables.len());
+            for pattern_table in pattern_tables {
+                let pattern = SequencerPattern::load_settings(pattern_table)?;
+                if patterns.iter().any(|other| other.name == pattern.name) {
+                    let msg = format!("Sequencer already has a pattern named \"{}\"", pattern.name);
+                    return Err(SynthError::new(&msg));
+                }
+                if patterns.first().filter(|first| first.lanes.len() != pattern.lanes.len()).is_some() {
+                    let msg = format!("Sequencer pattern \"{}\" has a different number of lanes to the others", pattern.name);
+                    return Err(SynthError::new(&msg));
+                }
+                patterns.push(pattern);
+            }
+            if patterns.is_empty() {
+                return Err(SynthError::new("Sequencer needs at least one pattern"));
+            }
+            sequencer.patterns = patterns;
+            sequencer.switch_pattern(0);
+        }
+        if let Some(current_pattern) = settings.get_str("current_pattern")? {
+            sequencer.select_pattern(current_pattern)?;
+        }
+        if let Some(direction) = settings.get_choice("direction", &DIRECTION_NAMES)? {
+            sequencer.set_direction(direction);
+        }
+        if let Some(cycle) = settings.get_bool("cycle")? {
+            sequencer.set_cycle(cycle);
+        }
+        if let Some(edge_detection) = settings.get_choice("edge_detection", &EDGE_DETECTION_NAMES)? {
+            sequencer.set_edge_detection(edge_detection);
+        }
+        if let Some(edge_tolerance) = settings.get_float("edge_tolerance")? {
+            sequencer.set_edge_tolerance(edge_tolerance);
+        }
+        if let Some(slide_shape) = settings.get_choice("slide_shape", &SLIDE_SHAPE_NAMES)? {
+            sequencer.set_slide_shape(slide_shape);
+        }
+        if let Some(entry_tables) = settings.get_array_of("song", "a table", Value::as_table)? {
+            let mut song = Vec::with_capacity(entry_tables.len());
+            for entry_table in entry_tables {
+                entry_table.check_keys(&["pattern", "repeats"])?;
+                let pattern = match entry_table.get_str("pattern")? {
+                    Some(pattern) => pattern,
+                    None => return Err(SynthError::new("Song entry is missing \"pattern\""))
+                };
+                let repeats = entry_table.get_usize("repeats")?.unwrap_or(1);
+                song.push(SongEntry::new(pattern, repeats));
+            }
+            sequencer.set_song(song)?;
+        }
+        if let Some(song_mode) = settings.get_bool("song_mode")? {
+            sequencer.set_song_mode(song_mode);
+        }
+        Ok(sequencer)
     }
 
     pub fn add_step(&mut self) {
-        self.steps.push(DEFAULT_STEP_INFO);
+        self.add_step_with_info(&DEFAULT_STEP_INFO);
     }
 
     pub fn add_step_with_info(&mut self, info: &StepInfo) {
-        self.steps.push(*info);
+        let pattern = self.get_pattern_mut();
+        pattern.steps.push(*info);
+        for lane in pattern.lanes.iter_mut() {
+            lane.push(0.0);
+        }
     }
 
     pub fn get_step_info(&self, step_index: usize) -> Option<&StepInfo> {
-        self.steps.get(step_index)
+        self.get_pattern().steps.get(step_index)
     }
 
     pub fn get_step_info_mut(&mut self, step_index: usize) -> Option<&mut StepInfo> {
-        self.steps.get_mut(step_index)
+        self.get_pattern_mut().steps.get_mut(step_index)
     }
 
     pub fn get_current_step_info(&self) -> Option<&StepInfo> {
-        self.steps.get(self.current_step.get())
+        self.get_pattern().steps.get(self.current_step.get())
     }
 
     pub fn set_step_info(&mut self, step_index: usize, step_info: &StepInfo) -> SynthResult<()> {
-        match self.steps.get_mut(step_index) {
+        match self.get_pattern_mut().steps.get_mut(step_index) {
             Some(step) => *step = *step_info,
             None => {
                 let msg = "Failed to set sequencer step info because index was out of bounds";
@@ -93,18 +394,286 @@ impl Sequencer {
         Ok(())
     }
 
+    /// Sets a step's value to the pitch of a note
+    pub fn set_step_note(&mut self, step_index: usize, note: &Note) -> SynthResult<()> {
+        match self.get_pattern_mut().steps.get_mut(step_index) {
+            Some(step) => step.value = note.to_pitch(),
+            None => {
+                let msg = "Failed to set sequencer step note because index was out of bounds";
+                return Err(SynthError::new(msg));
+            }
+        }
+        Ok(())
+    }
+
     pub fn remove_step(&mut self, step_index: usize) -> SynthResult<()> {
-        if step_index > self.steps.len() {
+        let pattern = self.get_pattern_mut();
+        if step_index >= pattern.steps.len() {
             let msg = "Failed to remove sequencer step because index is out of bounds";
             return Err(SynthError::new(msg));
         }
 
-        self.steps.remove(step_index);
+        pattern.steps.remove(step_index);
+        for lane in pattern.lanes.iter_mut() {
+            lane.remove(step_index);
+        }
+
+        // Keep the current step in the pattern if it was the one at the end
+        let step_count = self.len();
+        if self.current_step.get() >= step_count {
+            self.current_step.set(step_count.saturating_sub(1));
+        }
+        Ok(())
+    }
+
+    /// Adds a lane of extra values to every pattern with one value for every step, all starting at 0.0.
+    /// Returns the index of the new lane
+    pub fn add_lane(&mut self) -> usize {
+        for pattern in self.patterns.iter_mut() {
+            pattern.lanes.push(vec![0.0; pattern.steps.len()]);
+        }
+        self.lane_count() - 1
+    }
+
+    pub fn remove_lane(&mut self, lane_index: usize) -> SynthResult<()> {
+        if lane_index >= self.lane_count() {
+            let msg = "Failed to remove sequencer lane because index is out of bounds";
+            return Err(SynthError::new(msg));
+        }
+        for pattern in self.patterns.iter_mut() {
+            pattern.lanes.remove(lane_index);
+        }
+        Ok(())
+    }
+
+    pub fn lane_count(&self) -> usize {
+        self.get_pattern().lanes.len()
+    }
+
+    pub fn set_lane_value(&mut self, lane_index: usize, step_index: usize, value: f32) -> SynthResult<()> {
+        match self.get_pattern_mut().lanes.get_mut(lane_index).and_then(|lane| lane.get_mut(step_index)) {
+            Some(lane_value) => *lane_value = value,
+            None => {
+                let msg = "Failed to set sequencer lane value because index was out of bounds";
+                return Err(SynthError::new(msg));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get_lane_value(&self, lane_index: usize, step_index: usize) -> Option<f32> {
+        self.get_pattern().lanes.get(lane_index).and_then(|lane| lane.get(step_index)).copied()
+    }
+
+    /// Adds an empty pattern to the bank. Pattern names have to be unique
+    pub fn add_pattern(&mut self, name: &str) -> SynthResult<()> {
+        self.add_pattern_with_steps(name, 0)
+    }
+
+    pub fn add_pattern_with_steps(&mut self, name: &str, step_count: usize) -> SynthResult<()> {
+        if self.get_pattern_index(name).is_some() {
+            let msg = format!("Sequencer already has a pattern named \"{}\"", name);
+            return Err(SynthError::new(&msg));
+        }
+        let lane_count = self.lane_count();
+        self.patterns.push(SequencerPattern::new(name, step_count, lane_count));
+        Ok(())
+    }
+
+    /// Removes a pattern from the bank. The last pattern and patterns used by the song can't be removed
+    pub fn remove_pattern(&mut self, name: &str) -> SynthResult<()> {
+        let pattern_index = self.find_pattern(name)?;
+        if self.patterns.len() == 1 {
+            let msg = "Can't remove the only pattern in a sequencer";
+            return Err(SynthError::new(msg));
+        }
+        if self.song.iter().any(|entry| entry.pattern == name) {
+            let msg = format!("Can't remove pattern \"{}\" because the song uses it", name);
+            return Err(SynthError::new(&msg));
+        }
+
+        self.patterns.remove(pattern_index);
+        let current_pattern = self.current_pattern.get();
+        if current_pattern == pattern_index {
+            self.switch_pattern(0);
+        }
+        else if current_pattern > pattern_index {
+            self.current_pattern.set(current_pattern - 1);
+        }
+        self.queued_pattern.set(match self.queued_pattern.get() {
+            Some(queued_pattern) if queued_pattern == pattern_index => None,
+            Some(queued_pattern) if queued_pattern > pattern_index => Some(queued_pattern - 1),
+            queued_pattern => queued_pattern
+        });
+        Ok(())
+    }
+
+    pub fn get_pattern_index(&self, name: &str) -> Option<usize> {
+        self.patterns.iter().position(|pattern| pattern.name == name)
+    }
+
+    pub fn get_pattern_names(&self) -> Vec<&str> {
+        self.patterns.iter().map(|pattern| pattern.name.as_str()).collect()
+    }
+
+    pub fn pattern_count(&self) -> usize {
+        self.patterns.len()
+    }
+
+    pub fn get_current_pattern_name(&self) -> &str {
+        &self.get_pattern().name
+    }
+
+    /// Switches to a pattern right away, starting it from the beginning. This is also how
+    /// to pick which pattern gets edited
+    pub fn select_pattern(&mut self, name: &str) -> SynthResult<()> {
+        let pattern_index = self.find_pattern(name)?;
+        self.switch_pattern(pattern_index);
+        self.invalidate_block();
         Ok(())
     }
 
+    /// Switches to a pattern once the one that's playing gets to its end
+    pub fn queue_pattern(&self, name: &str) -> SynthResult<()> {
+        let pattern_index = self.find_pattern(name)?;
+        self.queued_pattern.set(Some(pattern_index));
+        Ok(())
+    }
+
+    pub fn cancel_queued_pattern(&self) {
+        self.queued_pattern.set(None);
+    }
+
+    pub fn get_queued_pattern_name(&self) -> Option<&str> {
+        self.queued_pattern.get().map(|pattern_index| self.patterns[pattern_index].name.as_str())
+    }
+
+    /// Sets the pattern select input. When its value changes it's rounded to the index of
+    /// a pattern, which gets queued. Values past the end of the bank select the last pattern
+    pub fn set_pattern_select_in(&mut self, pattern_select_in: Option<Rc<dyn SynthModule>>) {
+        self.pattern_select_in = pattern_select_in;
+        self.last_pattern_select.set(None);
+    }
+
+    /// Sets the patterns that get chained together in song mode. Every entry has to name
+    /// a pattern in the bank and play at least once
+    pub fn set_song(&mut self, song: Vec<SongEntry>) -> SynthResult<()> {
+        for entry in song.iter() {
+            self.find_pattern(&entry.pattern)?;
+            if entry.repeats == 0 {
+                let msg = format!("Song entry for pattern \"{}\" has to repeat at least once", entry.pattern);
+                return Err(SynthError::new(&msg));
+            }
+        }
+        self.song = song;
+        if self.song_mode {
+            self.restart_song();
+        }
+        Ok(())
+    }
+
+    pub fn get_song(&self) -> &[SongEntry] {
+        &self.song
+    }
+
+    /// Turns song mode on or off. Turning it on starts the song from the beginning
+    pub fn set_song_mode(&mut self, song_mode: bool) {
+        self.song_mode = song_mode;
+        if song_mode {
+            self.restart_song();
+        }
+    }
+
+    pub fn get_song_mode(&self) -> bool {
+        self.song_mode
+    }
+
+    fn restart_song(&mut self) {
+        self.song_position.set(0);
+        self.song_repeats.set(0);
+        if let Some(pattern_index) = self.song.first().and_then(|entry| self.get_pattern_index(&entry.pattern)) {
+            self.switch_pattern(pattern_index);
+        }
+        self.invalidate_block();
+    }
+
+    fn get_pattern(&self) -> &SequencerPattern {
+        &self.patterns[self.current_pattern.get()]
+    }
+
+    fn get_pattern_mut(&mut self) -> &mut SequencerPattern {
+        &mut self.patterns[self.current_pattern.get()]
+    }
+
+    fn find_pattern(&self, name: &str) -> SynthResult<usize> {
+        match self.get_pattern_index(name) {
+            Some(pattern_index) => Ok(pattern_index),
+            None => {
+                let msg = format!("Sequencer has no pattern named \"{}\"", name);
+                Err(SynthError::new(&msg))
+            }
+        }
+    }
+
+    /// Starts playing a pattern from its first step
+    fn switch_pattern(&self, pattern_index: usize) {
+        self.current_pattern.set(pattern_index);
+        self.ascending.set(true);
+        self.steps_this_pass.set(0);
+        self.current_step.set(self.get_first_step());
+    }
+
+    /// Works out which pattern plays after the current one has played through.
+    /// Returns `None` if nothing is queued and the song doesn't move on
+    fn get_next_pattern(&self) -> Option<usize> {
+        if let Some(queued_pattern) = self.queued_pattern.take() {
+            return Some(queued_pattern);
+        }
+        if !self.song_mode || self.song.is_empty() {
+            return None;
+        }
+
+        let mut song_position = self.song_position.get().min(self.song.len() - 1);
+        let song_repeats = self.song_repeats.get() + 1;
+        if song_repeats < self.song[song_position].repeats {
+            self.song_repeats.set(song_repeats);
+        }
+        else {
+            song_position += 1;
+            if song_position == self.song.len() {
+                if !self.cycle {
+                    // The song is over
+                    return None;
+                }
+                song_position = 0;
+            }
+            self.song_position.set(song_position);
+            self.song_repeats.set(0);
+        }
+        self.get_pattern_index(&self.song[song_position].pattern)
+    }
+
+    /// Seeds the random number generator used for step probabilities and the random directions
+    /// so the sequence comes out the same every time
+    pub fn set_seed(&mut self, seed: u64) {
+        *self.rng.get_mut() = StdRng::seed_from_u64(seed);
+    }
+
     pub fn increment_step(&self, force: bool) {
+        self.samples_since_step.set(0);
+        self.invalidate_block();
         self.increment_step_body(force, true);
+        self.roll_step_probability();
+    }
+
+    /// Decides whether the gate will fire for the step we just moved to
+    fn roll_step_probability(&self) {
+        let probability = match self.get_current_step_info() {
+            Some(step_info) => step_info.probability,
+            None => 1.0
+        };
+        let step_fires = probability >= 1.0 || self.rng.borrow_mut().gen::<f32>() < probability;
+        self.step_fires.set(step_fires);
     }
 
     // This is the recursive component of increment_step. it has the additional
@@ -112,34 +681,54 @@ impl Sequencer {
     // case where all steps are skip so we don't have to iterate every step
     // every time there's a skip step.
     fn increment_step_body(&self, force: bool, needs_skip_check: bool) {
-        let sequence_length = self.steps.len();
-        if sequence_length == 0 {
-            // There are no steps, bail
-            return;
-        }
-        if !force && !self.cycle && self.current_step.get() == sequence_length - 1 {
-            // We're on the last step and we're not cycling and it's not being forced, do nothing
+        if self.get_pattern().steps.is_empty() {
+            // There are no steps. Nothing to do unless there's another pattern to go to
+            if let Some(next_pattern) = self.get_next_pattern() {
+                self.switch_pattern(next_pattern);
+            }
             return;
         }
 
         // Set us to the next step
-        if self.steps[self.current_step.get()].kind == SequencerStepKind::Repeat {
-            self.current_step.set(0);
-        } else {
-            self.current_step.set(self.current_step.get() + 1);
-            if self.current_step.get() % sequence_length == 0 {
-                if !self.cycle {
-                    self.playing.set(false);
-                    self.current_step.set(self.current_step.get() - 1);
-                }
-                else {
-                    self.current_step.set(0); // return to 0 even if not cycle
+        let next_step = if self.get_current_step_info().is_some_and(|step| step.kind == SequencerStepKind::Repeat) {
+            self.ascending.set(true);
+            NextStep::Restart(self.get_first_step())
+        }
+        else {
+            self.get_next_step()
+        };
+        let mut needs_skip_check = needs_skip_check;
+        match next_step {
+            NextStep::Step(next_step) => {
+                self.current_step.set(next_step);
+                self.steps_this_pass.set(self.steps_this_pass.get() + 1);
+            },
+            NextStep::Restart(_) | NextStep::End => {
+                self.steps_this_pass.set(0);
+                match (self.get_next_pattern(), next_step) {
+                    (Some(next_pattern), NextStep::Restart(next_step)) if next_pattern == self.current_pattern.get() => {
+                        // Playing the same pattern again so carry on like it's cycling
+                        self.current_step.set(next_step);
+                    },
+                    (Some(next_pattern), _) => {
+                        self.switch_pattern(next_pattern);
+                        // The new pattern could be all skip steps
+                        needs_skip_check = true;
+                    },
+                    (None, NextStep::Restart(next_step)) => self.current_step.set(next_step),
+                    (None, _) => {
+                        // We're at the end and we're not cycling. Only stop if it's being forced
+                        if force {
+                            self.playing.set(false);
+                        }
+                        return;
+                    }
                 }
             }
         }
 
         // Check if the step we're on now is a skipped step. If it is, recurse
-        if self.steps[self.current_step.get()].kind == SequencerStepKind::Skip {
+        if self.get_current_step_info().is_some_and(|step| step.kind == SequencerStepKind::Skip) {
             // If every step is skip just stop
             if needs_skip_check && self.all_steps_skip() {
                 self.current_step.set(0);
@@ -149,8 +738,99 @@ impl Sequencer {
         }
     }
 
+    /// Gets the step the sequence starts on in the current direction
+    fn get_first_step(&self) -> usize {
+        match self.direction {
+            SequencerDirection::Reverse => self.get_pattern().steps.len().saturating_sub(1),
+            _ => 0
+        }
+    }
+
+    /// Gets the step after the current one in the current direction
+    fn get_next_step(&self) -> NextStep {
+        let sequence_length = self.get_pattern().steps.len();
+        let last_step = sequence_length - 1;
+        let current_step = self.current_step.get();
+        match self.direction {
+            SequencerDirection::Forward => {
+                if current_step < last_step {
+                    NextStep::Step(current_step + 1)
+                }
+                else if self.cycle {
+                    NextStep::Restart(0)
+                }
+                else {
+                    NextStep::End
+                }
+            },
+            SequencerDirection::Reverse => {
+                if current_step > 0 {
+                    NextStep::Step(current_step - 1)
+                }
+                else if self.cycle {
+                    NextStep::Restart(last_step)
+                }
+                else {
+                    NextStep::End
+                }
+            },
+            SequencerDirection::Pendulum => {
+                if self.ascending.get() {
+                    if current_step < last_step {
+                        NextStep::Step(current_step + 1)
+                    }
+                    else {
+                        // Turn around at the last step
+                        self.ascending.set(false);
+                        NextStep::Step(current_step.saturating_sub(1))
+                    }
+                }
+                else if current_step > 0 {
+                    NextStep::Step(current_step - 1)
+                }
+                else if self.cycle {
+                    // Turn around at the first step
+                    self.ascending.set(true);
+                    NextStep::Restart(last_step.min(1))
+                }
+                else {
+                    NextStep::End
+                }
+            },
+            SequencerDirection::Random | SequencerDirection::RandomWalk => {
+                let next_step = if self.direction == SequencerDirection::Random {
+                    // Pick from steps that won't be skipped so we don't have to go looking again
+                    let playable_steps: Vec<usize> = self.get_pattern().steps.iter().enumerate()
+                        .filter(|(_, step)| step.kind != SequencerStepKind::Skip)
+                        .map(|(step_index, _)| step_index)
+                        .collect();
+                    if playable_steps.is_empty() {
+                        0
+                    }
+                    else {
+                        playable_steps[self.rng.borrow_mut().gen_range(0, playable_steps.len())]
+                    }
+                }
+                else if self.rng.borrow_mut().gen::<bool>() {
+                    (current_step + 1) % sequence_length
+                }
+                else {
+                    (current_step + last_step) % sequence_length
+                };
+
+                // There's no end to reach so the pattern is over once it's played as many steps as it has
+                if self.steps_this_pass.get() + 1 < sequence_length {
+                    NextStep::Step(next_step)
+                }
+                else {
+                    NextStep::Restart(next_step)
+                }
+            }
+        }
+    }
+
     fn all_steps_skip(&self) -> bool {
-        for step in self.steps.iter() {
+        for step in self.get_pattern().steps.iter() {
             if step.kind != SequencerStepKind::Skip {
                 return false;
             }
@@ -160,10 +840,50 @@ impl Sequencer {
 
     pub fn start(&self) {
         self.playing.set(true);
+        self.invalidate_block();
     }
 
     pub fn stop(&self) {
         self.playing.set(false);
+        self.invalidate_block();
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing.get()
+    }
+
+    pub fn set_cycle(&mut self, cycle: bool) {
+        self.cycle = cycle;
+    }
+
+    pub fn get_cycle(&self) -> bool {
+        self.cycle
+    }
+
+    pub fn set_direction(&mut self, direction: SequencerDirection) {
+        self.direction = direction;
+        self.ascending.set(true);
+    }
+
+    pub fn get_direction(&self) -> SequencerDirection {
+        self.direction
+    }
+
+    /// Gets the port for this sequencer's gate. The gate goes high at the start of
+    /// each step and stays high for the step's gate length. How long a step is can't be
+    /// known ahead of time so it's taken from the time between the last two clock edges.
+    /// Until there have been two edges the gate stays high for the whole step.
+    pub fn gate_output(sequencer: &Rc<Self>) -> OutputPort {
+        OutputPort::with_index(sequencer.clone(), GATE_PORT).expect("Sequencer should always have a gate port")
+    }
+
+    /// Gets the port for one of this sequencer's lanes of extra values
+    pub fn lane_output(sequencer: &Rc<Self>, lane_index: usize) -> SynthResult<OutputPort> {
+        if lane_index >= sequencer.lane_count() {
+            let msg = format!("Sequencer has no lane {}", lane_index);
+            return Err(SynthError::new(&msg));
+        }
+        OutputPort::with_index(sequencer.clone(), FIRST_LANE_PORT + lane_index)
     }
 
     pub fn set_clock(&mut self, clock: Option<Rc<dyn SynthModule>>) {
@@ -186,84 +906,244 @@ impl Sequencer {
         self.edge_tolerance
     }
 
+    /// Sets how the output moves between steps that have a slide
+    pub fn set_slide_shape(&mut self, slide_shape: SlewShape) {
+        self.slew.set_shape(slide_shape);
+    }
+
+    pub fn get_slide_shape(&self) -> SlewShape {
+        self.slew.get_shape()
+    }
+
     pub fn iter(&self) -> std::slice::Iter<StepInfo> {
-        self.steps.iter()
+        self.get_pattern().steps.iter()
     }
 
     pub fn iter_mut(&mut self) -> std::slice::IterMut<StepInfo> {
-        self.steps.iter_mut()
+        self.get_pattern_mut().steps.iter_mut()
     }
 
-    pub fn into_iter(self) -> std::vec::IntoIter<StepInfo> {
-        self.steps.into_iter()
+    pub fn into_iter(mut self) -> std::vec::IntoIter<StepInfo> {
+        let current_pattern = self.current_pattern.get();
+        self.patterns.swap_remove(current_pattern).steps.into_iter()
     }
 
     pub fn len(&self) -> usize {
-        self.steps.len()
+        self.get_pattern().steps.len()
     }
 
     pub fn is_empty(&self) -> bool {
-        self.steps.len() == 0
+        self.get_pattern().steps.len() == 0
     }
-}
 
-impl SynthModule for Sequencer {
-    fn fill_output_buffer(&self, data: &mut [f32], output_info: &OutputInfo) {
-        let data_size = data.len();
-
-        // Closure to fill the actual data buffer
-        // TODO: slide
-        let fill_sequencer_buffer = |sequencer: &Self, data: &mut [f32], start: usize, stop: usize| {
-            let (step_value, _step_slide) = match sequencer.get_current_step_info() {
-                Some(step_info) => (step_info.value, step_info.slide),
-                None => (0_f32, 0_f32)
-            };
-
-            if stop > data_size {
-                // TODO: remove this when I know this is safe
-                panic!("Went out of bounds filling sequencer buffer... Probably off-by-one");
-            }
-            let sub_data = &mut data[start..stop]; // It's quite important that `stop` is < `data_size`
-            for datum in sub_data.iter_mut() {
-                *datum = step_value;
-            }
+    fn select_pattern_from_signal(&self, pattern_select: f32) {
+        let pattern_index = (pattern_select.round().max(0.0) as usize).min(self.patterns.len() - 1);
+        if self.last_pattern_select.get() == Some(pattern_index) {
+            return;
+        }
+        self.last_pattern_select.set(Some(pattern_index));
+        if pattern_index == self.current_pattern.get() {
+            self.queued_pattern.set(None);
+        }
+        else {
+            self.queued_pattern.set(Some(pattern_index));
+        }
+    }
+
+    fn invalidate_block(&self) {
+        self.port_buffers.borrow_mut().invalidate();
+    }
+
+    fn needs_step(&self, previous_clock_signal: f32, current_clock_signal: f32) -> bool {
+        match self.edge_detection {
+            EdgeDetection::Both => 
+                f32::abs(previous_clock_signal - current_clock_signal) > self.edge_tolerance,
+            EdgeDetection::Falling => 
+                current_clock_signal < previous_clock_signal - self.edge_tolerance,
+            EdgeDetection::Rising =>
+                current_clock_signal > previous_clock_signal + self.edge_tolerance
+        }
+    }
+
+    fn get_gate(&self, step_info: &StepInfo) -> f32 {
+        if !self.step_fires.get() {
+            return 0.0;
+        }
+        let gate_high = match self.step_length.get() {
+            Some(step_length) => {
+                // The step may run longer than the last one did. Ratchets don't start again if it does
+                let ratchets = step_info.ratchets.max(1);
+                let ratchet_length = step_length as f32 / ratchets as f32;
+                let samples_since_step = self.samples_since_step.get() as f32;
+                let ratchet_index = (samples_since_step / ratchet_length).floor().min(ratchets as f32 - 1.0);
+                let ratchet_position = samples_since_step - ratchet_index * ratchet_length;
+                ratchet_position < ratchet_length * step_info.gate_length
+            },
+            None => step_info.gate_length > 0.0
         };
+        if gate_high { 1.0 } else { 0.0 }
+    }
+
+    /// Renders every output for a block if they haven't been already
+    fn render_block(&self, block_size: usize, output_info: &OutputInfo) {
+        let mut port_buffers = self.port_buffers.borrow_mut();
+        if port_buffers.is_current(block_size, output_info) {
+            return;
+        }
+        let buffers = port_buffers.start_block(FIRST_LANE_PORT + self.lane_count(), block_size, output_info);
+        let (buffers, lane_buffers) = buffers.split_at_mut(FIRST_LANE_PORT);
+
+        let sample_rate = output_info.sample_rate;
+        let playing = self.playing.get();
 
-        if self.playing.get() {
-            // We are playing which means which step we are on is subject to change
-            let mut clock_signals = Vec::with_capacity(data_size);
-            clock_signals.resize(data_size, 0_f32);
+        // Which step we are on can only change while we're playing
+        let mut clock_signals = vec![0_f32; block_size];
+        if playing {
             if let Some(clock) = &self.clock {
                 clock.fill_output_buffer(&mut clock_signals, output_info);
             }
+        }
+        let mut pattern_select = vec![0_f32; block_size];
+        if let Some(pattern_select_in) = &self.pattern_select_in {
+            pattern_select_in.fill_output_buffer(&mut pattern_select, output_info);
+        }
 
-            let mut data_filled = 0_usize;
-            for i in 1..data_size {
-                // Step the sequence
-                let previous_clock_signal = clock_signals[i - 1];
-                let current_clock_signal = clock_signals[i];
-                let needs_step = match self.edge_detection {
-                    EdgeDetection::Both => 
-                        f32::abs(previous_clock_signal - current_clock_signal) > self.edge_tolerance,
-                    EdgeDetection::Falling => 
-                        current_clock_signal < previous_clock_signal - self.edge_tolerance,
-                    EdgeDetection::Rising =>
-                        current_clock_signal > previous_clock_signal + self.edge_tolerance
-                };
-                if needs_step {
-                    // Fill what we've passed by with the previous step
-                    fill_sequencer_buffer(self, data, data_filled, i);
-                    data_filled = i;
-                    // Do the increment
-                    self.increment_step(false);
+        for i in 0..block_size {
+            if self.pattern_select_in.is_some() {
+                self.select_pattern_from_signal(pattern_select[i]);
+            }
+
+            // Step the sequence
+            if playing {
+                let previous_clock_signal = self.previous_clock_signal.replace(Some(clock_signals[i]));
+                if previous_clock_signal.is_some_and(|previous| self.needs_step(previous, clock_signals[i])) {
+                    self.step_length.set(Some(self.samples_since_step.get()));
+                    self.samples_since_step.set(0);
+                    self.increment_step_body(false, true);
+                    self.roll_step_probability();
+                }
+            }
+            else {
+                // The clock isn't read while stopped so there's nothing to compare against when we start again
+                self.previous_clock_signal.set(None);
+            }
+
+            let current_step = self.current_step.get();
+            match self.get_current_step_info() {
+                Some(step_info) => {
+                    buffers[VALUE_PORT][i] = self.slew.next_value(step_info.value, step_info.slide, step_info.slide, sample_rate);
+                    buffers[GATE_PORT][i] = if playing { self.get_gate(step_info) } else { 0.0 };
+                },
+                None => {
+                    buffers[VALUE_PORT][i] = self.slew.next_value(0.0, 0.0, 0.0, sample_rate);
                 }
             }
-            fill_sequencer_buffer(self, data, data_filled, data_size);
+            for (lane_buffer, lane) in lane_buffers.iter_mut().zip(self.get_pattern().lanes.iter()) {
+                lane_buffer[i] = lane.get(current_step).copied().unwrap_or(0.0);
+            }
+
+            if playing {
+                self.samples_since_step.set(self.samples_since_step.get() + 1);
+            }
         }
-        else {
-            // We are not playing which means whichever step we're on will fill the whole buffer
-            fill_sequencer_buffer(self, data, 0, data_size);
+    }
+}
+
+impl SynthModule for Sequencer {
+    fn fill_output_buffer(&self, data: &mut [f32], output_info: &OutputInfo) {
+        self.fill_port_buffer(VALUE_PORT, data, output_info);
+    }
+}
+
+impl MultiOutputModule for Sequencer {
+    fn get_output_names(&self) -> Vec<String> {
+        let mut output_names = vec![String::from("value"), String::from("gate")];
+        output_names.extend((0..self.lane_count()).map(|lane_index| format!("lane {}", lane_index)));
+        output_names
+    }
+
+    fn fill_port_buffer(&self, port: usize, buffer: &mut [f32], output_info: &OutputInfo) {
+        self.render_block(buffer.len(), output_info);
+        self.port_buffers.borrow().copy_port(port, buffer);
+    }
+}
+
+impl PatchModule for Sequencer {
+    fn get_input_names(&self) -> Vec<String> {
+        input_names(&["clock", "pattern select"])
+    }
+
+    fn set_input(&mut self, input: usize, module: Option<Rc<dyn SynthModule>>) -> SynthResult<()> {
+        match input {
+            0 => self.set_clock(module),
+            1 => self.set_pattern_select_in(module),
+            _ => return no_such_input(input)
+        }
+        Ok(())
+    }
+
+    fn as_multi_output(&self) -> Option<&dyn MultiOutputModule> {
+        Some(self)
+    }
+
+    fn save_settings(&self) -> Option<ModuleSettings> {
+        let mut settings = Table::new();
+        settings.set_choice("direction", self.direction, &DIRECTION_NAMES);
+        settings.set("cycle", self.cycle);
+        settings.set_choice("edge_detection", self.edge_detection, &EDGE_DETECTION_NAMES);
+        settings.set("edge_tolerance", self.edge_tolerance);
+        settings.set_choice("slide_shape", self.get_slide_shape(), &SLIDE_SHAPE_NAMES);
+        settings.set("patterns", self.patterns.iter().map(SequencerPattern::save_settings).collect::<Vec<_>>());
+        settings.set("current_pattern", self.get_current_pattern_name());
+        let song: Vec<Table> = self.song.iter().map(|entry| {
+            let mut entry_table = Table::new();
+            entry_table.set("pattern", entry.pattern.as_str());
+            entry_table.set("repeats", entry.repeats);
+            entry_table
+        }).collect();
+        settings.set("song", song);
+        settings.set("song_mode", self.song_mode);
+        Some(ModuleSettings::new("Sequencer", settings))
+    }
+}
+
+/// Steps, lanes, patterns and the song aren't parameters
+impl Parameters for Sequencer {
+    fn get_parameter_info(&self) -> Vec<ParameterInfo> {
+        vec![
+            ParameterInfo::choice("direction", "Direction", &DIRECTION_NAMES, SequencerDirection::Forward),
+            ParameterInfo::toggle("cycle", "Cycle", true),
+            ParameterInfo::choice("edge_detection", "Edge Detection", &EDGE_DETECTION_NAMES, EdgeDetection::Falling),
+            ParameterInfo::new("edge_tolerance", "Edge Tolerance", "", 0.0, 1.0, 0.8, ParameterScale::Linear),
+            ParameterInfo::choice("slide_shape", "Slide Shape", &SLIDE_SHAPE_NAMES, SlewShape::Exponential),
+            ParameterInfo::toggle("song_mode", "Song Mode", false)
+        ]
+    }
+
+    fn get_parameter(&self, id: &str) -> Option<f32> {
+        match id {
+            "direction" => Some(choice_index(&DIRECTION_NAMES, self.direction)),
+            "cycle" => Some(toggle_value(self.cycle)),
+            "edge_detection" => Some(choice_index(&EDGE_DETECTION_NAMES, self.edge_detection)),
+            "edge_tolerance" => Some(self.edge_tolerance),
+            "slide_shape" => Some(choice_index(&SLIDE_SHAPE_NAMES, self.get_slide_shape())),
+            "song_mode" => Some(toggle_value(self.song_mode)),
+            _ => None
+        }
+    }
+
+    fn set_parameter(&mut self, id: &str, value: f32) -> SynthResult<()> {
+        let value = clamp_parameter(self, id, value)?;
+        match id {
+            "direction" => self.set_direction(choice_value(&DIRECTION_NAMES, value)),
+            "cycle" => self.set_cycle(value > 0.0),
+            "edge_detection" => self.set_edge_detection(choice_value(&EDGE_DETECTION_NAMES, value)),
+            "edge_tolerance" => self.set_edge_tolerance(value),
+            "slide_shape" => self.set_slide_shape(choice_value(&SLIDE_SHAPE_NAMES, value)),
+            "song_mode" => self.set_song_mode(value > 0.0),
+            _ => return no_such_parameter(id)
         }
+        Ok(())
     }
 }
 
@@ -271,6 +1151,7 @@ impl SynthModule for Sequencer {
 mod tests {
     use super::*;
     use super::super::oscillator;
+    use super::super::sample_buffer::SampleBuffer;
     use crate::prelude::*;
     use crate::clock;
 
@@ -310,6 +1191,16 @@ mod tests {
         for step in sequencer.iter() {
             assert!(!float_eq(step.value, 2.0, 0.0001), "Step 2 is still there after being removed");
         }
+
+        assert!(sequencer.remove_step(4).is_err(), "Removing one past the last step should fail");
+
+        // Removing the step the sequencer is on at the end should leave it on the new last step
+        sequencer.current_step.set(3);
+        if let Err(err) = sequencer.remove_step(3) {
+            panic!("Failed to remove step 3: {}", err);
+        }
+        assert_eq!(sequencer.current_step.get(), 2, "Expected the current step to move back to 2");
+        assert!(sequencer.get_current_step_info().is_some(), "Current step is out of the pattern");
     }
 
     #[test]
@@ -361,6 +1252,289 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_slide() {
+        const SAMPLE_RATE: usize = 4;
+        const EXPECTED_DATA: [f32; 4] = [0.5, 1.0, 1.0, 1.0];
+        let mut sequencer = Sequencer::with_steps(2);
+        sequencer.set_slide_shape(SlewShape::Linear);
+        let step_1 = sequencer.get_step_info_mut(1).expect("There is no step 1?");
+        step_1.value = 1.0;
+        step_1.slide = 500.0;
+
+        let output_info = create_output_info(SAMPLE_RATE, EXPECTED_DATA.len());
+        let mut data = vec![0.0; SAMPLE_RATE];
+        sequencer.fill_output_buffer(&mut data, &output_info);
+        sequencer.increment_step(true);
+        sequencer.fill_output_buffer(&mut data, &output_info);
+        for i in 0..SAMPLE_RATE {
+            assert!(
+                float_eq(EXPECTED_DATA[i], data[i], 0.000001),
+                "Output does not match expected.\n\tExpected: {:?}\n\tGot: {:?}\n", EXPECTED_DATA, data
+            );
+        }
+    }
+
+    #[test]
+    fn test_step_note() {
+        let mut sequencer = Sequencer::with_steps(2);
+        sequencer.set_step_note(1, &"A4".parse().unwrap()).unwrap();
+        let step_1 = sequencer.get_step_info(1).expect("There is no step 1?");
+        assert!(float_eq(step_1.value, 0.75, 0.000001), "Expected A4 to be 0.75. Got {}", step_1.value);
+        assert!(sequencer.set_step_note(2, &"A4".parse().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_directions() {
+        let cases = [
+            (SequencerDirection::Forward, [1, 2, 3, 0, 1, 2, 3, 0]),
+            (SequencerDirection::Reverse, [3, 2, 1, 0, 3, 2, 1, 0]),
+            (SequencerDirection::Pendulum, [1, 2, 3, 2, 1, 0, 1, 2])
+        ];
+        for (direction, expected_steps) in cases.iter() {
+            let mut sequencer = Sequencer::with_steps(4);
+            sequencer.set_direction(*direction);
+            let mut steps = [0_usize; 8];
+            for step in steps.iter_mut() {
+                sequencer.increment_step(true);
+                *step = sequencer.current_step.get();
+            }
+            assert_eq!(steps, *expected_steps, "Unexpected steps going {:?}", direction);
+        }
+
+        // Random directions can go anywhere but should never land on a skipped step
+        for direction in [SequencerDirection::Random, SequencerDirection::RandomWalk].iter() {
+            let mut sequencer = Sequencer::with_steps(4);
+            sequencer.set_direction(*direction);
+            sequencer.get_step_info_mut(2).expect("There is no step 2?").kind = SequencerStepKind::Skip;
+            for _ in 0..100 {
+                sequencer.increment_step(true);
+                let step = sequencer.current_step.get();
+                assert!(step < 4 && step != 2, "Landed on step {} going {:?}", step, direction);
+            }
+        }
+    }
+
+    #[test]
+    fn test_stop_at_end() {
+        let mut sequencer = Sequencer::with_steps(3);
+        sequencer.set_direction(SequencerDirection::Pendulum);
+        sequencer.set_cycle(false);
+        sequencer.start();
+        for _ in 0..4 {
+            sequencer.increment_step(false);
+        }
+        assert_eq!(sequencer.current_step.get(), 0);
+        assert!(sequencer.is_playing());
+        sequencer.increment_step(true);
+        assert_eq!(sequencer.current_step.get(), 0);
+        assert!(!sequencer.is_playing());
+    }
+
+    #[test]
+    fn test_gate_output() {
+        const SAMPLE_RATE: usize = 8;
+        const EXPECTED_VALUES: [f32; SAMPLE_RATE] = [0.0, 1.0, 1.0, 1.0, 1.0, 2.0, 2.0, 2.0];
+        const EXPECTED_GATES: [f32; SAMPLE_RATE] = [1.0, 1.0, 0.0, 0.0, 0.0, 1.0, 1.0, 0.0];
+        let sequencer = create_clocked_sequencer();
+
+        let sequencer = Rc::new(sequencer);
+        let gate_output = Sequencer::gate_output(&sequencer);
+        let output_info = create_output_info(SAMPLE_RATE, SAMPLE_RATE);
+
+        // Reading both outputs of the same block should only step through the sequence once
+        let mut gates = vec![0.0; SAMPLE_RATE];
+        gate_output.fill_output_buffer(&mut gates, &output_info);
+        let mut values = vec![0.0; SAMPLE_RATE];
+        sequencer.fill_output_buffer(&mut values, &output_info);
+        assert_eq!(values, EXPECTED_VALUES, "Values do not match expected");
+        assert_eq!(gates, EXPECTED_GATES, "Gates do not match expected");
+    }
+
+    #[test]
+    fn test_sample_feedback_loop() {
+        const SAMPLE_RATE: usize = 4;
+        const EXPECTED_VALUES: [[f32; SAMPLE_RATE]; 2] = [[0.0, 1.0, 1.0, 1.0], [1.0, 2.0, 2.0, 2.0]];
+
+        // In a sample feedback loop the sequencer renders a sample at a time, so it has to
+        // remember the last clock sample to see any edges at all
+        let mut patch = super::super::Patch::new();
+        let clock = patch.add_node("clock", SampleBuffer::new(vec![0.0, 1.0, 0.0, 0.0])).unwrap();
+        let sequencer = patch.add_node("sequencer", create_clocked_sequencer()).unwrap();
+        patch.connect(clock, "out", sequencer, "clock").unwrap();
+        // There's only one pattern so the selected pattern never changes
+        patch.connect_sample_feedback(sequencer, "value", sequencer, "pattern select").unwrap();
+        patch.set_output(sequencer, "value").unwrap();
+        assert!(patch.is_rendered_per_sample(sequencer));
+
+        let mut clock = clock::SampleClock::new(SAMPLE_RATE);
+        for expected_values in EXPECTED_VALUES.iter() {
+            let output_info = OutputInfo::new_basic(SAMPLE_RATE, clock.get_range(SAMPLE_RATE));
+            let mut values = vec![0.0; SAMPLE_RATE];
+            patch.fill_output_buffer(&mut values, &output_info);
+            assert_eq!(&values, expected_values, "Values do not match expected");
+        }
+    }
+
+    fn create_clocked_sequencer() -> Sequencer {
+        let mut sequencer = Sequencer::with_steps(4);
+        for (i, step) in sequencer.iter_mut().enumerate() {
+            step.value = i as f32;
+        }
+        let clock = SampleBuffer::new(vec![0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0]);
+        sequencer.set_clock(Some(Rc::new(clock)));
+        sequencer.set_edge_detection(EdgeDetection::Rising);
+        sequencer.start();
+        sequencer
+    }
+
+    fn get_gates(sequencer: Sequencer, sample_rate: usize) -> Vec<f32> {
+        let gate_output = Sequencer::gate_output(&Rc::new(sequencer));
+        let output_info = create_output_info(sample_rate, sample_rate);
+        let mut gates = vec![0.0; sample_rate];
+        gate_output.fill_output_buffer(&mut gates, &output_info);
+        gates
+    }
+
+    #[test]
+    fn test_probability_and_ratchets() {
+        const SAMPLE_RATE: usize = 8;
+        const EXPECTED_GATES: [f32; SAMPLE_RATE] = [1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 1.0];
+        let mut sequencer = create_clocked_sequencer();
+        sequencer.get_step_info_mut(1).expect("There is no step 1?").probability = 0.0;
+        sequencer.get_step_info_mut(2).expect("There is no step 2?").ratchets = 2;
+        let gates = get_gates(sequencer, SAMPLE_RATE);
+        assert_eq!(gates, EXPECTED_GATES, "Gates do not match expected");
+    }
+
+    #[test]
+    fn test_seeded_probability() {
+        const SAMPLE_RATE: usize = 8;
+        let make_sequencer = || {
+            let mut sequencer = create_clocked_sequencer();
+            sequencer.set_seed(42);
+            for step in sequencer.iter_mut() {
+                step.probability = 0.5;
+            }
+            sequencer
+        };
+
+        let mut first_gates = Vec::new();
+        for _ in 0..10 {
+            first_gates.push(get_gates(make_sequencer(), SAMPLE_RATE));
+        }
+        let second_gates = (0..10).map(|_| get_gates(make_sequencer(), SAMPLE_RATE));
+        for (first, second) in first_gates.iter().zip(second_gates) {
+            assert_eq!(*first, second, "Sequencers with the same seed made different gates");
+        }
+    }
+
+    #[test]
+    fn test_lane_output() {
+        const SAMPLE_RATE: usize = 8;
+        const EXPECTED_LANE: [f32; SAMPLE_RATE] = [0.5, 0.25, 0.25, 0.25, 0.25, 1.0, 1.0, 1.0];
+        let mut sequencer = create_clocked_sequencer();
+        let lane_index = sequencer.add_lane();
+        for (step_index, value) in [0.5, 0.25, 1.0, 0.0].iter().enumerate() {
+            sequencer.set_lane_value(lane_index, step_index, *value).unwrap();
+        }
+        assert!(sequencer.set_lane_value(lane_index, 4, 0.0).is_err());
+
+        let sequencer = Rc::new(sequencer);
+        assert!(Sequencer::lane_output(&sequencer, lane_index + 1).is_err());
+        assert!(OutputPort::new(sequencer.clone(), "lane 1").is_err());
+        let lane_output = OutputPort::new(sequencer.clone(), "lane 0").unwrap();
+        let output_info = create_output_info(SAMPLE_RATE, SAMPLE_RATE);
+        let mut lane = vec![0.0; SAMPLE_RATE];
+        lane_output.fill_output_buffer(&mut lane, &output_info);
+        assert_eq!(lane, EXPECTED_LANE, "Lane does not match expected");
+    }
+
+    /// Makes a sequencer with two step patterns "default" and "b". The values of "b" start at 10
+    fn create_two_pattern_sequencer() -> Sequencer {
+        let mut sequencer = Sequencer::with_steps(2);
+        sequencer.add_pattern_with_steps("b", 2).unwrap();
+        for (pattern_name, first_value) in [("default", 0.0), ("b", 10.0)].iter() {
+            sequencer.select_pattern(pattern_name).unwrap();
+            for (i, step) in sequencer.iter_mut().enumerate() {
+                step.value = first_value + i as f32;
+            }
+        }
+        sequencer.select_pattern("default").unwrap();
+        sequencer
+    }
+
+    fn get_step_values(sequencer: &Sequencer, step_count: usize) -> Vec<f32> {
+        (0..step_count).map(|_| {
+            sequencer.increment_step(false);
+            sequencer.get_current_step_info().expect("There is no current step?").value
+        })
+        .collect()
+    }
+
+    #[test]
+    fn test_pattern_bank() {
+        let mut sequencer = create_two_pattern_sequencer();
+        assert!(sequencer.add_pattern("b").is_err(), "Added a pattern with a name that's taken");
+        assert!(sequencer.select_pattern("c").is_err(), "Selected a pattern that doesn't exist");
+        assert_eq!(sequencer.get_pattern_names(), vec!["default", "b"]);
+
+        // Lanes are shared by every pattern
+        let lane_index = sequencer.add_lane();
+        sequencer.select_pattern("b").unwrap();
+        assert_eq!(sequencer.lane_count(), 1);
+        sequencer.set_lane_value(lane_index, 1, 0.5).unwrap();
+        assert_eq!(sequencer.get_lane_value(lane_index, 1), Some(0.5));
+
+        sequencer.set_song(vec![SongEntry::new("b", 1)]).unwrap();
+        assert!(sequencer.remove_pattern("b").is_err(), "Removed a pattern the song uses");
+        sequencer.set_song(Vec::new()).unwrap();
+        sequencer.remove_pattern("b").unwrap();
+        assert_eq!(sequencer.get_current_pattern_name(), "default");
+        assert!(sequencer.remove_pattern("default").is_err(), "Removed the only pattern");
+    }
+
+    #[test]
+    fn test_queued_pattern() {
+        let sequencer = create_two_pattern_sequencer();
+        sequencer.queue_pattern("b").unwrap();
+        assert_eq!(sequencer.get_queued_pattern_name(), Some("b"));
+        let values = get_step_values(&sequencer, 4);
+        assert_eq!(values, vec![1.0, 10.0, 11.0, 10.0], "Pattern didn't switch at the end");
+        assert_eq!(sequencer.get_queued_pattern_name(), None);
+    }
+
+    #[test]
+    fn test_song() {
+        let mut sequencer = create_two_pattern_sequencer();
+        let song = vec![SongEntry::new("default", 2), SongEntry::new("b", 1)];
+        sequencer.set_song(song).unwrap();
+        sequencer.set_song_mode(true);
+        let values = get_step_values(&sequencer, 7);
+        assert_eq!(values, vec![1.0, 0.0, 1.0, 10.0, 11.0, 0.0, 1.0], "Song played in the wrong order");
+
+        assert!(sequencer.set_song(vec![SongEntry::new("c", 1)]).is_err());
+        assert!(sequencer.set_song(vec![SongEntry::new("b", 0)]).is_err());
+    }
+
+    #[test]
+    fn test_pattern_select_in() {
+        const SAMPLE_RATE: usize = 8;
+        const EXPECTED_VALUES: [f32; SAMPLE_RATE] = [0.0, 1.0, 1.0, 1.0, 1.0, 10.0, 10.0, 10.0];
+        let mut sequencer = create_two_pattern_sequencer();
+        let clock = SampleBuffer::new(vec![0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0]);
+        sequencer.set_clock(Some(Rc::new(clock)));
+        sequencer.set_edge_detection(EdgeDetection::Rising);
+        let pattern_select = SampleBuffer::new(vec![0.0, 0.0, 0.9, 1.0, 1.0, 1.0, 1.0, 1.0]);
+        sequencer.set_pattern_select_in(Some(Rc::new(pattern_select)));
+        sequencer.start();
+
+        let output_info = create_output_info(SAMPLE_RATE, SAMPLE_RATE);
+        let mut values = vec![0.0; SAMPLE_RATE];
+        sequencer.fill_output_buffer(&mut values, &output_info);
+        assert_eq!(values, EXPECTED_VALUES, "Values do not match expected");
+    }
+
     #[test]
     fn test_playing_output() {
         const SAMPLE_RATE: usize = 9;
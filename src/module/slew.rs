@@ -0,0 +1,256 @@
+use std::rc::Rc;
+use std::cell::Cell;
+
+use super::{SynthModule, PatchModule, OutputInfo, no_such_input, input_names};
+use crate::SynthResult;
+use super::detector::smoothing_coefficient;
+use super::parameter::{Parameters, ParameterInfo, ParameterScale, clamp_parameter, no_such_parameter, choice_index, choice_value};
+use super::render_cache::BlockCache;
+
+const MILLISECONDS_PER_SECOND: f32 = 1000.0;
+
+/// How a `Slew` moves towards its input
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum SlewShape {
+    /// Moves at a constant rate. The rise and fall times are how long it takes to move by 1.0,
+    /// so bigger jumps take longer
+    Linear,
+    /// Moves quickly at first and slows as it gets close. The rise and fall times are time
+    /// constants, so every jump takes about the same time no matter how big it is
+    Exponential
+}
+
+pub(super) const SHAPE_NAMES: [(&str, SlewShape); 2] = [
+    ("Linear", SlewShape::Linear),
+    ("Exponential", SlewShape::Exponential)
+];
+
+/// Limits how fast a signal can change. Useful for glide between notes or for smoothing
+/// out stepped control signals.
+pub struct Slew {
+    signal_in: Option<Rc<dyn SynthModule>>,
+    shape: SlewShape,
+    // Times here should be in milliseconds
+    rise_time: f32,
+    fall_time: f32,
+
+    // `None` until the first sample so the output doesn't glide up from 0.0 at the start
+    value: Cell<Option<f32>>,
+    block_cache: BlockCache
+}
+
+impl Slew {
+    pub fn new() -> Self {
+        Self::with_shape(SlewShape::Linear)
+    }
+
+    pub fn with_shape(shape: SlewShape) -> Self {
+        let signal_in = None;
+        let rise_time = 100.0;
+        let fall_time = 100.0;
+        let value = Cell::new(None);
+        let block_cache = BlockCache::new();
+        Self { signal_in, shape, rise_time, fall_time, value, block_cache }
+    }
+
+    pub fn set_signal_in(&mut self, signal_in: Option<Rc<dyn SynthModule>>) {
+        self.signal_in = signal_in;
+    }
+
+    pub fn set_shape(&mut self, shape: SlewShape) {
+        self.shape = shape;
+    }
+
+    pub fn get_shape(&self) -> SlewShape {
+        self.shape
+    }
+
+    pub fn set_rise_time(&mut self, rise_time: f32) {
+        self.rise_time = rise_time.max(0.0);
+    }
+
+    pub fn get_rise_time(&self) -> f32 {
+        self.rise_time
+    }
+
+    pub fn set_fall_time(&mut self, fall_time: f32) {
+        self.fall_time = fall_time.max(0.0);
+    }
+
+    pub fn get_fall_time(&self) -> f32 {
+        self.fall_time
+    }
+
+    /// Makes the next sample jump straight to the input
+    pub fn reset(&self) {
+        self.value.set(None);
+    }
+
+    /// Moves one sample towards `target` using the given times rather than the ones set on the module
+    pub(super) fn next_value(&self, target: f32, rise_time: f32, fall_time: f32, sample_rate: usize) -> f32 {
+        let value = match self.value.get() {
+            Some(value) => value,
+            None => target
+        };
+        let time = if target > value { rise_time } else { fall_time };
+
+        let next_value = match self.shape {
+            SlewShape::Linear => {
+                let time_in_samples = time * sample_rate as f32 / MILLISECONDS_PER_SECOND;
+                if time_in_samples <= 0.0 {
+                    target
+                }
+                else {
+                    let max_change = 1.0 / time_in_samples;
+                    value + (target - value).clamp(-max_change, max_change)
+                }
+            },
+            SlewShape::Exponential => {
+                let coefficient = smoothing_coefficient(time, sample_rate);
+                coefficient * value + (1.0 - coefficient) * target
+            }
+        };
+        self.value.set(Some(next_value));
+        next_value
+    }
+}
+
+impl Default for Slew {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SynthModule for Slew {
+    fn fill_output_buffer(&self, buffer: &mut [f32], output_info: &OutputInfo) {
+        self.block_cache.fill(buffer, output_info, |buffer| {
+            // Get the signal to be slewed
+            if let Some(signal_in) = &self.signal_in {
+                signal_in.fill_output_buffer(buffer, output_info);
+            }
+            else {
+                buffer.fill(0.0);
+                return;
+            }
+
+            for datum in buffer.iter_mut() {
+                *datum = self.next_value(*datum, self.rise_time, self.fall_time, output_info.sample_rate);
+            }
+        });
+    }
+}
+
+impl PatchModule for Slew {
+    fn get_input_names(&self) -> Vec<String> {
+        input_names(&["signal"])
+    }
+
+    fn set_input(&mut self, input: usize, module: Option<Rc<dyn SynthModule>>) -> SynthResult<()> {
+        match input {
+            0 => self.set_signal_in(module),
+            _ => return no_such_input(input)
+        }
+        Ok(())
+    }
+}
+
+impl Parameters for Slew {
+    fn get_parameter_info(&self) -> Vec<ParameterInfo> {
+        vec![
+            ParameterInfo::choice("shape", "Shape", &SHAPE_NAMES, SlewShape::Linear),
+            ParameterInfo::new("rise_time", "Rise Time", "ms", 0.0, 10_000.0, 100.0, ParameterScale::Logarithmic),
+            ParameterInfo::new("fall_time", "Fall Time", "ms", 0.0, 10_000.0, 100.0, ParameterScale::Logarithmic)
+        ]
+    }
+
+    fn get_parameter(&self, id: &str) -> Option<f32> {
+        match id {
+            "shape" => Some(choice_index(&SHAPE_NAMES, self.shape)),
+            "rise_time" => Some(self.rise_time),
+            "fall_time" => Some(self.fall_time),
+            _ => None
+        }
+    }
+
+    fn set_parameter(&mut self, id: &str, value: f32) -> SynthResult<()> {
+        let value = clamp_parameter(self, id, value)?;
+        match id {
+            "shape" => self.set_shape(choice_value(&SHAPE_NAMES, value)),
+            "rise_time" => self.set_rise_time(value),
+            "fall_time" => self.set_fall_time(value),
+            _ => return no_such_parameter(id)
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::*;
+    use crate::module::sample_buffer::SampleBuffer;
+    use crate::clock;
+
+    const SAMPLE_RATE: usize = 8;
+
+    fn get_slew_output(slew: &Slew) -> Vec<f32> {
+        let mut clock = clock::SampleClock::new(SAMPLE_RATE);
+        let clock_values = clock.get_range(SAMPLE_RATE);
+        let output_info = OutputInfo::new_basic(SAMPLE_RATE, clock_values);
+
+        let mut output_buffer = vec![0_f32; SAMPLE_RATE];
+        slew.fill_output_buffer(&mut output_buffer, &output_info);
+        output_buffer
+    }
+
+    fn assert_output_eq(got: &[f32], expected: &[f32]) {
+        for (got_datum, expected_datum) in got.iter().zip(expected.iter()) {
+            assert!(
+                float_eq(*got_datum, *expected_datum, 0.0001),
+                "Slew output does not match expected:\n\tGot: {:?}\n\tExpected: {:?}", got, expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_linear_rise_and_fall() {
+        // Rising by 1.0 takes 4 samples, falling by 1.0 takes 2
+        let signal = vec![0.0, 1.0, 1.0, 1.0, 1.0, 0.0, 0.0, 0.0];
+        let mut slew = Slew::new();
+        slew.set_rise_time(500.0);
+        slew.set_fall_time(250.0);
+        slew.set_signal_in(Some(Rc::new(SampleBuffer::new(signal))));
+
+        let output = get_slew_output(&slew);
+        assert_output_eq(&output, &[0.0, 0.25, 0.5, 0.75, 1.0, 0.5, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_exponential() {
+        let signal = vec![0.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0];
+        let mut slew = Slew::with_shape(SlewShape::Exponential);
+        slew.set_rise_time(250.0);
+        slew.set_signal_in(Some(Rc::new(SampleBuffer::new(signal))));
+
+        // Each sample should cover the same fraction of the remaining distance
+        let coefficient = smoothing_coefficient(250.0, SAMPLE_RATE);
+        let output = get_slew_output(&slew);
+        assert_eq!(output[0], 0.0);
+        for (i, datum) in output.iter().enumerate().skip(1) {
+            let expected = 1.0 - coefficient.powi(i as i32);
+            assert!(float_eq(*datum, expected, 0.0001), "Expected {}. Got {}", expected, datum);
+        }
+    }
+
+    #[test]
+    fn test_no_slew() {
+        let signal = vec![0.0, 1.0, -1.0, 0.5, 0.5, 2.0, 0.0, 1.0];
+        let mut slew = Slew::new();
+        slew.set_rise_time(0.0);
+        slew.set_fall_time(0.0);
+        slew.set_signal_in(Some(Rc::new(SampleBuffer::new(signal.clone()))));
+
+        let output = get_slew_output(&slew);
+        assert_output_eq(&output, &signal);
+    }
+}
@@ -0,0 +1,182 @@
+use std::cell::Cell;
+
+const MILLISECONDS_PER_SECOND: f32 = 1000.0;
+
+/// The ramp time in milliseconds that smoothed parameters use unless told otherwise
+pub const DEFAULT_SMOOTHING_TIME: f32 = 10.0;
+
+/// A parameter value that ramps linearly to a new target instead of jumping to it.
+/// Modules read it once per sample while filling their output so that changing a
+/// parameter during playback doesn't produce zipper noise or clicks.
+#[derive(Debug, Clone)]
+pub struct SmoothedValue {
+    target: f32,
+    // Time here should be in milliseconds
+    ramp_time: f32,
+
+    current: Cell<f32>,
+    step: Cell<f32>,
+    remaining: Cell<usize>,
+    // Set when the target changes. The step isn't known until we know the sample rate
+    pending: Cell<bool>,
+    // Nothing has been heard until the first sample is read, so until then changes apply immediately
+    started: Cell<bool>
+}
+
+impl SmoothedValue {
+    pub fn new(value: f32) -> Self {
+        let target = value;
+        let ramp_time = DEFAULT_SMOOTHING_TIME;
+        let current = Cell::new(value);
+        let step = Cell::new(0.0);
+        let remaining = Cell::new(0);
+        let pending = Cell::new(false);
+        let started = Cell::new(false);
+        Self { target, ramp_time, current, step, remaining, pending, started }
+    }
+
+    /// Sets the value to ramp towards. The ramp starts from wherever the value currently is.
+    pub fn set(&mut self, value: f32) {
+        if value == self.target {
+            return;
+        }
+
+        self.target = value;
+        if self.started.get() {
+            self.pending.set(true);
+        }
+        else {
+            self.set_immediately(value);
+        }
+    }
+
+    /// Sets the value without ramping to it
+    pub fn set_immediately(&mut self, value: f32) {
+        self.target = value;
+        self.current.set(value);
+        self.remaining.set(0);
+        self.pending.set(false);
+    }
+
+    /// Gets the value being ramped towards. This is the value that was last set.
+    pub fn get(&self) -> f32 {
+        self.target
+    }
+
+    /// Gets the value as of the most recently read sample
+    pub fn get_current(&self) -> f32 {
+        self.current.get()
+    }
+
+    /// Checks if the value is still on its way to the target
+    pub fn is_smoothing(&self) -> bool {
+        self.pending.get() || self.remaining.get() > 0
+    }
+
+    /// Sets how long in milliseconds it takes to ramp to a new value.
+    /// A ramp that's already in progress keeps its old time.
+    pub fn set_ramp_time(&mut self, ramp_time: f32) {
+        self.ramp_time = ramp_time.max(0.0);
+    }
+
+    pub fn get_ramp_time(&self) -> f32 {
+        self.ramp_time
+    }
+
+    /// Advances the value by one sample and returns it
+    pub fn next(&self, sample_rate: usize) -> f32 {
+        self.started.set(true);
+
+        if self.pending.replace(false) {
+            let ramp_samples = (self.ramp_time * sample_rate as f32 / MILLISECONDS_PER_SECOND).round() as usize;
+            if ramp_samples == 0 {
+                self.current.set(self.target);
+                self.remaining.set(0);
+            }
+            else {
+                self.step.set((self.target - self.current.get()) / ramp_samples as f32);
+                self.remaining.set(ramp_samples);
+            }
+        }
+
+        let remaining = self.remaining.get();
+        if remaining > 0 {
+            self.remaining.set(remaining - 1);
+            if remaining == 1 {
+                // Land exactly on the target rather than wherever rounding errors leave us
+                self.current.set(self.target);
+            }
+            else {
+                self.current.set(self.current.get() + self.step.get());
+            }
+        }
+        self.current.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: usize = 1000;
+
+    #[test]
+    fn test_ramp() {
+        let mut value = SmoothedValue::new(0.0);
+
+        // Before anything is read, changes apply immediately
+        value.set(1.0);
+        assert_eq!(value.next(SAMPLE_RATE), 1.0);
+        assert!(!value.is_smoothing());
+
+        // A 10ms ramp at 1kHz takes 10 samples
+        value.set(2.0);
+        assert!(value.is_smoothing());
+        assert_eq!(value.get(), 2.0);
+        for i in 1..10 {
+            let sample = value.next(SAMPLE_RATE);
+            assert!((sample - (1.0 + i as f32 * 0.1)).abs() < 0.0001);
+        }
+        assert_eq!(value.next(SAMPLE_RATE), 2.0);
+        assert!(!value.is_smoothing());
+        assert_eq!(value.next(SAMPLE_RATE), 2.0);
+
+        // Changing the target mid ramp starts a new ramp from where we are
+        value.set(3.0);
+        for _ in 0..5 {
+            value.next(SAMPLE_RATE);
+        }
+        value.set(0.0);
+        let start = value.get_current();
+        assert!((start - 2.5).abs() < 0.0001);
+        let sample = value.next(SAMPLE_RATE);
+        assert!((sample - (start - 0.25)).abs() < 0.0001);
+        for _ in 0..9 {
+            value.next(SAMPLE_RATE);
+        }
+        assert_eq!(value.get_current(), 0.0);
+    }
+
+    #[test]
+    fn test_ramp_time() {
+        let mut value = SmoothedValue::new(0.0);
+        value.next(SAMPLE_RATE);
+
+        value.set_ramp_time(0.0);
+        value.set(1.0);
+        assert_eq!(value.next(SAMPLE_RATE), 1.0);
+
+        value.set_ramp_time(100.0);
+        value.set(0.0);
+        for _ in 0..99 {
+            assert!(value.next(SAMPLE_RATE) > 0.0);
+        }
+        assert_eq!(value.next(SAMPLE_RATE), 0.0);
+
+        value.set(1.0);
+        value.next(SAMPLE_RATE);
+        value.set_immediately(0.5);
+        assert!(!value.is_smoothing());
+        assert_eq!(value.next(SAMPLE_RATE), 0.5);
+    }
+}
@@ -0,0 +1,317 @@
+use std::rc::Rc;
+
+use crate::prelude::*;
+use crate::SynthResult;
+use crate::patch_file::{Table, ModuleSettings};
+use super::{SynthModule, PatchModule, OutputInfo, SmoothedValue, no_such_input, input_names};
+use super::parameter::{Parameters, ParameterInfo, ParameterScale, clamp_parameter, no_such_parameter, choice_index, choice_value};
+use super::render_cache::BlockCache;
+
+/// How a `Vca` turns its control signal into gain
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum VcaMode {
+    /// Gain follows the control linearly. Control below 0.0 is silence
+    Linear,
+    /// Control is mapped onto a decibel scale so 1.0 is unity gain and 0.0 is silence.
+    /// This sounds more even to the ear when fed from an envelope
+    Exponential,
+    /// The signal is multiplied by the control directly. Negative control inverts the signal
+    RingModulator
+}
+
+const MODE_NAMES: [(&str, VcaMode); 3] = [
+    ("Linear", VcaMode::Linear),
+    ("Exponential", VcaMode::Exponential),
+    ("RingModulator", VcaMode::RingModulator)
+];
+
+/// Voltage controlled amplifier. Scales a signal by a control signal, usually an envelope.
+/// In ring modulator mode it is a true four-quadrant multiplier of its two inputs.
+#[derive(Clone)]
+pub struct Vca {
+    signal_in: Option<Rc<dyn SynthModule>>,
+    control_in: Option<Rc<dyn SynthModule>>,
+    mode: VcaMode,
+    /// Offset added to the control signal
+    bias: SmoothedValue,
+    /// Scale applied to the control signal before the bias is added
+    control_gain: SmoothedValue,
+    /// Number of decibels between full and no control in exponential mode
+    exponential_range: SmoothedValue,
+    block_cache: BlockCache
+}
+
+impl Vca {
+    pub fn new() -> Self {
+        Self::with_mode(VcaMode::Linear)
+    }
+
+    pub fn with_mode(mode: VcaMode) -> Self {
+        let signal_in = None;
+        let control_in = None;
+        let bias = SmoothedValue::new(0.0);
+        let control_gain = SmoothedValue::new(1.0);
+        let exponential_range = SmoothedValue::new(60.0);
+        let block_cache = BlockCache::new();
+        Self { signal_in, control_in, mode, bias, control_gain, exponential_range, block_cache }
+    }
+
+    /// Makes a VCA from its settings in a patch file
+    pub fn load_settings(settings: &Table) -> SynthResult<Self> {
+        settings.check_keys(&["mode", "bias", "control_gain", "exponential_range"])?;
+        let mut vca = Self::new();
+        if let Some(mode) = settings.get_choice("mode", &MODE_NAMES)? {
+            vca.set_mode(mode);
+        }
+        if let Some(bias) = settings.get_float("bias")? {
+            vca.set_bias(bias);
+        }
+        if let Some(control_gain) = settings.get_float("control_gain")? {
+            vca.set_control_gain(control_gain);
+        }
+        if let Some(exponential_range) = settings.get_float("exponential_range")? {
+            vca.set_exponential_range(exponential_range);
+        }
+        Ok(vca)
+    }
+
+    pub fn set_signal_in(&mut self, signal_in: Option<Rc<dyn SynthModule>>) {
+        self.signal_in = signal_in;
+    }
+
+    pub fn set_control_in(&mut self, control_in: Option<Rc<dyn SynthModule>>) {
+        self.control_in = control_in;
+    }
+
+    pub fn set_mode(&mut self, mode: VcaMode) {
+        self.mode = mode;
+    }
+
+    pub fn get_mode(&self) -> VcaMode {
+        self.mode
+    }
+
+    pub fn set_bias(&mut self, bias: f32) {
+        self.bias.set(bias);
+    }
+
+    pub fn get_bias(&self) -> f32 {
+        self.bias.get()
+    }
+
+    pub fn set_control_gain(&mut self, control_gain: f32) {
+        self.control_gain.set(control_gain);
+    }
+
+    pub fn get_control_gain(&self) -> f32 {
+        self.control_gain.get()
+    }
+
+    /// Sets how long in milliseconds bias, control gain and exponential range changes take to ramp in
+    pub fn set_smoothing_time(&mut self, smoothing_time: f32) {
+        self.bias.set_ramp_time(smoothing_time);
+        self.control_gain.set_ramp_time(smoothing_time);
+        self.exponential_range.set_ramp_time(smoothing_time);
+    }
+
+    pub fn get_smoothing_time(&self) -> f32 {
+        self.bias.get_ramp_time()
+    }
+
+    pub fn set_exponential_range(&mut self, exponential_range: f32) {
+        self.exponential_range.set(exponential_range.max(0.0));
+    }
+
+    pub fn get_exponential_range(&self) -> f32 {
+        self.exponential_range.get()
+    }
+
+    /// Gets the gain for a control value that has already had the control gain and bias applied
+    fn get_gain(&self, control: f32, exponential_range: f32) -> f32 {
+        match self.mode {
+            VcaMode::Linear => control.max(0.0),
+            VcaMode::Exponential => {
+                if control <= 0.0 {
+                    0.0
+                }
+                else {
+                    db_to_amplitude((control - 1.0) * exponential_range)
+                }
+            },
+            VcaMode::RingModulator => control
+        }
+    }
+}
+
+impl Default for Vca {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SynthModule for Vca {
+    fn fill_output_buffer(&self, buffer: &mut [f32], output_info: &OutputInfo) {
+        self.block_cache.fill(buffer, output_info, |buffer| {
+            let buffer_len = buffer.len();
+
+            // Get the signal to be amplified
+            let mut signal = vec![0.0; buffer_len];
+            if let Some(signal_in) = &self.signal_in {
+                signal_in.fill_output_buffer(&mut signal, output_info);
+            }
+            else {
+                buffer.fill(0.0);
+                return;
+            }
+
+            // Get control signal
+            let mut control = vec![0.0; buffer_len];
+            if let Some(control_in) = &self.control_in {
+                control_in.fill_output_buffer(&mut control, output_info);
+            }
+
+            for i in 0..buffer_len {
+                let control_gain = self.control_gain.next(output_info.sample_rate);
+                let control_datum = control[i] * control_gain + self.bias.next(output_info.sample_rate);
+                let exponential_range = self.exponential_range.next(output_info.sample_rate);
+                buffer[i] = signal[i] * self.get_gain(control_datum, exponential_range);
+            }
+        });
+    }
+}
+
+impl PatchModule for Vca {
+    fn get_input_names(&self) -> Vec<String> {
+        input_names(&["signal", "control"])
+    }
+
+    fn set_input(&mut self, input: usize, module: Option<Rc<dyn SynthModule>>) -> SynthResult<()> {
+        match input {
+            0 => self.set_signal_in(module),
+            1 => self.set_control_in(module),
+            _ => return no_such_input(input)
+        }
+        Ok(())
+    }
+
+    fn save_settings(&self) -> Option<ModuleSettings> {
+        let mut settings = Table::new();
+        settings.set_choice("mode", self.mode, &MODE_NAMES);
+        settings.set("bias", self.bias.get());
+        settings.set("control_gain", self.control_gain.get());
+        settings.set("exponential_range", self.exponential_range.get());
+        Some(ModuleSettings::new("Vca", settings))
+    }
+}
+
+impl Parameters for Vca {
+    fn get_parameter_info(&self) -> Vec<ParameterInfo> {
+        vec![
+            ParameterInfo::choice("mode", "Mode", &MODE_NAMES, VcaMode::Linear),
+            ParameterInfo::new("bias", "Bias", "", -1.0, 1.0, 0.0, ParameterScale::Linear),
+            ParameterInfo::new("control_gain", "Control Gain", "", -1.0, 1.0, 1.0, ParameterScale::Linear),
+            ParameterInfo::new("exponential_range", "Exponential Range", "dB", 0.0, 120.0, 60.0, ParameterScale::Linear)
+        ]
+    }
+
+    fn get_parameter(&self, id: &str) -> Option<f32> {
+        match id {
+            "mode" => Some(choice_index(&MODE_NAMES, self.mode)),
+            "bias" => Some(self.bias.get()),
+            "control_gain" => Some(self.control_gain.get()),
+            "exponential_range" => Some(self.exponential_range.get()),
+            _ => None
+        }
+    }
+
+    fn set_parameter(&mut self, id: &str, value: f32) -> SynthResult<()> {
+        let value = clamp_parameter(self, id, value)?;
+        match id {
+            "mode" => self.set_mode(choice_value(&MODE_NAMES, value)),
+            "bias" => self.set_bias(value),
+            "control_gain" => self.set_control_gain(value),
+            "exponential_range" => self.set_exponential_range(value),
+            _ => return no_such_parameter(id)
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module::sample_buffer::SampleBuffer;
+    use crate::clock;
+
+    const SAMPLE_RATE: usize = 4;
+
+    fn get_vca_output(vca: &Vca) -> Vec<f32> {
+        let mut clock = clock::SampleClock::new(SAMPLE_RATE);
+        let clock_values = clock.get_range(SAMPLE_RATE);
+        let output_info = OutputInfo::new_basic(SAMPLE_RATE, clock_values);
+
+        let mut output_buffer = vec![0_f32; SAMPLE_RATE];
+        vca.fill_output_buffer(&mut output_buffer, &output_info);
+        output_buffer
+    }
+
+    fn create_vca(mode: VcaMode, signal: [f32; SAMPLE_RATE], control: [f32; SAMPLE_RATE]) -> Vca {
+        let mut vca = Vca::with_mode(mode);
+        vca.set_signal_in(Some(Rc::new(SampleBuffer::new(signal.to_vec()))));
+        vca.set_control_in(Some(Rc::new(SampleBuffer::new(control.to_vec()))));
+        vca
+    }
+
+    fn assert_output_eq(got: &[f32], expected: &[f32]) {
+        for (got_datum, expected_datum) in got.iter().zip(expected.iter()) {
+            assert!(
+                float_eq(*got_datum, *expected_datum, 0.0001),
+                "VCA output does not match expected:\n\tGot: {:?}\n\tExpected: {:?}", got, expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_linear() {
+        let vca = create_vca(VcaMode::Linear, [1.0, -1.0, 0.5, 1.0], [0.5, 0.5, 1.0, -1.0]);
+        let output = get_vca_output(&vca);
+        assert_output_eq(&output, &[0.5, -0.5, 0.5, 0.0]);
+    }
+
+    #[test]
+    fn test_exponential() {
+        let vca = create_vca(VcaMode::Exponential, [1.0; SAMPLE_RATE], [1.0, 0.5, 0.0, -1.0]);
+        let output = get_vca_output(&vca);
+        assert_output_eq(&output, &[1.0, db_to_amplitude(-30.0), 0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_ring_modulator() {
+        let vca = create_vca(VcaMode::RingModulator, [1.0, -1.0, 0.5, -0.5], [-1.0, -1.0, 0.5, 0.5]);
+        let output = get_vca_output(&vca);
+        assert_output_eq(&output, &[-1.0, 1.0, 0.25, -0.25]);
+    }
+
+    #[test]
+    fn test_bias() {
+        let mut vca = create_vca(VcaMode::Linear, [1.0; SAMPLE_RATE], [0.0, 0.25, 0.5, 1.0]);
+        vca.set_bias(0.25);
+        vca.set_control_gain(0.5);
+        let output = get_vca_output(&vca);
+        assert_output_eq(&output, &[0.25, 0.375, 0.5, 0.75]);
+    }
+
+    #[test]
+    fn test_exponential_range_smoothing() {
+        let mut vca = create_vca(VcaMode::Exponential, [1.0; SAMPLE_RATE], [0.5; SAMPLE_RATE]);
+        vca.set_smoothing_time(1_000.0);
+        let output = get_vca_output(&vca);
+        assert_output_eq(&output, &[db_to_amplitude(-30.0); SAMPLE_RATE]);
+
+        // A one second ramp takes the whole buffer to move the range from 60dB to 100dB
+        vca.set_exponential_range(100.0);
+        let output = get_vca_output(&vca);
+        let expected = [-35.0, -40.0, -45.0, -50.0].map(db_to_amplitude);
+        assert_output_eq(&output, &expected);
+    }
+}
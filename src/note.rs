@@ -1,3 +1,6 @@
+use std::fmt;
+use std::str::FromStr;
+
 use crate::error::{SynthError, SynthResult};
 
 pub const FREQ_A:       f32 = 440.00; // A4
@@ -15,9 +18,16 @@ pub const FREQ_G_SHARP: f32 = 830.61;
 
 const MIDI_NOTE_BASE_OCTAVE: i8 = -1;
 const MIDI_NOTE_BASE_TONE_OFFSET: u8 = 3; // numeric offset from A i.e. 3 is C
+const MAX_MIDI_NOTE: i32 = 127;
+const SEMITONES_PER_OCTAVE: i32 = 12;
+
+/// Highest velocity a MIDI note can have
+pub const MAX_VELOCITY: u8 = 127;
 
-const NORMALIZATION_FREQ: f32 = FREQ_A * 16.0;
-const NORMALIZATION_OCTAVES: f32 = 8.0;
+/// Frequency of C4, the note a pitch signal of 0.0 represents. Pitch signals go up by 1.0 per octave
+pub const PITCH_REFERENCE_FREQ: f32 = FREQ_C / 2.0;
+/// The note a pitch signal of 0.0 represents
+pub const PITCH_REFERENCE_NOTE: Note = Note::new(4, Tone::C);
 
 /// Represents the notes within an octave
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Hash)]
@@ -37,6 +47,24 @@ pub enum Tone {
 }
 
 impl Tone {
+    /// Gets the name of the tone, using sharps for black keys
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            Tone::A      => "A",
+            Tone::ASharp => "A#",
+            Tone::B      => "B",
+            Tone::C      => "C",
+            Tone::CSharp => "C#",
+            Tone::D      => "D",
+            Tone::DSharp => "D#",
+            Tone::E      => "E",
+            Tone::F      => "F",
+            Tone::FSharp => "F#",
+            Tone::G      => "G",
+            Tone::GSharp => "G#",
+        }
+    }
+
     fn from_u8(n: u8) -> SynthResult<Self> {
         use Tone::*;
         let tone = match n {
@@ -61,6 +89,198 @@ impl Tone {
     }
 }
 
+/// Scales and modes, each described by the semitones above its root that are in it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Scale {
+    Chromatic,
+    Major,
+    Minor,
+    HarmonicMinor,
+    MelodicMinor,
+    Dorian,
+    Phrygian,
+    Lydian,
+    Mixolydian,
+    Locrian,
+    MajorPentatonic,
+    MinorPentatonic
+}
+
+impl Scale {
+    /// Gets the semitones above the root that are in the scale, lowest first
+    pub fn get_intervals(&self) -> &'static [u8] {
+        match self {
+            Scale::Chromatic       => &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11],
+            Scale::Major           => &[0, 2, 4, 5, 7, 9, 11],
+            Scale::Minor           => &[0, 2, 3, 5, 7, 8, 10],
+            Scale::HarmonicMinor   => &[0, 2, 3, 5, 7, 8, 11],
+            Scale::MelodicMinor    => &[0, 2, 3, 5, 7, 9, 11],
+            Scale::Dorian          => &[0, 2, 3, 5, 7, 9, 10],
+            Scale::Phrygian        => &[0, 1, 3, 5, 7, 8, 10],
+            Scale::Lydian          => &[0, 2, 4, 6, 7, 9, 11],
+            Scale::Mixolydian      => &[0, 2, 4, 5, 7, 9, 10],
+            Scale::Locrian         => &[0, 1, 3, 5, 6, 8, 10],
+            Scale::MajorPentatonic => &[0, 2, 4, 7, 9],
+            Scale::MinorPentatonic => &[0, 3, 5, 7, 10]
+        }
+    }
+
+    /// Gets the tones in the scale when it starts on `root`
+    pub fn get_tones(&self, root: Tone) -> Vec<Tone> {
+        self.get_intervals().iter().map(|interval| {
+            Tone::from_u8((root as u8 + interval) % 12).expect("Whoops! Tone out of range")
+        })
+        .collect()
+    }
+
+    /// Gets the intervals of a mode of the scale. Mode 0 is the scale itself, mode 1 starts on
+    /// its second degree and so on, e.g. mode 1 of `Major` is `Dorian`
+    pub fn get_mode_intervals(&self, mode: usize) -> Vec<u8> {
+        let intervals = self.get_intervals();
+        let mode = mode % intervals.len();
+        let mode_root = intervals[mode];
+        intervals.iter().cycle().skip(mode).take(intervals.len()).map(|interval| {
+            (interval + SEMITONES_PER_OCTAVE as u8 - mode_root) % SEMITONES_PER_OCTAVE as u8
+        })
+        .collect()
+    }
+
+    /// Gets one octave of the scale going up from `root`
+    pub fn get_notes(&self, root: Note) -> Vec<Note> {
+        self.get_mode_notes(root, 0)
+    }
+
+    /// Gets one octave of a mode of the scale going up from `root`
+    pub fn get_mode_notes(&self, root: Note, mode: usize) -> Vec<Note> {
+        self.get_mode_intervals(mode).iter().map(|interval| root.transpose(*interval as i32)).collect()
+    }
+}
+
+/// Musical intervals up to an octave
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Interval {
+    Unison,
+    MinorSecond,
+    MajorSecond,
+    MinorThird,
+    MajorThird,
+    PerfectFourth,
+    Tritone,
+    PerfectFifth,
+    MinorSixth,
+    MajorSixth,
+    MinorSeventh,
+    MajorSeventh,
+    Octave
+}
+
+impl Interval {
+    pub fn get_semitones(&self) -> i32 {
+        match self {
+            Interval::Unison        => 0,
+            Interval::MinorSecond   => 1,
+            Interval::MajorSecond   => 2,
+            Interval::MinorThird    => 3,
+            Interval::MajorThird    => 4,
+            Interval::PerfectFourth => 5,
+            Interval::Tritone       => 6,
+            Interval::PerfectFifth  => 7,
+            Interval::MinorSixth    => 8,
+            Interval::MajorSixth    => 9,
+            Interval::MinorSeventh  => 10,
+            Interval::MajorSeventh  => 11,
+            Interval::Octave        => 12
+        }
+    }
+}
+
+/// Kinds of chord, each described by the semitones above its root that are in it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ChordQuality {
+    Major,
+    Minor,
+    Diminished,
+    Augmented,
+    Suspended2,
+    Suspended4,
+    Major7,
+    Minor7,
+    Dominant7,
+    HalfDiminished7,
+    Diminished7,
+    MinorMajor7
+}
+
+impl ChordQuality {
+    /// Gets the semitones above the root that are in the chord, lowest first
+    pub fn get_intervals(&self) -> &'static [u8] {
+        match self {
+            ChordQuality::Major           => &[0, 4, 7],
+            ChordQuality::Minor           => &[0, 3, 7],
+            ChordQuality::Diminished      => &[0, 3, 6],
+            ChordQuality::Augmented       => &[0, 4, 8],
+            ChordQuality::Suspended2      => &[0, 2, 7],
+            ChordQuality::Suspended4      => &[0, 5, 7],
+            ChordQuality::Major7          => &[0, 4, 7, 11],
+            ChordQuality::Minor7          => &[0, 3, 7, 10],
+            ChordQuality::Dominant7       => &[0, 4, 7, 10],
+            ChordQuality::HalfDiminished7 => &[0, 3, 6, 10],
+            ChordQuality::Diminished7     => &[0, 3, 6, 9],
+            ChordQuality::MinorMajor7     => &[0, 3, 7, 11]
+        }
+    }
+}
+
+/// A chord built up from a root note, optionally inverted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Chord {
+    root: Note,
+    quality: ChordQuality,
+    inversion: usize
+}
+
+impl Chord {
+    pub fn new(root: Note, quality: ChordQuality) -> Self {
+        let inversion = 0;
+        Self { root, quality, inversion }
+    }
+
+    pub fn get_root(&self) -> Note {
+        self.root
+    }
+
+    pub fn get_quality(&self) -> ChordQuality {
+        self.quality
+    }
+
+    /// Sets how many of the lowest notes are moved up an octave. 0 is root position
+    pub fn set_inversion(&mut self, inversion: usize) -> SynthResult<()> {
+        let note_count = self.quality.get_intervals().len();
+        if inversion >= note_count {
+            let msg = format!("A chord with {} notes can't have inversion {}", note_count, inversion);
+            return Err(SynthError::new(&msg));
+        }
+        self.inversion = inversion;
+        Ok(())
+    }
+
+    pub fn get_inversion(&self) -> usize {
+        self.inversion
+    }
+
+    /// Gets the notes of the chord, lowest first
+    pub fn get_notes(&self) -> Vec<Note> {
+        let intervals = self.quality.get_intervals();
+        let mut notes: Vec<Note> = intervals.iter().map(|interval| self.root.transpose(*interval as i32)).collect();
+        notes.rotate_left(self.inversion);
+        let note_count = notes.len();
+        for note in notes[note_count - self.inversion..].iter_mut() {
+            *note = note.transpose(SEMITONES_PER_OCTAVE);
+        }
+        notes
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Note {
     pub octave: i8,
@@ -79,6 +299,46 @@ impl Note {
         Self { octave, tone }
     }
 
+    pub fn to_midi_note(&self) -> SynthResult<u8> {
+        let semitones = self.get_semitones();
+        if !(0..=MAX_MIDI_NOTE).contains(&semitones) {
+            let msg = format!("{} is out of the MIDI note range", self);
+            return Err(SynthError::new(&msg));
+        }
+        Ok(semitones as u8)
+    }
+
+    /// Like a MIDI note number but without the range limit, so it can be negative or above 127
+    fn get_semitones(&self) -> i32 {
+        let tone_from_c = (self.tone as i32 - MIDI_NOTE_BASE_TONE_OFFSET as i32).rem_euclid(SEMITONES_PER_OCTAVE);
+        (self.octave as i32 - MIDI_NOTE_BASE_OCTAVE as i32) * SEMITONES_PER_OCTAVE + tone_from_c
+    }
+
+    fn from_semitones(semitones: i32) -> Self {
+        let octave = (semitones.div_euclid(SEMITONES_PER_OCTAVE) + MIDI_NOTE_BASE_OCTAVE as i32) as i8;
+        let tone_index = (semitones + MIDI_NOTE_BASE_TONE_OFFSET as i32).rem_euclid(SEMITONES_PER_OCTAVE) as u8;
+        let tone = Tone::from_u8(tone_index).expect("Whoops! Tone out of range");
+        Self { octave, tone }
+    }
+
+    /// Moves the note up by some number of semitones. Negative numbers move it down
+    pub fn transpose(&self, semitones: i32) -> Self {
+        Self::from_semitones(self.get_semitones() + semitones)
+    }
+
+    pub fn transpose_up(&self, interval: Interval) -> Self {
+        self.transpose(interval.get_semitones())
+    }
+
+    pub fn transpose_down(&self, interval: Interval) -> Self {
+        self.transpose(-interval.get_semitones())
+    }
+
+    /// Gets the number of semitones from this note up to `other`. Negative if `other` is lower
+    pub fn semitones_to(&self, other: &Note) -> i32 {
+        other.get_semitones() - self.get_semitones()
+    }
+
     pub fn to_freq(&self) -> f32 {
         let default_freq = match self.tone {
             Tone::A      => FREQ_A,
@@ -103,27 +363,109 @@ impl Note {
         default_freq * freq_shift_degree
     }
 
-    /// A frequency squished to a value between 0.0 and 1.0 with NORMALIZATION_FREQ representing 1.0
-    pub fn to_freq_normalized(&self) -> f32 {
-        let freq = self.to_freq();
-        (freq / NORMALIZATION_FREQ).min(1.0)
+    /// Gets the note as a pitch signal, where C4 is 0.0 and each octave is 1.0
+    pub fn to_pitch(&self) -> f32 {
+        semitones_to_pitch(PITCH_REFERENCE_NOTE.semitones_to(self) as f32)
+    }
+
+    /// Gets the nearest note to a pitch signal
+    pub fn from_pitch(pitch: f32) -> Self {
+        PITCH_REFERENCE_NOTE.transpose(pitch_to_semitones(pitch).round() as i32)
+    }
+}
+
+impl fmt::Display for Note {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.tone.get_name(), self.octave)
     }
+}
+
+impl FromStr for Note {
+    type Err = SynthError;
+
+    /// Parses notes in scientific pitch notation like "C4", "C#4", "Bb3" or "A-1"
+    fn from_str(s: &str) -> SynthResult<Self> {
+        let invalid_note = || {
+            let msg = format!("Failed to parse note \"{}\"", s);
+            SynthError::new(&msg)
+        };
+
+        let mut chars = s.trim().chars().peekable();
+        let tone = match chars.next().map(|c| c.to_ascii_uppercase()) {
+            Some('A') => Tone::A,
+            Some('B') => Tone::B,
+            Some('C') => Tone::C,
+            Some('D') => Tone::D,
+            Some('E') => Tone::E,
+            Some('F') => Tone::F,
+            Some('G') => Tone::G,
+            _ => return Err(invalid_note())
+        };
 
-    pub fn normalized_to_coefficient(normalized: f32) -> f32 {
-        normalized * NORMALIZATION_OCTAVES
+        let mut accidental = 0;
+        while let Some(c) = chars.peek() {
+            match c {
+                '#' => accidental += 1,
+                'b' => accidental -= 1,
+                _ => break
+            }
+            chars.next();
+        }
+
+        let octave_str: String = chars.collect();
+        let octave: i8 = octave_str.parse().map_err(|_| invalid_note())?;
+        Ok(Note::new(octave, tone).transpose(accidental))
     }
 }
 
+/// Turns a frequency in hertz into a pitch signal. 
+/// Pitch signals are 0.0 at `PITCH_REFERENCE_FREQ` and go up by 1.0 per octave
+pub fn freq_to_pitch(freq: f32) -> f32 {
+    (freq / PITCH_REFERENCE_FREQ).log2()
+}
+
+/// Turns a pitch signal into a frequency in hertz
+pub fn pitch_to_freq(pitch: f32) -> f32 {
+    PITCH_REFERENCE_FREQ * 2_f32.powf(pitch)
+}
+
+/// Gets how far a number of semitones moves a pitch signal
+pub fn semitones_to_pitch(semitones: f32) -> f32 {
+    semitones / SEMITONES_PER_OCTAVE as f32
+}
+
+pub fn pitch_to_semitones(pitch: f32) -> f32 {
+    pitch * SEMITONES_PER_OCTAVE as f32
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Hash)]
 pub struct NoteInterval {
     pub note: Note,
     pub start: Option<usize>,
-    pub end: Option<usize>
+    pub end: Option<usize>,
+    /// MIDI velocity the note was played with
+    pub velocity: u8,
+    /// MIDI velocity the note was released with, if it was released in this interval
+    pub release_velocity: Option<u8>
 }
 
 impl NoteInterval {
     pub const fn new(note: Note, start: Option<usize>, end: Option<usize>) -> Self {
-        NoteInterval {note, start, end }
+        Self::with_velocity(note, start, end, MAX_VELOCITY)
+    }
+
+    pub const fn with_velocity(note: Note, start: Option<usize>, end: Option<usize>, velocity: u8) -> Self {
+        NoteInterval { note, start, end, velocity, release_velocity: None }
+    }
+
+    /// Velocity squished to a value between 0.0 and 1.0
+    pub fn get_velocity_normalized(&self) -> f32 {
+        self.velocity as f32 / MAX_VELOCITY as f32
+    }
+
+    /// Release velocity squished to a value between 0.0 and 1.0
+    pub fn get_release_velocity_normalized(&self) -> Option<f32> {
+        self.release_velocity.map(|release_velocity| release_velocity as f32 / MAX_VELOCITY as f32)
     }
 
     pub fn overlaps(&self, other: &NoteInterval) -> bool {
@@ -152,6 +494,7 @@ impl NoteInterval {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::prelude::*;
 
     #[test]
     fn note_from_midi_note() {
@@ -168,5 +511,90 @@ mod tests {
         assert_eq!(note, Note{ octave: -1, tone: Tone::C });
     }
 
+    #[test]
+    fn note_to_midi_note() {
+        for midi_note in 0..=127 {
+            assert_eq!(Note::from_midi_note(midi_note).to_midi_note().unwrap(), midi_note);
+        }
+        assert!(Note::new(-2, Tone::B).to_midi_note().is_err());
+        assert!(Note::new(9, Tone::GSharp).to_midi_note().is_err());
+    }
+
+    #[test]
+    fn parse_note() {
+        assert_eq!("C#4".parse::<Note>().unwrap(), Note::new(4, Tone::CSharp));
+        assert_eq!("Bb3".parse::<Note>().unwrap(), Note::new(3, Tone::ASharp));
+        assert_eq!("a-1".parse::<Note>().unwrap(), Note::new(-1, Tone::A));
+        // Accidentals can cross into the next octave
+        assert_eq!("Cb4".parse::<Note>().unwrap(), Note::new(3, Tone::B));
+        assert_eq!("B#3".parse::<Note>().unwrap(), Note::new(4, Tone::C));
+
+        assert!("H4".parse::<Note>().is_err());
+        assert!("C".parse::<Note>().is_err());
+        assert!("C#x".parse::<Note>().is_err());
+
+        let note = Note::new(5, Tone::FSharp);
+        assert_eq!(note.to_string().parse::<Note>().unwrap(), note);
+    }
+
+    #[test]
+    fn transpose_note() {
+        let note = Note::new(4, Tone::A);
+        assert_eq!(note.transpose(3), Note::new(5, Tone::C));
+        assert_eq!(note.transpose(-10), Note::new(3, Tone::B));
+        assert_eq!(note.transpose_up(Interval::PerfectFifth), Note::new(5, Tone::E));
+        assert_eq!(note.transpose_down(Interval::Octave), Note::new(3, Tone::A));
+        assert_eq!(note.semitones_to(&Note::new(5, Tone::C)), 3);
+    }
+
+    #[test]
+    fn scale_modes() {
+        assert_eq!(Scale::Major.get_mode_intervals(1), Scale::Dorian.get_intervals());
+        assert_eq!(Scale::Major.get_mode_intervals(5), Scale::Minor.get_intervals());
+
+        let notes = Scale::Major.get_mode_notes(Note::new(4, Tone::D), 1);
+        let expected: Vec<Note> = ["D4", "E4", "F4", "G4", "A4", "B4", "C5"].iter()
+            .map(|name| name.parse().unwrap())
+            .collect();
+        assert_eq!(notes, expected);
+    }
+
+    #[test]
+    fn chords() {
+        let mut chord = Chord::new(Note::new(4, Tone::C), ChordQuality::Dominant7);
+        let expected: Vec<Note> = ["C4", "E4", "G4", "A#4"].iter().map(|name| name.parse().unwrap()).collect();
+        assert_eq!(chord.get_notes(), expected);
+
+        chord.set_inversion(2).unwrap();
+        let expected: Vec<Note> = ["G4", "A#4", "C5", "E5"].iter().map(|name| name.parse().unwrap()).collect();
+        assert_eq!(chord.get_notes(), expected);
+
+        assert!(chord.set_inversion(4).is_err());
+    }
+
+    #[test]
+    fn pitch_conversion() {
+        assert_eq!(Note::new(4, Tone::C).to_pitch(), 0.0);
+        assert_eq!(Note::new(5, Tone::C).to_pitch(), 1.0);
+        assert_eq!(Note::new(3, Tone::FSharp).to_pitch(), -0.5);
+        assert_eq!(Note::from_pitch(0.26), Note::new(4, Tone::DSharp));
+
+        for midi_note in 0..=127 {
+            let note = Note::from_midi_note(midi_note);
+            let freq = pitch_to_freq(note.to_pitch());
+            assert!(float_eq(freq, note.to_freq(), note.to_freq() * 0.0001), "{} should be {}hz. Got {}hz", note, note.to_freq(), freq);
+            assert!(float_eq(freq_to_pitch(freq), note.to_pitch(), 0.0001));
+        }
+    }
+
+    #[test]
+    fn scale_tones() {
+        let tones = Scale::Major.get_tones(Tone::G);
+        assert_eq!(tones, vec![Tone::G, Tone::A, Tone::B, Tone::C, Tone::D, Tone::E, Tone::FSharp]);
+
+        let tones = Scale::MinorPentatonic.get_tones(Tone::A);
+        assert_eq!(tones, vec![Tone::A, Tone::C, Tone::D, Tone::E, Tone::G]);
+    }
+
 
 }
\ No newline at end of file
@@ -0,0 +1,418 @@
+mod table;
+
+pub use table::{Table, Value};
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::module::{
+    Patch, NodeId, ConnectionKind, PatchModule, Output,
+    Oscillator, Envelope, Mixer, Sequencer, Attenuverter, Compressor, Vca, MidiModuleBase, MidiNoteOutput
+};
+use crate::{SynthError, SynthResult};
+
+/// The newest version of the patch file format. Anything newer can't be loaded
+pub const PATCH_FILE_VERSION: usize = 1;
+
+const CONNECTION_KINDS: [(&str, ConnectionKind); 3] = [
+    ("Direct", ConnectionKind::Direct),
+    ("BlockFeedback", ConnectionKind::BlockFeedback),
+    ("SampleFeedback", ConnectionKind::SampleFeedback)
+];
+
+/// A module's type and settings as they're written in a patch file
+pub struct ModuleSettings {
+    pub module_type: &'static str,
+    pub settings: Table,
+    /// The MIDI source the module reads from. It's saved separately so modules can share it
+    pub midi_source: Option<Rc<MidiModuleBase>>
+}
+
+impl ModuleSettings {
+    pub fn new(module_type: &'static str, settings: Table) -> Self {
+        let midi_source = None;
+        Self { module_type, settings, midi_source }
+    }
+
+    pub fn with_midi_source(mut self, midi_source: Rc<MidiModuleBase>) -> Self {
+        self.midi_source = Some(midi_source);
+        self
+    }
+}
+
+/// Makes a module from its type and settings in a patch file
+fn load_module(
+    module_type: &str, settings: &Table, midi_sources: &HashMap<String, Rc<MidiModuleBase>>
+) -> SynthResult<Box<dyn PatchModule>> {
+    let module: Box<dyn PatchModule> = match module_type {
+        "Oscillator" => Box::new(Oscillator::load_settings(settings)?),
+        "Envelope" => Box::new(Envelope::load_settings(settings)?),
+        "Mixer" => Box::new(Mixer::load_settings(settings)?),
+        "Sequencer" => Box::new(Sequencer::load_settings(settings)?),
+        "Attenuverter" => Box::new(Attenuverter::load_settings(settings)?),
+        "Compressor" => Box::new(Compressor::load_settings(settings)?),
+        "Vca" => Box::new(Vca::load_settings(settings)?),
+        "MidiNoteOutput" => Box::new(MidiNoteOutput::load_settings(settings, midi_sources)?),
+        _ => {
+            let msg = format!("Unknown module type \"{}\"", module_type);
+            return Err(SynthError::new(&msg));
+        }
+    };
+    Ok(module)
+}
+
+/// Puts what went wrong in front of an error
+fn with_context<T>(result: SynthResult<T>, context: &str) -> SynthResult<T> {
+    result.map_err(|err| {
+        let msg = format!("{}: {}", context, err);
+        SynthError::new(&msg)
+    })
+}
+
+fn get_required_str<'a>(table: &'a Table, key: &str) -> SynthResult<&'a str> {
+    match table.get_str(key)? {
+        Some(value) => Ok(value),
+        None => {
+            let msg = format!("Missing \"{}\"", key);
+            Err(SynthError::new(&msg))
+        }
+    }
+}
+
+/// Gets the tables in an array of tables. It's fine for there to be none
+fn get_tables<'a>(table: &'a Table, key: &str) -> SynthResult<Vec<&'a Table>> {
+    Ok(table.get_array_of(key, "a table", Value::as_table)?.unwrap_or_default())
+}
+
+fn check_version(file: &Table) -> SynthResult<()> {
+    let version = match file.get_usize("version")? {
+        Some(version) => version,
+        None => return Err(SynthError::new("Patch file has no version"))
+    };
+    if version == 0 || version > PATCH_FILE_VERSION {
+        let msg = format!(
+            "Patch file version {} isn't supported. The newest supported version is {}",
+            version, PATCH_FILE_VERSION
+        );
+        return Err(SynthError::new(&msg));
+    }
+    Ok(())
+}
+
+/// Writes a patch in the patch file format. The output module's settings are saved too if it's given.
+/// Fails if the patch has a node whose module type can't be saved
+pub fn save_patch(patch: &Patch, output: Option<&Output>) -> SynthResult<String> {
+    let mut file = Table::new();
+    file.set("version", PATCH_FILE_VERSION);
+
+    let mut output_table = Table::new();
+    if let Some((node, port)) = patch.get_output() {
+        output_table.set("node", patch.get_node_name(node).unwrap());
+        output_table.set("port", patch.get_output_names(node)?.swap_remove(port));
+    }
+    if let Some(output) = output {
+        for (key, value) in output.save_settings().iter() {
+            output_table.set(key, value.clone());
+        }
+    }
+    if !output_table.is_empty() {
+        file.set("output", output_table);
+    }
+
+    // Every MIDI source is saved once no matter how many nodes read from it
+    let mut midi_sources: Vec<Rc<MidiModuleBase>> = Vec::new();
+    let mut midi_tables = Vec::new();
+    let mut nodes = Vec::new();
+    for node in patch.get_node_ids() {
+        let name = patch.get_node_name(node).unwrap();
+        let module_settings = match patch.get_module(node).and_then(|module| module.save_settings()) {
+            Some(module_settings) => module_settings,
+            None => {
+                let msg = format!("Node \"{}\" can't be saved because patch files don't support its module type", name);
+                return Err(SynthError::new(&msg));
+            }
+        };
+        let mut node_table = Table::new();
+        node_table.set("name", name);
+        node_table.set("type", module_settings.module_type);
+        if let Some(midi_source) = module_settings.midi_source {
+            let index = match midi_sources.iter().position(|saved| Rc::ptr_eq(saved, &midi_source)) {
+                Some(index) => index,
+                None => {
+                    let mut midi_table = Table::new();
+                    midi_table.set("name", midi_source_name(midi_sources.len()));
+                    for (key, value) in midi_source.save_settings().iter() {
+                        midi_table.set(key, value.clone());
+                    }
+                    midi_tables.push(Value::Table(midi_table));
+                    midi_sources.push(midi_source);
+                    midi_sources.len() - 1
+                }
+            };
+            node_table.set("midi", midi_source_name(index));
+        }
+        for (key, value) in module_settings.settings.iter() {
+            node_table.set(key, value.clone());
+        }
+        nodes.push(Value::Table(node_table));
+    }
+    if !midi_tables.is_empty() {
+        file.set("midi", midi_tables);
+    }
+    file.set("node", nodes);
+
+    let mut connections = Vec::new();
+    for connection in patch.get_connections() {
+        let mut connection_table = Table::new();
+        connection_table.set("from", patch.get_node_name(connection.from).unwrap());
+        connection_table.set("output", patch.get_output_names(connection.from)?.swap_remove(connection.output));
+        connection_table.set("to", patch.get_node_name(connection.to).unwrap());
+        connection_table.set("input", patch.get_input_names(connection.to)?.swap_remove(connection.input));
+        connection_table.set_choice("kind", connection.kind, &CONNECTION_KINDS);
+        connections.push(Value::Table(connection_table));
+    }
+    file.set("connection", connections);
+
+    Ok(file.write())
+}
+
+/// Reads a patch from the patch file format. If an output module is given it gets the output
+/// settings in the file
+pub fn load_patch(text: &str, output: Option<&mut Output>) -> SynthResult<Patch> {
+    let file = Table::parse(text)?;
+    check_version(&file)?;
+    with_context(file.check_keys(&["version", "output", "midi", "node", "connection"]), "Patch file")?;
+
+    let mut midi_sources = HashMap::new();
+    for (midi_index, midi_table) in get_tables(&file, "midi")?.into_iter().enumerate() {
+        let name = with_context(get_required_str(midi_table, "name"), &format!("MIDI source {}", midi_index))?;
+        let context = format!("MIDI source \"{}\"", name);
+        if midi_sources.contains_key(name) {
+            let msg = format!("There's more than one MIDI source named \"{}\"", name);
+            return Err(SynthError::new(&msg));
+        }
+        let mut settings = midi_table.clone();
+        settings.remove("name");
+        let midi_source = with_context(MidiModuleBase::load_settings(&settings), &context)?;
+        midi_sources.insert(name.to_string(), Rc::new(midi_source));
+    }
+
+    let mut patch = Patch::new();
+    for (node_index, node_table) in get_tables(&file, "node")?.into_iter().enumerate() {
+        let name = with_context(get_required_str(node_table, "name"), &format!("Node {}", node_index))?;
+        let context = format!("Node \"{}\"", name);
+        let module_type = with_context(get_required_str(node_table, "type"), &context)?;
+        let mut settings = node_table.clone();
+        settings.remove("name");
+        settings.remove("type");
+        let module = with_context(load_module(module_type, &settings, &midi_sources), &context)?;
+        with_context(patch.add_boxed_node(name, module), &context)?;
+    }
+
+    for (connection_index, connection_table) in get_tables(&file, "connection")?.into_iter().enumerate() {
+        let context = format!("Connection {}", connection_index);
+        with_context(connection_table.check_keys(&["from", "output", "to", "input", "kind"]), &context)?;
+        let from_name = with_context(get_required_str(connection_table, "from"), &context)?;
+        let output_name = with_context(get_required_str(connection_table, "output"), &context)?;
+        let to_name = with_context(get_required_str(connection_table, "to"), &context)?;
+        let input_name = with_context(get_required_str(connection_table, "input"), &context)?;
+        let kind = with_context(connection_table.get_choice("kind", &CONNECTION_KINDS), &context)?;
+
+        let context = format!(
+            "Connection from \"{}\" \"{}\" to \"{}\" \"{}\"", from_name, output_name, to_name, input_name
+        );
+        let from = with_context(find_node(&patch, from_name), &context)?;
+        let to = with_context(find_node(&patch, to_name), &context)?;
+        let connected = match kind.unwrap_or(ConnectionKind::Direct) {
+            ConnectionKind::Direct => patch.connect(from, output_name, to, input_name),
+            ConnectionKind::BlockFeedback => patch.connect_feedback(from, output_name, to, input_name),
+            ConnectionKind::SampleFeedback => patch.connect_sample_feedback(from, output_name, to, input_name)
+        };
+        with_context(connected, &context)?;
+    }
+
+    if let Some(output_table) = file.get_table("output")? {
+        let mut output_settings = output_table.clone();
+        if let Some(node_name) = with_context(output_table.get_str("node"), "Output")? {
+            let port_name = with_context(output_table.get_str("port"), "Output")?.unwrap_or("out");
+            let node = with_context(find_node(&patch, node_name), "Output")?;
+            with_context(patch.set_output(node, port_name), "Output")?;
+        }
+        output_settings.remove("node");
+        output_settings.remove("port");
+        if let Some(output) = output {
+            with_context(output.apply_settings(&output_settings), "Output")?;
+        }
+    }
+
+    Ok(patch)
+}
+
+/// The name a MIDI source is saved under
+fn midi_source_name(index: usize) -> String {
+    format!("midi {}", index)
+}
+
+fn find_node(patch: &Patch, name: &str) -> SynthResult<NodeId> {
+    match patch.get_node_id(name) {
+        Some(node) => Ok(node),
+        None => {
+            let msg = format!("There's no node named \"{}\"", name);
+            Err(SynthError::new(&msg))
+        }
+    }
+}
+
+/// Saves a patch to a file. See `save_patch`
+pub fn save_patch_file<P: AsRef<Path>>(path: P, patch: &Patch, output: Option<&Output>) -> SynthResult<()> {
+    let text = save_patch(patch, output)?;
+    match std::fs::write(path.as_ref(), text) {
+        Ok(()) => Ok(()),
+        Err(err) => {
+            let msg = format!("Failed to write patch file {}: {}", path.as_ref().display(), err);
+            Err(SynthError::new(&msg))
+        }
+    }
+}
+
+/// Loads a patch from a file. See `load_patch`
+pub fn load_patch_file<P: AsRef<Path>>(path: P, output: Option<&mut Output>) -> SynthResult<Patch> {
+    let text = match std::fs::read_to_string(path.as_ref()) {
+        Ok(text) => text,
+        Err(err) => {
+            let msg = format!("Failed to read patch file {}: {}", path.as_ref().display(), err);
+            return Err(SynthError::new(&msg));
+        }
+    };
+    load_patch(&text, output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+    use crate::module::{
+        MixerInput, MidiModuleBase, MidiNoteSignal, Waveform, EnvelopeCurve, SequencerDirection, SongEntry, VcaMode
+    };
+    use crate::util::test_util;
+
+    fn create_patch() -> Patch {
+        let mut patch = Patch::new();
+
+        let midi_source = Rc::new(MidiModuleBase::open(test_util::get_test_midi_file_path()).unwrap());
+        let pitch = patch.add_node("pitch", MidiNoteOutput::new(midi_source.clone())).unwrap();
+        let velocity = patch.add_node("velocity", MidiNoteOutput::with_signal(midi_source, MidiNoteSignal::Velocity)).unwrap();
+
+        let mut sequencer = Sequencer::with_steps(4);
+        sequencer.set_direction(SequencerDirection::Pendulum);
+        sequencer.get_step_info_mut(1).unwrap().value = 0.5;
+        sequencer.get_step_info_mut(2).unwrap().ratchets = 3;
+        let lane = sequencer.add_lane();
+        sequencer.set_lane_value(lane, 3, -1.0).unwrap();
+        sequencer.add_pattern_with_steps("fill", 2).unwrap();
+        sequencer.set_song(vec![SongEntry::new("default", 2), SongEntry::new("fill", 1)]).unwrap();
+        let sequencer = patch.add_node("sequencer", sequencer).unwrap();
+
+        let mut oscillator = Oscillator::new();
+        oscillator.set_waveform(Waveform::Saw);
+        oscillator.set_frequency(220.0);
+        let oscillator = patch.add_node("oscillator", oscillator).unwrap();
+
+        let mut envelope = Envelope::new();
+        envelope.set_attack_time(20.0);
+        envelope.set_decay_curve(EnvelopeCurve::Custom(2.5));
+        let envelope = patch.add_node("envelope", envelope).unwrap();
+
+        let mut vca = Vca::with_mode(VcaMode::Exponential);
+        vca.set_control_gain(0.5);
+        let vca = patch.add_node("vca", vca).unwrap();
+
+        let mut mixer = Mixer::new();
+        let mut quiet_input = MixerInput::new();
+        quiet_input.set_level(0.25);
+        mixer.add_input(MixerInput::new());
+        mixer.add_input(quiet_input);
+        let mixer = patch.add_node("mixer", mixer).unwrap();
+
+        let mut compressor = Compressor::new();
+        compressor.set_threshold(-12.0);
+        let compressor = patch.add_node("compressor", compressor).unwrap();
+
+        patch.connect(pitch, "pitch", oscillator, "exponential freq").unwrap();
+        patch.connect(velocity, "velocity", envelope, "velocity").unwrap();
+        patch.connect(sequencer, "gate", envelope, "trigger").unwrap();
+        patch.connect(oscillator, "out", vca, "signal").unwrap();
+        patch.connect(envelope, "out", vca, "control").unwrap();
+        patch.connect(vca, "out", mixer, "in 0").unwrap();
+        patch.connect(compressor, "out", mixer, "in 1").unwrap();
+        patch.connect_feedback(mixer, "out", compressor, "signal").unwrap();
+        patch.set_output(mixer, "out").unwrap();
+        patch
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let patch = create_patch();
+        let mut output = Output::new();
+        output.set_volume(0.5);
+        output.set_panning(-0.25);
+        let text = save_patch(&patch, Some(&output)).unwrap();
+
+        let mut loaded_output = Output::new();
+        let loaded = load_patch(&text, Some(&mut loaded_output)).unwrap();
+        assert_eq!(loaded.node_count(), patch.node_count());
+        assert_eq!(loaded.get_connections().len(), patch.get_connections().len());
+        let mixer = loaded.get_node_id("mixer").unwrap();
+        let compressor = loaded.get_node_id("compressor").unwrap();
+        let feedback = loaded.get_connection(compressor, "signal").unwrap().unwrap();
+        assert_eq!((feedback.from, feedback.kind), (mixer, ConnectionKind::BlockFeedback));
+        assert_eq!(loaded.get_output(), Some((mixer, 0)));
+        assert_eq!(loaded_output.get_volume(), 0.5);
+        assert_eq!(loaded_output.get_panning(), -0.25);
+
+        // Saving what was loaded should give back the same file
+        let saved_again = save_patch(&loaded, Some(&loaded_output)).unwrap();
+        assert_eq!(text, saved_again);
+    }
+
+    #[test]
+    fn test_midi_sources() {
+        // The pitch and velocity outputs share a source, so it's saved once and both refer to it.
+        // Saving again only finds one source if the loaded outputs still share it
+        let text = save_patch(&create_patch(), None).unwrap();
+        assert_eq!(text.matches("[[midi]]").count(), 1, "Expected one MIDI source in:\n{}", text);
+        assert_eq!(text.matches("midi = \"midi 0\"").count(), 2, "Expected both outputs to use the source in:\n{}", text);
+        let saved_again = save_patch(&load_patch(&text, None).unwrap(), None).unwrap();
+        assert_eq!(saved_again.matches("[[midi]]").count(), 1, "Expected the loaded outputs to share their source");
+
+        // Outputs with sources of their own keep them, even if they read the same file
+        let mut patch = Patch::new();
+        for name in ["first", "second"].iter() {
+            let midi_source = Rc::new(MidiModuleBase::open(test_util::get_test_midi_file_path()).unwrap());
+            patch.add_node(name, MidiNoteOutput::new(midi_source)).unwrap();
+        }
+        let text = save_patch(&patch, None).unwrap();
+        assert_eq!(text.matches("[[midi]]").count(), 2, "Expected two MIDI sources in:\n{}", text);
+        assert_eq!(save_patch(&load_patch(&text, None).unwrap(), None).unwrap(), text);
+    }
+
+    #[test]
+    fn test_errors() {
+        let text = save_patch(&create_patch(), None).unwrap();
+        let expect_error = |text: &str, expected: &str| {
+            let err = load_patch(text, None).err().expect("Expected loading the patch to fail").to_string();
+            assert!(err.contains(expected), "Expected an error about {:?} but got {:?}", expected, err);
+        };
+
+        expect_error(&text.replacen("version = 1", "version = 2", 1), "version 2 isn't supported");
+        expect_error(&text.replacen("version = 1\n", "", 1), "has no version");
+        expect_error(&text.replacen("type = \"Envelope\"", "type = \"Theremin\"", 1), "Unknown module type \"Theremin\"");
+        expect_error(&text.replacen("attack_time = 20", "atack_time = 20", 1), "Unknown setting \"atack_time\"");
+        expect_error(&text.replacen("to = \"envelope\"", "to = \"nowhere\"", 1), "no node named \"nowhere\"");
+        expect_error(&text.replacen("input = \"velocity\"", "input = \"volume\"", 1), "volume");
+        expect_error(&text.replacen("kind = \"BlockFeedback\"", "kind = \"Direct\"", 1), "cycle");
+        expect_error(&text.replacen("midi = \"midi 0\"", "midi = \"midi 1\"", 1), "no MIDI source named \"midi 1\"");
+        expect_error(&text.replacen("[[node]]", "[[midi]]\nname = \"midi 0\"\nfile = \"x.mid\"\n\n[[node]]", 1), "more than one MIDI source");
+    }
+}
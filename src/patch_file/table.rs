@@ -0,0 +1,760 @@
+use crate::{SynthError, SynthResult};
+
+/// A value in a patch file
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Bool(bool),
+    Integer(i64),
+    Float(f32),
+    String(String),
+    Array(Vec<Value>),
+    Table(Table)
+}
+
+impl Value {
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(value) => Some(*value),
+            _ => None
+        }
+    }
+
+    pub fn as_integer(&self) -> Option<i64> {
+        match self {
+            Value::Integer(value) => Some(*value),
+            _ => None
+        }
+    }
+
+    /// Gets the value as a float. Integers count too, so "1" works as well as "1.0"
+    pub fn as_float(&self) -> Option<f32> {
+        match self {
+            Value::Float(value) => Some(*value),
+            Value::Integer(value) => Some(*value as f32),
+            _ => None
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(value) => Some(value),
+            _ => None
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(values) => Some(values),
+            _ => None
+        }
+    }
+
+    pub fn as_table(&self) -> Option<&Table> {
+        match self {
+            Value::Table(table) => Some(table),
+            _ => None
+        }
+    }
+
+    /// Checks if this is a non-empty array of tables. Those get their own sections when written
+    fn is_table_array(&self) -> bool {
+        match self {
+            Value::Array(values) => !values.is_empty() && values.iter().all(|value| value.as_table().is_some()),
+            _ => false
+        }
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Bool(value)
+    }
+}
+
+impl From<usize> for Value {
+    fn from(value: usize) -> Self {
+        Value::Integer(value as i64)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(value: i64) -> Self {
+        Value::Integer(value)
+    }
+}
+
+impl From<f32> for Value {
+    fn from(value: f32) -> Self {
+        Value::Float(value)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::String(value.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Value::String(value)
+    }
+}
+
+impl From<Table> for Value {
+    fn from(table: Table) -> Self {
+        Value::Table(table)
+    }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(values: Vec<T>) -> Self {
+        Value::Array(values.into_iter().map(|value| value.into()).collect())
+    }
+}
+
+/// Keys and values in the order they were set. The whole patch file is one of these, as are the
+/// settings of each module in it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Table {
+    entries: Vec<(String, Value)>
+}
+
+impl Table {
+    pub fn new() -> Self {
+        let entries = Vec::new();
+        Self { entries }
+    }
+
+    /// Sets a value, replacing whatever the key had before
+    pub fn set<V: Into<Value>>(&mut self, key: &str, value: V) {
+        let value = value.into();
+        match self.entries.iter_mut().find(|(entry_key, _)| entry_key == key) {
+            Some((_, entry_value)) => *entry_value = value,
+            None => self.entries.push((key.to_string(), value))
+        }
+    }
+
+    /// Sets a value to the name of one of a set of choices
+    pub fn set_choice<T: PartialEq>(&mut self, key: &str, value: T, choices: &[(&str, T)]) {
+        if let Some((name, _)) = choices.iter().find(|(_, choice)| *choice == value) {
+            self.set(key, *name);
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.entries.iter().find(|(entry_key, _)| entry_key == key).map(|(_, value)| value)
+    }
+
+    fn get_mut(&mut self, key: &str) -> Option<&mut Value> {
+        self.entries.iter_mut().find(|(entry_key, _)| entry_key == key).map(|(_, value)| value)
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<Value> {
+        let index = self.entries.iter().position(|(entry_key, _)| entry_key == key)?;
+        Some(self.entries.remove(index).1)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Value)> {
+        self.entries.iter().map(|(key, value)| (key.as_str(), value))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get_bool(&self, key: &str) -> SynthResult<Option<bool>> {
+        self.get_typed(key, "true or false", Value::as_bool)
+    }
+
+    pub fn get_float(&self, key: &str) -> SynthResult<Option<f32>> {
+        self.get_typed(key, "a number", Value::as_float)
+    }
+
+    /// Gets a value that has to be a whole number of zero or more
+    pub fn get_usize(&self, key: &str) -> SynthResult<Option<usize>> {
+        let as_usize = |value: &Value| value.as_integer().filter(|value| *value >= 0).map(|value| value as usize);
+        self.get_typed(key, "a whole number of zero or more", as_usize)
+    }
+
+    pub fn get_str(&self, key: &str) -> SynthResult<Option<&str>> {
+        self.get_typed(key, "a string", Value::as_str)
+    }
+
+    pub fn get_array(&self, key: &str) -> SynthResult<Option<&[Value]>> {
+        self.get_typed(key, "an array", Value::as_array)
+    }
+
+    pub fn get_table(&self, key: &str) -> SynthResult<Option<&Table>> {
+        self.get_typed(key, "a table", Value::as_table)
+    }
+
+    /// Gets a value that has to be the name of one of a set of choices
+    pub fn get_choice<T: Copy>(&self, key: &str, choices: &[(&str, T)]) -> SynthResult<Option<T>> {
+        let name = match self.get_str(key)? {
+            Some(name) => name,
+            None => return Ok(None)
+        };
+        match choices.iter().find(|(choice_name, _)| *choice_name == name) {
+            Some((_, choice)) => Ok(Some(*choice)),
+            None => {
+                let choice_names: Vec<&str> = choices.iter().map(|(choice_name, _)| *choice_name).collect();
+                let msg = format!(
+                    "Setting \"{}\" can't be \"{}\". It has to be one of: {}", key, name, choice_names.join(", ")
+                );
+                Err(SynthError::new(&msg))
+            }
+        }
+    }
+
+    /// Gets an array where every value has to be the same type
+    pub fn get_array_of<'a, T>(
+        &'a self, key: &str, expected: &str, convert: impl Fn(&'a Value) -> Option<T>
+    ) -> SynthResult<Option<Vec<T>>> {
+        let values = match self.get_array(key)? {
+            Some(values) => values,
+            None => return Ok(None)
+        };
+        let mut converted = Vec::with_capacity(values.len());
+        for value in values {
+            match convert(value) {
+                Some(value) => converted.push(value),
+                None => {
+                    let msg = format!("Every value in \"{}\" should be {}", key, expected);
+                    return Err(SynthError::new(&msg));
+                }
+            }
+        }
+        Ok(Some(converted))
+    }
+
+    /// Makes sure every key in the table is one of the given keys, so typos don't get ignored
+    pub fn check_keys(&self, known_keys: &[&str]) -> SynthResult<()> {
+        match self.entries.iter().find(|(key, _)| !known_keys.contains(&key.as_str())) {
+            Some((key, _)) => {
+                let msg = format!("Unknown setting \"{}\"", key);
+                Err(SynthError::new(&msg))
+            }
+            None => Ok(())
+        }
+    }
+
+    fn get_typed<'a, T>(
+        &'a self, key: &str, expected: &str, convert: impl Fn(&'a Value) -> Option<T>
+    ) -> SynthResult<Option<T>> {
+        match self.get(key) {
+            Some(value) => match convert(value) {
+                Some(value) => Ok(Some(value)),
+                None => {
+                    let msg = format!("Setting \"{}\" should be {}", key, expected);
+                    Err(SynthError::new(&msg))
+                }
+            },
+            None => Ok(None)
+        }
+    }
+
+    /// Writes the table in the patch file format. Values come first, then tables and arrays of
+    /// tables get a section each
+    pub fn write(&self) -> String {
+        let mut text = String::new();
+        self.write_section(&mut text, "");
+        text
+    }
+
+    fn write_section(&self, text: &mut String, path: &str) {
+        for (key, value) in self.entries.iter() {
+            if value.as_table().is_none() && !value.is_table_array() {
+                text.push_str(&format!("{} = {}\n", write_key(key), write_value(value)));
+            }
+        }
+        for (key, value) in self.entries.iter() {
+            let section_path = if path.is_empty() {
+                write_key(key)
+            }
+            else {
+                format!("{}.{}", path, write_key(key))
+            };
+            if let Some(table) = value.as_table() {
+                text.push_str(&format!("\n[{}]\n", section_path));
+                table.write_section(text, &section_path);
+            }
+            else if value.is_table_array() {
+                for table in value.as_array().unwrap().iter().filter_map(|value| value.as_table()) {
+                    text.push_str(&format!("\n[[{}]]\n", section_path));
+                    table.write_section(text, &section_path);
+                }
+            }
+        }
+    }
+
+    /// Reads a table written in the patch file format
+    pub fn parse(text: &str) -> SynthResult<Self> {
+        let mut parser = Parser::new(text);
+        parser.parse_document()
+    }
+}
+
+impl Default for Table {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn is_bare_key_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-'
+}
+
+fn write_key(key: &str) -> String {
+    if !key.is_empty() && key.chars().all(is_bare_key_char) {
+        key.to_string()
+    }
+    else {
+        write_string(key)
+    }
+}
+
+fn write_string(string: &str) -> String {
+    let mut written = String::from("\"");
+    for c in string.chars() {
+        match c {
+            '"' => written.push_str("\\\""),
+            '\\' => written.push_str("\\\\"),
+            '\n' => written.push_str("\\n"),
+            '\r' => written.push_str("\\r"),
+            '\t' => written.push_str("\\t"),
+            c if c.is_control() => written.push_str(&format!("\\u{:04X}", c as u32)),
+            c => written.push(c)
+        }
+    }
+    written.push('"');
+    written
+}
+
+fn write_value(value: &Value) -> String {
+    match value {
+        Value::Bool(value) => value.to_string(),
+        Value::Integer(value) => value.to_string(),
+        Value::Float(value) if value.is_nan() => String::from("nan"),
+        Value::Float(value) if value.is_infinite() => String::from(if *value > 0.0 { "inf" } else { "-inf" }),
+        // Debug always has a decimal point or exponent so it reads back as a float
+        Value::Float(value) => format!("{:?}", value),
+        Value::String(value) => write_string(value),
+        Value::Array(values) => {
+            let values: Vec<String> = values.iter().map(write_value).collect();
+            format!("[{}]", values.join(", "))
+        }
+        Value::Table(table) => {
+            let entries: Vec<String> = table.iter()
+                .map(|(key, value)| format!("{} = {}", write_key(key), write_value(value)))
+                .collect();
+            if entries.is_empty() {
+                String::from("{}")
+            }
+            else {
+                format!("{{ {} }}", entries.join(", "))
+            }
+        }
+    }
+}
+
+/// Reads the patch file format, which is a subset of TOML. It has bare and quoted keys,
+/// strings, integers, floats, booleans, arrays, inline tables, `[table]` and `[[array]]` sections
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    line: usize
+}
+
+impl<'a> Parser<'a> {
+    fn new(text: &'a str) -> Self {
+        let chars = text.chars().peekable();
+        let line = 1;
+        Self { chars, line }
+    }
+
+    fn error<T>(&self, msg: &str) -> SynthResult<T> {
+        let msg = format!("Patch file line {}: {}", self.line, msg);
+        Err(SynthError::new(&msg))
+    }
+
+    fn next_char(&mut self) -> Option<char> {
+        let c = self.chars.next();
+        if c == Some('\n') {
+            self.line += 1;
+        }
+        c
+    }
+
+    fn expect(&mut self, expected: char) -> SynthResult<()> {
+        match self.next_char() {
+            Some(c) if c == expected => Ok(()),
+            Some(c) => self.error(&format!("Expected '{}' but found '{}'", expected, c)),
+            None => self.error(&format!("Expected '{}' but the file ended", expected))
+        }
+    }
+
+    /// Skips spaces and tabs
+    fn skip_spaces(&mut self) {
+        while let Some(' ') | Some('\t') = self.chars.peek() {
+            self.next_char();
+        }
+    }
+
+    /// Skips spaces, comments and line breaks
+    fn skip_blank(&mut self) {
+        loop {
+            match self.chars.peek() {
+                Some(' ') | Some('\t') | Some('\r') | Some('\n') => {
+                    self.next_char();
+                }
+                Some('#') => self.skip_comment(),
+                _ => return
+            }
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        while let Some(c) = self.chars.peek() {
+            if *c == '\n' {
+                return;
+            }
+            self.next_char();
+        }
+    }
+
+    /// Makes sure nothing but a comment follows on the line
+    fn end_line(&mut self) -> SynthResult<()> {
+        self.skip_spaces();
+        if let Some('#') = self.chars.peek() {
+            self.skip_comment();
+        }
+        match self.chars.peek() {
+            None | Some('\n') => Ok(()),
+            Some('\r') => {
+                self.next_char();
+                self.end_line()
+            }
+            Some(c) => {
+                let c = *c;
+                self.error(&format!("Expected the line to end but found '{}'", c))
+            }
+        }
+    }
+
+    fn parse_document(&mut self) -> SynthResult<Table> {
+        let mut root = Table::new();
+        // Path to the section that values are going into
+        let mut section: Vec<String> = Vec::new();
+        loop {
+            self.skip_blank();
+            match self.chars.peek() {
+                None => return Ok(root),
+                Some('[') => {
+                    self.next_char();
+                    let is_array = self.chars.peek() == Some(&'[');
+                    if is_array {
+                        self.next_char();
+                    }
+                    section = self.parse_header_path()?;
+                    self.expect(']')?;
+                    if is_array {
+                        self.expect(']')?;
+                    }
+                    self.end_line()?;
+                    self.open_section(&mut root, &section, is_array)?;
+                }
+                Some(_) => {
+                    let (key, value) = self.parse_key_value()?;
+                    self.end_line()?;
+                    let table = self.find_section(&mut root, &section)?;
+                    if table.get(&key).is_some() {
+                        return self.error(&format!("\"{}\" is set more than once", key));
+                    }
+                    table.set(&key, value);
+                }
+            }
+        }
+    }
+
+    fn parse_header_path(&mut self) -> SynthResult<Vec<String>> {
+        let mut path = Vec::new();
+        loop {
+            self.skip_spaces();
+            path.push(self.parse_key()?);
+            self.skip_spaces();
+            match self.chars.peek() {
+                Some('.') => {
+                    self.next_char();
+                }
+                _ => return Ok(path)
+            }
+        }
+    }
+
+    /// Creates the table a section header names
+    fn open_section(&self, root: &mut Table, path: &[String], is_array: bool) -> SynthResult<()> {
+        let (last_key, parent_path) = path.split_last().unwrap();
+        let parent = self.find_section(root, parent_path)?;
+        match (parent.get_mut(last_key), is_array) {
+            (None, false) => parent.set(last_key, Table::new()),
+            (None, true) => parent.set(last_key, Value::Array(vec![Value::Table(Table::new())])),
+            (Some(Value::Array(tables)), true) => tables.push(Value::Table(Table::new())),
+            _ => return self.error(&format!("\"{}\" is defined more than once", path.join(".")))
+        }
+        Ok(())
+    }
+
+    /// Finds the table a section path leads to. Arrays of tables lead to their last table
+    fn find_section<'t>(&self, root: &'t mut Table, path: &[String]) -> SynthResult<&'t mut Table> {
+        let mut table = root;
+        for key in path {
+            let value = match table.get_mut(key) {
+                Some(value) => value,
+                None => return self.error(&format!("Section \"{}\" doesn't exist", path.join(".")))
+            };
+            table = match value {
+                Value::Table(table) => table,
+                Value::Array(values) => match values.last_mut() {
+                    Some(Value::Table(table)) => table,
+                    _ => return self.error(&format!("\"{}\" isn't a table", key))
+                },
+                _ => return self.error(&format!("\"{}\" isn't a table", key))
+            };
+        }
+        Ok(table)
+    }
+
+    fn parse_key(&mut self) -> SynthResult<String> {
+        match self.chars.peek() {
+            Some('"') => self.parse_string(),
+            _ => {
+                let mut key = String::new();
+                while let Some(c) = self.chars.peek() {
+                    if !is_bare_key_char(*c) {
+                        break;
+                    }
+                    key.push(*c);
+                    self.next_char();
+                }
+                if key.is_empty() {
+                    return self.error("Expected a key");
+                }
+                Ok(key)
+            }
+        }
+    }
+
+    fn parse_key_value(&mut self) -> SynthResult<(String, Value)> {
+        let key = self.parse_key()?;
+        self.skip_spaces();
+        self.expect('=')?;
+        self.skip_spaces();
+        let value = self.parse_value()?;
+        Ok((key, value))
+    }
+
+    fn parse_value(&mut self) -> SynthResult<Value> {
+        match self.chars.peek() {
+            Some('"') => Ok(Value::String(self.parse_string()?)),
+            Some('[') => self.parse_array(),
+            Some('{') => self.parse_inline_table(),
+            Some(_) => self.parse_word(),
+            None => self.error("Expected a value but the file ended")
+        }
+    }
+
+    fn parse_string(&mut self) -> SynthResult<String> {
+        self.expect('"')?;
+        let mut string = String::new();
+        loop {
+            match self.next_char() {
+                Some('"') => return Ok(string),
+                Some('\\') => {
+                    let escaped = match self.next_char() {
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('n') => '\n',
+                        Some('r') => '\r',
+                        Some('t') => '\t',
+                        Some('u') => {
+                            let hex: String = (0..4).filter_map(|_| self.next_char()).collect();
+                            match u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32) {
+                                Some(c) => c,
+                                None => return self.error(&format!("\"\\u{}\" isn't a valid character", hex))
+                            }
+                        }
+                        Some(c) => return self.error(&format!("Unknown escape \"\\{}\" in string", c)),
+                        None => return self.error("String isn't closed")
+                    };
+                    string.push(escaped);
+                }
+                Some('\n') | None => return self.error("String isn't closed"),
+                Some(c) => string.push(c)
+            }
+        }
+    }
+
+    fn parse_array(&mut self) -> SynthResult<Value> {
+        self.expect('[')?;
+        let mut values = Vec::new();
+        loop {
+            self.skip_blank();
+            if self.chars.peek() == Some(&']') {
+                self.next_char();
+                return Ok(Value::Array(values));
+            }
+            values.push(self.parse_value()?);
+            self.skip_blank();
+            match self.next_char() {
+                Some(',') => (),
+                Some(']') => return Ok(Value::Array(values)),
+                _ => return self.error("Expected ',' or ']' in array")
+            }
+        }
+    }
+
+    fn parse_inline_table(&mut self) -> SynthResult<Value> {
+        self.expect('{')?;
+        let mut table = Table::new();
+        self.skip_spaces();
+        if self.chars.peek() == Some(&'}') {
+            self.next_char();
+            return Ok(Value::Table(table));
+        }
+        loop {
+            self.skip_spaces();
+            let (key, value) = self.parse_key_value()?;
+            if table.get(&key).is_some() {
+                return self.error(&format!("\"{}\" is set more than once", key));
+            }
+            table.set(&key, value);
+            self.skip_spaces();
+            match self.next_char() {
+                Some(',') => (),
+                Some('}') => return Ok(Value::Table(table)),
+                _ => return self.error("Expected ',' or '}' in inline table")
+            }
+        }
+    }
+
+    /// Parses booleans and numbers
+    fn parse_word(&mut self) -> SynthResult<Value> {
+        let mut word = String::new();
+        while let Some(c) = self.chars.peek() {
+            if !(c.is_ascii_alphanumeric() || ['+', '-', '.', '_'].contains(c)) {
+                break;
+            }
+            word.push(*c);
+            self.next_char();
+        }
+
+        let number = word.replace('_', "");
+        let unsigned = number.trim_start_matches(['+', '-']);
+        if word == "true" {
+            Ok(Value::Bool(true))
+        }
+        else if word == "false" {
+            Ok(Value::Bool(false))
+        }
+        else if let Ok(integer) = number.parse::<i64>() {
+            Ok(Value::Integer(integer))
+        }
+        else if unsigned == "inf" || unsigned == "nan" || unsigned.starts_with(|c: char| c.is_ascii_digit()) {
+            match number.parse::<f32>() {
+                Ok(float) => Ok(Value::Float(float)),
+                Err(_) => self.error(&format!("\"{}\" isn't a valid number", word))
+            }
+        }
+        else if word.is_empty() {
+            self.error("Expected a value")
+        }
+        else {
+            self.error(&format!("\"{}\" isn't a valid value. Strings need quotes", word))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut step = Table::new();
+        step.set("name", "first \"step\"");
+        step.set("values", vec![0.5_f32, -1.0, 2.0]);
+
+        let mut table = Table::new();
+        table.set("version", 1_i64);
+        table.set("enabled", true);
+        table.set("frequency", 440.0_f32);
+        table.set("quoted key", "line\nbreak");
+        table.set("lanes", Value::Array(vec![vec![0.0_f32, 1.0].into(), Value::Array(Vec::new())]));
+        let mut section = Table::new();
+        section.set("level", 0.25_f32);
+        section.set("steps", Value::Array(vec![step.clone().into(), step.into()]));
+        table.set("section", section);
+
+        let text = table.write();
+        assert_eq!(Table::parse(&text).unwrap(), table, "Table changed after writing:\n{}", text);
+    }
+
+    #[test]
+    fn test_parse() {
+        let text = "
+            # A comment
+            version = 1 # another comment
+            frequency = 1_000
+            pitches = [
+                -1.5,
+                2e1,
+            ]
+            step = { value = 0.5, kind = \"Skip\" }
+
+            [[node]]
+            name = \"first\"
+
+            [[node]]
+            name = \"second\"
+
+            [[node.lane]]
+            values = []
+        ";
+        let table = Table::parse(text).unwrap();
+        assert_eq!(table.get_usize("version").unwrap(), Some(1));
+        assert_eq!(table.get_float("frequency").unwrap(), Some(1000.0));
+        assert_eq!(table.get_array_of("pitches", "a number", Value::as_float).unwrap(), Some(vec![-1.5, 20.0]));
+        assert_eq!(table.get_table("step").unwrap().unwrap().get_str("kind").unwrap(), Some("Skip"));
+
+        let nodes = table.get_array("node").unwrap().unwrap();
+        assert_eq!(nodes.len(), 2);
+        let second = nodes[1].as_table().unwrap();
+        assert_eq!(second.get_str("name").unwrap(), Some("second"));
+        assert_eq!(second.get_array("lane").unwrap().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_errors() {
+        let bad_texts = [
+            "version 1",
+            "name = unquoted",
+            "name = \"not closed",
+            "version = 1\nversion = 2",
+            "values = [1, 2",
+            "[table]\n[table]",
+            "version = 1 extra",
+            "[[nothing.here]]"
+        ];
+        for text in bad_texts.iter() {
+            assert!(Table::parse(text).is_err(), "Expected an error parsing:\n{}", text);
+        }
+
+        let line_error = Table::parse("version = 1\n\nname = unquoted").unwrap_err();
+        assert!(line_error.to_string().starts_with("Patch file line 3"), "Wrong error: {}", line_error);
+
+        let table = Table::parse("version = \"one\"").unwrap();
+        assert!(table.get_usize("version").is_err());
+        assert!(table.check_keys(&["name"]).is_err());
+        assert!(table.check_keys(&["version"]).is_ok());
+    }
+}
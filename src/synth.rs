@@ -1,17 +1,19 @@
 use cpal::SupportedBufferSize;
-use crate::module::{SynthModule, OutputInfo};
+use crate::module::{SynthModule, OutputInfo, Patch};
 
 use super::error::{SynthResult, SynthError};
 use super::module::Output;
 use super::output::AudioInterface;
 use super::clock;
 use super::SignalLogger;
+use super::patch_file;
 
 use std::sync::{Arc, Mutex};
 
 pub struct Synth {
     audio_interface: AudioInterface,
     output_module: Output,
+    patch: Option<Patch>,
     sample_rate: usize,
     master_sample_clock: clock::SampleClock,
     signal_logger: SignalLogger,
@@ -31,6 +33,7 @@ impl Synth {
         let sample_rate = audio_interface.get_sample_rate().0 as usize;
 
         let output_module = Output::new();
+        let patch = None;
         let master_sample_clock = clock::SampleClock::new(sample_rate);
 
         #[cfg(feature = "signal_logging")]
@@ -43,6 +46,7 @@ impl Synth {
         let synth = Synth {
             audio_interface,
             output_module,
+            patch,
             sample_rate,
             master_sample_clock,
             signal_logger,
@@ -59,6 +63,44 @@ impl Synth {
         &mut self.output_module
     }
 
+    /// Plays a patch. The patch's output is connected to the output module's audio input
+    pub fn set_patch(&mut self, patch: Patch) {
+        self.output_module.set_audio_input(Some(patch.get_output_module()));
+        self.patch = Some(patch);
+    }
+
+    pub fn get_patch(&self) -> Option<&Patch> {
+        self.patch.as_ref()
+    }
+
+    pub fn get_patch_mut(&mut self) -> Option<&mut Patch> {
+        self.patch.as_mut()
+    }
+
+    /// Stops playing the patch and gives it back
+    pub fn take_patch(&mut self) -> Option<Patch> {
+        let patch = self.patch.take();
+        if patch.is_some() {
+            self.output_module.set_audio_input(None);
+        }
+        patch
+    }
+
+    /// Loads a patch file and plays it. The file's output settings are applied to the output module
+    pub fn load_patch_file<P: AsRef<std::path::Path>>(&mut self, path: P) -> SynthResult<()> {
+        let patch = patch_file::load_patch_file(path, Some(&mut self.output_module))?;
+        self.set_patch(patch);
+        Ok(())
+    }
+
+    /// Saves the patch that's playing and the output module's settings to a patch file
+    pub fn save_patch_file<P: AsRef<std::path::Path>>(&self, path: P) -> SynthResult<()> {
+        match &self.patch {
+            Some(patch) => patch_file::save_patch_file(path, patch, Some(&self.output_module)),
+            None => Err(SynthError::new("Can't save a patch file because no patch is playing"))
+        }
+    }
+
     fn init_cpal_callback<T: cpal::Sample>(&mut self) -> SynthResult<()> {
         let audio_queue = self.audio_queue.clone();
         let callback = move |audio: &mut [T], _callback_info: &cpal::OutputCallbackInfo| {
@@ -127,6 +169,9 @@ impl Synth {
             self.master_sample_clock.get_range(n_mono_samples),
             std::time::Instant::now() // wrong
         );
+        if let Some(patch) = &self.patch {
+            patch.render(n_mono_samples, &output_info);
+        }
         self.output_module.fill_output_buffer(&mut multi_channel_audio, &output_info);
 
         if let Ok(mut audio_queue) = self.audio_queue.lock() {
@@ -0,0 +1,405 @@
+use std::cell::RefCell;
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::note::{Note, FREQ_A};
+use crate::error::{SynthError, SynthResult};
+
+const CENTS_PER_OCTAVE: f64 = 1200.0;
+const MIDI_NOTE_COUNT: usize = 128;
+const MIDI_NOTE_A4: u8 = 69;
+const MIDI_NOTE_C4: u8 = 60;
+
+thread_local! {
+    static GLOBAL_TUNING: RefCell<Rc<Tuning>> = RefCell::new(Rc::new(Tuning::new()));
+}
+
+/// Sets the tuning used by every module that hasn't been given a tuning of its own
+pub fn set_global_tuning(tuning: Tuning) {
+    GLOBAL_TUNING.with(|global_tuning| *global_tuning.borrow_mut() = Rc::new(tuning));
+}
+
+pub fn get_global_tuning() -> Rc<Tuning> {
+    GLOBAL_TUNING.with(|global_tuning| global_tuning.borrow().clone())
+}
+
+/// Gets the lines of a Scala file that aren't comments
+fn scala_lines(text: &str) -> impl Iterator<Item = &str> {
+    text.lines().filter(|line| !line.starts_with('!'))
+}
+
+/// Gets the first whitespace separated value on a Scala file line
+fn scala_value(line: Option<&str>, name: &str) -> SynthResult<String> {
+    match line.and_then(|line| line.split_whitespace().next()) {
+        Some(value) => Ok(value.to_string()),
+        None => {
+            let msg = format!("Scala file is missing its {}", name);
+            Err(SynthError::new(&msg))
+        }
+    }
+}
+
+fn parse_scala_value<T: std::str::FromStr>(line: Option<&str>, name: &str) -> SynthResult<T> {
+    let value = scala_value(line, name)?;
+    match value.parse() {
+        Ok(parsed) => Ok(parsed),
+        Err(_) => {
+            let msg = format!("Failed to parse {} \"{}\" in Scala file", name, value);
+            Err(SynthError::new(&msg))
+        }
+    }
+}
+
+fn read_file(path: &Path) -> SynthResult<String> {
+    match std::fs::read_to_string(path) {
+        Ok(text) => Ok(text),
+        Err(err) => {
+            let msg = format!("Failed to read tuning file {}: {}", path.display(), err);
+            Err(SynthError::new(&msg))
+        }
+    }
+}
+
+/// Says which scale degree each MIDI key plays and which key is tuned to a reference frequency.
+/// This is the information in a Scala .kbm file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyboardMapping {
+    /// Number of keys before the mapping repeats. 0 means every key is the next scale degree
+    map_size: usize,
+    first_note: u8,
+    last_note: u8,
+    /// Key that plays the first entry of the mapping
+    middle_note: u8,
+    reference_note: u8,
+    reference_freq: f32,
+    /// Scale degree the mapping moves up by each time it repeats. 0 means the size of the scale
+    octave_degree: usize,
+    /// Scale degree for each key in the map. `None` keys aren't played
+    mapping: Vec<Option<usize>>
+}
+
+impl KeyboardMapping {
+    /// A mapping where each key plays the next scale degree, with `middle_note` playing the
+    /// first degree and `reference_note` tuned to `reference_freq`
+    pub fn new(middle_note: u8, reference_note: u8, reference_freq: f32) -> Self {
+        let map_size = 0;
+        let first_note = 0;
+        let last_note = (MIDI_NOTE_COUNT - 1) as u8;
+        let octave_degree = 0;
+        let mapping = Vec::new();
+        Self { map_size, first_note, last_note, middle_note, reference_note, reference_freq, octave_degree, mapping }
+    }
+
+    /// Parses the contents of a Scala .kbm file
+    pub fn from_kbm_str(text: &str) -> SynthResult<Self> {
+        let mut lines = scala_lines(text);
+        let map_size: usize = parse_scala_value(lines.next(), "map size")?;
+        let first_note: u8 = parse_scala_value(lines.next(), "first note")?;
+        let last_note: u8 = parse_scala_value(lines.next(), "last note")?;
+        let middle_note: u8 = parse_scala_value(lines.next(), "middle note")?;
+        let reference_note: u8 = parse_scala_value(lines.next(), "reference note")?;
+        let reference_freq: f32 = parse_scala_value(lines.next(), "reference frequency")?;
+        let octave_degree: usize = parse_scala_value(lines.next(), "octave degree")?;
+
+        let mut mapping = Vec::with_capacity(map_size);
+        for line in lines.filter(|line| !line.trim().is_empty()).take(map_size) {
+            let entry = scala_value(Some(line), "mapping")?;
+            if entry == "x" {
+                mapping.push(None);
+            }
+            else {
+                mapping.push(Some(parse_scala_value(Some(line), "mapping")?));
+            }
+        }
+        // Keys missing from the end of the mapping aren't played
+        mapping.resize(map_size, None);
+
+        if reference_freq <= 0.0 {
+            let msg = format!("Keyboard mapping reference frequency must be positive. Got {}", reference_freq);
+            return Err(SynthError::new(&msg));
+        }
+        Ok(Self { map_size, first_note, last_note, middle_note, reference_note, reference_freq, octave_degree, mapping })
+    }
+
+    pub fn from_kbm_file<P: AsRef<Path>>(path: P) -> SynthResult<Self> {
+        Self::from_kbm_str(&read_file(path.as_ref())?)
+    }
+
+    pub fn get_reference_note(&self) -> u8 {
+        self.reference_note
+    }
+
+    pub fn get_reference_freq(&self) -> f32 {
+        self.reference_freq
+    }
+
+    /// Gets the scale degree a key plays, counting from the middle note
+    fn get_degree(&self, midi_note: u8, scale_size: usize) -> Option<i64> {
+        if midi_note < self.first_note || midi_note > self.last_note {
+            return None;
+        }
+        let offset = midi_note as i64 - self.middle_note as i64;
+        if self.map_size == 0 {
+            return Some(offset);
+        }
+
+        let map_size = self.map_size as i64;
+        let octave_degree = if self.octave_degree == 0 { scale_size } else { self.octave_degree } as i64;
+        let degree = self.mapping[offset.rem_euclid(map_size) as usize]?;
+        Some(degree as i64 + offset.div_euclid(map_size) * octave_degree)
+    }
+}
+
+/// A tuning system. Made from a scale, which is a list of pitches in cents that repeats every
+/// period, and a keyboard mapping which says how MIDI keys play the scale.
+/// The default is 12 tone equal temperament with A4 at 440hz.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tuning {
+    /// Cents above the first degree for each degree after it. The last one is the period, usually an octave
+    scale: Vec<f64>,
+    mapping: KeyboardMapping,
+    /// Frequency of every MIDI key. Unmapped keys are `None`
+    key_freqs: Vec<Option<f32>>
+}
+
+impl Tuning {
+    pub fn new() -> Self {
+        Self::equal_divisions(12, FREQ_A).expect("Whoops! 12 tone equal temperament should be valid")
+    }
+
+    /// Divides the octave into `divisions` equal steps with A4 tuned to `reference_freq`
+    pub fn equal_divisions(divisions: usize, reference_freq: f32) -> SynthResult<Self> {
+        if divisions == 0 {
+            return Err(SynthError::new("An octave can't be divided into 0 steps"));
+        }
+        let step = CENTS_PER_OCTAVE / divisions as f64;
+        let scale = (1..=divisions).map(|degree| degree as f64 * step).collect();
+        Self::with_mapping(scale, KeyboardMapping::new(MIDI_NOTE_A4, MIDI_NOTE_A4, reference_freq))
+    }
+
+    /// Creates a tuning from a scale in cents, which should end with its period, and a keyboard mapping
+    pub fn with_mapping(scale: Vec<f64>, mapping: KeyboardMapping) -> SynthResult<Self> {
+        if scale.is_empty() {
+            return Err(SynthError::new("A tuning needs at least one scale degree"));
+        }
+        if mapping.reference_freq <= 0.0 {
+            let msg = format!("Tuning reference frequency must be positive. Got {}", mapping.reference_freq);
+            return Err(SynthError::new(&msg));
+        }
+        if let Some(Some(max_degree)) = mapping.mapping.iter().max() {
+            if *max_degree > scale.len() {
+                let msg = format!("Keyboard mapping uses degree {} but the scale only has {}", max_degree, scale.len());
+                return Err(SynthError::new(&msg));
+            }
+        }
+
+        let mut tuning = Self { scale, mapping, key_freqs: Vec::new() };
+        let reference_cents = match tuning.get_key_cents(tuning.mapping.reference_note) {
+            Some(reference_cents) => reference_cents,
+            None => {
+                let msg = format!("Tuning reference note {} isn't mapped to a scale degree", tuning.mapping.reference_note);
+                return Err(SynthError::new(&msg));
+            }
+        };
+        let reference_freq = tuning.mapping.reference_freq as f64;
+        tuning.key_freqs = (0..MIDI_NOTE_COUNT).map(|midi_note| {
+            let cents = tuning.get_key_cents(midi_note as u8)?;
+            Some((reference_freq * 2_f64.powf((cents - reference_cents) / CENTS_PER_OCTAVE)) as f32)
+        })
+        .collect();
+        Ok(tuning)
+    }
+
+    /// Parses the contents of a Scala .scl file. The scale is mapped onto the keyboard with
+    /// middle C playing the first degree and A4 at 440hz
+    pub fn from_scl_str(text: &str) -> SynthResult<Self> {
+        let mut lines = scala_lines(text);
+        // The first line is a description which can be anything, even empty
+        if lines.next().is_none() {
+            return Err(SynthError::new("Scala file is missing its description"));
+        }
+        let note_count: usize = parse_scala_value(lines.next(), "note count")?;
+
+        let mut scale = Vec::with_capacity(note_count);
+        for _ in 0..note_count {
+            let pitch = scala_value(lines.next(), "pitches")?;
+            scale.push(Self::parse_scl_pitch(&pitch)?);
+        }
+        Self::with_mapping(scale, KeyboardMapping::new(MIDI_NOTE_C4, MIDI_NOTE_A4, FREQ_A))
+    }
+
+    pub fn from_scl_file<P: AsRef<Path>>(path: P) -> SynthResult<Self> {
+        Self::from_scl_str(&read_file(path.as_ref())?)
+    }
+
+    /// Parses a pitch from a Scala file into cents. Pitches with a '.' are in cents, anything
+    /// else is a ratio like "3/2" or a whole number like "2"
+    fn parse_scl_pitch(pitch: &str) -> SynthResult<f64> {
+        let invalid_pitch = || {
+            let msg = format!("Failed to parse pitch \"{}\" in Scala file", pitch);
+            SynthError::new(&msg)
+        };
+
+        if pitch.contains('.') {
+            return pitch.parse().map_err(|_| invalid_pitch());
+        }
+        let (numerator, denominator) = match pitch.split_once('/') {
+            Some((numerator, denominator)) => (numerator, denominator),
+            None => (pitch, "1")
+        };
+        let numerator: f64 = numerator.parse().map_err(|_| invalid_pitch())?;
+        let denominator: f64 = denominator.parse().map_err(|_| invalid_pitch())?;
+        if numerator <= 0.0 || denominator <= 0.0 {
+            return Err(invalid_pitch());
+        }
+        Ok(CENTS_PER_OCTAVE * (numerator / denominator).log2())
+    }
+
+    pub fn set_keyboard_mapping(&mut self, mapping: KeyboardMapping) -> SynthResult<()> {
+        *self = Self::with_mapping(self.scale.clone(), mapping)?;
+        Ok(())
+    }
+
+    pub fn get_keyboard_mapping(&self) -> &KeyboardMapping {
+        &self.mapping
+    }
+
+    /// Tunes the reference note of the keyboard mapping to a new frequency
+    pub fn set_reference_freq(&mut self, reference_freq: f32) -> SynthResult<()> {
+        let mut mapping = self.mapping.clone();
+        mapping.reference_freq = reference_freq;
+        self.set_keyboard_mapping(mapping)
+    }
+
+    pub fn get_scale(&self) -> &[f64] {
+        &self.scale
+    }
+
+    /// Gets the frequency of a MIDI key. Keys that aren't mapped to anything give `None`
+    pub fn get_freq(&self, midi_note: u8) -> Option<f32> {
+        self.key_freqs.get(midi_note as usize).cloned().flatten()
+    }
+
+    /// Gets the frequency of the key that would normally play `note`
+    pub fn get_note_freq(&self, note: &Note) -> Option<f32> {
+        self.get_freq(note.to_midi_note().ok()?)
+    }
+
+    fn get_key_cents(&self, midi_note: u8) -> Option<f64> {
+        let degree = self.mapping.get_degree(midi_note, self.scale.len())?;
+        let scale_size = self.scale.len() as i64;
+        let period = self.scale[self.scale.len() - 1];
+        let degree_in_period = degree.rem_euclid(scale_size) as usize;
+        let cents_in_period = if degree_in_period == 0 { 0.0 } else { self.scale[degree_in_period - 1] };
+        Some(degree.div_euclid(scale_size) as f64 * period + cents_in_period)
+    }
+}
+
+impl Default for Tuning {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::*;
+
+    const JUST_MAJOR_SCL: &str = "! just.scl
+!
+5-limit just major scale
+ 7
+!
+ 9/8
+ 5/4
+ 4/3
+ 3/2
+ 5/3
+ 15/8
+ 2/1
+";
+
+    fn assert_freq_eq(got: Option<f32>, expected: f32) {
+        let got = got.expect("Expected key to be mapped");
+        assert!(float_eq(got, expected, 0.01), "Expected {}hz. Got {}hz", expected, got);
+    }
+
+    #[test]
+    fn test_equal_temperament() {
+        let tuning = Tuning::new();
+        for midi_note in 0..=127 {
+            let note = Note::from_midi_note(midi_note);
+            let expected = note.to_freq();
+            let got = tuning.get_note_freq(&note).unwrap();
+            assert!(float_eq(got, expected, expected * 0.0001), "Expected {}hz for {}. Got {}hz", expected, note, got);
+        }
+    }
+
+    #[test]
+    fn test_equal_divisions_and_reference() {
+        let mut tuning = Tuning::equal_divisions(19, 440.0).unwrap();
+        assert_freq_eq(tuning.get_freq(69 + 19), 880.0);
+        assert_freq_eq(tuning.get_freq(70), 440.0 * 2_f32.powf(1.0 / 19.0));
+
+        tuning.set_reference_freq(432.0).unwrap();
+        assert_freq_eq(tuning.get_freq(69), 432.0);
+        assert!(Tuning::equal_divisions(0, 440.0).is_err());
+        assert!(tuning.set_reference_freq(0.0).is_err());
+    }
+
+    #[test]
+    fn test_scl() {
+        let tuning = Tuning::from_scl_str(JUST_MAJOR_SCL).unwrap();
+        assert_eq!(tuning.get_scale().len(), 7);
+        // Every key plays the next degree so A4, the reference, is 9 degrees above middle C.
+        // That's an octave and a major third
+        let c4 = 440.0 / (2.0 * 5.0 / 4.0);
+        assert_freq_eq(tuning.get_freq(60), c4);
+        assert_freq_eq(tuning.get_freq(64), c4 * 3.0 / 2.0);
+        assert_freq_eq(tuning.get_freq(60 - 7), c4 / 2.0);
+
+        let cents = Tuning::from_scl_str("cents\n2\n700.0\n1200.0\n").unwrap();
+        assert!((cents.get_scale()[0] - 700.0).abs() < 0.0001);
+
+        assert!(Tuning::from_scl_str("bad\n2\n3/2\n").is_err(), "Expected missing pitches to fail");
+        assert!(Tuning::from_scl_str("bad\n1\n-3/2\n").is_err(), "Expected negative ratios to fail");
+        assert!(Tuning::from_scl_str("bad\none\n3/2\n").is_err(), "Expected a bad note count to fail");
+    }
+
+    #[test]
+    fn test_kbm() {
+        // Map the 7 note scale onto the white keys only, with A4 at 440hz
+        let kbm = "! white keys
+12
+0
+127
+60
+69
+440.0
+7
+0
+x
+1
+x
+2
+3
+x
+4
+x
+5
+x
+6
+";
+        let mapping = KeyboardMapping::from_kbm_str(kbm).unwrap();
+        let mut tuning = Tuning::from_scl_str(JUST_MAJOR_SCL).unwrap();
+        tuning.set_keyboard_mapping(mapping).unwrap();
+
+        let c4 = 440.0 * 3.0 / 5.0;
+        assert_freq_eq(tuning.get_freq(60), c4);
+        assert!(tuning.get_freq(61).is_none(), "Expected black keys to be unmapped");
+        assert_freq_eq(tuning.get_freq(67), c4 * 3.0 / 2.0);
+        assert_freq_eq(tuning.get_freq(72), c4 * 2.0);
+        assert_freq_eq(tuning.get_freq(69), 440.0);
+    }
+}
@@ -2,6 +2,19 @@ pub fn float_eq(a: f32, b:f32, variation: f32) -> bool {
     f32::abs(a - b) < variation
 }
 
+/// Lowest level in decibels that `amplitude_to_db` will report. Keeps silence from becoming -inf
+pub const MIN_DB: f32 = -120.0;
+
+/// Converts a linear amplitude to decibels relative to full scale
+pub fn amplitude_to_db(amplitude: f32) -> f32 {
+    (20.0 * amplitude.abs().log10()).max(MIN_DB)
+}
+
+/// Converts decibels relative to full scale to a linear amplitude
+pub fn db_to_amplitude(db: f32) -> f32 {
+    10_f32.powf(db / 20.0)
+}
+
 #[cfg(test)]
 pub mod test_util {
     use std::path::PathBuf;